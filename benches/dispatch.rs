@@ -0,0 +1,129 @@
+//! Compares how fast jobs get from many producers to the workers that run them.
+//!
+//! `ThreadPool` is measured against the scheme it used to have, where every worker
+//! took jobs from one `mpsc::Receiver` behind a mutex and held the lock while it
+//! waited. Both run the same trivial job and do the same per-job bookkeeping, so
+//! the difference between them is the dispatch.
+//!
+//! Run with `cargo bench --bench dispatch`. Workers log each job to stdout, so
+//! redirect it; the results go to stderr.
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::ThreadPool;
+
+const WORKERS: usize = 4;
+const PRODUCERS: &[usize] = &[1, 4, 16];
+const JOBS_PER_PRODUCER: usize = 20_000;
+const ROUNDS: usize = 3;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// The pool's old dispatch: a channel whose receiver the workers take turns to lock.
+// It counts pending and busy jobs as `ThreadPool` does.
+struct MutexReceiverPool {
+    sender: Option<mpsc::Sender<Job>>,
+    pending: Arc<AtomicUsize>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MutexReceiverPool {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let busy = Arc::new(AtomicUsize::new(0));
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let pending = Arc::clone(&pending);
+                let busy = Arc::clone(&busy);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => {
+                            println!("Worker {id} got a job; executing.");
+                            pending.fetch_sub(1, Ordering::SeqCst);
+                            busy.fetch_add(1, Ordering::SeqCst);
+                            job();
+                            busy.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        MutexReceiverPool { sender: Some(sender), pending, workers }
+    }
+
+    fn execute(&self, job: Job) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for MutexReceiverPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// Sends `producers * JOBS_PER_PRODUCER` jobs from `producers` threads at once and
+// returns how long it took for all of them to run.
+fn run<P: Sync + Send + 'static>(pool: Arc<P>, producers: usize, execute: fn(&P, Job)) -> Duration {
+    let total = producers * JOBS_PER_PRODUCER;
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Arc::new(Barrier::new(producers + 1));
+    let handles: Vec<_> = (0..producers)
+        .map(|_| {
+            let pool = Arc::clone(&pool);
+            let done = Arc::clone(&done);
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                start.wait();
+                for i in 0..JOBS_PER_PRODUCER {
+                    let done = Arc::clone(&done);
+                    execute(&pool, Box::new(move || {
+                        black_box(i);
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }));
+                }
+            })
+        })
+        .collect();
+
+    start.wait();
+    let began = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    while done.load(Ordering::Relaxed) < total {
+        thread::yield_now();
+    }
+    began.elapsed()
+}
+
+fn best_of(mut round: impl FnMut() -> Duration) -> Duration {
+    (0..ROUNDS).map(|_| round()).min().unwrap()
+}
+
+fn main() {
+    eprintln!("{WORKERS} workers, {JOBS_PER_PRODUCER} jobs per producer, best of {ROUNDS}");
+    for &producers in PRODUCERS {
+        let jobs = (producers * JOBS_PER_PRODUCER) as f64;
+        let old = best_of(|| run(Arc::new(MutexReceiverPool::new(WORKERS)), producers, |pool, job| pool.execute(job)));
+        let new = best_of(|| run(Arc::new(ThreadPool::new(WORKERS)), producers, |pool, job| pool.execute(job)));
+        eprintln!(
+            "{producers:>3} producers: mutex receiver {:>10.0} jobs/s, job queue {:>10.0} jobs/s",
+            jobs / old.as_secs_f64(),
+            jobs / new.as_secs_f64(),
+        );
+    }
+}
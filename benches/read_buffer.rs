@@ -0,0 +1,63 @@
+//! Measures how the transmitter's read buffer size affects relay throughput.
+//!
+//! A client streams the same run of frames over loopback TCP into
+//! `handle_transmitter` once per buffer size, with no receivers attached, so the
+//! time taken is the reads and the parsing. Small buffers mean a read call for
+//! every few bytes; large ones let a burst of frames through in a single call.
+//!
+//! Run with `cargo bench --bench read_buffer`. The relay logs each frame header to
+//! stdout, so redirect it; the results go to stderr.
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::{build_frame, handle_transmitter, Destinations, TransmitterPolicy};
+
+const BUFFER_SIZES: &[usize] = &[16, 1024, 8 * 1024, 64 * 1024, 1024 * 1024];
+const PAYLOAD_SIZES: &[usize] = &[64, 1024, 32 * 1024];
+const STREAM_BYTES: usize = 64 * 1024 * 1024;
+const ROUNDS: usize = 3;
+
+// Relays `input` from a loopback client and returns how long the session took.
+fn run(input: &Arc<Vec<u8>>, read_buffer_size: usize) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = {
+        let input = Arc::clone(input);
+        thread::spawn(move || TcpStream::connect(addr).unwrap().write_all(&input).unwrap())
+    };
+    let (stream, peer) = listener.accept().unwrap();
+    let destinations = Destinations::<TcpStream>::default();
+    let slot = Arc::new(Mutex::new(None::<TcpStream>));
+    let policy = TransmitterPolicy { read_buffer_size, ..TransmitterPolicy::default() };
+
+    let began = Instant::now();
+    handle_transmitter(stream, Some(peer), destinations, slot, policy);
+    let elapsed = began.elapsed();
+    client.join().unwrap();
+    elapsed
+}
+
+fn best_of(mut round: impl FnMut() -> Duration) -> Duration {
+    (0..ROUNDS).map(|_| round()).min().unwrap()
+}
+
+fn main() {
+    eprintln!("{} MiB per session, best of {ROUNDS}", STREAM_BYTES >> 20);
+    for &payload_size in PAYLOAD_SIZES {
+        let frame = build_frame(&vec![0x5A; payload_size], true).unwrap();
+        let frames = STREAM_BYTES / frame.len();
+        let input = Arc::new(frame.repeat(frames));
+        for &buffer_size in BUFFER_SIZES {
+            let elapsed = best_of(|| run(&input, buffer_size));
+            eprintln!(
+                "{payload_size:>6} byte frames, {buffer_size:>8} byte buffer: {:>10.0} frames/s, {:>7.1} MiB/s",
+                frames as f64 / elapsed.as_secs_f64(),
+                input.len() as f64 / elapsed.as_secs_f64() / (1 << 20) as f64,
+            );
+        }
+    }
+}
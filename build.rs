@@ -0,0 +1,22 @@
+// Records the commit the crate was built from, for `coretech_wirestorm::version`.
+use std::process::Command;
+
+fn main() {
+    let version = env!("CARGO_PKG_VERSION");
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty());
+
+    // builds from a source archive have no repository to ask
+    match hash {
+        Some(hash) => println!("cargo:rustc-env=WIRESTORM_VERSION={} ({})", version, hash),
+        None => println!("cargo:rustc-env=WIRESTORM_VERSION={}", version),
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
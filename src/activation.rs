@@ -0,0 +1,84 @@
+//! Adopting listeners passed in by a supervisor (systemd socket activation).
+//!
+//! When started with `LISTEN_PID` set to our process id and `LISTEN_FDS` set to the
+//! number of sockets, the sockets are already open at file descriptors 3 onwards.
+//! Adopting them instead of binding means the listening sockets outlive the process,
+//! so a restart never refuses connections.
+//!
+//! If `LISTEN_FDNAMES` names the sockets `source` and `destination` they are matched
+//! by name; otherwise the first is the source listener and the second, if present,
+//! the destination listener.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+
+/// The first file descriptor passed by the supervisor.
+pub const LISTEN_FDS_START: i32 = 3;
+
+/// Listeners inherited from the supervisor.
+#[derive(Debug)]
+pub struct InheritedListeners {
+    /// The listener transmitters connect to.
+    pub source: TcpListener,
+    /// The listener receivers connect to, if one was passed.
+    pub destination: Option<TcpListener>,
+}
+
+/// Returns the listeners passed to this process, or `None` if there are none.
+///
+/// # Returns
+///
+/// * `Ok(Some(_))` - The adopted listeners.
+/// * `Ok(None)` - Socket activation is not in use for this process.
+/// * `Err(io::Error)` - The environment names sockets that could not be adopted.
+pub fn inherited_listeners() -> io::Result<Option<InheritedListeners>> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    if !for_us {
+        return Ok(None);
+    }
+    let count = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => return Ok(None),
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let names: Vec<&str> = names.split(':').collect();
+
+    let by_name = names.iter().any(|n| *n == "source" || *n == "destination");
+    let find = |name: &str, position: i32| -> Option<i32> {
+        let index = match by_name {
+            true => names.iter().position(|n| *n == name)? as i32,
+            false => position,
+        };
+        (index < count).then_some(LISTEN_FDS_START + index)
+    };
+
+    let source = find("source", 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no inherited source listener"))?;
+    let destination = find("destination", 1);
+
+    Ok(Some(InheritedListeners {
+        source: adopt(source)?,
+        destination: destination.map(adopt).transpose()?,
+    }))
+}
+
+// Takes ownership of an inherited descriptor, checking that it is a listening TCP socket.
+#[cfg(unix)]
+fn adopt(fd: i32) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: the supervisor passed this descriptor to us for exactly this purpose and
+    // nothing else in the process owns it.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.local_addr()?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn adopt(_fd: i32) -> io::Result<TcpListener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "socket activation needs a unix platform"))
+}
@@ -0,0 +1,98 @@
+use std::{fs::{self, File}, io::BufReader, path::PathBuf, process};
+// Offline capture analysis lives in the library so it can be tested directly.
+use coretech_wirestorm::inspect;
+
+const USAGE: &str = "usage: ctmp-inspect <file> [--json] [--extract N --out DIR]";
+
+// Parsed command line options.
+struct Args {
+    file: PathBuf,
+    json: bool,
+    extract: Option<u64>,
+    out: Option<PathBuf>,
+}
+
+// Parses the command line, returning a message suitable for the user on error.
+fn parse_args() -> Result<Args, String> {
+    let mut file = None;
+    let mut json = false;
+    let mut extract = None;
+    let mut out = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--extract" => {
+                let value = args.next().ok_or("--extract needs a frame index")?;
+                extract = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid frame index: {}", value))?,
+                );
+            }
+            "--out" => out = Some(PathBuf::from(args.next().ok_or("--out needs a directory")?)),
+            "-h" | "--help" => return Err(USAGE.into()),
+            _ if file.is_none() => file = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let file = file.ok_or(USAGE)?;
+    if extract.is_some() != out.is_some() {
+        return Err("--extract and --out must be used together".into());
+    }
+    Ok(Args { file, json, extract, out })
+}
+
+// Entry point for the inspector.
+// Prints a report for the capture, or dumps one frame's payload with --extract.
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let open = || {
+        File::open(&args.file).map(BufReader::new).unwrap_or_else(|e| {
+            eprintln!("Failed to open {}: {}", args.file.display(), e);
+            process::exit(1);
+        })
+    };
+
+    if let (Some(index), Some(out)) = (args.extract, &args.out) {
+        let payload = match inspect::extract(open(), index) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => {
+                eprintln!("No complete frame at index {}", index);
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", args.file.display(), e);
+                process::exit(1);
+            }
+        };
+        let path = out.join(format!("frame-{}.bin", index));
+        if let Err(e) = fs::create_dir_all(out).and_then(|_| fs::write(&path, &payload)) {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        eprintln!("Wrote {} bytes to {}", payload.len(), path.display());
+        return;
+    }
+
+    let report = inspect::analyze(open()).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", args.file.display(), e);
+        process::exit(1);
+    });
+
+    if args.json {
+        println!("{}", report.to_json());
+        // keep the warning visible to whoever runs the script
+        if let Some(offset) = report.truncated_at {
+            eprintln!("warning: truncated final frame at byte {}", offset);
+        }
+    } else {
+        print!("{}", report);
+    }
+}
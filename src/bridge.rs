@@ -0,0 +1,207 @@
+//! Adapters between CTMP and a plain length-prefixed framing.
+//!
+//! Some producers and consumers speak a simpler protocol: each message is a 4-byte
+//! big-endian length followed by that many bytes. [`LengthPrefixedSource`] reads such
+//! a stream and presents it as a stream of CTMP frames, so it can be handed to
+//! [`handle_transmitter`](crate::handle_transmitter) like any other transmitter and
+//! goes through the same validation and broadcast path. [`LengthPrefixedSink`] goes the
+//! other way: frames broadcast to it are stripped of their CTMP header and written out
+//! length-prefixed.
+
+use std::io::{self, Read, Write};
+
+use crate::config::{BridgeConfig, OversizePolicy};
+use crate::frame::CtmpFrame;
+use crate::{read_full, Transport, CTMP_HEADER_LEN, CTMP_MAGIC_BYTE, CTMP_MAX_PAYLOAD_SIZE};
+
+// The largest payload that fits in a frame's 16-bit length field.
+const MAX_MESSAGE_LEN: usize = if CTMP_MAX_PAYLOAD_SIZE < u16::MAX as usize {
+    CTMP_MAX_PAYLOAD_SIZE
+} else {
+    u16::MAX as usize
+};
+
+/// Reads length-prefixed messages and yields them as encoded CTMP frames.
+///
+/// Empty messages have no CTMP equivalent and are skipped. Messages larger than a
+/// frame can carry are handled by the configured [`OversizePolicy`].
+pub struct LengthPrefixedSource<R> {
+    inner: R,
+    sensitive: bool,
+    oversize: OversizePolicy,
+    // the encoded frame currently being handed out, and how much of it has been read
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> LengthPrefixedSource<R> {
+    /// Wraps a length-prefixed stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The producer's stream.
+    /// * `config` - Whether frames are marked sensitive and how oversized messages are handled.
+    pub fn new(inner: R, config: &BridgeConfig) -> Self {
+        LengthPrefixedSource {
+            inner,
+            sensitive: config.sensitive,
+            oversize: config.oversize,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Reads the next message and wraps it in a frame.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(CtmpFrame))` - The next message as a frame.
+    /// * `Ok(None)` - The stream ended cleanly between messages.
+    /// * `Err(io::Error)` - A read failure, a stream that ends part way through a
+    ///   message, or an `InvalidData` error for an oversized message under
+    ///   [`OversizePolicy::Reject`].
+    pub fn next_frame(&mut self) -> io::Result<Option<CtmpFrame>> {
+        loop {
+            let mut prefix = [0u8; 4];
+            match self.inner.read(&mut prefix[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => read_full(&mut self.inner, &mut prefix[1..], None)?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            let length = u32::from_be_bytes(prefix) as usize;
+
+            if length == 0 {
+                continue;
+            }
+            if length > MAX_MESSAGE_LEN {
+                match self.oversize {
+                    OversizePolicy::Reject => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("message of {} bytes exceeds the CTMP payload limit", length),
+                        ));
+                    }
+                    OversizePolicy::Drop => {
+                        eprintln!("Dropping bridged message of {} bytes, too large for a frame", length);
+                        let mut scratch = [0u8; 4096];
+                        let mut remaining = length;
+                        while remaining > 0 {
+                            let n = remaining.min(scratch.len());
+                            read_full(&mut self.inner, &mut scratch[..n], None)?;
+                            remaining -= n;
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            let mut payload = vec![0u8; length];
+            read_full(&mut self.inner, &mut payload, None)?;
+            let frame = CtmpFrame::new(payload, self.sensitive)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            return Ok(Some(frame));
+        }
+    }
+
+    /// Returns the wrapped stream.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for LengthPrefixedSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.pending.len() {
+            match self.next_frame()? {
+                Some(frame) => {
+                    self.pending = frame.to_bytes();
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Accepts encoded CTMP frames and writes each payload out length-prefixed.
+///
+/// Frames may arrive split across any number of writes; a payload is only written
+/// once its whole frame has been received. Once the wrapped writer fails, every
+/// later write fails too, since the output can no longer be trusted to be aligned.
+pub struct LengthPrefixedSink<W> {
+    inner: W,
+    buf: Vec<u8>,
+    broken: bool,
+}
+
+impl<W: Write> LengthPrefixedSink<W> {
+    /// Wraps a consumer's stream.
+    pub fn new(inner: W) -> Self {
+        LengthPrefixedSink { inner, buf: Vec::new(), broken: false }
+    }
+
+    /// The wrapped stream.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns the wrapped stream, discarding any partially received frame.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // Writes out every complete frame in the buffer.
+    fn emit(&mut self) -> io::Result<()> {
+        while self.buf.len() >= CTMP_HEADER_LEN {
+            if self.buf[0] != CTMP_MAGIC_BYTE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a CTMP frame"));
+            }
+            let length = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+            if self.buf.len() < CTMP_HEADER_LEN + length {
+                break;
+            }
+            let payload = &self.buf[CTMP_HEADER_LEN..CTMP_HEADER_LEN + length];
+            self.inner.write_all(&(length as u32).to_be_bytes())?;
+            self.inner.write_all(payload)?;
+            self.buf.drain(..CTMP_HEADER_LEN + length);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for LengthPrefixedSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.broken {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "length-prefixed sink has failed"));
+        }
+        self.buf.extend_from_slice(buf);
+        if let Err(e) = self.emit() {
+            self.broken = true;
+            return Err(e);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Transport> Transport for LengthPrefixedSink<W> {
+    fn set_write_timeout(&self, timeout: Option<std::time::Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
@@ -0,0 +1,239 @@
+//! Checksum algorithms for sensitive frames.
+//!
+//! The protocol defines a 16-bit one's-complement sum, which is what every current
+//! transmitter sends and what [`ChecksumAlgorithm::default`] selects. Other algorithms
+//! can be selected per transmitter listener, for peers that agree to use them. Each
+//! algorithm covers the header, with its checksum bytes set to `0xCC`, followed by the
+//! payload.
+//!
+//! Separately, [`TrailingCrc`] reads the extra CRC that some producers append after
+//! each payload, outside the frame's declared length.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::CTMP_MAGIC_BYTE;
+
+/// How the checksum of a sensitive frame is computed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    /// The protocol's 16-bit one's-complement sum of big-endian words.
+    #[default]
+    OnesComplement,
+    /// CRC-16/CCITT-FALSE: polynomial `0x1021`, initial value `0xFFFF`, no reflection.
+    Crc16,
+}
+
+impl ChecksumAlgorithm {
+    /// Computes the checksum of raw bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::checksum::ChecksumAlgorithm;
+    /// // RFC 1071, section 3
+    /// let words = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+    /// assert_eq!(ChecksumAlgorithm::OnesComplement.checksum(&words), 0x220D);
+    /// assert_eq!(ChecksumAlgorithm::Crc16.checksum(b"123456789"), 0x29B1);
+    /// ```
+    pub fn checksum(self, data: &[u8]) -> u16 {
+        match self {
+            ChecksumAlgorithm::OnesComplement => ones_complement_finish(ones_complement_sum(data)),
+            ChecksumAlgorithm::Crc16 => crc16_update(CRC16_INIT, data),
+        }
+    }
+
+    /// Computes the checksum of a frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The frame header. Its checksum bytes are ignored.
+    /// * `payload` - The frame payload.
+    pub fn frame_checksum(self, header: &[u8], payload: &[u8]) -> u16 {
+        // Set checksum bytes in header to magic byte for calculation
+        let mut checksum_header = header.to_owned();
+        checksum_header[4] = CTMP_MAGIC_BYTE;
+        checksum_header[5] = CTMP_MAGIC_BYTE;
+
+        match self {
+            ChecksumAlgorithm::OnesComplement => ones_complement_finish(
+                ones_complement_sum(&checksum_header) + ones_complement_sum(payload),
+            ),
+            ChecksumAlgorithm::Crc16 => crc16_update(crc16_update(CRC16_INIT, &checksum_header), payload),
+        }
+    }
+
+    /// Whether the checksum carried in `header` matches the frame's contents.
+    pub fn verify(self, header: &[u8], payload: &[u8]) -> bool {
+        u16::from_be_bytes([header[4], header[5]]) == self.frame_checksum(header, payload)
+    }
+
+    /// The name used for this algorithm on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::OnesComplement => "ones-complement",
+            ChecksumAlgorithm::Crc16 => "crc16",
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ones-complement" => Ok(ChecksumAlgorithm::OnesComplement),
+            "crc16" => Ok(ChecksumAlgorithm::Crc16),
+            _ => Err(format!("Unknown checksum algorithm: {}", s)),
+        }
+    }
+}
+
+/// A CRC that some producers append after each frame's payload, outside the length
+/// the header declares.
+///
+/// It covers the payload alone and is sent big-endian. The relay checks it and strips
+/// it, so receivers only ever see standard CTMP frames.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io::{self, Write};
+/// # use std::net::TcpStream;
+/// # use std::sync::{Arc, Mutex};
+/// # use coretech_wirestorm::{build_frame, handle_transmitter, read_frame, Destinations, Transport, TransmitterPolicy};
+/// # use coretech_wirestorm::checksum::TrailingCrc;
+/// // A receiver whose writes can be read back.
+/// struct Recorder(Arc<Mutex<Vec<u8>>>);
+/// impl Write for Recorder {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         self.0.lock().unwrap().write(buf)
+///     }
+///     fn flush(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+/// impl Transport for Recorder {}
+///
+/// assert_eq!(TrailingCrc::Crc32.compute(b"123456789"), 0xCBF4_3926);
+///
+/// let crc = TrailingCrc::Crc16;
+/// let mut input = build_frame(b"good", false).unwrap();
+/// input.extend(&(crc.compute(b"good") as u16).to_be_bytes());
+/// input.extend(build_frame(b"bad", false).unwrap());
+/// input.extend(&(crc.compute(b"bad") as u16 ^ 1).to_be_bytes());
+///
+/// let received = Arc::new(Mutex::new(Vec::new()));
+/// let destinations = Destinations::default();
+/// destinations.add(Recorder(Arc::clone(&received)));
+/// let policy = TransmitterPolicy { trailing_crc: Some(crc), ..TransmitterPolicy::default() };
+/// handle_transmitter(&input[..], None, destinations.clone(), Arc::new(Mutex::new(None::<TcpStream>)), policy);
+///
+/// // the good frame is relayed without its CRC, and the bad one dropped
+/// let received = received.lock().unwrap();
+/// assert_eq!(&received[..], &build_frame(b"good", false).unwrap()[..]);
+/// assert_eq!(destinations.metrics().frames_rejected.get(), 1);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrailingCrc {
+    /// Two bytes of CRC-16/CCITT-FALSE, the same CRC as [`ChecksumAlgorithm::Crc16`].
+    Crc16,
+    /// Four bytes of CRC-32 as used by Ethernet and zlib: reflected polynomial
+    /// `0xEDB88320`, initial value and final XOR `0xFFFFFFFF`.
+    Crc32,
+}
+
+impl TrailingCrc {
+    /// How many bytes the CRC takes up after the payload.
+    pub fn size(self) -> usize {
+        match self {
+            TrailingCrc::Crc16 => 2,
+            TrailingCrc::Crc32 => 4,
+        }
+    }
+
+    /// Computes the CRC of a payload.
+    pub fn compute(self, payload: &[u8]) -> u32 {
+        match self {
+            TrailingCrc::Crc16 => crc16_update(CRC16_INIT, payload) as u32,
+            TrailingCrc::Crc32 => crc32(payload),
+        }
+    }
+
+    /// Whether `trailer`, the bytes read after `payload`, is the payload's CRC.
+    pub fn verify(self, payload: &[u8], trailer: &[u8]) -> bool {
+        let carried = trailer.iter().fold(0u32, |crc, &byte| (crc << 8) | byte as u32);
+        trailer.len() == self.size() && carried == self.compute(payload)
+    }
+
+    /// The name used for this CRC on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            TrailingCrc::Crc16 => "crc16",
+            TrailingCrc::Crc32 => "crc32",
+        }
+    }
+}
+
+impl fmt::Display for TrailingCrc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TrailingCrc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc16" => Ok(TrailingCrc::Crc16),
+            "crc32" => Ok(TrailingCrc::Crc32),
+            _ => Err(format!("Unknown trailing CRC: {}", s)),
+        }
+    }
+}
+
+// Sums big-endian u16 words, padding an odd trailing byte with zero.
+fn ones_complement_sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32)
+        .sum()
+}
+
+// Folds carry bits once and takes the one's complement, exactly as the protocol's
+// reference implementation does.
+fn ones_complement_finish(sum: u32) -> u16 {
+    !(((sum & 0xFFFF) + (sum >> 16)) as u16)
+}
+
+const CRC16_INIT: u16 = 0xFFFF;
+const CRC16_POLY: u16 = 0x1021;
+
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32_POLY } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ CRC16_POLY } else { crc << 1 };
+        }
+    }
+    crc
+}
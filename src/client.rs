@@ -0,0 +1,366 @@
+//! A client for talking to the relay from Rust code.
+//!
+//! [`CtmpClient`] opens either half of the protocol: a [`CtmpSender`] that connects to
+//! the source port and turns payloads into frames, or a [`CtmpReceiver`] that connects
+//! to the destination port and decodes the frames broadcast to it. Both verify what
+//! they can locally, so a caller never sends a frame the relay would drop, and never
+//! sees a sensitive frame whose checksum is wrong.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::frame::{read_frame, CtmpFrame};
+
+/// Timeouts and reconnect behaviour shared by senders and receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// How long to wait for the TCP handshake. `None` uses the OS default.
+    pub connect_timeout: Option<Duration>,
+    /// How long a read may block before failing. `None` blocks forever.
+    pub read_timeout: Option<Duration>,
+    /// How long a write may block before failing. `None` blocks forever.
+    pub write_timeout: Option<Duration>,
+    /// How many times `reconnect` retries after the first failed attempt.
+    pub reconnect_attempts: u32,
+    /// Delay before the first retry. Doubles after every failed attempt.
+    pub reconnect_delay: Duration,
+    /// Upper bound for the delay between retries.
+    pub max_reconnect_delay: Duration,
+    /// How much each retry delay is randomised, so that many clients reconnecting
+    /// after the same outage spread out instead of retrying in lockstep.
+    pub reconnect_jitter: Jitter,
+}
+
+/// Randomisation applied to a reconnect delay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// Wait exactly the backoff delay.
+    #[default]
+    None,
+    /// Wait anywhere between zero and the backoff delay.
+    Full,
+    /// Wait at least half the backoff delay, and up to all of it.
+    Equal,
+}
+
+impl Jitter {
+    /// Applies the jitter to `delay`, given `sample` drawn uniformly from `[0, 1)`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::client::Jitter;
+    /// # use std::time::Duration;
+    /// let delay = Duration::from_millis(800);
+    /// assert_eq!(Jitter::None.apply(delay, 0.25), delay);
+    /// assert_eq!(Jitter::Full.apply(delay, 0.25), Duration::from_millis(200));
+    /// assert_eq!(Jitter::Equal.apply(delay, 0.25), Duration::from_millis(500));
+    /// ```
+    pub fn apply(self, delay: Duration, sample: f64) -> Duration {
+        let sample = sample.clamp(0.0, 1.0);
+        match self {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(sample),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(sample),
+        }
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: None,
+            write_timeout: Some(Duration::from_secs(5)),
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_millis(100),
+            max_reconnect_delay: Duration::from_secs(5),
+            reconnect_jitter: Jitter::None,
+        }
+    }
+}
+
+/// Entry point for connecting to a relay as a transmitter or a receiver.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use coretech_wirestorm::client::CtmpClient;
+/// let mut sender = CtmpClient::connect_source("127.0.0.1:33333")?;
+/// sender.send(b"hello", true)?;
+///
+/// let mut receiver = CtmpClient::connect_destination("127.0.0.1:44444")?;
+/// let frame = receiver.recv()?;
+/// println!("{:?}", frame.payload());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CtmpClient {
+    options: ClientOptions,
+}
+
+impl CtmpClient {
+    /// Creates a client that connects with the given options.
+    pub fn new(options: ClientOptions) -> Self {
+        CtmpClient { options }
+    }
+
+    /// Connects to a relay's source port with default options.
+    pub fn connect_source<A: ToSocketAddrs>(addr: A) -> io::Result<CtmpSender> {
+        CtmpClient::default().source(addr)
+    }
+
+    /// Connects to a relay's destination port with default options.
+    pub fn connect_destination<A: ToSocketAddrs>(addr: A) -> io::Result<CtmpReceiver> {
+        CtmpClient::default().destination(addr)
+    }
+
+    /// Connects to a relay's source port.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The relay's source (transmitter) address.
+    pub fn source<A: ToSocketAddrs>(&self, addr: A) -> io::Result<CtmpSender> {
+        let (stream, addr) = connect(addr, &self.options)?;
+        Ok(CtmpSender { stream, addr, options: self.options.clone() })
+    }
+
+    /// Connects to a relay's destination port.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The relay's destination (receiver) address.
+    pub fn destination<A: ToSocketAddrs>(&self, addr: A) -> io::Result<CtmpReceiver> {
+        let (stream, addr) = connect(addr, &self.options)?;
+        Ok(CtmpReceiver { reader: BufReader::new(stream), addr, options: self.options.clone(), goodbye: false })
+    }
+}
+
+/// The transmitting half: sends payloads to the relay as CTMP frames.
+#[derive(Debug)]
+pub struct CtmpSender {
+    stream: TcpStream,
+    addr: SocketAddr,
+    options: ClientOptions,
+}
+
+impl CtmpSender {
+    /// Sends `payload` as a single frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The message body. Must fit in one frame.
+    /// * `sensitive` - Whether to mark the frame sensitive and stamp a checksum.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(io::Error)` of kind `InvalidInput` if the payload is empty or too large,
+    ///   or any error from the socket.
+    pub fn send(&mut self, payload: &[u8], sensitive: bool) -> io::Result<()> {
+        let frame = CtmpFrame::new(payload.to_vec(), sensitive)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.send_frame(&frame)
+    }
+
+    /// Sends `payload` as one message, split over as many frames as it needs.
+    ///
+    /// See [`CtmpFrame::fragments`]. Relays only reassemble messages when configured
+    /// with a message size limit; otherwise receivers see the fragments as they come.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(io::Error)` of kind `InvalidInput` if the payload is empty, or any error
+    ///   from the socket.
+    pub fn send_message(&mut self, payload: &[u8], sensitive: bool) -> io::Result<()> {
+        let frames = CtmpFrame::fragments(payload, sensitive)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        frames.iter().try_for_each(|frame| self.send_frame(frame))
+    }
+
+    /// Sends an already built frame.
+    pub fn send_frame(&mut self, frame: &CtmpFrame) -> io::Result<()> {
+        self.stream.write_all(&frame.to_bytes())
+    }
+
+    /// Names this transmitter in the relay's logs and status with a
+    /// [label frame](CtmpFrame::label). Only honoured as the first frame sent on a
+    /// connection, so send it again after reconnecting.
+    pub fn send_label(&mut self, name: &str) -> io::Result<()> {
+        self.send_frame(&CtmpFrame::label(name))
+    }
+
+    /// Drops the current connection and dials the relay again, backing off between attempts.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        self.stream = reconnect(self.addr, &self.options)?;
+        Ok(())
+    }
+
+    /// The relay address this sender is connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// The receiving half: decodes frames broadcast by the relay.
+///
+/// Also usable as an iterator, which ends when the relay closes the connection.
+#[derive(Debug)]
+pub struct CtmpReceiver {
+    reader: BufReader<TcpStream>,
+    addr: SocketAddr,
+    options: ClientOptions,
+    // the relay announced it was closing this connection
+    goodbye: bool,
+}
+
+impl CtmpReceiver {
+    /// Blocks until the next frame arrives.
+    ///
+    /// Probe frames the relay sends on connect, and keepalive frames it sends while
+    /// idle, are skipped. Frames the relay compressed for this receiver are returned
+    /// decompressed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(CtmpFrame)` - A frame with a valid header and, if sensitive, a correct checksum.
+    /// * `Err(io::Error)` - A read failure or timeout, `UnexpectedEof` when the relay
+    ///   closes the connection, `ConnectionAborted` when the relay said goodbye before
+    ///   closing it, or `InvalidData` wrapping a [`CtmpError`](crate::CtmpError) for a
+    ///   frame that fails validation or does not decompress.
+    pub fn recv(&mut self) -> io::Result<CtmpFrame> {
+        let mut frame = read_frame(&mut self.reader)?;
+        while frame.is_probe() || frame.is_keepalive() {
+            frame = read_frame(&mut self.reader)?;
+        }
+        if frame.is_goodbye() {
+            self.goodbye = true;
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "relay closed the connection"));
+        }
+        Ok(frame.decompressed()?)
+    }
+
+    /// Blocks until a whole message has arrived, joining the payloads of its fragments.
+    ///
+    /// Frames are read until one without the continued bit ends the message, so a
+    /// message sent as a single frame is just that frame's payload.
+    ///
+    /// # Returns
+    ///
+    /// The message, or any error [`recv`](Self::recv) returns. Fragments read before
+    /// an error are lost.
+    pub fn recv_message(&mut self) -> io::Result<Vec<u8>> {
+        let mut message = Vec::new();
+        loop {
+            let frame = self.recv()?;
+            let continued = frame.is_continued();
+            message.extend_from_slice(frame.payload());
+            if !continued {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Names this receiver in the relay's logs and status with a
+    /// [label frame](CtmpFrame::label). The relay only reads it if it is configured to
+    /// wait for one, and only straight after connecting, so send it again after
+    /// reconnecting.
+    pub fn send_label(&mut self, name: &str) -> io::Result<()> {
+        self.reader.get_mut().write_all(&CtmpFrame::label(name).to_bytes())
+    }
+
+    /// Asks the relay to [compress](crate::compression) the frames it sends this receiver,
+    /// for slow links. Like a label, it is only read straight after connecting, and must
+    /// come before the label if both are sent. [`recv`](Self::recv) decompresses frames
+    /// either way.
+    pub fn request_compression(&mut self) -> io::Result<()> {
+        self.reader.get_mut().write_all(&CtmpFrame::compression_request().to_bytes())
+    }
+
+    /// Whether the relay closed this connection deliberately, with a goodbye frame.
+    pub fn closed_by_relay(&self) -> bool {
+        self.goodbye
+    }
+
+    /// Drops the current connection and dials the relay again, backing off between attempts.
+    ///
+    /// After a goodbye the relay is going away on purpose, typically to restart, so the
+    /// first attempt waits the full `max_reconnect_delay` instead of dialling straight back.
+    /// Frames broadcast while disconnected are not recovered.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        if self.goodbye {
+            thread::sleep(self.options.max_reconnect_delay);
+        }
+        self.reader = BufReader::new(reconnect(self.addr, &self.options)?);
+        self.goodbye = false;
+        Ok(())
+    }
+
+    /// The relay address this receiver is connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Iterator for CtmpReceiver {
+    type Item = io::Result<CtmpFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.recv() {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(_) if self.goodbye => None,
+            result => Some(result),
+        }
+    }
+}
+
+// Connects to the first address that accepts, applying the socket options.
+fn connect<A: ToSocketAddrs>(addr: A, options: &ClientOptions) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for addr in addr.to_socket_addrs()? {
+        match open(addr, options) {
+            Ok(stream) => return Ok((stream, addr)),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Opens a single connection with timeouts applied.
+fn open(addr: SocketAddr, options: &ClientOptions) -> io::Result<TcpStream> {
+    let stream = match options.connect_timeout {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+        None => TcpStream::connect(addr)?,
+    };
+    stream.set_read_timeout(options.read_timeout)?;
+    stream.set_write_timeout(options.write_timeout)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+// Draws a number uniformly from [0, 1). Only needs to differ between clients, not be unpredictable.
+fn random_sample() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Retries `open` with exponential backoff, returning the last error if every attempt fails.
+fn reconnect(addr: SocketAddr, options: &ClientOptions) -> io::Result<TcpStream> {
+    let mut delay = options.reconnect_delay;
+    let mut attempt = 0;
+    loop {
+        match open(addr, options) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt >= options.reconnect_attempts => return Err(e),
+            Err(_) => {
+                thread::sleep(options.reconnect_jitter.apply(delay, random_sample()));
+                delay = (delay * 2).min(options.max_reconnect_delay);
+                attempt += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Compressing frames for receivers on slow links.
+//!
+//! A receiver can ask, straight after connecting, for the frames sent to it to be
+//! compressed (see [`CtmpFrame::compression_request`]). The relay then compresses each
+//! frame once per broadcast, for every receiver that asked, and sends everyone else the
+//! frame as it arrived. A compressed frame carries the compressed bit (`0x04`) in its
+//! options byte, and its length and checksum describe the compressed payload, so it is
+//! still a well-formed CTMP frame. A frame that would not shrink is sent uncompressed.
+//!
+//! The encoding is a PackBits-style run-length code, which suits the long runs of
+//! repeated bytes in padded or sparse payloads and costs almost nothing to apply. Each
+//! control byte below `0x80` is followed by that many plus one literal bytes; a control
+//! byte of `0x80` or above is followed by a single byte repeated
+//! `control - 0x80 + 3` times.
+
+use crate::{verify_checksum, CTMP_COMPRESSED_FLAG, CTMP_CONTROL_FLAG, CTMP_HEADER_LEN, CTMP_SENSITIVE_FLAG};
+
+// The shortest run worth encoding as a run, and the longest one control byte can hold.
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 0x7F + MIN_RUN;
+// The most literal bytes one control byte can hold.
+const MAX_LITERALS: usize = 0x80;
+
+/// Compresses `data`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::compression::{compress, decompress};
+/// let data = [&[0u8; 200][..], b"header", &[0xFF; 50]].concat();
+/// let compressed = compress(&data);
+/// assert_eq!(compressed.len(), 13);
+/// assert_eq!(decompress(&compressed).unwrap(), data);
+/// ```
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_LITERALS + 1);
+    let mut literals = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(MAX_RUN).take_while(|&&byte| byte == data[i]).count();
+        if run < MIN_RUN {
+            i += 1;
+            continue;
+        }
+        push_literals(&mut out, &data[literals..i]);
+        out.push(0x80 | (run - MIN_RUN) as u8);
+        out.push(data[i]);
+        i += run;
+        literals = i;
+    }
+    push_literals(&mut out, &data[literals..]);
+    out
+}
+
+/// Reverses [`compress`].
+///
+/// # Returns
+///
+/// The original bytes, or `None` if `data` ends part way through a control byte's run
+/// or literals.
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while let Some(&control) = data.get(i) {
+        match control {
+            0..0x80 => {
+                let count = control as usize + 1;
+                out.extend_from_slice(data.get(i + 1..i + 1 + count)?);
+                i += 1 + count;
+            }
+            _ => {
+                let count = (control & 0x7F) as usize + MIN_RUN;
+                out.resize(out.len() + count, *data.get(i + 1)?);
+                i += 2;
+            }
+        }
+    }
+    Some(out)
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+// The compressed form of an encoded frame, or `None` if it should be sent as it is: a
+// control frame, one that is already compressed, or one that would not shrink.
+pub(crate) fn compress_frame(frame: &[u8]) -> Option<Vec<u8>> {
+    let (header, payload) = frame.split_at(CTMP_HEADER_LEN);
+    if header[1] & (CTMP_CONTROL_FLAG | CTMP_COMPRESSED_FLAG) != 0 {
+        return None;
+    }
+    let compressed = compress(payload);
+    if compressed.len() >= payload.len() {
+        return None;
+    }
+    let mut header: [u8; CTMP_HEADER_LEN] = header.try_into().expect("header slice");
+    header[1] |= CTMP_COMPRESSED_FLAG;
+    stamp(&mut header, &compressed);
+    Some([&header[..], &compressed].concat())
+}
+
+// Sets a header's length, and its checksum if the frame is sensitive, for `payload`.
+pub(crate) fn stamp(header: &mut [u8; CTMP_HEADER_LEN], payload: &[u8]) {
+    header[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    if header[1] & CTMP_SENSITIVE_FLAG != 0 {
+        let checksum = verify_checksum(header, payload);
+        header[4..6].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
@@ -0,0 +1,1030 @@
+//! Server configuration and command line parsing.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::checksum::{ChecksumAlgorithm, TrailingCrc};
+use crate::CTMP_MAX_PAYLOAD_SIZE;
+
+/// Default number of threads in the transmitter thread pool.
+pub const DEFAULT_THREAD_COUNT: usize = 2;
+/// Default source (transmitter) port.
+pub const DEFAULT_SRC_PORT: u16 = 33333;
+/// Default destination (receiver) port.
+pub const DEFAULT_DEST_PORT: u16 = 44444;
+/// Default IP address for the listeners.
+pub const DEFAULT_IP_ADDRESS: &str = "127.0.0.1";
+/// Default largest frame sent over multicast: an Ethernet MTU less the IPv4 and UDP headers.
+pub const DEFAULT_MULTICAST_MTU: usize = 1472;
+/// Default time an extra worker in an autoscaling thread pool may sit idle before it retires.
+pub const DEFAULT_THREAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default window over which a transmitter's offenses count towards quarantine.
+pub const DEFAULT_QUARANTINE_WINDOW: Duration = Duration::from_secs(60);
+/// Default time a quarantined transmitter address is refused for.
+pub const DEFAULT_QUARANTINE_COOLDOWN: Duration = Duration::from_secs(300);
+/// Default capacity of the buffer each transmitter connection is read through.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Settings for broadcasting frames to a UDP multicast group.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MulticastConfig {
+    /// The multicast group and port every frame is sent to.
+    pub group: SocketAddrV4,
+    /// The local interface to send from. `UNSPECIFIED` lets the OS choose.
+    pub interface: Ipv4Addr,
+    /// Frames larger than this many bytes (header included) are not sent over multicast.
+    pub mtu: usize,
+    /// The IP time-to-live for outgoing datagrams; `1` keeps them on the local network.
+    pub ttl: u32,
+}
+
+impl MulticastConfig {
+    /// Creates a multicast configuration for `group` with default settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The multicast group and port to send frames to.
+    pub fn new(group: SocketAddrV4) -> Self {
+        MulticastConfig {
+            group,
+            interface: Ipv4Addr::UNSPECIFIED,
+            mtu: DEFAULT_MULTICAST_MTU,
+            ttl: 1,
+        }
+    }
+}
+
+/// How writes to receivers behave when a receiver's socket buffer is full.
+///
+/// Both models go through the same delivery path: a frame that cannot be written
+/// straight away joins the receiver's queue, a frame cut off part way is finished
+/// first next time, and failed writes count as strikes under the [`DeliveryPolicy`].
+/// They differ in what a full socket buffer does to the broadcast.
+///
+/// - [`Blocking`](Self::Blocking) waits for the receiver, for up to
+///   [`DeliveryPolicy::write_timeout`] or forever without one. A slow receiver holds up
+///   every other receiver of the same frame, but one that falls behind only briefly
+///   never loses anything, and a receiver is only written to by broadcasts.
+/// - [`NonBlocking`](Self::NonBlocking) never waits: whatever the socket will not take
+///   is queued at once, and the broadcast moves on to the next receiver. One slow
+///   receiver cannot hold up the rest, which is what lets a relay scale to many
+///   receivers, but every full buffer is a strike, so set
+///   [`max_strikes`](DeliveryPolicy::max_strikes) or
+///   [`strike_grace`](DeliveryPolicy::strike_grace), and size
+///   [`queue_depth`](DeliveryPolicy::queue_depth) for the bursts expected. Queued frames
+///   are only written on the next broadcast, or keepalive.
+///
+/// Either way, draining on shutdown blocks, bounded by
+/// [`drain_timeout`](DeliveryPolicy::drain_timeout).
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::net::{TcpListener, TcpStream};
+/// # use coretech_wirestorm::{broadcast_message, read_frame, DeliveryPolicy, DestinationIo, Destinations};
+/// for io in [DestinationIo::Blocking, DestinationIo::NonBlocking] {
+///     let policy = DeliveryPolicy { io, max_strikes: Some(10), queue_depth: 16, ..DeliveryPolicy::default() };
+///     let destinations = Destinations::new().with_policy(policy);
+///     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+///     let mut receivers = Vec::new();
+///     for _ in 0..2 {
+///         receivers.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+///         destinations.add(listener.accept().unwrap().0);
+///     }
+///
+///     for payload in [&b"one"[..], b"two"] {
+///         let header = [0xCC, 0, 0, payload.len() as u8, 0, 0, 0, 0];
+///         broadcast_message(&header, payload, &destinations);
+///     }
+///
+///     for receiver in &mut receivers {
+///         assert_eq!(read_frame(receiver).unwrap().payload(), b"one");
+///         assert_eq!(read_frame(receiver).unwrap().payload(), b"two");
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DestinationIo {
+    /// Writes wait for the receiver to take the frame.
+    #[default]
+    Blocking,
+    /// Writes take what the socket accepts and queue the rest.
+    NonBlocking,
+}
+
+impl DestinationIo {
+    /// The name used for this model on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            DestinationIo::Blocking => "blocking",
+            DestinationIo::NonBlocking => "non-blocking",
+        }
+    }
+}
+
+impl FromStr for DestinationIo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocking" => Ok(DestinationIo::Blocking),
+            "non-blocking" => Ok(DestinationIo::NonBlocking),
+            _ => Err(format!("Unknown destination I/O model: {}", s)),
+        }
+    }
+}
+
+/// What happens when a receiver connects while
+/// [`max_destinations`](DeliveryPolicy::max_destinations) are already connected.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io::{self, Write};
+/// # use coretech_wirestorm::{broadcast_message, CapacityPolicy, DeliveryPolicy, Destinations, Transport};
+/// // A receiver that takes everything, or nothing at all.
+/// struct Receiver(bool);
+/// impl Write for Receiver {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         match self.0 {
+///             true => Ok(buf.len()),
+///             false => Err(io::ErrorKind::WouldBlock.into()),
+///         }
+///     }
+///     fn flush(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+/// impl Transport for Receiver {}
+///
+/// let policy = DeliveryPolicy {
+///     max_destinations: Some(2),
+///     at_capacity: CapacityPolicy::EvictOldest,
+///     max_strikes: Some(10),
+///     ..DeliveryPolicy::default()
+/// };
+/// let destinations = Destinations::default().with_policy(policy);
+/// destinations.add_labelled(Receiver(true), Some("first".into()));
+/// destinations.add_labelled(Receiver(false), Some("stalled".into()));
+/// broadcast_message(&[0xCC, 0, 0, 2, 0, 0, 0, 0], b"hi", &destinations);
+/// assert!(destinations.add_labelled(Receiver(true), Some("newest".into())));
+///
+/// // the receiver that joined first took the frame, so the stalled one made room
+/// let receivers = destinations.clone_inner();
+/// let labels: Vec<_> = receivers.lock().unwrap().iter().map(|dest| dest.label().unwrap().to_string()).collect();
+/// assert_eq!(labels, ["first", "newest"]);
+/// assert_eq!(destinations.metrics().destinations_evicted.get(), 1);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CapacityPolicy {
+    /// Turn the new receiver away, sending it a goodbye frame if the policy sends them.
+    #[default]
+    RejectNew,
+    /// Evict the receiver that has gone longest without taking a frame, and add the new
+    /// one. Receivers that have never been sent anything count from when they joined,
+    /// and between equally idle receivers the one that joined first goes.
+    EvictOldest,
+}
+
+impl CapacityPolicy {
+    /// The name used for this policy on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            CapacityPolicy::RejectNew => "reject-new",
+            CapacityPolicy::EvictOldest => "evict-oldest",
+        }
+    }
+}
+
+impl FromStr for CapacityPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-new" => Ok(CapacityPolicy::RejectNew),
+            "evict-oldest" => Ok(CapacityPolicy::EvictOldest),
+            _ => Err(format!("Unknown capacity policy: {}", s)),
+        }
+    }
+}
+
+/// What happens to a sensitive frame once the
+/// [checksum verification budget](crate::Destinations::with_checksum_limit) for the
+/// current second is used up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ChecksumOverload {
+    /// Hold the transmitter that sent it until the budget refills, then verify it as
+    /// usual. Only that transmitter's reads stop, so the back-pressure lands on it.
+    #[default]
+    Throttle,
+    /// Drop it without verifying it. Frames that are not sensitive carry no checksum
+    /// and are still relayed.
+    Drop,
+}
+
+impl ChecksumOverload {
+    /// The name used for this policy on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumOverload::Throttle => "throttle",
+            ChecksumOverload::Drop => "drop",
+        }
+    }
+}
+
+impl FromStr for ChecksumOverload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "throttle" => Ok(ChecksumOverload::Throttle),
+            "drop" => Ok(ChecksumOverload::Drop),
+            _ => Err(format!("Unknown checksum overload policy: {}", s)),
+        }
+    }
+}
+
+/// What to do with a message too large to relay: a bridged message too large for a
+/// single CTMP frame, or a fragmented message over
+/// [`TransmitterPolicy::max_message_size`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OversizePolicy {
+    /// Treat it as a protocol error and disconnect the producer.
+    #[default]
+    Reject,
+    /// Skip the message and carry on with the next one.
+    Drop,
+}
+
+/// Settings for accepting producers that speak length-prefixed framing.
+///
+/// Each message on a bridge connection is a 4-byte big-endian length followed by the
+/// message bytes. Bridged producers compete for the active source slot like any
+/// other transmitter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BridgeConfig {
+    /// Address the bridge listener binds to.
+    pub addr: String,
+    /// Mark every bridged message sensitive, with a computed checksum.
+    pub sensitive: bool,
+    /// How messages too large for a frame are handled.
+    pub oversize: OversizePolicy,
+}
+
+impl BridgeConfig {
+    /// Creates a bridge configuration for `addr` with default settings.
+    pub fn new(addr: String) -> Self {
+        BridgeConfig { addr, sensitive: false, oversize: OversizePolicy::default() }
+    }
+}
+
+/// Bounds for a thread pool that grows under load and shrinks when idle.
+///
+/// See [`ThreadPool::autoscaling`](crate::ThreadPool::autoscaling).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AutoscalePolicy {
+    /// Workers the pool starts with and never shrinks below. Must be at least 1.
+    pub min: usize,
+    /// Workers the pool never grows beyond.
+    pub max: usize,
+    /// How long a worker waits for a job before retiring.
+    pub idle_timeout: Duration,
+}
+
+/// A capture file broadcast by the server itself, as if from a transmitter.
+///
+/// The file holds concatenated CTMP frames, as read by [`ctmp-inspect`](crate::inspect).
+/// Broadcasting starts once the first receiver connects, so it gets every frame.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PreloadConfig {
+    /// The capture file.
+    pub path: PathBuf,
+    /// Start again from the beginning after the last frame, until shutdown.
+    pub repeat: bool,
+    /// How long to wait after broadcasting each frame.
+    pub interval: Duration,
+}
+
+impl PreloadConfig {
+    /// Creates a preload that broadcasts `path` once, as fast as it can be read.
+    pub fn new(path: PathBuf) -> Self {
+        PreloadConfig { path, repeat: false, interval: Duration::ZERO }
+    }
+}
+
+/// An additional transmitter listener with a policy of its own.
+///
+/// Every transmitter listener shares the single transmitter slot and the same
+/// receivers; only how connections on it are validated and limited differs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceConfig {
+    /// Address the listener binds to.
+    pub addr: String,
+    /// Validation and limits for each connection accepted on it.
+    pub policy: TransmitterPolicy,
+}
+
+impl SourceConfig {
+    /// Creates a listener configuration for `addr` with `policy`.
+    pub fn new(addr: String, policy: TransmitterPolicy) -> Self {
+        SourceConfig { addr, policy }
+    }
+}
+
+/// When a transmitter's IP address is refused for sending bad frames.
+///
+/// See [`Quarantine`](crate::quarantine::Quarantine).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuarantinePolicy {
+    /// Offenses within `window` that put an address in quarantine. Must be at least 1.
+    pub max_offenses: u32,
+    /// How far back offenses count.
+    pub window: Duration,
+    /// How long connections from a quarantined address are refused.
+    pub cooldown: Duration,
+}
+
+/// How writes to TCP receivers are bounded and when a failing receiver is evicted.
+///
+/// A write that fails outright (connection reset, broken pipe) always evicts the
+/// receiver. A write that times out, or that accepts zero bytes, instead counts as a
+/// strike, and the receiver is evicted once it has `max_strikes` consecutive strikes or
+/// has been failing for `strike_grace`, whichever comes first. With neither set, the
+/// first strike evicts.
+///
+/// `write_timeout` alone bounds each write call. Setting `write_timeout_per_kib` as
+/// well turns it into a deadline for the whole frame, extended in proportion to the
+/// frame's size so that large frames to a slow receiver are not cut off early.
+/// Setting `write_chunk_size` instead bounds the time each chunk of a frame may take,
+/// so a receiver that stalls part way through a large frame is caught there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DeliveryPolicy {
+    /// How long a single write to a receiver may block. `None` blocks forever.
+    pub write_timeout: Option<Duration>,
+    /// Extra time allowed per KiB of frame on top of `write_timeout`.
+    pub write_timeout_per_kib: Duration,
+    /// Write frames larger than this many bytes in chunks of it, each of which must be
+    /// written within `write_timeout`; smaller frames are written in one go under the
+    /// same limit. Needs `write_timeout`, and is ignored without it.
+    pub write_chunk_size: Option<usize>,
+    /// Evict after this many consecutive timed out or zero-byte writes.
+    pub max_strikes: Option<u32>,
+    /// Evict once writes have been failing for at least this long.
+    pub strike_grace: Option<Duration>,
+    /// How many frames a receiver that is not keeping up may have waiting. Once full,
+    /// the oldest waiting frame is dropped. `0` keeps nothing: frames that cannot be
+    /// written are dropped straight away.
+    pub queue_depth: usize,
+    /// How many bytes may be waiting across all receivers' queues. Once reached, a
+    /// receiver that cannot take a frame straight away skips it, and other receivers
+    /// still get it. A frame queued for several receivers counts once for each.
+    pub queue_memory: Option<usize>,
+    /// Discard a queued data frame, rather than send it, once it has waited this long
+    /// for a slow receiver, so a receiver that catches up gets current frames rather
+    /// than stale ones. The wait counts from when the frame was queued, straight after
+    /// it arrived. A frame already partly written is always finished.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use std::sync::Arc;
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// # use coretech_wirestorm::{broadcast_message, build_frame, DeliveryPolicy, Destinations, Transport};
+    /// // A receiver that takes nothing until it is unblocked, then counts its frames.
+    /// struct Receiver { open: Arc<AtomicBool>, frames: usize }
+    /// impl Write for Receiver {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         match self.open.load(Ordering::SeqCst) {
+    ///             true => { self.frames += 1; Ok(buf.len()) }
+    ///             false => Err(io::ErrorKind::WouldBlock.into()),
+    ///         }
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    /// }
+    /// impl Transport for Receiver {}
+    ///
+    /// let policy = DeliveryPolicy {
+    ///     queue_depth: 16,
+    ///     max_strikes: Some(10),
+    ///     frame_ttl: Some(Duration::from_millis(50)),
+    ///     ..DeliveryPolicy::default()
+    /// };
+    /// let destinations = Destinations::default().with_policy(policy);
+    /// let open = Arc::new(AtomicBool::new(false));
+    /// destinations.add(Receiver { open: Arc::clone(&open), frames: 0 });
+    ///
+    /// let frame = build_frame(b"reading", false).unwrap();
+    /// for _ in 0..3 {
+    ///     broadcast_message(&frame[..8], &frame[8..], &destinations);
+    /// }
+    /// thread::sleep(Duration::from_millis(100));
+    /// open.store(true, Ordering::SeqCst);
+    /// broadcast_message(&frame[..8], &frame[8..], &destinations);
+    ///
+    /// // the three stale frames were discarded, and only the latest one sent
+    /// let receivers = destinations.clone_inner();
+    /// assert_eq!(receivers.lock().unwrap()[0].stream().frames, 1);
+    /// assert_eq!(destinations.metrics().frames_expired.get(), 3);
+    /// ```
+    pub frame_ttl: Option<Duration>,
+    /// Send receivers a goodbye frame before closing their connections on shutdown.
+    pub goodbye: bool,
+    /// On shutdown, give each receiver this long to take the frames still queued for
+    /// it, and the goodbye frame, before its connection is closed anyway. `None` sends
+    /// only the goodbye frame, each write bounded by `write_timeout` alone, and discards
+    /// anything still queued.
+    pub drain_timeout: Option<Duration>,
+    /// Write a probe frame to each new receiver and only add it if the write succeeds.
+    pub probe: bool,
+    /// Flush receivers this often instead of after every frame. Only matters for
+    /// receivers whose [`Transport`](crate::Transport) buffers writes; flushing a
+    /// `TcpStream` does nothing.
+    pub flush_interval: Option<Duration>,
+    /// Cork TCP receivers (`TCP_CORK`, Linux only), so the kernel packs bursts of small
+    /// frames into full packets, and uncork them on every `flush_interval`. Needs
+    /// `flush_interval`; without it the kernel holds partial packets for up to 200ms.
+    pub cork: bool,
+    /// Whether writes wait for a receiver with a full socket buffer.
+    pub io: DestinationIo,
+    /// Compress the frames sent to receivers that ask for it with a
+    /// [compression request](crate::CtmpFrame::compression_request). Receivers are only
+    /// listened to while the server waits for their
+    /// [label](Config::destination_label_wait), so that wait must be set too.
+    pub compression: bool,
+    /// How many receivers may be connected at once. `None` allows any number.
+    pub max_destinations: Option<usize>,
+    /// What happens to a receiver that connects once `max_destinations` are connected.
+    pub at_capacity: CapacityPolicy,
+}
+
+impl DeliveryPolicy {
+    /// How long writing a frame of `frame_len` bytes may take, or `None` for no limit.
+    pub fn write_timeout_for(&self, frame_len: usize) -> Option<Duration> {
+        let allowance = u32::try_from(frame_len)
+            .ok()
+            .and_then(|len| self.write_timeout_per_kib.checked_mul(len))
+            .map_or(Duration::MAX, |total| total / 1024);
+        self.write_timeout.map(|base| base.saturating_add(allowance))
+    }
+
+    /// Whether a receiver with `strikes` consecutive failures, the first of them
+    /// `failing_for` ago, should be evicted.
+    pub fn should_evict(&self, strikes: u32, failing_for: Duration) -> bool {
+        if self.max_strikes.is_none() && self.strike_grace.is_none() {
+            return true;
+        }
+        self.max_strikes.is_some_and(|max| strikes >= max)
+            || self.strike_grace.is_some_and(|grace| failing_for >= grace)
+    }
+}
+
+/// How each transmitter connection is validated and limited.
+///
+/// With the defaults a transmitter may stay connected indefinitely, checksums use
+/// the protocol's one's-complement sum and reads go through a 64 KiB buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransmitterPolicy {
+    /// Disconnect a transmitter once it has been connected this long. A frame still
+    /// arriving when the limit passes is abandoned, not relayed.
+    pub max_session: Option<Duration>,
+    /// How checksums on sensitive frames are verified.
+    pub checksum: ChecksumAlgorithm,
+    /// Capacity in bytes of the buffer the connection is read through. Larger buffers
+    /// mean fewer reads for large payloads and high frame rates.
+    pub read_buffer_size: usize,
+    /// Drop frames whose payload is longer than this many bytes. `None` allows up to
+    /// [`CTMP_MAX_PAYLOAD_SIZE`], which also caps any larger value set here;
+    /// [`Server::bind`](crate::Server::bind) refuses one.
+    pub max_payload: Option<usize>,
+    /// Disconnect a transmitter once the payloads it has had broadcast in this session
+    /// would add up to more than this many bytes. The frame that would go over is not
+    /// relayed.
+    pub max_bytes_per_session: Option<u64>,
+    /// Hold the fragments of messages split over several frames with the continued bit
+    /// until each message is complete, and drop messages whose payloads add up to more
+    /// than this many bytes. `None` relays fragments as they arrive.
+    pub max_message_size: Option<usize>,
+    /// What happens to a transmitter whose message goes over
+    /// [`max_message_size`](Self::max_message_size). Under [`OversizePolicy::Drop`], the
+    /// default here, the message is dropped and the transmitter carries on. Under
+    /// [`OversizePolicy::Reject`] it is disconnected on the fragment that goes over,
+    /// with a [`CtmpError::ReassemblyTooLarge`](crate::CtmpError::ReassemblyTooLarge).
+    /// Either way no more than the limit, and that one fragment, is ever held.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::net::TcpStream;
+    /// # use std::sync::{Arc, Mutex};
+    /// # use coretech_wirestorm::{build_frame, handle_transmitter, CtmpError, Destinations, DisconnectReason,
+    /// #     OversizePolicy, TransmitterPolicy};
+    /// let mut input = Vec::new();
+    /// for _ in 0..4 {
+    ///     let mut fragment = build_frame(b"sixsix", false).unwrap();
+    ///     fragment[1] |= 0x08; // continued
+    ///     input.extend(fragment);
+    /// }
+    /// input.extend(build_frame(b"end", false).unwrap());
+    ///
+    /// let policy = TransmitterPolicy {
+    ///     max_message_size: Some(10),
+    ///     oversized_messages: OversizePolicy::Reject,
+    ///     ..TransmitterPolicy::default()
+    /// };
+    /// let destinations = Destinations::new();
+    /// let reason = handle_transmitter(&input[..], None, destinations.clone(), Arc::new(Mutex::new(None::<TcpStream>)), policy);
+    ///
+    /// // the second fragment took the message to 12 bytes, over the limit of 10
+    /// assert!(matches!(reason, DisconnectReason::InvalidHeader(CtmpError::ReassemblyTooLarge { limit: 10, size: 12 })));
+    /// assert_eq!(destinations.metrics().frames_relayed.get(), 0);
+    /// assert_eq!(destinations.metrics().frames_rejected.get(), 2);
+    /// ```
+    pub oversized_messages: OversizePolicy,
+    /// Log one in every this many frames read from a transmitter, counted per
+    /// connection, with its length, sensitivity and peer. `None` logs none.
+    pub log_every_nth_frame: Option<u64>,
+    /// Expect a CRC of this kind after every payload, outside its declared length, for
+    /// producers that append one. It is checked and stripped, and frames whose CRC does
+    /// not match are dropped. Never applied to bridged producers.
+    pub trailing_crc: Option<TrailingCrc>,
+}
+
+impl Default for TransmitterPolicy {
+    fn default() -> Self {
+        TransmitterPolicy {
+            max_session: None,
+            checksum: ChecksumAlgorithm::default(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            max_payload: None,
+            max_bytes_per_session: None,
+            max_message_size: None,
+            oversized_messages: OversizePolicy::Drop,
+            log_every_nth_frame: None,
+            trailing_crc: None,
+        }
+    }
+}
+
+impl TransmitterPolicy {
+    /// Whether a payload of `length` bytes is within this policy's limit.
+    ///
+    /// Nothing over [`CTMP_MAX_PAYLOAD_SIZE`] is ever allowed, however high
+    /// [`max_payload`](Self::max_payload) is set.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::{TransmitterPolicy, CTMP_MAX_PAYLOAD_SIZE};
+    /// let policy = TransmitterPolicy { max_payload: Some(1 << 20), ..TransmitterPolicy::default() };
+    /// assert!(policy.allows_payload(CTMP_MAX_PAYLOAD_SIZE));
+    /// assert!(!policy.allows_payload(CTMP_MAX_PAYLOAD_SIZE + 1));
+    /// ```
+    pub fn allows_payload(&self, length: usize) -> bool {
+        length <= CTMP_MAX_PAYLOAD_SIZE && self.max_payload.is_none_or(|max| length <= max)
+    }
+
+    /// Whether a session that has had `total` payload bytes broadcast is over its quota.
+    pub fn over_quota(&self, total: u64) -> bool {
+        self.max_bytes_per_session.is_some_and(|max| total > max)
+    }
+
+    /// Whether a session that started `elapsed` ago has run out.
+    pub fn session_expired(&self, elapsed: Duration) -> bool {
+        self.max_session.is_some_and(|max| elapsed >= max)
+    }
+}
+
+/// Runtime configuration for the relay server.
+///
+/// `Config::default()` reproduces the original fixed behaviour: a two-thread pool,
+/// transmitters on `127.0.0.1:33333` and TCP receivers on `127.0.0.1:44444`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    /// Address the source (transmitter) listener binds to.
+    pub source_addr: String,
+    /// Address the destination (receiver) listener binds to.
+    pub destination_addr: String,
+    /// Number of threads in the transmitter thread pool.
+    pub thread_count: usize,
+    /// Let the transmitter thread pool grow and shrink within these bounds instead of
+    /// keeping `thread_count` threads.
+    pub autoscale: Option<AutoscalePolicy>,
+    /// Accept TCP destination clients. Disable to broadcast over multicast only.
+    pub tcp_destinations: bool,
+    /// Set up new TCP receivers, including any probe, on their own threads, at most
+    /// this many at a time. Further receivers wait in the listen backlog until one
+    /// finishes. `None` sets each one up on the accept thread, one at a time.
+    pub max_handshakes: Option<usize>,
+    /// Give each new TCP receiver this long to name itself with a
+    /// [label frame](crate::CtmpFrame::label) before it is added. Receivers that send
+    /// nothing are added, unlabelled, once it has passed. Each waits on a thread of its
+    /// own, bounded by `max_handshakes` if set.
+    pub destination_label_wait: Option<Duration>,
+    /// Also broadcast every frame to a UDP multicast group.
+    pub multicast: Option<MulticastConfig>,
+    /// Write timeout and eviction rules for TCP receivers.
+    pub delivery: DeliveryPolicy,
+    /// Validation and limits for each transmitter connection.
+    pub transmitter: TransmitterPolicy,
+    /// Further transmitter listeners, each with its own policy.
+    pub extra_sources: Vec<SourceConfig>,
+    /// Accept any number of transmitters at once instead of refusing all but one. Each
+    /// is handled on its own pool thread, so at most as many run as the pool has threads.
+    pub allow_multiple_sources: bool,
+    /// Refuse a transmitter when every pool thread is already handling one, instead of
+    /// holding it until a thread frees up.
+    pub reject_when_pool_full: bool,
+    /// Refuse transmitters from addresses that keep sending frames the relay rejects.
+    pub quarantine: Option<QuarantinePolicy>,
+    /// Broadcast the frames in a capture file, in addition to any from transmitters.
+    pub preload: Option<PreloadConfig>,
+    /// Adopt listeners passed in by the supervisor (`LISTEN_FDS`) instead of binding.
+    pub socket_activation: bool,
+    /// Check the framing and checksum logic before binding, and refuse to start if it
+    /// is broken. See [`selftest`](crate::selftest).
+    pub self_test: bool,
+    /// Also accept length-prefixed producers on a separate listener.
+    pub bridge: Option<BridgeConfig>,
+    /// Log a one-line summary of the server's metrics this often.
+    pub stats_log_interval: Option<Duration>,
+    /// Send receivers a keepalive frame whenever nothing has been broadcast for this
+    /// long, so idle connections survive load balancers and NAT that drop them.
+    pub keepalive_interval: Option<Duration>,
+    /// Keep the lifetime counters in this file, so they carry on from where the last
+    /// run left them. Loaded at startup and saved periodically and at shutdown. See
+    /// [`Metrics::save`](crate::Metrics::save).
+    pub metrics_file: Option<PathBuf>,
+    /// Tag frames with routing ids and drop any whose id is among this many recent ones.
+    /// See [`routing`](crate::routing).
+    pub loop_detection: Option<usize>,
+    /// Run at most this many broadcasts at once, however many transmitters are sending.
+    /// See [`Destinations::with_broadcast_limit`](crate::Destinations::with_broadcast_limit).
+    pub max_concurrent_broadcasts: Option<usize>,
+    /// Verify the checksums of at most this many sensitive frames a second, across every
+    /// transmitter. See [`Destinations::with_checksum_limit`](crate::Destinations::with_checksum_limit).
+    pub max_checksums_per_second: Option<u32>,
+    /// What happens to sensitive frames over
+    /// [`max_checksums_per_second`](Self::max_checksums_per_second).
+    pub checksum_overload: ChecksumOverload,
+    /// Shut down cleanly once the server has been up this long, so a supervisor can
+    /// start a fresh process. See [`Server::run`](crate::Server::run).
+    pub max_uptime: Option<Duration>,
+    /// The exit status the binary reports after shutting down for
+    /// [`max_uptime`](Self::max_uptime), so a supervisor can tell a planned recycle from
+    /// a crash.
+    pub max_uptime_exit_code: i32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            source_addr: format!("{}:{}", DEFAULT_IP_ADDRESS, DEFAULT_SRC_PORT),
+            destination_addr: format!("{}:{}", DEFAULT_IP_ADDRESS, DEFAULT_DEST_PORT),
+            thread_count: DEFAULT_THREAD_COUNT,
+            autoscale: None,
+            tcp_destinations: true,
+            max_handshakes: None,
+            destination_label_wait: None,
+            multicast: None,
+            delivery: DeliveryPolicy::default(),
+            transmitter: TransmitterPolicy::default(),
+            extra_sources: Vec::new(),
+            allow_multiple_sources: false,
+            reject_when_pool_full: false,
+            quarantine: None,
+            preload: None,
+            socket_activation: true,
+            self_test: true,
+            bridge: None,
+            stats_log_interval: None,
+            keepalive_interval: None,
+            metrics_file: None,
+            loop_detection: None,
+            max_concurrent_broadcasts: None,
+            max_checksums_per_second: None,
+            checksum_overload: ChecksumOverload::default(),
+            max_uptime: None,
+            max_uptime_exit_code: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Builds a configuration from command line arguments, starting from the defaults.
+    ///
+    /// The program name must already have been skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The arguments to parse.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Config)` - The parsed configuration.
+    /// * `Err(String)` - A message describing the first invalid argument.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut mtu = None;
+        let mut bridge_sensitive = false;
+        let mut bridge_oversize = None;
+        // extra listeners and their payload limits, applied once the main policy is known
+        let mut extra_sources: Vec<(String, Option<usize>)> = Vec::new();
+        let mut quarantine_window = None;
+        let mut preload_repeat = false;
+        let mut at_capacity = None;
+        let mut max_uptime_exit_code = None;
+        let mut checksum_overload = None;
+        let mut max_threads = None;
+        let mut thread_idle_timeout = None;
+        let mut preload_interval = None;
+        let mut quarantine_cooldown = None;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--source" => config.source_addr = value()?,
+                "--destination" => config.destination_addr = value()?,
+                "--threads" => config.thread_count = parse(&arg, &value()?)?,
+                "--allow-multiple-sources" => config.allow_multiple_sources = true,
+                "--reject-when-pool-full" => config.reject_when_pool_full = true,
+                "--max-concurrent-handshakes" => config.max_handshakes = Some(parse(&arg, &value()?)?),
+                "--destination-label-wait-ms" => {
+                    config.destination_label_wait = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--max-threads" => max_threads = Some(parse(&arg, &value()?)?),
+                "--thread-idle-timeout-ms" => {
+                    thread_idle_timeout = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--multicast" => {
+                    let group: SocketAddrV4 = parse(&arg, &value()?)?;
+                    if !group.ip().is_multicast() {
+                        return Err(format!("{} is not a multicast address", group.ip()));
+                    }
+                    config.multicast = Some(MulticastConfig::new(group));
+                }
+                "--multicast-mtu" => mtu = Some(parse(&arg, &value()?)?),
+                "--no-tcp-destinations" => config.tcp_destinations = false,
+                "--no-socket-activation" => config.socket_activation = false,
+                "--no-self-test" => config.self_test = false,
+                "--extra-source" => extra_sources.push((value()?, None)),
+                "--extra-source-max-payload" => {
+                    let max = parse(&arg, &value()?)?;
+                    match extra_sources.last_mut() {
+                        Some((_, max_payload)) => *max_payload = Some(max),
+                        None => return Err("--extra-source-max-payload must follow --extra-source".into()),
+                    }
+                }
+                "--quarantine-offenses" => {
+                    config.quarantine = Some(QuarantinePolicy {
+                        max_offenses: parse(&arg, &value()?)?,
+                        window: DEFAULT_QUARANTINE_WINDOW,
+                        cooldown: DEFAULT_QUARANTINE_COOLDOWN,
+                    })
+                }
+                "--quarantine-window-ms" => quarantine_window = Some(Duration::from_millis(parse(&arg, &value()?)?)),
+                "--quarantine-cooldown-ms" => {
+                    quarantine_cooldown = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--preload" => config.preload = Some(PreloadConfig::new(value()?.into())),
+                "--preload-loop" => preload_repeat = true,
+                "--keepalive-interval-ms" => {
+                    config.keepalive_interval = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--metrics-file" => config.metrics_file = Some(value()?.into()),
+                "--preload-interval-ms" => preload_interval = Some(Duration::from_millis(parse(&arg, &value()?)?)),
+                "--max-payload" => config.transmitter.max_payload = Some(parse(&arg, &value()?)?),
+                "--bridge" => config.bridge = Some(BridgeConfig::new(value()?)),
+                "--bridge-sensitive" => bridge_sensitive = true,
+                "--bridge-drop-oversized" => bridge_oversize = Some(OversizePolicy::Drop),
+                "--write-timeout-ms" => {
+                    config.delivery.write_timeout = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--write-timeout-per-kib-ms" => {
+                    config.delivery.write_timeout_per_kib = Duration::from_millis(parse(&arg, &value()?)?)
+                }
+                "--write-chunk-size" => config.delivery.write_chunk_size = Some(parse(&arg, &value()?)?),
+                "--max-destinations" => config.delivery.max_destinations = Some(parse(&arg, &value()?)?),
+                "--at-capacity" => at_capacity = Some(value()?.parse()?),
+                "--compress-on-request" => config.delivery.compression = true,
+                "--probe-destinations" => config.delivery.probe = true,
+                "--destination-io" => config.delivery.io = value()?.parse()?,
+                "--flush-interval-ms" => {
+                    config.delivery.flush_interval = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--goodbye" => config.delivery.goodbye = true,
+                "--drain-timeout-ms" => {
+                    config.delivery.drain_timeout = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--tcp-cork" => config.delivery.cork = true,
+                "--queue-depth" => config.delivery.queue_depth = parse(&arg, &value()?)?,
+                "--queue-memory" => config.delivery.queue_memory = Some(parse(&arg, &value()?)?),
+                "--frame-ttl-ms" => config.delivery.frame_ttl = Some(Duration::from_millis(parse(&arg, &value()?)?)),
+                "--max-strikes" => config.delivery.max_strikes = Some(parse(&arg, &value()?)?),
+                "--strike-grace-ms" => {
+                    config.delivery.strike_grace = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--max-transmitter-session-ms" => {
+                    config.transmitter.max_session = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--log-every-nth-frame" => config.transmitter.log_every_nth_frame = Some(parse(&arg, &value()?)?),
+                "--max-message-size" => config.transmitter.max_message_size = Some(parse(&arg, &value()?)?),
+                "--reject-oversized-messages" => config.transmitter.oversized_messages = OversizePolicy::Reject,
+                "--max-bytes-per-session" => {
+                    config.transmitter.max_bytes_per_session = Some(parse(&arg, &value()?)?)
+                }
+                "--stats-log-interval-ms" => {
+                    config.stats_log_interval = Some(Duration::from_millis(parse(&arg, &value()?)?))
+                }
+                "--loop-detection-window" => config.loop_detection = Some(parse(&arg, &value()?)?),
+                "--max-uptime-ms" => config.max_uptime = Some(Duration::from_millis(parse(&arg, &value()?)?)),
+                "--max-uptime-exit-code" => max_uptime_exit_code = Some(parse(&arg, &value()?)?),
+                "--max-checksums-per-sec" => config.max_checksums_per_second = Some(parse(&arg, &value()?)?),
+                "--checksum-overload" => checksum_overload = Some(value()?.parse()?),
+                "--max-concurrent-broadcasts" => config.max_concurrent_broadcasts = Some(parse(&arg, &value()?)?),
+                "--read-buffer-size" => config.transmitter.read_buffer_size = parse(&arg, &value()?)?,
+                "--checksum" => config.transmitter.checksum = value()?.parse()?,
+                "--trailing-crc" => config.transmitter.trailing_crc = Some(value()?.parse()?),
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+
+        if let Some(mtu) = mtu {
+            match config.multicast.as_mut() {
+                Some(multicast) => multicast.mtu = mtu,
+                None => return Err("--multicast-mtu requires --multicast".into()),
+            }
+        }
+        match config.bridge.as_mut() {
+            Some(bridge) => {
+                bridge.sensitive = bridge_sensitive;
+                bridge.oversize = bridge_oversize.unwrap_or_default();
+            }
+            None if bridge_sensitive || bridge_oversize.is_some() => {
+                return Err("--bridge-sensitive and --bridge-drop-oversized require --bridge".into());
+            }
+            None => {}
+        }
+        if !config.delivery.write_timeout_per_kib.is_zero() && config.delivery.write_timeout.is_none() {
+            return Err("--write-timeout-per-kib-ms requires --write-timeout-ms".into());
+        }
+        match (config.max_uptime, max_uptime_exit_code) {
+            (Some(uptime), _) if uptime.is_zero() => return Err("--max-uptime-ms must be greater than zero".into()),
+            (Some(_), Some(code)) => config.max_uptime_exit_code = code,
+            (None, Some(_)) => return Err("--max-uptime-exit-code requires --max-uptime-ms".into()),
+            _ => {}
+        }
+        match (config.max_checksums_per_second, checksum_overload) {
+            (Some(0), _) => return Err("--max-checksums-per-sec must be greater than zero".into()),
+            (Some(_), Some(overload)) => config.checksum_overload = overload,
+            (None, Some(_)) => return Err("--checksum-overload requires --max-checksums-per-sec".into()),
+            _ => {}
+        }
+        if config.delivery.compression && config.destination_label_wait.is_none() {
+            return Err("--compress-on-request requires --destination-label-wait-ms".into());
+        }
+        match (config.delivery.max_destinations, at_capacity) {
+            (Some(0), _) => return Err("--max-destinations must be greater than zero".into()),
+            (Some(_), Some(policy)) => config.delivery.at_capacity = policy,
+            (None, Some(_)) => return Err("--at-capacity requires --max-destinations".into()),
+            _ => {}
+        }
+        if config.delivery.write_chunk_size.is_some() && config.delivery.write_timeout.is_none() {
+            return Err("--write-chunk-size requires --write-timeout-ms".into());
+        }
+        if config.delivery.write_chunk_size == Some(0) {
+            return Err("--write-chunk-size must be greater than zero".into());
+        }
+        if config.delivery.io == DestinationIo::NonBlocking && config.delivery.write_timeout.is_some() {
+            return Err("--write-timeout-ms has no effect with --destination-io non-blocking".into());
+        }
+        match config.preload.as_mut() {
+            Some(preload) => {
+                preload.repeat = preload_repeat;
+                preload.interval = preload_interval.unwrap_or(preload.interval);
+            }
+            None if preload_repeat || preload_interval.is_some() => {
+                return Err("--preload-loop and --preload-interval-ms require --preload".into());
+            }
+            None => {}
+        }
+        match config.quarantine.as_mut() {
+            Some(quarantine) => {
+                quarantine.window = quarantine_window.unwrap_or(quarantine.window);
+                quarantine.cooldown = quarantine_cooldown.unwrap_or(quarantine.cooldown);
+                if quarantine.max_offenses == 0 {
+                    return Err("--quarantine-offenses must be greater than zero".into());
+                }
+            }
+            None if quarantine_window.is_some() || quarantine_cooldown.is_some() => {
+                return Err("--quarantine-window-ms and --quarantine-cooldown-ms require --quarantine-offenses".into());
+            }
+            None => {}
+        }
+        if config.transmitter.max_payload.is_some_and(|max| max > CTMP_MAX_PAYLOAD_SIZE) {
+            return Err(format!("--max-payload cannot exceed {}", CTMP_MAX_PAYLOAD_SIZE));
+        }
+        for (addr, max_payload) in extra_sources {
+            if max_payload.is_some_and(|max| max > CTMP_MAX_PAYLOAD_SIZE) {
+                return Err(format!("--extra-source-max-payload cannot exceed {}", CTMP_MAX_PAYLOAD_SIZE));
+            }
+            let mut policy = config.transmitter;
+            policy.max_payload = max_payload.or(policy.max_payload);
+            config.extra_sources.push(SourceConfig::new(addr, policy));
+        }
+        if config.delivery.queue_memory.is_some() && config.delivery.queue_depth == 0 {
+            return Err("--queue-memory requires --queue-depth".into());
+        }
+        if config.delivery.frame_ttl.is_some() && config.delivery.queue_depth == 0 {
+            return Err("--frame-ttl-ms requires --queue-depth".into());
+        }
+        if config.delivery.frame_ttl == Some(Duration::ZERO) {
+            return Err("--frame-ttl-ms must be greater than zero".into());
+        }
+        if config.delivery.drain_timeout == Some(Duration::ZERO) {
+            return Err("--drain-timeout-ms must be greater than zero".into());
+        }
+        if config.delivery.flush_interval == Some(Duration::ZERO) {
+            return Err("--flush-interval-ms must be greater than zero".into());
+        }
+        if config.delivery.cork {
+            if !cfg!(target_os = "linux") {
+                return Err("--tcp-cork is only supported on Linux".into());
+            }
+            if config.delivery.flush_interval.is_none() {
+                return Err("--tcp-cork requires --flush-interval-ms".into());
+            }
+        }
+        if config.keepalive_interval == Some(Duration::ZERO) {
+            return Err("--keepalive-interval-ms must be greater than zero".into());
+        }
+        if config.stats_log_interval == Some(Duration::ZERO) {
+            return Err("--stats-log-interval-ms must be greater than zero".into());
+        }
+        if let Some(window) = config.loop_detection
+            && (window == 0 || window > u16::MAX as usize)
+        {
+            return Err("--loop-detection-window must be between 1 and 65535".into());
+        }
+        if config.max_concurrent_broadcasts == Some(0) {
+            return Err("--max-concurrent-broadcasts must be greater than zero".into());
+        }
+        if config.transmitter.max_session == Some(Duration::ZERO) {
+            return Err("--max-transmitter-session-ms must be greater than zero".into());
+        }
+        if config.transmitter.log_every_nth_frame == Some(0) {
+            return Err("--log-every-nth-frame must be greater than zero".into());
+        }
+        if config.transmitter.oversized_messages == OversizePolicy::Reject && config.transmitter.max_message_size.is_none() {
+            return Err("--reject-oversized-messages requires --max-message-size".into());
+        }
+        if config.transmitter.max_message_size == Some(0) {
+            return Err("--max-message-size must be greater than zero".into());
+        }
+        if config.transmitter.read_buffer_size == 0 {
+            return Err("--read-buffer-size must be greater than zero".into());
+        }
+        if config.max_handshakes == Some(0) {
+            return Err("--max-concurrent-handshakes must be greater than zero".into());
+        }
+        if config.destination_label_wait == Some(Duration::ZERO) {
+            return Err("--destination-label-wait-ms must be greater than zero".into());
+        }
+        if config.thread_count == 0 {
+            return Err("--threads must be greater than zero".into());
+        }
+        match max_threads {
+            Some(max) if max < config.thread_count => {
+                return Err("--max-threads must be at least --threads".into());
+            }
+            Some(max) => {
+                config.autoscale = Some(AutoscalePolicy {
+                    min: config.thread_count,
+                    max,
+                    idle_timeout: thread_idle_timeout.unwrap_or(DEFAULT_THREAD_IDLE_TIMEOUT),
+                })
+            }
+            None if thread_idle_timeout.is_some() => {
+                return Err("--thread-idle-timeout-ms requires --max-threads".into());
+            }
+            None => {}
+        }
+        if !config.tcp_destinations && config.multicast.is_none() {
+            return Err("--no-tcp-destinations requires --multicast".into());
+        }
+
+        Ok(config)
+    }
+}
+
+// Parses a single argument value, naming the flag in the error.
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
@@ -0,0 +1,111 @@
+//! Error type for CTMP protocol violations.
+
+use std::{fmt, io};
+
+/// A reason a CTMP frame was rejected, or could not be read.
+///
+/// Every variant but [`Io`](CtmpError::Io) maps to one of the protocol rules checked by
+/// [`validate_header`](crate::validate_header) or by the checksum step in
+/// [`handle_transmitter`](crate::handle_transmitter).
+#[derive(Debug)]
+pub enum CtmpError {
+    /// The first header byte was not the CTMP magic byte.
+    InvalidMagic(u8),
+    /// The trailing padding bytes of the header were not zero.
+    InvalidPadding,
+    /// A non-sensitive header carried a non-zero checksum field.
+    InvalidChecksumPadding,
+    /// The declared payload length was zero or above the protocol maximum.
+    InvalidLength(usize),
+    /// A sensitive frame's checksum did not match its contents.
+    ChecksumMismatch {
+        /// The checksum carried in the header.
+        expected: u16,
+        /// The checksum computed over the frame.
+        computed: u16,
+    },
+    /// A sensitive frame was built without a checksum.
+    MissingChecksum,
+    /// A [compressed](crate::compression) frame's payload did not decode.
+    InvalidCompression,
+    /// A fragment took its message over
+    /// [`TransmitterPolicy::max_message_size`](crate::TransmitterPolicy::max_message_size).
+    ReassemblyTooLarge {
+        /// The largest message allowed, in bytes.
+        limit: usize,
+        /// The message's size with the fragment that went over.
+        size: usize,
+    },
+    /// Reading the frame failed, or the stream ended part way through it.
+    Io(io::Error),
+}
+
+impl CtmpError {
+    /// Returns a short, stable name for the error kind.
+    ///
+    /// Used as a key when counting drop reasons, so it never changes with the
+    /// values carried by the variant.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CtmpError::InvalidMagic(_) => "invalid_magic",
+            CtmpError::InvalidPadding => "invalid_padding",
+            CtmpError::InvalidChecksumPadding => "invalid_checksum_padding",
+            CtmpError::InvalidLength(_) => "invalid_length",
+            CtmpError::ChecksumMismatch { .. } => "checksum_mismatch",
+            CtmpError::MissingChecksum => "missing_checksum",
+            CtmpError::InvalidCompression => "invalid_compression",
+            CtmpError::ReassemblyTooLarge { .. } => "reassembly_too_large",
+            CtmpError::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for CtmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtmpError::InvalidMagic(byte) => write!(f, "Invalid magic byte: {:#04x}", byte),
+            CtmpError::InvalidPadding => write!(f, "Invalid padding"),
+            CtmpError::InvalidChecksumPadding => {
+                write!(f, "Invalid Padding for non sensitive headers")
+            }
+            CtmpError::InvalidLength(length) => write!(f, "Invalid payload length: {}", length),
+            CtmpError::ChecksumMismatch { expected, computed } => write!(
+                f,
+                "Checksum mismatch: header {:#06x}, computed {:#06x}",
+                expected, computed
+            ),
+            CtmpError::MissingChecksum => write!(f, "Sensitive frame without a checksum"),
+            CtmpError::InvalidCompression => write!(f, "Compressed payload does not decode"),
+            CtmpError::ReassemblyTooLarge { limit, size } => {
+                write!(f, "Fragmented message of {} bytes exceeds the limit of {}", size, limit)
+            }
+            CtmpError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CtmpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CtmpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CtmpError {
+    fn from(e: io::Error) -> Self {
+        CtmpError::Io(e)
+    }
+}
+
+// Lets code that works in `io::Result` use `?` on CTMP errors: I/O errors come back
+// as they were, protocol violations become `InvalidData`.
+impl From<CtmpError> for io::Error {
+    fn from(e: CtmpError) -> Self {
+        match e {
+            CtmpError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
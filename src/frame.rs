@@ -0,0 +1,683 @@
+//! Building and reading complete CTMP frames.
+//!
+//! The relay itself only ever forwards frames it has received, but clients need to
+//! produce frames with correct headers and checksums, and receivers need to decode
+//! them. Both halves live here so every caller shares one implementation of the
+//! wire format.
+
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::compression;
+use crate::{validation_report, verify_checksum, CtmpError, CTMP_COMPRESSED_FLAG, CTMP_CONTINUED_FLAG,
+    CTMP_CONTROL_FLAG, CTMP_HEADER_LEN, CTMP_MAGIC_BYTE, CTMP_MAX_PAYLOAD_SIZE, CTMP_SENSITIVE_FLAG};
+
+// Payload of a control frame announcing that the relay is closing the connection.
+const GOODBYE: &[u8] = b"GOODBYE";
+// Payload of a control frame sent to check that a new receiver can be written to.
+const PROBE: &[u8] = b"PROBE";
+// Payload of a control frame sent to receivers while no data is flowing.
+const KEEPALIVE: &[u8] = b"KEEPALIVE";
+// Start of the payload of a control frame in which a client names itself.
+const LABEL: &[u8] = b"LABEL:";
+// Payload of a control frame in which a receiver asks for compressed frames.
+const COMPRESS: &[u8] = b"COMPRESS";
+
+/// The most characters of a client's [label](CtmpFrame::label) the relay keeps.
+pub const MAX_LABEL_LEN: usize = 64;
+// The most payload one frame can carry, bounded by the 16-bit length field.
+const MAX_FRAGMENT_LEN: usize = if CTMP_MAX_PAYLOAD_SIZE < u16::MAX as usize {
+    CTMP_MAX_PAYLOAD_SIZE
+} else {
+    u16::MAX as usize
+};
+
+/// A decoded CTMP frame: the header as it appeared on the wire and its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtmpFrame {
+    header: [u8; CTMP_HEADER_LEN],
+    payload: Vec<u8>,
+}
+
+impl CtmpFrame {
+    /// Builds a frame around `payload`, computing the checksum if it is sensitive.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The message body. Must be between 1 byte and the protocol maximum.
+    /// * `sensitive` - Whether to set the sensitive flag and stamp a checksum.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(CtmpFrame)` - A frame the relay will accept.
+    /// * `Err(CtmpError::InvalidLength)` - If the payload is empty or too large.
+    pub fn new(payload: Vec<u8>, sensitive: bool) -> Result<Self, CtmpError> {
+        let length = payload.len();
+        if length == 0 || length > CTMP_MAX_PAYLOAD_SIZE || length > u16::MAX as usize {
+            return Err(CtmpError::InvalidLength(length));
+        }
+
+        let mut header = [0u8; CTMP_HEADER_LEN];
+        header[0] = CTMP_MAGIC_BYTE;
+        if sensitive {
+            header[1] = CTMP_SENSITIVE_FLAG;
+        }
+        header[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+        if sensitive {
+            let checksum = verify_checksum(&header, &payload);
+            header[4..6].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        Ok(CtmpFrame { header, payload })
+    }
+
+    /// Builds a frame around `payload` with a checksum the caller already has.
+    ///
+    /// The combination is checked against the rules the relay applies, so a frame it
+    /// would be certain to drop is never built: a sensitive frame needs its correct
+    /// checksum, and a non-sensitive frame must have none.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The message body. Must be between 1 byte and the protocol maximum.
+    /// * `sensitive` - Whether to set the sensitive flag.
+    /// * `checksum` - The checksum to carry, or `None` for none.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(CtmpFrame)` - A frame the relay will accept.
+    /// * `Err(CtmpError::InvalidLength)` - If the payload is empty or too large.
+    /// * `Err(CtmpError::MissingChecksum)` - If a sensitive frame has no checksum.
+    /// * `Err(CtmpError::ChecksumMismatch)` - If a sensitive frame's checksum is wrong.
+    /// * `Err(CtmpError::InvalidChecksumPadding)` - If a non-sensitive frame has a
+    ///   non-zero checksum.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::{CtmpError, CtmpFrame};
+    /// let frame = CtmpFrame::new(b"hi".to_vec(), true).unwrap();
+    /// assert!(CtmpFrame::with_checksum(b"hi".to_vec(), true, Some(frame.checksum())).is_ok());
+    ///
+    /// let missing = CtmpFrame::with_checksum(b"hi".to_vec(), true, None);
+    /// assert!(matches!(missing, Err(CtmpError::MissingChecksum)));
+    /// let stray = CtmpFrame::with_checksum(b"hi".to_vec(), false, Some(0x1234));
+    /// assert!(matches!(stray, Err(CtmpError::InvalidChecksumPadding)));
+    /// ```
+    pub fn with_checksum(payload: Vec<u8>, sensitive: bool, checksum: Option<u16>) -> Result<Self, CtmpError> {
+        let frame = CtmpFrame::new(payload, sensitive)?;
+        match (sensitive, checksum) {
+            (true, None) => Err(CtmpError::MissingChecksum),
+            (true, Some(expected)) if expected != frame.checksum() => {
+                Err(CtmpError::ChecksumMismatch { expected, computed: frame.checksum() })
+            }
+            (false, Some(checksum)) if checksum != 0 => Err(CtmpError::InvalidChecksumPadding),
+            _ => Ok(frame),
+        }
+    }
+
+    /// Splits a message of any size into frames, setting the continued bit (`0x08`) on
+    /// every frame but the last. Each frame is as full as it can be.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The whole message. Must not be empty.
+    /// * `sensitive` - Whether to mark every frame sensitive and stamp its checksum.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<CtmpFrame>)` - The frames, in the order they must be sent.
+    /// * `Err(CtmpError::InvalidLength)` - If the payload is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::CtmpFrame;
+    /// let message = vec![7u8; 100_000];
+    /// let frames = CtmpFrame::fragments(&message, true).unwrap();
+    /// assert_eq!(frames.len(), 2);
+    /// assert!(frames[0].is_continued() && !frames[1].is_continued());
+    /// assert_eq!([frames[0].payload(), frames[1].payload()].concat(), message);
+    /// ```
+    pub fn fragments(payload: &[u8], sensitive: bool) -> Result<Vec<Self>, CtmpError> {
+        if payload.is_empty() {
+            return Err(CtmpError::InvalidLength(0));
+        }
+        let count = payload.len().div_ceil(MAX_FRAGMENT_LEN);
+        payload
+            .chunks(MAX_FRAGMENT_LEN)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut frame = CtmpFrame::new(chunk.to_vec(), false)?;
+                if i + 1 < count {
+                    frame.header[1] |= CTMP_CONTINUED_FLAG;
+                }
+                if sensitive {
+                    frame.header[1] |= CTMP_SENSITIVE_FLAG;
+                    let checksum = verify_checksum(&frame.header, &frame.payload);
+                    frame.header[4..6].copy_from_slice(&checksum.to_be_bytes());
+                }
+                Ok(frame)
+            })
+            .collect()
+    }
+
+    /// The control frame the relay sends before deliberately closing a receiver's connection.
+    ///
+    /// It carries the control bit (`0x20`) in the options byte, which transmitters may
+    /// not set, so it can never be confused with a relayed data frame.
+    pub fn goodbye() -> Self {
+        CtmpFrame::control(GOODBYE)
+    }
+
+    /// The control frame the relay may send a new receiver to check it can be written to.
+    ///
+    /// Receivers should ignore it; [`CtmpReceiver`](crate::CtmpReceiver) skips it.
+    pub fn probe() -> Self {
+        CtmpFrame::control(PROBE)
+    }
+
+    /// The control frame the relay sends receivers when no data has flowed for a while,
+    /// so idle connections are not torn down by load balancers or NAT on the way.
+    ///
+    /// Receivers should ignore it; [`CtmpReceiver`](crate::CtmpReceiver) skips it.
+    pub fn keepalive() -> Self {
+        CtmpFrame::control(KEEPALIVE)
+    }
+
+    /// The control frame a client may send as its very first frame, naming itself so
+    /// the relay's logs and status say which client is which.
+    ///
+    /// The name is [sanitised](sanitize_label) before it is sent, and again by the
+    /// relay. A transmitter's label is taken from its first frame; a receiver's only if
+    /// the relay waits for one, see
+    /// [`Config::destination_label_wait`](crate::Config::destination_label_wait).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::CtmpFrame;
+    /// let frame = CtmpFrame::label("feed-a\n\x1b[31m");
+    /// assert!(frame.is_control());
+    /// assert_eq!(frame.label_name().as_deref(), Some("feed-a[31m"));
+    /// assert_eq!(CtmpFrame::goodbye().label_name(), None);
+    /// ```
+    pub fn label(name: &str) -> Self {
+        let mut payload = LABEL.to_vec();
+        payload.extend(sanitize_label(name.as_bytes()).unwrap_or_default().into_bytes());
+        CtmpFrame::control(&payload)
+    }
+
+    /// The control frame a receiver may send straight after connecting, asking for the
+    /// frames sent to it to be [compressed](crate::compression). Only honoured if the
+    /// relay waits for it, see
+    /// [`DeliveryPolicy::compression`](crate::DeliveryPolicy::compression), and sent
+    /// before any label frame.
+    pub fn compression_request() -> Self {
+        CtmpFrame::control(COMPRESS)
+    }
+
+    // Builds a control frame carrying `payload`.
+    fn control(payload: &[u8]) -> Self {
+        let mut header = [0u8; CTMP_HEADER_LEN];
+        header[0] = CTMP_MAGIC_BYTE;
+        header[1] = CTMP_CONTROL_FLAG;
+        header[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        CtmpFrame { header, payload: payload.to_vec() }
+    }
+
+    /// The header bytes exactly as built or received.
+    pub fn header(&self) -> &[u8; CTMP_HEADER_LEN] {
+        &self.header
+    }
+
+    /// The payload bytes.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Consumes the frame, returning its payload.
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+
+    /// Whether the sensitive flag is set.
+    pub fn is_sensitive(&self) -> bool {
+        (self.header[1] & CTMP_SENSITIVE_FLAG) != 0
+    }
+
+    /// Whether more fragments of this frame's message follow it.
+    pub fn is_continued(&self) -> bool {
+        (self.header[1] & CTMP_CONTINUED_FLAG) != 0
+    }
+
+    /// Whether this is a control frame sent by the relay rather than relayed data.
+    pub fn is_control(&self) -> bool {
+        (self.header[1] & CTMP_CONTROL_FLAG) != 0
+    }
+
+    /// Whether this is the relay's goodbye frame.
+    pub fn is_goodbye(&self) -> bool {
+        self.is_control() && self.payload == GOODBYE
+    }
+
+    /// Whether this is the relay's probe frame.
+    pub fn is_probe(&self) -> bool {
+        self.is_control() && self.payload == PROBE
+    }
+
+    /// Whether this is a receiver's [compression request](Self::compression_request).
+    pub fn is_compression_request(&self) -> bool {
+        self.is_control() && self.payload == COMPRESS
+    }
+
+    /// Whether the payload is [compressed](crate::compression).
+    pub fn is_compressed(&self) -> bool {
+        (self.header[1] & CTMP_COMPRESSED_FLAG) != 0
+    }
+
+    /// The frame as the transmitter sent it, if it was compressed for this receiver.
+    ///
+    /// The compressed bit is cleared and the length, and the checksum of a sensitive
+    /// frame, are set for the original payload. A frame that is not compressed is
+    /// returned as it is.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(CtmpFrame)` - The uncompressed frame.
+    /// * `Err(CtmpError::InvalidCompression)` - If the payload does not decode to a
+    ///   payload a frame can carry.
+    pub fn decompressed(self) -> Result<Self, CtmpError> {
+        if !self.is_compressed() {
+            return Ok(self);
+        }
+        let payload = compression::decompress(&self.payload).ok_or(CtmpError::InvalidCompression)?;
+        if payload.is_empty() || payload.len() > CTMP_MAX_PAYLOAD_SIZE || payload.len() > u16::MAX as usize {
+            return Err(CtmpError::InvalidCompression);
+        }
+        let mut header = self.header;
+        header[1] &= !CTMP_COMPRESSED_FLAG;
+        compression::stamp(&mut header, &payload);
+        Ok(CtmpFrame { header, payload })
+    }
+
+    /// Whether this is the relay's keepalive frame.
+    pub fn is_keepalive(&self) -> bool {
+        self.is_control() && self.payload == KEEPALIVE
+    }
+
+    /// The sanitised name in a [label](Self::label) frame, or `None` if this is not a
+    /// label frame or nothing is left of the name.
+    pub fn label_name(&self) -> Option<String> {
+        frame_label(&self.header, &self.payload)
+    }
+
+    /// The checksum field of the header. Always zero for non-sensitive frames.
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes([self.header[4], self.header[5]])
+    }
+
+    /// Encodes the frame as it is sent on the wire, header first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CTMP_HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&self.header);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+// The sanitised name carried by a label frame, used where the frame is still in pieces.
+pub(crate) fn frame_label(header: &[u8], payload: &[u8]) -> Option<String> {
+    if header[1] & CTMP_CONTROL_FLAG == 0 {
+        return None;
+    }
+    payload.strip_prefix(LABEL).and_then(sanitize_label)
+}
+
+/// Makes a client-supplied name safe to log and show.
+///
+/// Invalid UTF-8 is replaced, control characters, which could forge log lines or send
+/// terminal escapes, are removed, surrounding whitespace is trimmed, and the result is
+/// cut to [`MAX_LABEL_LEN`] characters.
+///
+/// # Returns
+///
+/// The cleaned name, or `None` if nothing is left of it.
+pub fn sanitize_label(raw: &[u8]) -> Option<String> {
+    let cleaned: String = String::from_utf8_lossy(raw).chars().filter(|c| !c.is_control()).collect();
+    let label: String = cleaned.trim().chars().take(MAX_LABEL_LEN).collect();
+    let label = label.trim_end();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Encodes `payload` as a complete frame ready to write to the relay.
+///
+/// Shorthand for [`CtmpFrame::new`] followed by [`CtmpFrame::to_bytes`]. The checksum is
+/// always computed; use [`CtmpFrame::with_checksum`] to supply one.
+///
+/// # Arguments
+///
+/// * `payload` - The message body.
+/// * `sensitive` - Whether to set the sensitive flag and stamp a checksum.
+pub fn build_frame(payload: &[u8], sensitive: bool) -> Result<Vec<u8>, CtmpError> {
+    CtmpFrame::new(payload.to_vec(), sensitive).map(|frame| frame.to_bytes())
+}
+
+/// Reads and validates one frame from `reader`.
+///
+/// The header is checked with the same rules as the relay, and the checksum of a
+/// sensitive frame is verified. When the checksum is wrong the whole frame has still
+/// been consumed, so the caller may keep reading from the same stream.
+///
+/// # Arguments
+///
+/// * `reader` - The stream to read from.
+///
+/// # Returns
+///
+/// * `Ok(CtmpFrame)` - A valid frame.
+/// * `Err(CtmpError::Io)` - A read failure, including the stream ending part way through.
+/// * `Err(CtmpError)` - Any other variant for a protocol violation.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<CtmpFrame, CtmpError> {
+    let mut header = [0u8; CTMP_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+
+    if let Some(error) = validation_report(&header).into_iter().next() {
+        return Err(error);
+    }
+
+    let length = declared_length(&header);
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+
+    let frame = CtmpFrame { header, payload };
+    if frame.is_sensitive() {
+        let computed = verify_checksum(&frame.header, &frame.payload);
+        if computed != frame.checksum() {
+            return Err(CtmpError::ChecksumMismatch { expected: frame.checksum(), computed });
+        }
+    }
+
+    Ok(frame)
+}
+
+/// The length of the whole encoded frame a header starts, header included.
+///
+/// Only the fields that frame the stream are checked, the magic byte and the payload
+/// length, so a header that fails other rules still reports where its frame ends.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::{frame_len, CtmpError, CtmpFrame, CTMP_HEADER_LEN};
+/// let frame = CtmpFrame::new(b"hello".to_vec(), false).unwrap().to_bytes();
+/// assert_eq!(frame_len(&frame[..CTMP_HEADER_LEN]).unwrap(), frame.len());
+///
+/// let empty = [0xCC, 0, 0, 0, 0, 0, 0, 0];
+/// assert!(matches!(frame_len(&empty), Err(CtmpError::InvalidLength(0))));
+/// ```
+///
+/// # Panics
+///
+/// Panics if `header` is shorter than [`CTMP_HEADER_LEN`].
+pub fn frame_len(header: &[u8]) -> Result<usize, CtmpError> {
+    if header[0] != CTMP_MAGIC_BYTE {
+        return Err(CtmpError::InvalidMagic(header[0]));
+    }
+    let length = declared_length(header);
+    // the ceiling is only reachable if it is lowered below what 16 bits can declare
+    if length == 0 || length > CTMP_MAX_PAYLOAD_SIZE {
+        return Err(CtmpError::InvalidLength(length));
+    }
+    Ok(CTMP_HEADER_LEN + length)
+}
+
+// The payload length a header declares, whether or not it is valid.
+fn declared_length(header: &[u8]) -> usize {
+    u16::from_be_bytes([header[2], header[3]]) as usize
+}
+
+/// The frames [`validate_frames`] found in a buffer.
+#[derive(Debug)]
+pub struct FrameBatch {
+    /// A result for each frame, in order.
+    pub frames: Vec<Result<CtmpFrame, CtmpError>>,
+    /// How many bytes from the start of the buffer the frames took up. Anything after
+    /// it is an incomplete final frame, or follows a header that lost the framing.
+    pub consumed: usize,
+}
+
+/// Parses and validates every frame in a buffer of concatenated frames.
+///
+/// Each header is checked with the same rules as the relay, and the checksum of each
+/// sensitive frame is verified with `checksum`. A frame that fails does not stop the
+/// walk, since its length still says where the next one starts, unless the magic
+/// byte or length itself is bad: then the error is the last result and `consumed`
+/// stops before that header. An incomplete final frame is left out, and `consumed`
+/// stops before it, so the caller can keep those bytes and retry with more data.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::{validate_frames, ChecksumAlgorithm, CtmpFrame};
+/// let mut buffer = Vec::new();
+/// for payload in [&b"one"[..], b"two", b"three"] {
+///     buffer.extend(CtmpFrame::new(payload.to_vec(), true).unwrap().to_bytes());
+/// }
+/// let complete = buffer.len();
+/// buffer.extend(&CtmpFrame::new(b"partial".to_vec(), false).unwrap().to_bytes()[..10]);
+///
+/// let batch = validate_frames(&buffer, ChecksumAlgorithm::default());
+/// assert_eq!(batch.frames.len(), 3);
+/// assert!(batch.frames.iter().all(Result::is_ok));
+/// assert_eq!(batch.consumed, complete);
+/// ```
+pub fn validate_frames(buffer: &[u8], checksum: ChecksumAlgorithm) -> FrameBatch {
+    let mut frames = Vec::new();
+    let mut consumed = 0;
+    while let Some(header) = buffer.get(consumed..consumed + CTMP_HEADER_LEN) {
+        let mut errors = validation_report(header);
+        if let Some(lost) = errors
+            .iter()
+            .position(|e| matches!(e, CtmpError::InvalidMagic(_) | CtmpError::InvalidLength(_)))
+        {
+            frames.push(Err(errors.swap_remove(lost)));
+            break;
+        }
+
+        let length = declared_length(header);
+        let start = consumed + CTMP_HEADER_LEN;
+        let Some(payload) = buffer.get(start..start + length) else {
+            break;
+        };
+        consumed = start + length;
+
+        let frame = CtmpFrame { header: header.try_into().expect("header slice"), payload: payload.to_vec() };
+        frames.push(match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None if frame.is_sensitive() && !checksum.verify(&frame.header, &frame.payload) => {
+                let computed = checksum.frame_checksum(&frame.header, &frame.payload);
+                Err(CtmpError::ChecksumMismatch { expected: frame.checksum(), computed })
+            }
+            None => Ok(frame),
+        });
+    }
+    FrameBatch { frames, consumed }
+}
+
+/// Reads frames one after another from a stream, keeping track of where in the stream
+/// each one starts, for working out where a malformed stream goes wrong.
+///
+/// Frames are checked as by [`read_frame`], and errors come back as a
+/// [`FrameReadError`] saying where the frame that failed started and how far reading
+/// got. With [`verbose`](Self::verbose), the error also carries the offending header's
+/// bytes, shown as hex. That is off by default, since the header's length and checksum
+/// say something about the payload.
+///
+/// As an iterator it ends when the stream ends between frames. An error other than a
+/// checksum mismatch leaves the stream misframed, so iteration should stop there.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::{build_frame, CtmpError, FrameReader};
+/// let mut stream = build_frame(b"fine", false).unwrap();
+/// let good = stream.len();
+/// stream.extend([0xAB, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00]);
+///
+/// let mut reader = FrameReader::new(&stream[..]).verbose(true);
+/// assert_eq!(reader.read_frame().unwrap().payload(), b"fine");
+/// let err = reader.read_frame().unwrap_err();
+///
+/// assert!(matches!(err.error, CtmpError::InvalidMagic(0xAB)));
+/// assert_eq!(err.offset, good as u64);
+/// assert_eq!(err.to_string(), "Invalid magic byte: 0xab in the frame at byte 12, with 20 bytes read; header: ab 00 00 04 00 00 00 00");
+/// ```
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    position: u64,
+    verbose: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Wraps a stream positioned at the start of a frame.
+    pub fn new(inner: R) -> Self {
+        FrameReader { inner, position: 0, verbose: false }
+    }
+
+    /// Whether errors include the offending header's bytes.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// How many bytes have been read from the stream so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the wrapped stream.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads and validates the next frame.
+    ///
+    /// As with [`read_frame`], a frame with a bad checksum has been consumed whole, so
+    /// reading can carry on after it. After any other error the stream has lost its
+    /// framing.
+    pub fn read_frame(&mut self) -> Result<CtmpFrame, FrameReadError> {
+        let offset = self.position;
+        let mut header = [0u8; CTMP_HEADER_LEN];
+        let got = self.read_counted(&mut header);
+        if let Err(e) = got.1 {
+            return Err(self.error(e.into(), offset, &header[..got.0]));
+        }
+
+        if let Some(error) = validation_report(&header).into_iter().next() {
+            return Err(self.error(error, offset, &header));
+        }
+
+        let mut payload = vec![0u8; declared_length(&header)];
+        if let (_, Err(e)) = self.read_counted(&mut payload) {
+            return Err(self.error(e.into(), offset, &header));
+        }
+
+        let frame = CtmpFrame { header, payload };
+        if frame.is_sensitive() {
+            let computed = verify_checksum(&frame.header, &frame.payload);
+            if computed != frame.checksum() {
+                let error = CtmpError::ChecksumMismatch { expected: frame.checksum(), computed };
+                return Err(self.error(error, offset, &header));
+            }
+        }
+        Ok(frame)
+    }
+
+    // Fills `buf`, counting every byte read even if the stream ends part way.
+    fn read_counted(&mut self, buf: &mut [u8]) -> (usize, io::Result<()>) {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => return (filled, Err(io::ErrorKind::UnexpectedEof.into())),
+                Ok(n) => {
+                    filled += n;
+                    self.position += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return (filled, Err(e)),
+            }
+        }
+        (filled, Ok(()))
+    }
+
+    fn error(&self, error: CtmpError, offset: u64, header: &[u8]) -> FrameReadError {
+        FrameReadError {
+            error,
+            offset,
+            position: self.position,
+            header: self.verbose.then(|| header.to_vec()),
+        }
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<CtmpFrame, FrameReadError>;
+
+    /// Reads the next frame, ending cleanly when the stream ends between frames.
+    fn next(&mut self) -> Option<Self::Item> {
+        let before = self.position;
+        match self.read_frame() {
+            Err(FrameReadError { error: CtmpError::Io(e), position, .. })
+                if position == before && e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                None
+            }
+            result => Some(result),
+        }
+    }
+}
+
+/// A frame that [`FrameReader`] could not read, and where in the stream it was.
+#[derive(Debug)]
+pub struct FrameReadError {
+    /// Why the frame was rejected.
+    pub error: CtmpError,
+    /// Where the frame started: how many bytes of the stream came before it, all of
+    /// them parsed as frames.
+    pub offset: u64,
+    /// How many bytes had been read from the stream when the error was found.
+    pub position: u64,
+    /// The bytes of the frame's header, as many as were read, if the reader is
+    /// [verbose](FrameReader::verbose).
+    pub header: Option<Vec<u8>>,
+}
+
+impl fmt::Display for FrameReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in the frame at byte {}, with {} bytes read",
+            self.error, self.offset, self.position
+        )?;
+        if let Some(header) = &self.header {
+            let hex: Vec<String> = header.iter().map(|byte| format!("{:02x}", byte)).collect();
+            write!(f, "; header: {}", hex.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FrameReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<FrameReadError> for CtmpError {
+    fn from(e: FrameReadError) -> Self {
+        e.error
+    }
+}
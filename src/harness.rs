@@ -0,0 +1,469 @@
+//! A full server on ephemeral ports for end-to-end tests and examples.
+//!
+//! [`TestServer::start`] binds a [`Server`] to port `0` on the loopback interface and
+//! runs it in the background. Clients connected through [`TestServer::transmitter`]
+//! and [`TestServer::receiver`] are ordinary [`CtmpSender`]s and [`CtmpReceiver`]s, and
+//! the `wait_for_*` helpers replace sleeps when a test needs the server to have caught
+//! up. Dropping the `TestServer` shuts the server down and checks that none of its
+//! threads were left behind.
+//!
+//! A [`SlowReceiver`] is a receiver that reads at a fixed rate or stops reading
+//! altogether, for exercising write timeouts, queues and strikes. A [`Scenario`]
+//! plays raw bytes at the server as a transmitter, with scripted pauses, for
+//! truncated frames, slow dribbles and garbage.
+//!
+//! Only built with the `testing` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # use coretech_wirestorm::{Config, harness::TestServer};
+//! let server = TestServer::start(Config::default());
+//! let mut receiver = server.receiver();
+//! let mut transmitter = server.transmitter();
+//!
+//! transmitter.send(b"hello", true).unwrap();
+//! assert_eq!(receiver.recv().unwrap().payload(), b"hello");
+//! ```
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{ClientOptions, CtmpClient, CtmpReceiver, CtmpSender};
+use crate::{Config, Server, ServerHandle};
+
+/// How long the helpers wait for the server before giving up.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on ephemeral loopback ports.
+pub struct TestServer {
+    handle: ServerHandle,
+    client: CtmpClient,
+}
+
+impl TestServer {
+    /// Starts a server with `config`, overriding its listener addresses with `127.0.0.1:0`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server cannot be started, or if `config` disables TCP destinations.
+    pub fn start(mut config: Config) -> TestServer {
+        assert!(config.tcp_destinations, "TestServer needs TCP destinations");
+        config.source_addr = "127.0.0.1:0".into();
+        config.destination_addr = "127.0.0.1:0".into();
+        config.socket_activation = false;
+
+        let handle = Server::bind(config)
+            .and_then(Server::spawn)
+            .unwrap_or_else(|e| panic!("Failed to start test server: {}", e));
+        let client = CtmpClient::new(ClientOptions {
+            read_timeout: Some(DEFAULT_TIMEOUT),
+            ..ClientOptions::default()
+        });
+
+        TestServer { handle, client }
+    }
+
+    /// The address transmitters connect to.
+    pub fn source_addr(&self) -> SocketAddr {
+        self.handle.source_addr()
+    }
+
+    /// The address receivers connect to.
+    pub fn destination_addr(&self) -> SocketAddr {
+        self.handle
+            .destination_addr()
+            .expect("TestServer always has a destination listener")
+    }
+
+    /// The running server.
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// Connects a transmitter.
+    ///
+    /// Returns as soon as the connection is open. Use
+    /// [`wait_for_transmitter`](Self::wait_for_transmitter) if the test needs the server
+    /// to have accepted it as the active source.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails.
+    pub fn transmitter(&self) -> CtmpSender {
+        self.client
+            .source(self.source_addr())
+            .unwrap_or_else(|e| panic!("Failed to connect transmitter: {}", e))
+    }
+
+    /// Connects a receiver and waits until the server has registered it.
+    ///
+    /// Every frame broadcast after this returns reaches the receiver.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails or the server does not register it in time.
+    pub fn receiver(&self) -> CtmpReceiver {
+        let before = self.handle.destinations().len();
+        let receiver = self
+            .client
+            .destination(self.destination_addr())
+            .unwrap_or_else(|e| panic!("Failed to connect receiver: {}", e));
+        assert!(
+            self.wait_for_destinations(before + 1, DEFAULT_TIMEOUT),
+            "Server did not register the receiver"
+        );
+        receiver
+    }
+
+    /// Connects a receiver that names itself with `label`, and waits until the server
+    /// has registered it. The server must be configured with a
+    /// [`destination_label_wait`](Config::destination_label_wait) for the label to be
+    /// read.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// # use coretech_wirestorm::{Config, harness::TestServer};
+    /// let config = Config { destination_label_wait: Some(Duration::from_secs(1)), ..Config::default() };
+    /// let server = TestServer::start(config);
+    /// let _receiver = server.labelled_receiver("dashboard");
+    /// let mut transmitter = server.transmitter();
+    /// transmitter.send_label("feed-a").unwrap();
+    /// transmitter.send(b"hello", false).unwrap();
+    ///
+    /// while server.handle().status().metrics.frames_relayed == 0 {
+    ///     thread::sleep(Duration::from_millis(5));
+    /// }
+    /// let status = server.handle().status();
+    /// assert_eq!(status.destinations[0].label.as_deref(), Some("dashboard"));
+    /// assert_eq!(status.transmitter_label.as_deref(), Some("feed-a"));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails or the server does not register it in time.
+    pub fn labelled_receiver(&self, label: &str) -> CtmpReceiver {
+        let before = self.handle.destinations().len();
+        let mut receiver = self
+            .client
+            .destination(self.destination_addr())
+            .unwrap_or_else(|e| panic!("Failed to connect receiver: {}", e));
+        receiver
+            .send_label(label)
+            .unwrap_or_else(|e| panic!("Failed to send receiver label: {}", e));
+        assert!(
+            self.wait_for_destinations(before + 1, DEFAULT_TIMEOUT),
+            "Server did not register the receiver"
+        );
+        receiver
+    }
+
+    /// Connects a [`SlowReceiver`] and waits until the server has registered it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails or the server does not register it in time.
+    pub fn slow_receiver(&self, pace: Pace) -> SlowReceiver {
+        let before = self.handle.destinations().len();
+        let receiver = SlowReceiver::connect(self.destination_addr(), pace)
+            .unwrap_or_else(|e| panic!("Failed to connect slow receiver: {}", e));
+        assert!(
+            self.wait_for_destinations(before + 1, DEFAULT_TIMEOUT),
+            "Server did not register the slow receiver"
+        );
+        receiver
+    }
+
+    /// Plays `scenario` at the source listener.
+    ///
+    /// # Returns
+    ///
+    /// The transmitter connection, still open, so the test can go on writing or close it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if connecting or any write fails.
+    pub fn play(&self, scenario: &Scenario) -> TcpStream {
+        scenario
+            .play(self.source_addr())
+            .unwrap_or_else(|e| panic!("Failed to play scenario: {}", e))
+    }
+
+    /// Waits until exactly `count` receivers are connected.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the count was reached before `timeout`.
+    pub fn wait_for_destinations(&self, count: usize, timeout: Duration) -> bool {
+        wait_until(timeout, || self.handle.destinations().len() == count)
+    }
+
+    /// Waits until a transmitter is (`true`) or is not (`false`) the active source.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the state was reached before `timeout`.
+    pub fn wait_for_transmitter(&self, connected: bool, timeout: Duration) -> bool {
+        wait_until(timeout, || self.handle.has_transmitter() == connected)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        let leaked = self.handle.running_threads();
+        if leaked != 0 && !thread::panicking() {
+            panic!("{} server threads still running after shutdown", leaked);
+        }
+    }
+}
+
+/// A script of raw bytes for a transmitter to send, each after a pause.
+///
+/// # Examples
+///
+/// A frame dribbled a byte at a time still arrives whole, while one cut off part way
+/// through its payload is never relayed:
+///
+/// ```rust
+/// # use std::net::Shutdown;
+/// # use std::time::Duration;
+/// # use coretech_wirestorm::{Config, CtmpFrame, harness::{Scenario, TestServer, DEFAULT_TIMEOUT}};
+/// let server = TestServer::start(Config::default());
+/// let mut receiver = server.receiver();
+/// let frame = CtmpFrame::new(b"slowly".to_vec(), true).unwrap().to_bytes();
+///
+/// let dribble = Scenario::new().dribble(&frame, Duration::from_millis(5));
+/// let transmitter = server.play(&dribble);
+/// assert_eq!(receiver.recv().unwrap().payload(), b"slowly");
+/// drop(transmitter);
+/// assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+///
+/// let truncated = Scenario::new().send(&frame[..frame.len() - 2]);
+/// let transmitter = server.play(&truncated);
+/// transmitter.shutdown(Shutdown::Write).unwrap();
+/// assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+/// assert_eq!(server.handle().metrics().frames_relayed.get(), 1);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scenario {
+    steps: Vec<(Duration, Vec<u8>)>,
+}
+
+impl Scenario {
+    /// Creates an empty scenario.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `bytes` after waiting `delay`.
+    pub fn then(mut self, delay: Duration, bytes: &[u8]) -> Self {
+        self.steps.push((delay, bytes.to_vec()));
+        self
+    }
+
+    /// Sends `bytes` straight away.
+    pub fn send(self, bytes: &[u8]) -> Self {
+        self.then(Duration::ZERO, bytes)
+    }
+
+    /// Waits `delay` without sending anything.
+    pub fn wait(self, delay: Duration) -> Self {
+        self.then(delay, &[])
+    }
+
+    /// Sends `bytes` one at a time, waiting `delay` before each.
+    pub fn dribble(self, bytes: &[u8], delay: Duration) -> Self {
+        bytes.iter().fold(self, |scenario, &byte| scenario.then(delay, &[byte]))
+    }
+
+    /// The `(delay, bytes)` steps, in order.
+    pub fn steps(&self) -> &[(Duration, Vec<u8>)] {
+        &self.steps
+    }
+
+    /// Connects to a transmitter listener at `addr` and plays every step.
+    ///
+    /// # Returns
+    ///
+    /// The connection, still open, or the first error connecting or writing.
+    pub fn play(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        for (delay, bytes) in &self.steps {
+            thread::sleep(*delay);
+            stream.write_all(bytes)?;
+        }
+        Ok(stream)
+    }
+}
+
+/// How fast a [`SlowReceiver`] reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// Read at most this many bytes per second.
+    BytesPerSec(usize),
+    /// Read this many bytes as fast as they arrive, then stop reading but stay connected.
+    StopAfter(usize),
+}
+
+// How often a paced receiver reads its allowance.
+const PACE_TICK: Duration = Duration::from_millis(10);
+
+/// A receiver that reads slowly, or stops reading, to hold the relay back.
+///
+/// It reads on a background thread and keeps every byte it reads. Once the relay
+/// closes the connection [`closed`](Self::closed) becomes true. Dropping it closes
+/// the connection.
+///
+/// # Examples
+///
+/// A receiver that stops reading is evicted once a write to it times out:
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use coretech_wirestorm::{Config, harness::{Pace, TestServer, DEFAULT_TIMEOUT}};
+/// let mut config = Config::default();
+/// config.delivery.write_timeout = Some(Duration::from_millis(50));
+/// let server = TestServer::start(config);
+/// let stalled = server.slow_receiver(Pace::StopAfter(0));
+/// let mut transmitter = server.transmitter();
+///
+/// // keep sending until the socket buffers fill and a write times out
+/// let payload = vec![0u8; 60_000];
+/// for _ in 0..1_000 {
+///     if server.handle().destinations().is_empty() {
+///         break;
+///     }
+///     transmitter.send(&payload, false).unwrap();
+/// }
+/// assert!(server.wait_for_destinations(0, DEFAULT_TIMEOUT));
+/// assert_eq!(stalled.received(), 0);
+/// assert_eq!(server.handle().metrics().destinations_evicted.get(), 1);
+/// ```
+pub struct SlowReceiver {
+    stream: TcpStream,
+    received: Arc<Mutex<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SlowReceiver {
+    /// Connects to the relay's destination listener at `addr`.
+    pub fn connect(addr: SocketAddr, pace: Pace) -> std::io::Result<SlowReceiver> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(PACE_TICK))?;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let mut stream = stream.try_clone()?;
+            let received = Arc::clone(&received);
+            let closed = Arc::clone(&closed);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || read_paced(&mut stream, pace, &received, &closed, &stop))
+        };
+
+        Ok(SlowReceiver { stream, received, closed, stop, thread: Some(thread) })
+    }
+
+    /// Number of bytes read so far.
+    pub fn received(&self) -> usize {
+        self.received.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// A copy of every byte read so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.received.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether the relay has closed the connection. Only noticed while still reading.
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Waits until at least `bytes` bytes have been read.
+    ///
+    /// # Returns
+    ///
+    /// `true` if they were before `timeout`.
+    pub fn wait_for_received(&self, bytes: usize, timeout: Duration) -> bool {
+        wait_until(timeout, || self.received() >= bytes)
+    }
+}
+
+impl Drop for SlowReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Reads from `stream` at `pace` until it closes or `stop` is set.
+fn read_paced(
+    stream: &mut TcpStream,
+    pace: Pace,
+    received: &Mutex<Vec<u8>>,
+    closed: &AtomicBool,
+    stop: &AtomicBool,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    while !stop.load(Ordering::SeqCst) {
+        let allowance = match pace {
+            Pace::BytesPerSec(rate) => (rate * PACE_TICK.as_millis() as usize / 1000).max(1),
+            Pace::StopAfter(limit) => limit - total,
+        };
+        if allowance == 0 {
+            thread::sleep(PACE_TICK);
+            continue;
+        }
+        let started = Instant::now();
+        let len = allowance.min(buf.len());
+        match stream.read(&mut buf[..len]) {
+            Ok(0) => {
+                closed.store(true, Ordering::SeqCst);
+                return;
+            }
+            Ok(n) => {
+                total += n;
+                received.lock().unwrap_or_else(|e| e.into_inner()).extend_from_slice(&buf[..n]);
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => {
+                closed.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+        if let Pace::BytesPerSec(_) = pace {
+            thread::sleep(PACE_TICK.saturating_sub(started.elapsed()));
+        }
+    }
+}
+
+// Polls `condition` until it holds or `timeout` passes.
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
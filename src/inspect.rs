@@ -0,0 +1,335 @@
+//! Offline analysis of files containing concatenated CTMP frames.
+//!
+//! The [`analyze`] function walks a capture one frame at a time, running the same
+//! checks the relay applies to live traffic, and summarises what it found in an
+//! [`InspectReport`]. Only a single frame is held in memory at once, so captures of
+//! any size can be inspected. The `ctmp-inspect` binary is a thin wrapper around
+//! this module.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+
+use crate::{validation_report, verify_checksum, CtmpError, CTMP_HEADER_LEN, CTMP_SENSITIVE_FLAG};
+
+/// Upper bounds (inclusive) of the payload size buckets in [`InspectReport::size_buckets`].
+pub const SIZE_BUCKETS: [usize; 6] = [64, 256, 1024, 4096, 16384, 65536];
+
+/// Summary of a capture file produced by [`analyze`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InspectReport {
+    /// Total bytes read from the input.
+    pub bytes_read: u64,
+    /// Number of frames with a complete header, valid or not.
+    pub total_frames: u64,
+    /// Number of frames that would have been broadcast by the relay.
+    pub valid_frames: u64,
+    /// Number of valid frames with the sensitive flag set.
+    pub sensitive_frames: u64,
+    /// Count of invalid frames keyed by [`CtmpError::reason`].
+    ///
+    /// Each invalid frame is counted once, under the first violation found.
+    pub drop_reasons: BTreeMap<&'static str, u64>,
+    /// Payload size distribution of valid frames, one count per entry of [`SIZE_BUCKETS`].
+    pub size_buckets: [u64; SIZE_BUCKETS.len()],
+    /// Smallest valid payload seen, in bytes.
+    pub min_payload: Option<usize>,
+    /// Largest valid payload seen, in bytes.
+    pub max_payload: Option<usize>,
+    /// Sum of all valid payload lengths, in bytes.
+    pub total_payload_bytes: u64,
+    /// Byte offset of the first invalid frame, if any.
+    pub first_corruption: Option<u64>,
+    /// Byte offset of a final frame cut short by the end of the input, if any.
+    pub truncated_at: Option<u64>,
+    /// Bytes left after framing was lost (bad magic byte or length), which could not be parsed.
+    pub unparsed_bytes: u64,
+}
+
+impl InspectReport {
+    /// Number of frames dropped for any reason.
+    pub fn invalid_frames(&self) -> u64 {
+        self.drop_reasons.values().sum()
+    }
+
+    /// Number of sensitive frames dropped for a bad checksum.
+    pub fn checksum_failures(&self) -> u64 {
+        self.drop_reasons
+            .get(CtmpError::ChecksumMismatch { expected: 0, computed: 0 }.reason())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Fraction of valid frames that were sensitive, or `0.0` if there were none.
+    pub fn sensitive_ratio(&self) -> f64 {
+        if self.valid_frames == 0 {
+            return 0.0;
+        }
+        self.sensitive_frames as f64 / self.valid_frames as f64
+    }
+
+    /// Renders the report as a single JSON object.
+    pub fn to_json(&self) -> String {
+        let drop_reasons = self
+            .drop_reasons
+            .iter()
+            .map(|(reason, count)| format!("\"{}\":{}", reason, count))
+            .collect::<Vec<_>>()
+            .join(",");
+        let size_buckets = SIZE_BUCKETS
+            .iter()
+            .zip(self.size_buckets.iter())
+            .map(|(bound, count)| format!("\"<={}\":{}", bound, count))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            concat!(
+                "{{\"bytes_read\":{},\"total_frames\":{},\"valid_frames\":{},",
+                "\"invalid_frames\":{},\"sensitive_frames\":{},\"sensitive_ratio\":{},",
+                "\"checksum_failures\":{},\"drop_reasons\":{{{}}},\"size_buckets\":{{{}}},",
+                "\"min_payload\":{},\"max_payload\":{},\"total_payload_bytes\":{},",
+                "\"first_corruption\":{},\"truncated_at\":{},\"unparsed_bytes\":{}}}"
+            ),
+            self.bytes_read,
+            self.total_frames,
+            self.valid_frames,
+            self.invalid_frames(),
+            self.sensitive_frames,
+            self.sensitive_ratio(),
+            self.checksum_failures(),
+            drop_reasons,
+            size_buckets,
+            json_opt(self.min_payload),
+            json_opt(self.max_payload),
+            self.total_payload_bytes,
+            json_opt(self.first_corruption),
+            json_opt(self.truncated_at),
+            self.unparsed_bytes,
+        )
+    }
+
+    fn record_valid(&mut self, length: usize, sensitive: bool) {
+        self.valid_frames += 1;
+        if sensitive {
+            self.sensitive_frames += 1;
+        }
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| length <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len() - 1);
+        self.size_buckets[bucket] += 1;
+        self.min_payload = Some(self.min_payload.map_or(length, |min| min.min(length)));
+        self.max_payload = Some(self.max_payload.map_or(length, |max| max.max(length)));
+        self.total_payload_bytes += length as u64;
+    }
+
+    fn record_invalid(&mut self, offset: u64, error: &CtmpError) {
+        *self.drop_reasons.entry(error.reason()).or_insert(0) += 1;
+        self.first_corruption.get_or_insert(offset);
+    }
+}
+
+impl std::fmt::Display for InspectReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "bytes read:         {}", self.bytes_read)?;
+        writeln!(f, "total frames:       {}", self.total_frames)?;
+        writeln!(f, "valid frames:       {}", self.valid_frames)?;
+        writeln!(f, "invalid frames:     {}", self.invalid_frames())?;
+        for (reason, count) in &self.drop_reasons {
+            writeln!(f, "  {:<24}{}", reason, count)?;
+        }
+        writeln!(f, "checksum failures:  {}", self.checksum_failures())?;
+        writeln!(
+            f,
+            "sensitive frames:   {} ({:.1}%)",
+            self.sensitive_frames,
+            self.sensitive_ratio() * 100.0
+        )?;
+        if let (Some(min), Some(max)) = (self.min_payload, self.max_payload) {
+            writeln!(f, "payload sizes:      min {} / max {} / total {}", min, max, self.total_payload_bytes)?;
+        }
+        for (bound, count) in SIZE_BUCKETS.iter().zip(self.size_buckets.iter()) {
+            writeln!(f, "  <= {:<21}{}", bound, count)?;
+        }
+        match self.first_corruption {
+            Some(offset) => writeln!(f, "first corruption:   byte {}", offset)?,
+            None => writeln!(f, "first corruption:   none")?,
+        }
+        if let Some(offset) = self.truncated_at {
+            writeln!(f, "warning: truncated final frame at byte {}", offset)?;
+        }
+        if self.unparsed_bytes > 0 {
+            writeln!(f, "warning: framing lost, {} trailing bytes not parsed", self.unparsed_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn json_opt<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |v| v.to_string())
+}
+
+/// One step of the walk over a capture.
+enum Step {
+    /// A complete frame starting at `offset`, with every violation it contains.
+    Frame { offset: u64, header: [u8; CTMP_HEADER_LEN], payload: Vec<u8>, errors: Vec<CtmpError> },
+    /// The input ended part way through the frame starting at `offset`.
+    Truncated { offset: u64 },
+    /// Framing was lost at `offset`; the remaining `unparsed` bytes were skipped.
+    Lost { offset: u64, errors: Vec<CtmpError>, unparsed: u64 },
+}
+
+/// Reads a capture one frame at a time.
+struct Walker<R> {
+    reader: R,
+    offset: u64,
+    done: bool,
+}
+
+impl<R: Read> Walker<R> {
+    fn new(reader: R) -> Self {
+        Walker { reader, offset: 0, done: false }
+    }
+
+    fn next_step(&mut self) -> io::Result<Option<Step>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let offset = self.offset;
+        let mut header = [0u8; CTMP_HEADER_LEN];
+        let read = read_full(&mut self.reader, &mut header)?;
+        self.offset += read as u64;
+        if read == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+        if read < CTMP_HEADER_LEN {
+            self.done = true;
+            return Ok(Some(Step::Truncated { offset }));
+        }
+
+        let errors = validation_report(&header);
+        // Without a trustworthy magic byte and length there is no way to find the next frame
+        if errors
+            .iter()
+            .any(|e| matches!(e, CtmpError::InvalidMagic(_) | CtmpError::InvalidLength(_)))
+        {
+            self.done = true;
+            let unparsed = io::copy(&mut self.reader, &mut io::sink())?;
+            self.offset += unparsed;
+            return Ok(Some(Step::Lost { offset, errors, unparsed }));
+        }
+
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut payload = vec![0u8; length];
+        let read = read_full(&mut self.reader, &mut payload)?;
+        self.offset += read as u64;
+        if read < length {
+            self.done = true;
+            return Ok(Some(Step::Truncated { offset }));
+        }
+
+        Ok(Some(Step::Frame { offset, header, payload, errors }))
+    }
+}
+
+/// Fills `buf` from `reader`, stopping early only at end of input.
+///
+/// Returns the number of bytes read, which is less than `buf.len()` only if the
+/// input ended.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Checks a sensitive frame's checksum, returning the mismatch if there is one.
+fn checksum_error(header: &[u8], payload: &[u8]) -> Option<CtmpError> {
+    let sensitive = (header[1] & CTMP_SENSITIVE_FLAG) != 0;
+    if !sensitive {
+        return None;
+    }
+    let expected = u16::from_be_bytes([header[4], header[5]]);
+    let computed = verify_checksum(header, payload);
+    (expected != computed).then_some(CtmpError::ChecksumMismatch { expected, computed })
+}
+
+/// Analyzes a stream of concatenated CTMP frames.
+///
+/// Every frame is validated as the relay would validate it. A frame with a bad
+/// magic byte or length loses the framing, so analysis stops there and the rest of
+/// the input is counted in [`InspectReport::unparsed_bytes`]. A final frame that is
+/// cut short is reported in [`InspectReport::truncated_at`] rather than as an error.
+///
+/// # Arguments
+/// * `reader` - The capture to read. It is consumed to the end.
+///
+/// # Returns
+/// * `Ok(InspectReport)` - The summary of the capture.
+/// * `Err(io::Error)` - If reading from `reader` fails.
+pub fn analyze<R: Read>(reader: R) -> io::Result<InspectReport> {
+    let mut report = InspectReport::default();
+    let mut walker = Walker::new(reader);
+
+    while let Some(step) = walker.next_step()? {
+        match step {
+            Step::Frame { offset, header, payload, errors } => {
+                report.total_frames += 1;
+                let error = errors
+                    .into_iter()
+                    .next()
+                    .or_else(|| checksum_error(&header, &payload));
+                match error {
+                    Some(error) => report.record_invalid(offset, &error),
+                    None => report.record_valid(payload.len(), (header[1] & CTMP_SENSITIVE_FLAG) != 0),
+                }
+            }
+            Step::Truncated { offset } => report.truncated_at = Some(offset),
+            Step::Lost { offset, errors, unparsed, .. } => {
+                report.total_frames += 1;
+                if let Some(error) = errors.first() {
+                    report.record_invalid(offset, error);
+                }
+                report.unparsed_bytes = unparsed;
+            }
+        }
+    }
+
+    report.bytes_read = walker.offset;
+    Ok(report)
+}
+
+/// Returns the payload of the frame at `index` (zero based), whether or not it is valid.
+///
+/// Frames are counted the same way as [`InspectReport::total_frames`]. Returns
+/// `Ok(None)` if the input has no complete frame at that index.
+///
+/// # Arguments
+/// * `reader` - The capture to read.
+/// * `index` - Which frame to extract, starting from `0`.
+pub fn extract<R: Read>(reader: R, index: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut walker = Walker::new(reader);
+    let mut current = 0;
+
+    while let Some(step) = walker.next_step()? {
+        match step {
+            Step::Frame { payload, .. } => {
+                if current == index {
+                    return Ok(Some(payload));
+                }
+                current += 1;
+            }
+            Step::Truncated { .. } | Step::Lost { .. } => break,
+        }
+    }
+
+    Ok(None)
+}
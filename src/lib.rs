@@ -9,93 +9,1486 @@
 //!
 //! [`Easy`]: http://thatwaseasy.example.com
 
-use std::{sync::{mpsc, Arc, Mutex}, io::{Write,Read,BufReader}, thread};
-use std::net::TcpStream;
+use std::{collections::VecDeque, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Condvar, Mutex, MutexGuard, TryLockError}, io::{self,Write,Read,BufRead,BufReader}, thread, time::{Duration, Instant}};
+use std::net::{SocketAddr, TcpStream};
 
-const CTMP_HEADER_LEN: usize = 8;
+pub mod activation;
+pub mod bridge;
+pub mod checksum;
+pub mod client;
+pub mod compression;
+pub mod config;
+mod error;
+pub mod frame;
+pub mod inspect;
+pub mod metrics;
+pub mod multicast;
+pub mod quarantine;
+pub mod routing;
+pub mod selftest;
+pub mod server;
+#[cfg(feature = "testing")]
+pub mod harness;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+pub use checksum::ChecksumAlgorithm;
+pub use client::{CtmpClient, CtmpReceiver, CtmpSender};
+pub use config::{
+    AutoscalePolicy, BridgeConfig, CapacityPolicy, ChecksumOverload, Config, DeliveryPolicy, DestinationIo, MulticastConfig, OversizePolicy,
+    PreloadConfig, QuarantinePolicy, SourceConfig, TransmitterPolicy,
+};
+pub use error::CtmpError;
+pub use frame::{
+    build_frame, frame_len, read_frame, sanitize_label, validate_frames, CtmpFrame, FrameBatch, FrameReadError,
+    FrameReader, MAX_LABEL_LEN,
+};
+pub use metrics::Metrics;
+use metrics::SessionStats;
+pub use multicast::MulticastDestination;
+use routing::{Admission, LoopGuard};
+pub use server::{Server, ServerHandle, ServerStatus};
+
+/// Length of a CTMP header in bytes.
+pub const CTMP_HEADER_LEN: usize = 8;
 const CTMP_PAD: u8 = 0x00;
-const CTMP_MAX_PAYLOAD_SIZE: usize = 65536; //16KiB
+/// Largest payload length the relay accepts, a compile-time ceiling that every header
+/// check and [`TransmitterPolicy::max_payload`] is held to, and that
+/// [`Server::bind`] refuses a policy to be configured above.
+///
+/// A header's 16-bit length field declares at most 65535 bytes, so at its current
+/// value headers never reach the ceiling; the checks against it only bite if it is
+/// lowered.
+pub const CTMP_MAX_PAYLOAD_SIZE: usize = 65536;
 const CTMP_MAGIC_BYTE: u8 = 0xCC;
+const CTMP_SENSITIVE_FLAG: u8 = 0x40; // bit 1 of the options byte
+const CTMP_CONTROL_FLAG: u8 = 0x20; // bit 2, set only on frames the relay itself sends
+const CTMP_ROUTED_FLAG: u8 = 0x10; // bit 3, the padding bytes carry a routing id
+const CTMP_CONTINUED_FLAG: u8 = 0x08; // bit 4, more fragments of the same message follow
+const CTMP_COMPRESSED_FLAG: u8 = 0x04; // bit 5, set only on frames the relay compressed for a receiver
+
+// The id the next receiver wrapped is given, so none is ever reused.
+static NEXT_DESTINATION_ID: AtomicU64 = AtomicU64::new(1);
+
+
+
+/// A stream that frames can be broadcast to.
+///
+/// Implemented for `TcpStream`, which is what the server uses. Anything else that
+/// implements it, such as the in-memory streams in the `testing` module, can stand
+/// in for a receiver connection.
+pub trait Transport: Write + Send + 'static {
+    /// Bounds how long a single write may block. `None` blocks forever.
+    ///
+    /// The default implementation ignores the timeout.
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+
+    /// The address of the remote end, used to pick out a receiver by address.
+    ///
+    /// The default implementation reports that the stream has none.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "stream has no peer address"))
+    }
+
+    /// While `corked`, holds back partly filled packets so that small writes go out
+    /// together; uncorking sends whatever is held. Used for [`DeliveryPolicy::cork`].
+    ///
+    /// The default implementation ignores it.
+    fn set_cork(&self, corked: bool) -> io::Result<()> {
+        let _ = corked;
+        Ok(())
+    }
+
+    /// Makes writes return [`WouldBlock`](io::ErrorKind::WouldBlock) instead of waiting
+    /// when the stream cannot take any more. Used for [`DestinationIo::NonBlocking`].
+    ///
+    /// The default implementation ignores it, so writes keep waiting.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let _ = nonblocking;
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    // TCP_CORK is Linux-only; elsewhere the policy is refused when the config is parsed.
+    fn set_cork(&self, corked: bool) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            set_tcp_cork(self, corked)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = corked;
+            Err(io::Error::new(io::ErrorKind::Unsupported, "TCP_CORK is only available on Linux"))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_cork(stream: &TcpStream, corked: bool) -> io::Result<()> {
+    use std::ffi::{c_int, c_void};
+    use std::os::fd::AsRawFd;
 
+    const IPPROTO_TCP: c_int = 6;
+    const TCP_CORK: c_int = 3;
 
+    unsafe extern "C" {
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+    }
+
+    let value = c_int::from(corked);
+    // SAFETY: the descriptor is open for as long as `stream` is borrowed, and `value`
+    // outlives the call and is as long as the length passed.
+    let ret = unsafe {
+        setsockopt(
+            stream.as_raw_fd(),
+            IPPROTO_TCP,
+            TCP_CORK,
+            (&value as *const c_int).cast(),
+            std::mem::size_of::<c_int>() as u32,
+        )
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+// Buffered receivers are flushed after every broadcast, or on `DeliveryPolicy::flush_interval`.
+impl<W: Transport> Transport for io::BufWriter<W> {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn set_cork(&self, corked: bool) -> io::Result<()> {
+        self.get_ref().set_cork(corked)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.get_ref().set_nonblocking(nonblocking)
+    }
+}
+
+/// The relay's version: the crate version, followed by the short hash of the commit
+/// it was built from when the build could read one, as in `0.1.0 (52bf522)`.
+///
+/// # Examples
+///
+/// ```rust
+/// let version = coretech_wirestorm::version();
+/// assert!(!version.is_empty());
+/// assert!(version.starts_with(env!("CARGO_PKG_VERSION")));
+/// ```
+pub fn version() -> &'static str {
+    env!("WIRESTORM_VERSION")
+}
+
+/// The address of a stream's peer, or `None` if it cannot be read, for example
+/// because the connection was reset before it was accepted.
+///
+/// Anything that decides on the address, such as the [quarantine](quarantine),
+/// must treat `None` as a peer it cannot verify and refuse it.
+pub fn peer_of<T: Transport + ?Sized>(stream: &T) -> Option<SocketAddr> {
+    stream.peer_addr().ok()
+}
+
+/// Formats a peer address for logging, as `unknown` when there is none.
+pub fn display_peer(peer: Option<SocketAddr>) -> String {
+    peer.map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+}
+
+// Formats a client for logging: its address, and its label if it gave one.
+fn display_client(peer: Option<SocketAddr>, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} ({:?})", display_peer(peer), label),
+        None => display_peer(peer),
+    }
+}
+
+/// A connected receiver client and its delivery state.
+///
+/// Besides the stream itself, a `Destination` counts consecutive failed writes
+/// ("strikes") so that a receiver that stalls briefly is not evicted on its first
+/// timeout, and keeps the unwritten tail of a frame interrupted part way through,
+/// which is finished before anything else is sent so the receiver's stream stays
+/// correctly framed.
+///
+/// Frames that could not be written at all wait in a queue bounded by
+/// [`DeliveryPolicy::queue_depth`]. When it is full the oldest queued frame is dropped,
+/// so a receiver that catches up gets the most recent frames. Control frames such as
+/// the goodbye frame have a queue of their own, with the same bound, which is always
+/// emptied before any queued data frame is sent. Every frame a receiver
+/// misses this way is counted in [`frames_dropped`](Self::frames_dropped). With a
+/// [`DeliveryPolicy::frame_ttl`], queued data frames that have waited longer than it
+/// are discarded instead of sent, and counted in [`frames_expired`](Self::frames_expired).
+///
+/// [`DeliveryPolicy::queue_memory`] also bounds the bytes waiting across all receivers.
+/// Once it is reached, delivery is lossy: a receiver that cannot take a frame straight
+/// away skips it, and counts it as dropped, while receivers that can take it still do.
+pub struct Destination<T = TcpStream> {
+    stream: T,
+    id: u64,
+    peer: Option<SocketAddr>,
+    strikes: u32,
+    failing_since: Option<Instant>,
+    // a frame interrupted part way through, and how many of its bytes were written
+    unsent: Option<(Arc<Vec<u8>>, usize)>,
+    // control frames waiting, delivered before `queue`, each with when it was queued
+    urgent: VecDeque<(Arc<Vec<u8>>, Instant)>,
+    queue: VecDeque<(Arc<Vec<u8>>, Instant)>,
+    frames_dropped: u64,
+    frames_expired: u64,
+    joined_at: u64,
+    label: Option<String>,
+    last_active: Instant,
+    compress: bool,
+}
+
+impl<T: Transport> Destination<T> {
+    /// Wraps a receiver stream with no strikes against it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connected receiver client.
+    pub fn new(stream: T) -> Self {
+        Destination {
+            peer: peer_of(&stream),
+            stream,
+            id: NEXT_DESTINATION_ID.fetch_add(1, Ordering::Relaxed),
+            strikes: 0,
+            failing_since: None,
+            unsent: None,
+            urgent: VecDeque::new(),
+            queue: VecDeque::new(),
+            frames_dropped: 0,
+            frames_expired: 0,
+            joined_at: 0,
+            label: None,
+            last_active: Instant::now(),
+            compress: false,
+        }
+    }
+    /// The receiver's stream.
+    pub fn stream(&self) -> &T {
+        &self.stream
+    }
+    /// A number identifying the receiver, unique among every receiver the process has
+    /// wrapped. Unlike the address, it is never shared with an earlier connection.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    /// The receiver's address, if its stream has one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+    /// The name the receiver gave itself with a [label frame](CtmpFrame::label), if any.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+    /// The [`generation`](Destinations::generation) when this receiver was added. The
+    /// first frame it is sent is the next one.
+    pub fn joined_at(&self) -> u64 {
+        self.joined_at
+    }
+    /// Whether the receiver asked for, and is sent, [compressed](compression) frames.
+    pub fn compressed(&self) -> bool {
+        self.compress
+    }
+    /// When the receiver last took a whole frame, or when it was created if it has not
+    /// taken one yet.
+    pub fn last_active(&self) -> Instant {
+        self.last_active
+    }
+    /// Number of consecutive failed writes since the last successful one.
+    pub fn strikes(&self) -> u32 {
+        self.strikes
+    }
+    /// Number of frames this receiver has missed because they could not be written or queued.
+    pub fn frames_dropped(&self) -> u64 {
+        self.frames_dropped
+    }
+    /// Number of queued frames this receiver was never sent because they waited past
+    /// the [`DeliveryPolicy::frame_ttl`].
+    pub fn frames_expired(&self) -> u64 {
+        self.frames_expired
+    }
+    /// Number of frames waiting to be written to this receiver.
+    pub fn queued_frames(&self) -> usize {
+        self.urgent.len() + self.queue.len()
+    }
+    /// Number of bytes held for this receiver: waiting frames, plus the whole of a
+    /// frame that was partially written. [`Metrics::queued_bytes`](metrics::Metrics::queued_bytes)
+    /// sums this over every receiver.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use coretech_wirestorm::{broadcast_message, DeliveryPolicy, Destinations, Transport};
+    /// // A receiver that never takes anything.
+    /// struct Stalled;
+    /// impl Write for Stalled {
+    ///     fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+    ///         Err(io::ErrorKind::WouldBlock.into())
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    /// impl Transport for Stalled {}
+    ///
+    /// let policy = DeliveryPolicy { max_strikes: Some(10), queue_depth: 10, ..DeliveryPolicy::default() };
+    /// let destinations = Destinations::default().with_policy(policy);
+    /// destinations.add(Stalled);
+    /// for _ in 0..3 {
+    ///     broadcast_message(&[0xCC, 0, 0, 2, 0, 0, 0, 0], b"hi", &destinations);
+    /// }
+    ///
+    /// assert_eq!(destinations.clone_inner().lock().unwrap()[0].queued_bytes(), 30);
+    /// assert_eq!(destinations.metrics().queued_bytes.get(), 30);
+    /// ```
+    pub fn queued_bytes(&self) -> usize {
+        self.unsent
+            .iter()
+            .map(|(frame, _)| frame)
+            .chain(self.urgent.iter().chain(&self.queue).map(|(frame, _)| frame))
+            .map(|frame| frame.len())
+            .sum()
+    }
+    /// Writes a frame, first finishing any frame left partially written by an earlier
+    /// failure and then any queued frames.
+    ///
+    /// Control frames go ahead of queued data frames; within each kind frames keep
+    /// their order. If a frame cannot be written it joins its queue, or is dropped if
+    /// the policy has no queue or the frame does not fit in `room`, the bytes the set's
+    /// queues may still grow by. When the policy sets a per-KiB allowance, each frame
+    /// must be written within a deadline sized to it rather than within a fixed
+    /// per-call timeout. When it sets a chunk size, frames are written that many bytes
+    /// at a time, each chunk within `write_timeout`.
+    fn deliver(&mut self, frame: &Arc<Vec<u8>>, policy: &DeliveryPolicy, room: &mut usize) -> io::Result<()> {
+        let chunk = policy.write_chunk_size.filter(|&size| size > 0).zip(policy.write_timeout);
+        let deadline = |len: usize| {
+            let deadline = match policy.write_timeout_per_kib.is_zero() {
+                true => None,
+                false => policy.write_timeout_for(len).map(|timeout| Instant::now() + timeout),
+            };
+            Pace { deadline, chunk }
+        };
+        let urgent = is_control_frame(frame);
+
+        self.expire(policy.frame_ttl);
+        let backlog = self
+            .write_unsent(&deadline)
+            .and_then(|()| write_queue(&mut self.stream, &mut self.urgent, &mut self.unsent, &deadline))
+            .and_then(|()| match urgent {
+                true => Ok(()),
+                false => write_queue(&mut self.stream, &mut self.queue, &mut self.unsent, &deadline),
+            });
+        if let Err(e) = backlog {
+            self.hold(frame, policy, room);
+            return Err(e);
+        }
+
+        let (written, result) = write_paced(&mut self.stream, frame, deadline(frame.len()));
+        if result.is_err() {
+            match written {
+                0 => self.hold(frame, policy, room),
+                _ => self.unsent = Some((Arc::clone(frame), written)),
+            }
+            return result;
+        }
+        match urgent {
+            true => write_queue(&mut self.stream, &mut self.queue, &mut self.unsent, &deadline),
+            false => Ok(()),
+        }
+    }
+    // Finishes the frame interrupted part way through, if any.
+    fn write_unsent(&mut self, deadline: &impl Fn(usize) -> Pace) -> io::Result<()> {
+        if let Some((frame, done)) = self.unsent.take() {
+            let rest = &frame[done..];
+            let (written, result) = write_paced(&mut self.stream, rest, deadline(rest.len()));
+            if let Err(e) = result {
+                self.unsent = Some((Arc::clone(&frame), done + written));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    // Discards the queued data frames that have waited longer than `ttl`, oldest first.
+    fn expire(&mut self, ttl: Option<Duration>) {
+        let Some(ttl) = ttl else {
+            return;
+        };
+        while self.queue.front().is_some_and(|(_, queued)| queued.elapsed() > ttl) {
+            self.queue.pop_front();
+            self.frames_expired += 1;
+        }
+    }
+    // Queues a frame that could not be written, making room by dropping the oldest of its kind.
+    // The frame is skipped instead if it would not fit in `room`, even after that.
+    fn hold(&mut self, frame: &Arc<Vec<u8>>, policy: &DeliveryPolicy, room: &mut usize) {
+        if policy.queue_depth == 0 {
+            self.frames_dropped += 1;
+            return;
+        }
+        let queue = match is_control_frame(frame) {
+            true => &mut self.urgent,
+            false => &mut self.queue,
+        };
+        let freed = match queue.len() >= policy.queue_depth {
+            true => queue.front().map_or(0, |(oldest, _)| oldest.len()),
+            false => 0,
+        };
+        if frame.len() > room.saturating_add(freed) {
+            self.frames_dropped += 1;
+            return;
+        }
+        while queue.len() >= policy.queue_depth {
+            if let Some((oldest, _)) = queue.pop_front() {
+                *room = room.saturating_add(oldest.len());
+            }
+            self.frames_dropped += 1;
+        }
+        *room -= frame.len();
+        queue.push_back((Arc::clone(frame), Instant::now()));
+    }
+    // Writes everything still waiting, giving up at `deadline`, and returns how many
+    // frames were left unwritten. `goodbye` goes ahead of queued data frames, as it
+    // would if delivered normally.
+    fn drain(&mut self, goodbye: Option<&Arc<Vec<u8>>>, deadline: Instant, ttl: Option<Duration>) -> usize {
+        if let Some(goodbye) = goodbye {
+            self.urgent.push_back((Arc::clone(goodbye), Instant::now()));
+        }
+        self.expire(ttl);
+        // a non-blocking receiver would give up on the first full buffer, not at the deadline
+        if let Err(e) = self.stream.set_nonblocking(false) {
+            eprintln!("Failed to make receiver blocking for draining: {}", e);
+        }
+        let deadline = |_: usize| Pace { deadline: Some(deadline), chunk: None };
+        let drained = self
+            .write_unsent(&deadline)
+            .and_then(|()| write_queue(&mut self.stream, &mut self.urgent, &mut self.unsent, &deadline))
+            .and_then(|()| write_queue(&mut self.stream, &mut self.queue, &mut self.unsent, &deadline))
+            .and_then(|()| self.stream.flush());
+        if let Err(e) = drained {
+            eprintln!("Receiver did not drain in time: {}", e);
+        }
+        self.pending_frames()
+    }
+    // How many frames are waiting or partly written.
+    fn pending_frames(&self) -> usize {
+        usize::from(self.unsent.is_some()) + self.urgent.len() + self.queue.len()
+    }
+    // Every frame not yet completely written, in the order it would have been sent.
+    fn into_pending(self) -> Vec<Arc<Vec<u8>>> {
+        self.unsent
+            .map(|(frame, _)| frame)
+            .into_iter()
+            .chain(self.urgent.into_iter().chain(self.queue).map(|(frame, _)| frame))
+            .collect()
+    }
+    /// Records the outcome of a write and decides whether to keep the destination.
+    ///
+    /// Only timeouts count as strikes; any other error means the connection is gone.
+    fn keep_after(&mut self, result: io::Result<()>, policy: &DeliveryPolicy, now: Instant) -> bool {
+        let error = match result {
+            Ok(()) => {
+                self.strikes = 0;
+                self.failing_since = None;
+                return true;
+            }
+            Err(e) => e,
+        };
+        // a write of zero bytes means the receiver is stalled, not gone
+        if !matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::WriteZero) {
+            return false;
+        }
+
+        self.strikes += 1;
+        let failing_for = now - *self.failing_since.get_or_insert(now);
+        if policy.should_evict(self.strikes, failing_for) {
+            eprintln!(
+                "Evicting destination {} after {} failed writes: {}",
+                display_client(self.peer, self.label()),
+                self.strikes,
+                error
+            );
+            return false;
+        }
+        true
+    }
+}
+
+// Writes queued frames oldest first, leaving a frame interrupted part way through in `unsent`.
+fn write_queue<W: Transport>(
+    stream: &mut W,
+    queue: &mut VecDeque<(Arc<Vec<u8>>, Instant)>,
+    unsent: &mut Option<(Arc<Vec<u8>>, usize)>,
+    deadline: &impl Fn(usize) -> Pace,
+) -> io::Result<()> {
+    while let Some((next, queued)) = queue.pop_front() {
+        let (written, result) = write_paced(stream, &next, deadline(next.len()));
+        if let Err(e) = result {
+            match written {
+                0 => queue.push_front((next, queued)),
+                _ => *unsent = Some((next, written)),
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Whether an encoded frame is a relay control frame, which is delivered ahead of data.
+fn is_control_frame(frame: &[u8]) -> bool {
+    frame[1] & CTMP_CONTROL_FLAG != 0
+}
+
+// How a frame is written: the deadline for all of it, and the chunk size and per-chunk
+// time limit it is split up with, if any.
+#[derive(Clone, Copy)]
+struct Pace {
+    deadline: Option<Instant>,
+    chunk: Option<(usize, Duration)>,
+}
+
+// Writes `buf` at the given pace. Each chunk must be written within its time limit, so a
+// receiver that stops keeping up is caught part way through a large frame instead of
+// after the last of it trickles out.
+fn write_paced<W: Transport>(stream: &mut W, buf: &[u8], pace: Pace) -> (usize, io::Result<()>) {
+    let Some((size, limit)) = pace.chunk else {
+        return write_tracked(stream, buf, pace.deadline);
+    };
+    let mut written = 0;
+    for piece in buf.chunks(size) {
+        let chunk_deadline = Instant::now() + limit;
+        let deadline = pace.deadline.map_or(chunk_deadline, |deadline| deadline.min(chunk_deadline));
+        let (n, result) = write_tracked(stream, piece, Some(deadline));
+        written += n;
+        if result.is_err() {
+            return (written, result);
+        }
+    }
+    (written, Ok(()))
+}
+
+// Writes as much of `buf` as possible, returning how much was written alongside the outcome.
+// With a deadline, each write may only block for whatever time is left before it.
+fn write_tracked<W: Transport>(stream: &mut W, buf: &[u8], deadline: Option<Instant>) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < buf.len() {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return (written, Err(io::ErrorKind::TimedOut.into()));
+            }
+            if let Err(e) = stream.set_write_timeout(Some(remaining)) {
+                return (written, Err(e));
+            }
+        }
+        match stream.write(&buf[written..]) {
+            Ok(0) => return (written, Err(io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return (written, Err(e)),
+        }
+    }
+    (written, Ok(()))
+}
+
+/// What a receiver still had waiting when [`Destinations::clear`] closed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrainReport {
+    /// The receiver's address, if its stream has one.
+    pub peer: Option<SocketAddr>,
+    /// Frames queued for the receiver, or partly written to it, that were never sent,
+    /// including the goodbye frame if it did not go out.
+    pub undrained: usize,
+}
 
 /// Holds all connected receiver clients and provides thread-safe methods to manage them.
 ///
-/// The `Destinations` struct wraps a vector of `TcpStream` objects in an `Arc<Mutex<...>>`,
+/// The `Destinations` struct wraps a vector of [`Destination`] objects in an `Arc<Mutex<...>>`,
 /// allowing safe concurrent access and modification from multiple threads. It is used to
 /// manage the set of receiver clients in a networked application, such as a broadcast server.
+/// Cloning a `Destinations` is cheap and every clone refers to the same set of clients.
+///
+/// An optional [`MulticastDestination`] can be attached, in which case every broadcast is
+/// also sent once to its multicast group. A [`DeliveryPolicy`] decides how long a receiver
+/// whose writes time out is kept before it is evicted.
+///
+/// # Receivers added during a broadcast
+///
+/// Broadcasts are numbered: the first frame broadcast is generation 1, and
+/// [`generation`](Self::generation) is the number of frames broadcast so far. A
+/// broadcast holds the set for its whole duration. Adding a receiver does not wait for
+/// it: a receiver added while a frame is being broadcast is queued, does not get that
+/// frame, and joins the set the next time the set is taken, at the latest before the
+/// next frame. It gets every later frame, in order, starting with generation
+/// [`joined_at`](Destination::joined_at)` + 1`. A receiver therefore never sees a gap
+/// it can't account for, apart from frames it is counted as having dropped.
 ///
 /// # Examples
 ///
-/// ```rust
+/// ```rust,no_run
+/// # use coretech_wirestorm::Destinations;
+/// # let client_stream = std::net::TcpStream::connect("127.0.0.1:44444").unwrap();
 /// let destinations = Destinations::new();
 /// destinations.add(client_stream);
 /// let receivers = destinations.clone_inner();
 /// ```
-pub struct Destinations {
-    receivers: Arc<Mutex<Vec<TcpStream>>>,
+pub struct Destinations<T = TcpStream> {
+    receivers: Arc<Mutex<Vec<Destination<T>>>>,
+    multicast: Option<Arc<MulticastDestination>>,
+    policy: DeliveryPolicy,
+    // frames broadcast so far; only changed while `receivers` is locked
+    generation: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+    loop_guard: Option<Arc<Mutex<LoopGuard>>>,
+    frame_log: Option<FrameLog>,
+    broadcast_limit: Option<Arc<BroadcastLimit>>,
+    checksum_budget: Option<Arc<ChecksumBudget>>,
+    // receivers added while `receivers` was held, waiting to join it
+    joining: Arc<Mutex<Vec<Destination<T>>>>,
 }
 
+// Called with the frames a transmitter's sampling picks out.
+type FrameLog = Arc<dyn Fn(&FrameSample) + Send + Sync>;
+
+// Derived `Clone` would needlessly require `T: Clone`.
+impl<T> Clone for Destinations<T> {
+    fn clone(&self) -> Self {
+        Destinations {
+            receivers: Arc::clone(&self.receivers),
+            multicast: self.multicast.clone(),
+            policy: self.policy,
+            generation: Arc::clone(&self.generation),
+            metrics: Arc::clone(&self.metrics),
+            loop_guard: self.loop_guard.clone(),
+            frame_log: self.frame_log.clone(),
+            broadcast_limit: self.broadcast_limit.clone(),
+            checksum_budget: self.checksum_budget.clone(),
+            joining: Arc::clone(&self.joining),
+        }
+    }
+}
+
+impl<T: Transport> Default for Destinations<T> {
+    fn default() -> Self {
+        Destinations {
+            receivers: Arc::new(Mutex::new(Vec::new())),
+            multicast: None,
+            policy: DeliveryPolicy::default(),
+            generation: Arc::new(AtomicU64::new(0)),
+            metrics: Arc::new(Metrics::new()),
+            loop_guard: None,
+            frame_log: None,
+            broadcast_limit: None,
+            checksum_budget: None,
+            joining: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
 
 impl Destinations {
-    /// Creates a new, empty `Destinations` instance.
+    /// Creates a new, empty `Destinations` instance for TCP receivers.
+    ///
+    /// Use `Destinations::<T>::default()` for other [`Transport`]s.
     ///
     /// # Returns
     ///
     /// A `Destinations` object with an empty list of receiver clients.
     pub fn new() -> Self {
-        Destinations {
-            receivers: Arc::new(Mutex::new(Vec::new())),
+        Self::default()
+    }
+    /// Creates a set of TCP receivers from streams accepted elsewhere.
+    ///
+    /// Each stream gets the same socket options as a receiver the server accepts
+    /// itself. A [`DeliveryPolicy`] set afterwards with [`with_policy`](Self::with_policy)
+    /// is applied to these streams too.
+    ///
+    /// # Arguments
+    ///
+    /// * `streams` - Connected receiver clients.
+    pub fn from_streams(streams: Vec<TcpStream>) -> Self {
+        let destinations = Self::default();
+        for stream in streams {
+            server::configure_connection(&stream, server::Role::Destination);
+            destinations.add(stream);
+        }
+        destinations
+    }
+}
+
+// Sets the backlog gauge to the bytes now waiting across `clients`.
+fn record_backlog<T: Transport>(metrics: &Metrics, clients: &[Destination<T>]) {
+    let queued: usize = clients.iter().map(Destination::queued_bytes).sum();
+    metrics.queued_bytes.set(queued as u64);
+}
+
+// Applies the delivery policy's write timeout and corking to a receiver's stream.
+fn prepare_receiver<T: Transport>(client: &T, policy: &DeliveryPolicy) {
+    let io = match policy.io {
+        DestinationIo::Blocking => client.set_write_timeout(policy.write_timeout),
+        DestinationIo::NonBlocking => client.set_nonblocking(true),
+    };
+    if let Err(e) = io {
+        eprintln!("Failed to set up destination for {} writes: {}", policy.io.name(), e);
+    }
+    if policy.cork
+        && let Err(e) = client.set_cork(true)
+    {
+        eprintln!("Failed to cork destination: {}", e);
+    }
+}
+
+impl<T: Transport> Destinations<T> {
+    /// Also broadcasts every frame to a multicast group.
+    ///
+    /// # Arguments
+    ///
+    /// * `multicast` - The multicast sender every frame is also sent through.
+    pub fn with_multicast(mut self, multicast: MulticastDestination) -> Self {
+        self.multicast = Some(Arc::new(multicast));
+        self
+    }
+    /// Sets the write timeout and eviction rules applied to receivers, including any
+    /// already in the set.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The delivery policy to apply.
+    pub fn with_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.policy = policy;
+        if let Ok(clients) = self.receivers.lock() {
+            for client in clients.iter() {
+                prepare_receiver(&client.stream, &policy);
+            }
         }
+        self
+    }
+    /// Records relayed, rejected and dropped frames, and evictions, into `metrics`.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own reference.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics` - Where to record them.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Drops frames that come back to the relay through a chain of relays.
+    ///
+    /// Frames handed to [`handle_transmitter`] are tagged with a routing id, and
+    /// frames carrying an id among the last `window` seen are dropped. See
+    /// [`routing`] for how ids are carried.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own reference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is zero or larger than `65535`.
+    pub fn with_loop_detection(mut self, window: usize) -> Self {
+        self.loop_guard = Some(Arc::new(Mutex::new(LoopGuard::new(window))));
+        self
+    }
+    /// Hands the frames picked out by [`TransmitterPolicy::log_every_nth_frame`] to
+    /// `callback` instead of logging them.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own reference.
+    pub fn with_frame_log(mut self, callback: impl Fn(&FrameSample) + Send + Sync + 'static) -> Self {
+        self.frame_log = Some(Arc::new(callback));
+        self
+    }
+    /// Runs at most `max` calls to [`broadcast_message`] at once, across every
+    /// transmitter, so a burst from many of them queues up instead of all fanning out
+    /// together. The rest wait their turn, in no particular order, and
+    /// [`Metrics::broadcasts_in_flight`] shows how many are running.
+    ///
+    /// Writes to TCP receivers already take turns on the receiver set; the limit also
+    /// covers building each frame and sending it to the multicast group.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own reference.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use std::sync::Arc;
+    /// # use std::sync::atomic::{AtomicU64, Ordering};
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// # use coretech_wirestorm::{broadcast_message, build_frame, Destinations, Metrics, Transport};
+    /// // A receiver that notes the most broadcasts it has seen running at once.
+    /// struct Watcher { metrics: Arc<Metrics>, peak: Arc<AtomicU64> }
+    /// impl Write for Watcher {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         self.peak.fetch_max(self.metrics.broadcasts_in_flight.get(), Ordering::SeqCst);
+    ///         thread::sleep(Duration::from_millis(1));
+    ///         Ok(buf.len())
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    /// }
+    /// impl Transport for Watcher {}
+    ///
+    /// let destinations = Destinations::<Watcher>::default().with_broadcast_limit(1);
+    /// let peak = Arc::new(AtomicU64::new(0));
+    /// destinations.add(Watcher { metrics: Arc::clone(destinations.metrics()), peak: Arc::clone(&peak) });
+    ///
+    /// let frame = build_frame(b"storm", false).unwrap();
+    /// let transmitters: Vec<_> = (0..4)
+    ///     .map(|_| {
+    ///         let (destinations, frame) = (destinations.clone(), frame.clone());
+    ///         thread::spawn(move || {
+    ///             for _ in 0..10 {
+    ///                 broadcast_message(&frame[..8], &frame[8..], &destinations);
+    ///             }
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for transmitter in transmitters {
+    ///     transmitter.join().unwrap();
+    /// }
+    ///
+    /// assert_eq!(peak.load(Ordering::SeqCst), 1);
+    /// assert_eq!(destinations.metrics().frames_relayed.get(), 40);
+    /// assert_eq!(destinations.metrics().broadcasts_in_flight.get(), 0);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn with_broadcast_limit(mut self, max: usize) -> Self {
+        assert!(max > 0, "broadcast limit must be greater than zero");
+        self.broadcast_limit = Some(Arc::new(BroadcastLimit::new(max)));
+        self
+    }
+    /// Verifies the checksums of at most `per_second` sensitive frames a second, across
+    /// every transmitter, so a flood of large sensitive frames cannot tie up the CPU.
+    ///
+    /// Each second starts a fresh budget. A sensitive frame read once it is used up is
+    /// handled as `overload` says and counted in [`Metrics::checksums_throttled`].
+    /// Frames that are not sensitive are never checked and never held back. A
+    /// sensitive frame only spends from the budget once it has passed the cheaper
+    /// checks, so one dropped for being over the payload limit, or for being a control
+    /// or compressed frame, never uses it up.
+    ///
+    /// Must be called before the set is shared, since each clone keeps its own reference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is zero.
+    pub fn with_checksum_limit(mut self, per_second: u32, overload: ChecksumOverload) -> Self {
+        assert!(per_second > 0, "checksum limit must be greater than zero");
+        self.checksum_budget = Some(Arc::new(ChecksumBudget::new(per_second, overload)));
+        self
+    }
+    /// The metrics this set records into.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
     }
     /// Adds a new receiver client to the set.
     ///
+    /// The delivery policy's write timeout and corking are applied to the stream. If the policy
+    /// asks for a probe, a probe frame (see [`CtmpFrame::probe`]) is written first and
+    /// the receiver is only added if that write succeeds. If the policy's
+    /// [`max_destinations`](DeliveryPolicy::max_destinations) are already connected, its
+    /// [`CapacityPolicy`] decides between turning this receiver away and evicting another.
+    ///
+    /// A broadcast in progress does not hold this up: the receiver is queued and joins
+    /// the set before the next frame, when the capacity policy is applied to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A stream representing the receiver client to add.
+    ///
+    /// # Returns
+    ///
+    /// Whether the receiver was added, or queued to be. A queued receiver can still be
+    /// turned away for capacity when it joins.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use std::sync::{Arc, Mutex};
+    /// # use std::thread;
+    /// # use std::time::{Duration, Instant};
+    /// # use coretech_wirestorm::{broadcast_message, read_frame, Destinations, Transport};
+    /// // A receiver that takes `delay` over every write, and records what it got.
+    /// struct Receiver(Duration, Arc<Mutex<Vec<u8>>>);
+    /// impl Write for Receiver {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         thread::sleep(self.0);
+    ///         self.1.lock().unwrap().write(buf)
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    /// impl Transport for Receiver {}
+    ///
+    /// let destinations = Destinations::default();
+    /// destinations.add(Receiver(Duration::from_millis(500), Arc::default()));
+    /// let broadcasting = destinations.clone();
+    /// let slow = thread::spawn(move || broadcast_message(&[0xCC, 0, 0, 3, 0, 0, 0, 0], b"one", &broadcasting));
+    /// thread::sleep(Duration::from_millis(100));
+    ///
+    /// // the broadcast holds the set for another 400ms, but adding does not wait for it
+    /// let received = Arc::new(Mutex::new(Vec::new()));
+    /// let started = Instant::now();
+    /// assert!(destinations.add(Receiver(Duration::ZERO, Arc::clone(&received))));
+    /// assert!(started.elapsed() < Duration::from_millis(200));
+    ///
+    /// slow.join().unwrap();
+    /// broadcast_message(&[0xCC, 0, 0, 3, 0, 0, 0, 0], b"two", &destinations);
+    /// let received = received.lock().unwrap();
+    /// assert_eq!(read_frame(&mut &received[..]).unwrap().payload(), b"two");
+    /// ```
+    pub fn add(&self, client: T) -> bool {
+        self.add_labelled(client, None)
+    }
+    /// Adds a receiver client that named itself with a [label frame](CtmpFrame::label).
+    ///
+    /// Otherwise the same as [`add`](Self::add). The label shows in logs about the
+    /// receiver and in [`Destination::label`]. It is sanitised again, in case it did not
+    /// come from [`CtmpFrame::label_name`].
+    pub fn add_labelled(&self, client: T, label: Option<String>) -> bool {
+        self.add_negotiated(client, label, false)
+    }
+    /// Adds a receiver client that may have named itself and asked for
+    /// [compressed](compression) frames when it connected.
+    ///
+    /// Otherwise the same as [`add_labelled`](Self::add_labelled). The request for
+    /// compression is only granted if the policy enables
+    /// [`compression`](DeliveryPolicy::compression).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use std::sync::{Arc, Mutex};
+    /// # use coretech_wirestorm::{broadcast_message, read_frame, DeliveryPolicy, Destinations, Transport};
+    /// // A receiver whose writes can be read back.
+    /// struct Recorder(Arc<Mutex<Vec<u8>>>);
+    /// impl Write for Recorder {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         self.0.lock().unwrap().write(buf)
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    /// impl Transport for Recorder {}
+    ///
+    /// let destinations = Destinations::default().with_policy(DeliveryPolicy { compression: true, ..DeliveryPolicy::default() });
+    /// let (slow, fast) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+    /// destinations.add_negotiated(Recorder(Arc::clone(&slow)), None, true);
+    /// destinations.add(Recorder(Arc::clone(&fast)));
+    /// let payload = [0u8; 1000];
+    /// broadcast_message(&[0xCC, 0, 0x03, 0xE8, 0, 0, 0, 0], &payload, &destinations);
+    ///
+    /// let compressed = read_frame(&mut &slow.lock().unwrap()[..]).unwrap();
+    /// assert!(compressed.is_compressed() && compressed.payload().len() < 20);
+    /// assert_eq!(compressed.decompressed().unwrap().payload(), payload);
+    /// let raw = read_frame(&mut &fast.lock().unwrap()[..]).unwrap();
+    /// assert!(!raw.is_compressed());
+    /// assert_eq!(raw.payload(), payload);
+    /// ```
+    pub fn add_negotiated(&self, mut client: T, label: Option<String>, compress: bool) -> bool {
+        prepare_receiver(&client, &self.policy);
+        if self.policy.probe
+            && let (_, Err(e)) = write_tracked(&mut client, &CtmpFrame::probe().to_bytes(), None)
+        {
+            eprintln!("Destination {} failed its probe, not adding it: {}", display_client(peer_of(&client), label.as_deref()), e);
+            return false;
+        }
+        let mut destination = Destination::new(client);
+        destination.label = label.and_then(|label| sanitize_label(label.as_bytes()));
+        if compress && !self.policy.compression {
+            eprintln!(
+                "Destination {} asked for compression, which is not enabled",
+                display_client(destination.peer, destination.label())
+            );
+        }
+        destination.compress = compress && self.policy.compression;
+
+        // a broadcast in progress holds the set, so queue the receiver rather than wait
+        let mut clients = match self.receivers.try_lock() {
+            Ok(clients) => clients,
+            Err(TryLockError::WouldBlock) => {
+                self.joining.lock().unwrap_or_else(|e| e.into_inner()).push(destination);
+                return true;
+            }
+            Err(TryLockError::Poisoned(e)) => {
+                eprintln!("Failed to lock clients mutex: {}", e);
+                return false;
+            }
+        };
+        self.merge_joining(&mut clients);
+        let refused = self.admit(&mut clients, destination);
+        drop(clients);
+        match refused {
+            Some(refused) => {
+                self.turn_away(refused);
+                false
+            }
+            None => true,
+        }
+    }
+    // Locks the set, first letting in any receivers that were added while it was held.
+    fn lock(&self) -> MutexGuard<'_, Vec<Destination<T>>> {
+        let mut clients = self.receivers.lock().unwrap_or_else(|e| e.into_inner());
+        self.merge_joining(&mut clients);
+        clients
+    }
+    // Moves the receivers waiting to join into the locked set.
+    fn merge_joining(&self, clients: &mut Vec<Destination<T>>) {
+        let joining = std::mem::take(&mut *self.joining.lock().unwrap_or_else(|e| e.into_inner()));
+        for destination in joining {
+            // a fresh receiver takes a goodbye straight away, so this barely holds the set
+            if let Some(refused) = self.admit(clients, destination) {
+                self.turn_away(refused);
+            }
+        }
+    }
+    // Adds a receiver to the locked set, making room under the capacity policy. Returns
+    // the receiver's stream if it is refused instead, to be turned away.
+    fn admit(&self, clients: &mut Vec<Destination<T>>, mut destination: Destination<T>) -> Option<T> {
+        if let Some(max) = self.policy.max_destinations
+            && clients.len() >= max
+        {
+            let newcomer = display_client(destination.peer, destination.label());
+            match self.policy.at_capacity {
+                CapacityPolicy::RejectNew => {
+                    eprintln!("Refusing destination {}: already at capacity of {}", newcomer, max);
+                    self.metrics.destinations_rejected.increment();
+                    return Some(destination.stream);
+                }
+                CapacityPolicy::EvictOldest => {
+                    while clients.len() >= max {
+                        let oldest = clients.iter().enumerate().min_by_key(|(_, dest)| dest.last_active).map(|(i, _)| i);
+                        let Some(oldest) = oldest else {
+                            break;
+                        };
+                        let evicted = clients.remove(oldest);
+                        eprintln!(
+                            "Evicting destination {}, idle for {:?}, to make room for {}",
+                            display_client(evicted.peer, evicted.label()),
+                            evicted.last_active.elapsed(),
+                            newcomer
+                        );
+                        self.metrics.destinations_evicted.increment();
+                    }
+                    record_backlog(&self.metrics, clients);
+                }
+            }
+        }
+        destination.joined_at = self.generation.load(Ordering::SeqCst);
+        clients.push(destination);
+        None
+    }
+    /// Closes a receiver that is not going to be added, such as one that connected while
+    /// the server was shutting down. If the policy asks for goodbye frames, it is sent
+    /// one first, so it can tell the close was deliberate.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::io::{self, Write};
+    /// # use std::sync::{Arc, Mutex};
+    /// # use coretech_wirestorm::{read_frame, DeliveryPolicy, Destinations, Transport};
+    /// // A receiver whose writes can be read back after it is dropped.
+    /// struct Recorder(Arc<Mutex<Vec<u8>>>);
+    /// impl Write for Recorder {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         self.0.lock().unwrap().write(buf)
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    /// impl Transport for Recorder {}
+    ///
+    /// let destinations = Destinations::default().with_policy(DeliveryPolicy { goodbye: true, ..DeliveryPolicy::default() });
+    /// let written = Arc::new(Mutex::new(Vec::new()));
+    /// destinations.turn_away(Recorder(Arc::clone(&written)));
+    ///
+    /// assert!(destinations.is_empty());
+    /// let written = written.lock().unwrap();
+    /// assert!(read_frame(&mut &written[..]).unwrap().is_goodbye());
+    /// ```
+    pub fn turn_away(&self, mut client: T) {
+        if !self.policy.goodbye {
+            return;
+        }
+        prepare_receiver(&client, &self.policy);
+        let (_, result) = write_tracked(&mut client, &CtmpFrame::goodbye().to_bytes(), None);
+        if let Err(e) = result.and_then(|()| client.flush()) {
+            eprintln!("Failed to send goodbye to turned away destination: {}", e);
+        }
+    }
+    /// Atomically swaps the whole set of receivers for `clients`.
+    ///
+    /// Every clone of this `Destinations` sees the new set, and since a broadcast holds
+    /// the set for its whole duration, each frame goes either entirely to the old
+    /// receivers or entirely to the new ones. The delivery policy's write timeout is
+    /// applied to each new stream.
+    ///
     /// # Arguments
     ///
-    /// * `client` - A `TcpStream` representing the receiver client to add.
-    pub fn add(&self, client: TcpStream) {
-        let mut clients = match self.receivers.lock() {
-            Ok(guard) => guard,
-            Err(e) => {
-                eprintln!("Failed to lock clients mutex: {}", e);
-                return;
-            }   
+    /// * `clients` - The receiver streams that make up the new set.
+    ///
+    /// # Returns
+    ///
+    /// The receivers that were replaced, still connected, so the caller can decide how
+    /// to close them.
+    pub fn replace_all<I: IntoIterator<Item = T>>(&self, clients: I) -> Vec<Destination<T>> {
+        let mut replacement: Vec<Destination<T>> = clients
+            .into_iter()
+            .map(|client| {
+                prepare_receiver(&client, &self.policy);
+                Destination::new(client)
+            })
+            .collect();
+        let mut current = self.lock();
+        let generation = self.generation.load(Ordering::SeqCst);
+        for destination in &mut replacement {
+            destination.joined_at = generation;
+        }
+        let replaced = std::mem::replace(&mut *current, replacement);
+        record_backlog(&self.metrics, &current);
+        replaced
+    }
+    /// Removes the receiver at `addr` and returns the frames it had not been sent.
+    ///
+    /// The frames are complete encoded frames, oldest first, ready to be re-sent to a
+    /// replacement. A frame that had been partially written is included whole, since
+    /// the receiver only got the start of it. The receiver's connection is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The receiver's address, as reported by [`Destination::peer_addr`].
+    ///
+    /// # Returns
+    ///
+    /// The undelivered frames, or an empty list if no receiver has that address.
+    pub fn take_pending(&self, addr: SocketAddr) -> Vec<Arc<Vec<u8>>> {
+        let mut clients = self.lock();
+        let pending = match clients.iter().position(|client| client.peer == Some(addr)) {
+            Some(index) => clients.remove(index).into_pending(),
+            None => Vec::new(),
         };
-        clients.push(client);
+        record_backlog(&self.metrics, &clients);
+        pending
     }
-    /// Returns a clone of the internal `Arc<Mutex<Vec<TcpStream>>>`.
+    /// Returns a clone of the internal `Arc<Mutex<Vec<Destination<T>>>>`.
     ///
     /// This allows other threads to access or modify the list of receiver clients.
     ///
     /// # Returns
     ///
-    /// An `Arc<Mutex<Vec<TcpStream>>>` pointing to the internal vector of clients.
-    pub fn clone_inner(&self) -> Arc<Mutex<Vec<TcpStream>>> {
+    /// An `Arc<Mutex<Vec<Destination<T>>>>` pointing to the internal vector of clients.
+    pub fn clone_inner(&self) -> Arc<Mutex<Vec<Destination<T>>>> {
+        drop(self.lock());
         Arc::clone(&self.receivers)
     }
+    /// Number of frames broadcast so far, which is also the generation of the latest one.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+    /// Number of connected receiver clients.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+    /// Whether there are no connected receiver clients.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Sends every receiver a keepalive frame (see [`CtmpFrame::keepalive`]), to keep
+    /// idle connections from being dropped by anything between the relay and them.
+    ///
+    /// It is delivered like a broadcast frame, so a receiver that cannot take it gets a
+    /// strike, but it is not relayed data: it does not start a new
+    /// [`generation`](Self::generation), is not counted as relayed, and is not sent to
+    /// the multicast group.
+    pub fn keepalive(&self) {
+        let frame = Arc::new(CtmpFrame::keepalive().to_bytes());
+        let mut dests = self.lock();
+        self.deliver_all(&mut dests, &frame);
+    }
+    // Delivers an encoded frame to every receiver, evicting any the policy gives up on,
+    // and returns how many it was written to in full.
+    fn deliver_all(&self, dests: &mut Vec<Destination<T>>, frame: &Arc<Vec<u8>>) -> u64 {
+        let mut room = self.policy.queue_memory.map_or(usize::MAX, |max| {
+            max.saturating_sub(dests.iter().map(Destination::queued_bytes).sum())
+        });
+        let now = Instant::now();
+        let mut delivered = 0;
+        // compressed once, for the first receiver that wants it, and `None` inside if it
+        // is sent as it is
+        let mut compressed: Option<Option<Arc<Vec<u8>>>> = None;
+        dests.retain_mut(|dest| {
+            let (dropped, expired) = (dest.frames_dropped, dest.frames_expired);
+            let frame = match dest.compress {
+                true => compressed
+                    .get_or_insert_with(|| compression::compress_frame(frame).map(Arc::new))
+                    .as_ref()
+                    .unwrap_or(frame),
+                false => frame,
+            };
+            let result = dest
+                .deliver(frame, &self.policy, &mut room)
+                .and_then(|()| match self.policy.flush_interval {
+                    Some(_) => Ok(()),
+                    None => dest.stream.flush(),
+                });
+            if result.is_ok() {
+                delivered += 1;
+                dest.last_active = now;
+            }
+            self.metrics.frames_dropped.add(dest.frames_dropped - dropped);
+            self.metrics.frames_expired.add(dest.frames_expired - expired);
+            let keep = dest.keep_after(result, &self.policy, now);
+            if !keep {
+                self.metrics.destinations_evicted.increment();
+            }
+            keep
+        });
+        record_backlog(&self.metrics, dests);
+        delivered
+    }
+    /// Flushes every receiver, for a [`DeliveryPolicy::flush_interval`].
+    ///
+    /// With [`DeliveryPolicy::cork`], each receiver is also uncorked and corked again,
+    /// which sends anything the kernel was holding back.
+    ///
+    /// A receiver whose flush fails is treated as if a write had failed: a timeout
+    /// counts as a strike and any other error evicts it.
+    pub fn flush(&self) {
+        let mut clients = self.lock();
+        let now = Instant::now();
+        clients.retain_mut(|client| {
+            let mut result = client.stream.flush();
+            if self.policy.cork {
+                result = result
+                    .and_then(|()| client.stream.set_cork(false))
+                    .and_then(|()| client.stream.set_cork(true));
+            }
+            let keep = client.keep_after(result, &self.policy, now);
+            if !keep {
+                self.metrics.destinations_evicted.increment();
+            }
+            keep
+        });
+        record_backlog(&self.metrics, &clients);
+    }
+    /// Disconnects every receiver client by dropping its stream.
+    ///
+    /// If the delivery policy asks for it, each receiver is first sent a goodbye frame
+    /// (see [`CtmpFrame::goodbye`]) so it can tell the close was deliberate. With a
+    /// [`drain_timeout`](DeliveryPolicy::drain_timeout), each receiver is given that
+    /// long to take the frames still queued for it before its connection is closed
+    /// regardless. Receivers are drained one after another, so a set with several
+    /// stalled receivers takes up to the timeout for each.
+    ///
+    /// # Returns
+    ///
+    /// How many frames each receiver was left without, in the order they were added.
+    pub fn clear(&self) -> Vec<DrainReport> {
+        let mut clients = match self.receivers.lock() {
+            Ok(clients) => clients,
+            Err(e) => {
+                eprintln!("Failed to lock clients mutex: {}", e);
+                return Vec::new();
+            }
+        };
+        self.merge_joining(&mut clients);
+        let goodbye = self.policy.goodbye.then(|| Arc::new(CtmpFrame::goodbye().to_bytes()));
+        let reports = clients
+            .iter_mut()
+            .map(|client| {
+                let undrained = match (self.policy.drain_timeout, &goodbye) {
+                    (Some(timeout), goodbye) => {
+                        let expired = client.frames_expired;
+                        let undrained = client.drain(goodbye.as_ref(), Instant::now() + timeout, self.policy.frame_ttl);
+                        self.metrics.frames_expired.add(client.frames_expired - expired);
+                        undrained
+                    }
+                    (None, Some(goodbye)) => {
+                        // the connections close straight after, so nothing queued here is kept
+                        let mut room = usize::MAX;
+                        let _ = client.deliver(goodbye, &self.policy, &mut room).and_then(|()| client.stream.flush());
+                        client.pending_frames()
+                    }
+                    (None, None) => client.pending_frames(),
+                };
+                DrainReport { peer: client.peer, undrained }
+            })
+            .collect();
+        clients.clear();
+        self.metrics.queued_bytes.set(0);
+        reports
+    }
 }
 
 /// A thread pool for executing jobs concurrently.
 ///
-/// The `ThreadPool` struct manages a fixed number of worker threads and a channel for sending jobs to them.
+/// The `ThreadPool` struct manages worker threads and a queue for sending jobs to them.
 /// It provides methods to create a new pool, execute jobs, and cleanly shut down all workers.
 ///
+/// A pool made with [`new`](Self::new) keeps a fixed number of workers. One made with
+/// [`autoscaling`](Self::autoscaling) starts with the policy's minimum, spawns another
+/// worker whenever a job is queued while every worker is busy, up to the maximum, and
+/// retires workers that have been idle for the policy's timeout, down to the minimum.
+///
+/// Workers only hold the queue's lock to take a job off it and wait for the next one
+/// with it released, so an idle worker never holds up another picking up work.
+///
 /// # Examples
 ///
 /// ```rust
+/// # use coretech_wirestorm::ThreadPool;
 /// let pool = ThreadPool::new(4);
 /// pool.execute(|| println!("Hello from a worker thread!"));
 /// ```
 pub struct ThreadPool {
 
-    // A vector to hold the workers in the pool
-    workers: Vec<Worker>,
-    // holds the sender end of the channel to send jobs to the workers
-    sender: Option<mpsc::Sender<Job>>,
+    // A vector to hold the workers in the pool, including retired ones not yet reaped
+    workers: Mutex<Vec<Worker>>,
+    // the jobs waiting for a worker, shared by every worker including ones spawned later
+    queue: Arc<JobQueue>,
+    // where job queue wait and run times are recorded, if anywhere
+    metrics: Option<Arc<Metrics>>,
+    autoscale: Option<AutoscalePolicy>,
+    state: Arc<PoolState>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// A queue of jobs any worker can take from. No job runs with the lock held, so it is
+// never poisoned by one that panics.
+struct JobQueue {
+    state: Mutex<JobQueueState>,
+    available: Condvar,
+}
+
+struct JobQueueState {
+    jobs: VecDeque<Job>,
+    // workers waiting on `available`, and how many of them have been woken but not yet
+    // run; a push only wakes a worker if one is still asleep
+    waiting: usize,
+    woken: usize,
+    // set when the pool is dropped; workers finish what is queued, then stop
+    closed: bool,
+}
+
+// What a worker got from the queue.
+enum Take {
+    Job(Job),
+    // nothing arrived within the worker's idle timeout
+    Idle,
+    // the pool has been dropped and the queue is empty
+    Closed,
+}
+
+impl JobQueue {
+    fn new() -> Self {
+        JobQueue {
+            state: Mutex::new(JobQueueState { jobs: VecDeque::new(), waiting: 0, woken: 0, closed: false }),
+            available: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JobQueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Queues a job, handing it back if the queue has been closed.
+    fn push(&self, job: Job) -> Result<(), Job> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(job);
+        }
+        state.jobs.push_back(job);
+        let wake = state.waiting > state.woken;
+        if wake {
+            state.woken += 1;
+        }
+        drop(state);
+        if wake {
+            self.available.notify_one();
+        }
+        Ok(())
+    }
+
+    // Takes the next job, waiting up to `timeout` for one, or forever if `None`.
+    fn take(&self, timeout: Option<Duration>) -> Take {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                return Take::Job(job);
+            }
+            if state.closed {
+                return Take::Closed;
+            }
+            let left = match deadline {
+                None => None,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => return Take::Idle,
+                },
+            };
+            state.waiting += 1;
+            state = match left {
+                None => self.available.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(left) => self.available.wait_timeout(state, left).unwrap_or_else(|e| e.into_inner()).0,
+            };
+            state.waiting -= 1;
+            // a worker that timed out may take another's wake-up, which only costs an
+            // extra wake later
+            state.woken = state.woken.saturating_sub(1);
+        }
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+    }
+}
+
+// What the pool and its workers count to decide when to grow and shrink.
+#[derive(Default)]
+struct PoolState {
+    // workers that have not retired or stopped
+    live: AtomicUsize,
+    // workers running a job
+    busy: AtomicUsize,
+    // jobs sent but not yet taken by a worker
+    pending: AtomicUsize,
+    next_id: AtomicUsize,
+}
+
 impl ThreadPool {
     // Create a new thread pool with the specified number of threads. 
     /// Creates a new thread pool with the specified number of worker threads.
@@ -109,19 +1502,106 @@ impl ThreadPool {
     /// Panics if `size` is zero.
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0, "Thread pool size must be greater than zero");
-
-        let (sender,receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
-
-
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        ThreadPool::start(size, None)
+    }
+    /// Creates a thread pool that grows under load and shrinks when idle.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The smallest and largest size, and how long a worker may be idle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the minimum is zero or larger than the maximum.
+    pub fn autoscaling(policy: AutoscalePolicy) -> ThreadPool {
+        assert!(policy.min > 0, "Thread pool size must be greater than zero");
+        assert!(policy.min <= policy.max, "Thread pool minimum must not exceed its maximum");
+        ThreadPool::start(policy.min, Some(policy))
+    }
+    fn start(size: usize, autoscale: Option<AutoscalePolicy>) -> ThreadPool {
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(size)),
+            queue: Arc::new(JobQueue::new()),
+            metrics: None,
+            autoscale,
+            state: Arc::new(PoolState::default()),
+        };
+        pool.state.live.store(size, Ordering::SeqCst);
+        for _ in 0..size {
+            pool.spawn_worker();
         }
-
-        ThreadPool { workers, sender: Some(sender) }
+        pool
+    }
+    /// Records how long each job waits in the queue and how long it runs.
+    ///
+    /// The times go to [`Metrics::pool_queue_wait`] and [`Metrics::pool_exec_time`], and
+    /// [`Metrics::pool_pending_jobs`] follows [`pending_jobs`](Self::pending_jobs).
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics` - Where to record the times.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    /// Number of workers currently in the pool.
+    pub fn size(&self) -> usize {
+        self.state.live.load(Ordering::SeqCst)
+    }
+    /// Number of jobs sent to the pool that no worker has taken yet.
+    ///
+    /// A job handed to an idle worker counts until the worker wakes up and takes it, so
+    /// this can briefly be non-zero on a pool with workers to spare.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::sync::mpsc;
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// # use coretech_wirestorm::ThreadPool;
+    /// let pool = ThreadPool::new(2);
+    /// let mut releases = Vec::new();
+    /// for _ in 0..5 {
+    ///     let (release, wait) = mpsc::channel::<()>();
+    ///     releases.push(release);
+    ///     pool.execute(move || { let _ = wait.recv(); });
+    /// }
+    /// while pool.pending_jobs() > 3 {
+    ///     thread::sleep(Duration::from_millis(1));
+    /// }
+    ///
+    /// // both workers are blocked, so the other three jobs wait their turn
+    /// thread::sleep(Duration::from_millis(50));
+    /// assert_eq!(pool.pending_jobs(), 3);
+    /// drop(releases);
+    /// ```
+    pub fn pending_jobs(&self) -> usize {
+        self.state.pending.load(Ordering::SeqCst)
+    }
+    /// Whether a job sent now would have to wait for a running one to finish.
+    ///
+    /// That is when every worker is busy or already has a job waiting for it, and an
+    /// autoscaling pool is at its maximum size.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::sync::mpsc;
+    /// # use coretech_wirestorm::ThreadPool;
+    /// let pool = ThreadPool::new(1);
+    /// assert!(!pool.is_saturated());
+    ///
+    /// let (release, wait) = mpsc::channel::<()>();
+    /// pool.execute(move || { let _ = wait.recv(); });
+    /// assert!(pool.is_saturated());
+    /// drop(release);
+    /// ```
+    pub fn is_saturated(&self) -> bool {
+        let state = &self.state;
+        let live = state.live.load(Ordering::SeqCst);
+        let claimed = state.pending.load(Ordering::SeqCst) + state.busy.load(Ordering::SeqCst);
+        claimed >= live && self.autoscale.is_none_or(|policy| live >= policy.max)
     }
     //this lets me send a task into the threadpool for execution by a thread.
     /// Sends a job to the thread pool for execution by a worker thread.
@@ -133,27 +1613,69 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
         {
-            let job = Box::new(f);
-            
-            if let Some(sender) = &self.sender {
-                if let Err(e) = sender.send(job) {
-                    eprintln!("Failed to send job to thread pool: {}", e);
+            let job: Job = match &self.metrics {
+                Some(metrics) => {
+                    let metrics = Arc::clone(metrics);
+                    let state = Arc::clone(&self.state);
+                    let queued = Instant::now();
+                    Box::new(move || {
+                        let started = Instant::now();
+                        metrics.pool_pending_jobs.set(state.pending.load(Ordering::SeqCst) as u64);
+                        metrics.pool_queue_wait.record_duration(started - queued);
+                        f();
+                        metrics.pool_exec_time.record_duration(started.elapsed());
+                    })
                 }
-            } else {
+                None => Box::new(f),
+            };
+            
+            self.state.pending.fetch_add(1, Ordering::SeqCst);
+            if self.queue.push(job).is_err() {
+                self.state.pending.fetch_sub(1, Ordering::SeqCst);
                 eprintln!("Thread pool has been shut down, cannot send job.");
+                return;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.pool_pending_jobs.set(self.pending_jobs() as u64);
             }
+            if let Some(policy) = self.autoscale {
+                self.grow(policy);
+            }
+        }
+    // Adds a worker if every worker is busy and a job is waiting, unless already at the maximum.
+    fn grow(&self, policy: AutoscalePolicy) {
+        let state = &self.state;
+        let saturated = state.pending.load(Ordering::SeqCst) + state.busy.load(Ordering::SeqCst)
+            > state.live.load(Ordering::SeqCst);
+        if saturated
+            && state
+                .live
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live < policy.max).then_some(live + 1))
+                .is_ok()
+        {
+            self.spawn_worker();
         }
+    }
+    // Starts a worker already counted in `state.live`, reaping any that have retired.
+    fn spawn_worker(&self) {
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        let worker = Worker::new(id, Arc::clone(&self.queue), Arc::clone(&self.state), self.autoscale);
+        let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+        workers.retain(|worker| !worker.thread.is_finished());
+        workers.push(worker);
+    }
 }
 /// Cleans up the thread pool and joins all worker threads when the pool is dropped.
 ///
 /// The `Drop` implementation for `ThreadPool` ensures that all worker threads are properly shut down
 /// and joined before the pool is destroyed. This prevents resource leaks and ensures a clean shutdown.
-/// The sender channel is closed, and each worker is joined in turn.
+/// The job queue is closed, and each worker is joined in turn once the jobs already
+/// queued have run.
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        //take the sender out of the option, which will close the channel
-        drop(self.sender.take()); 
-        for worker in self.workers.drain(..) {
+        self.queue.close();
+        let workers = self.workers.get_mut().unwrap_or_else(|e| e.into_inner());
+        for worker in workers.drain(..) {
             println!("Shutting down worker {}", worker.id);
             if let Err(e) = worker.thread.join() {
                 eprintln!("Worker {} thread failed to join: {:?}", worker.id, e);
@@ -176,31 +1698,42 @@ impl Worker {
     /// # Arguments
     ///
     /// * `id` - The worker's unique identifier.
-    /// * `receiver` - Shared receiver for job messages.
+    /// * `queue` - The pool's shared job queue.
+    /// * `state` - The pool's counts, which the worker keeps up to date.
+    /// * `autoscale` - If set, the worker retires after being idle for its timeout,
+    ///   as long as the pool stays at or above its minimum.
     ///
     /// # Returns
     ///
     /// A new `Worker` instance with its own thread.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(
+        id: usize,
+        queue: Arc<JobQueue>,
+        state: Arc<PoolState>,
+        autoscale: Option<AutoscalePolicy>,
+    ) -> Worker {
         //create a thread using the thread::spawn function
         let thread = thread::spawn(move || {
             loop {
-                
-                let message = match receiver.lock() {
-                    Ok(guard) => guard.recv(),
-                    Err(e) => {
-                        eprintln!("Worker {id} failed to lock the receiver: {e}");
-                        break;
-                    }
-                };
-
-                match message {
-                    Ok(job) => {
+                match queue.take(autoscale.map(|policy| policy.idle_timeout)) {
+                    Take::Job(job) => {
                         println!("Worker {id} got a job; executing.");
+                        let _busy = Busy::start(&state);
                         job();
                     }
-                    Err(_) => {
+                    Take::Idle => {
+                        let min = autoscale.map_or(0, |policy| policy.min);
+                        let retired = state
+                            .live
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live > min).then(|| live - 1));
+                        if retired.is_ok() {
+                            eprintln!("Worker {id} was idle; retiring.");
+                            break;
+                        }
+                    }
+                    Take::Closed => {
                         println!("Worker {id} got an error; shutting down.");
+                        state.live.fetch_sub(1, Ordering::SeqCst);
                         break;
                     }
                 }
@@ -210,6 +1743,63 @@ impl Worker {
     }
 }
 
+// Counts a worker as busy while a job runs. A job that panics takes its worker with it,
+// so the worker is no longer counted as live either.
+struct Busy<'a>(&'a PoolState);
+
+impl<'a> Busy<'a> {
+    fn start(state: &'a PoolState) -> Self {
+        state.pending.fetch_sub(1, Ordering::SeqCst);
+        state.busy.fetch_add(1, Ordering::SeqCst);
+        Busy(state)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.busy.fetch_sub(1, Ordering::SeqCst);
+        if thread::panicking() {
+            self.0.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+/// Collects every protocol violation found in a message header.
+///
+/// Unlike [`validate_header`], which stops at the first problem, this runs all the
+/// header checks and returns each failure in the order `validate_header` would
+/// report them. An empty vector means the header is valid. Nothing is logged, so
+/// it is suitable for offline tools working through large captures.
+///
+/// # Arguments
+/// * `header` - A byte slice representing the message header.
+///
+/// # Returns
+/// * `Vec<CtmpError>` - Every violation found, first one first.
+pub fn validation_report(header: &[u8]) -> Vec<CtmpError> {
+    let mut errors = Vec::new();
+
+    if header[0] != CTMP_MAGIC_BYTE {
+        errors.push(CtmpError::InvalidMagic(header[0]));
+    }
+
+    let sensitive = (header[1] & CTMP_SENSITIVE_FLAG) != 0;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    if header[1] & CTMP_ROUTED_FLAG == 0 && header[6..8] != [CTMP_PAD, CTMP_PAD] {
+        errors.push(CtmpError::InvalidPadding);
+    }
+
+    if !sensitive && header[4..6] != [0x00; 2] {
+        errors.push(CtmpError::InvalidChecksumPadding);
+    }
+
+    if length == 0 || length > CTMP_MAX_PAYLOAD_SIZE {
+        errors.push(CtmpError::InvalidLength(length));
+    }
+
+    errors
+}
+
 /// Validates a message header for protocol correctness.
 ///
 /// Checks magic byte, padding, and payload length. Returns the payload length and sensitivity flag if valid.
@@ -219,58 +1809,148 @@ impl Worker {
 ///
 /// # Returns
 /// * `Ok((u16, bool))` - The payload length and sensitivity flag.
-/// * `Err(String)` - An error message if validation fails.
-pub fn validate_header(header: &[u8]) -> Result<(u16,bool), String> {
+/// * `Err(CtmpError)` - The first protocol violation found.
+pub fn validate_header(header: &[u8]) -> Result<(u16,bool), CtmpError> {
 
         println!("Received header: {:?}", header);
 
-        // Validate magic byte
-        if header[0] != CTMP_MAGIC_BYTE {
-            return Err("Invalid magic byte".into());
+        if let Some(error) = validation_report(header).into_iter().next() {
+            return Err(error);
         }
 
-        let sensitive = (header[1] & 0x40) != 0; // bit 1
-        let length = u16::from_be_bytes([header[2],header[3]]) as usize;
+        let sensitive = (header[1] & CTMP_SENSITIVE_FLAG) != 0;
+        let length = u16::from_be_bytes([header[2],header[3]]);
         println!("length: {}", length);
 
-        if header[6..8] != [CTMP_PAD, CTMP_PAD] {
-            return Err("Invalid padding".into());
-        }
+        Ok((length, sensitive))
+        
+}
+
+// Bounds how many broadcasts run at once, for `Destinations::with_broadcast_limit`.
+struct BroadcastLimit {
+    max: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+impl BroadcastLimit {
+    fn new(max: usize) -> Self {
+        BroadcastLimit { max, in_flight: Mutex::new(0), released: Condvar::new() }
+    }
 
-        if !sensitive && header[4..6] != [0x00;2]{
-            return Err("Invalid Padding for non sensitive headers".into())
+    // Waits for a broadcast's turn, which lasts until the permit is dropped.
+    fn acquire<'a>(&'a self, metrics: &'a Metrics) -> BroadcastPermit<'a> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        while *in_flight >= self.max {
+            in_flight = self.released.wait(in_flight).unwrap_or_else(|e| e.into_inner());
         }
+        *in_flight += 1;
+        metrics.broadcasts_in_flight.set(*in_flight as u64);
+        BroadcastPermit { limit: self, metrics }
+    }
+}
+
+// Counts checksum verifications in one-second windows, for
+// `Destinations::with_checksum_limit`.
+struct ChecksumBudget {
+    per_second: u32,
+    overload: ChecksumOverload,
+    // when the current window started, and the verifications spent in it
+    window: Mutex<(Instant, u32)>,
+}
+
+impl ChecksumBudget {
+    fn new(per_second: u32, overload: ChecksumOverload) -> Self {
+        ChecksumBudget { per_second, overload, window: Mutex::new((Instant::now(), 0)) }
+    }
 
-        if length == 0 || length > CTMP_MAX_PAYLOAD_SIZE as usize {
-            return Err(format!("Invalid payload length: {}", length));
+    // Spends one verification, waiting for the next window under `Throttle`. Returns
+    // false if the frame is to be dropped unverified instead.
+    fn spend(&self, metrics: &Metrics) -> bool {
+        let mut throttled = false;
+        loop {
+            let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = window.0 + Duration::from_secs(1);
+            if now >= refill {
+                *window = (now, 0);
+            }
+            if window.1 < self.per_second {
+                window.1 += 1;
+                return true;
+            }
+            if !throttled {
+                throttled = true;
+                metrics.checksums_throttled.increment();
+            }
+            match self.overload {
+                ChecksumOverload::Drop => return false,
+                ChecksumOverload::Throttle => {
+                    drop(window);
+                    thread::sleep(refill.saturating_duration_since(now));
+                }
+            }
         }
+    }
+}
 
-        return Ok((length as u16, sensitive));
-        
+struct BroadcastPermit<'a> {
+    limit: &'a BroadcastLimit,
+    metrics: &'a Metrics,
+}
+
+impl Drop for BroadcastPermit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limit.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        *in_flight -= 1;
+        self.metrics.broadcasts_in_flight.set(*in_flight as u64);
+        drop(in_flight);
+        self.limit.released.notify_one();
+    }
 }
 
 /// Broadcasts a message to all destination clients.
 ///
-/// Builds a frame from the header and payload, then sends it to all connected destinations.
+/// Builds a frame from the header and payload, then sends it to all connected destinations,
+/// flushing each one unless the [`DeliveryPolicy`] flushes on an interval instead. A failed
+/// flush counts as a failed write.
+/// TCP destinations whose write fails are removed, except that write timeouts, and writes
+/// that accept zero bytes, are counted as strikes and only evict once the
+/// [`DeliveryPolicy`] says so. If a multicast group is configured the
+/// frame is also sent to it once; a frame too large for the multicast MTU is skipped for
+/// that transport only.
 ///
 /// # Arguments
 /// * `header` - The message header bytes.
 /// * `payload` - The message payload bytes.
 /// * `destinations` - Shared list of destination clients.
-pub fn broadcast_message(header: &[u8], payload: &[u8], destinations: Arc<Mutex<Vec<TcpStream>>>) {
+pub fn broadcast_message<T: Transport>(header: &[u8], payload: &[u8], destinations: &Destinations<T>) {
+    let _permit = destinations
+        .broadcast_limit
+        .as_ref()
+        .map(|limit| limit.acquire(&destinations.metrics));
     let mut frame = Vec::with_capacity(CTMP_HEADER_LEN + payload.len());
-    frame.extend_from_slice(&header);
-    frame.extend_from_slice(&payload);
+    frame.extend_from_slice(header);
+    frame.extend_from_slice(payload);
+    let frame = Arc::new(frame);
+
+        if let Some(multicast) = &destinations.multicast
+            && let Err(e) = multicast.send(&frame)
+        {
+            eprintln!("Failed to send frame to multicast group: {}", e);
+        }
 
-        let mut dests = destinations
-                .lock()
-                .unwrap_or_else(|_| panic!("Failed to lock destinations mutex"));
-        dests.retain_mut(|dest| dest.write_all(&frame).is_ok());
+        let mut dests = destinations.lock();
+        destinations.generation.fetch_add(1, Ordering::SeqCst);
+        destinations.metrics.frames_relayed.increment();
+        let delivered = destinations.deliver_all(&mut dests, &frame);
+        destinations.metrics.fanout.record(delivered);
 }
 
 /// Computes and verifies the checksum of a message.
 ///
-/// Calculates the checksum over the header and payload using the protocol's algorithm.
+/// Calculates the checksum over the header and payload using the protocol's
+/// one's-complement algorithm. See [`ChecksumAlgorithm`] for the alternatives.
 ///
 /// # Arguments
 /// * `header` - The message header bytes.
@@ -279,94 +1959,434 @@ pub fn broadcast_message(header: &[u8], payload: &[u8], destinations: Arc<Mutex<
 /// # Returns
 /// * `u16` - The computed checksum value.
 pub fn verify_checksum(header: &[u8], payload: &[u8]) -> u16 {
+    ChecksumAlgorithm::OnesComplement.frame_checksum(header, payload)
+}
 
-    // Set checksum bytes in header to magic byte for calculation
-    let mut checksum_header = header.to_owned();
-    checksum_header[4] = CTMP_MAGIC_BYTE;
-    checksum_header[5] = CTMP_MAGIC_BYTE;
-
-    let mut sum: u32 = 0;
-
-    // Sum header as u16 chunks
-    for chunk in checksum_header.chunks(2) {
-        let val = if chunk.len() == 2 {
-            u16::from_be_bytes([chunk[0], chunk[1]])
-        } else {
-            u16::from_be_bytes([chunk[0], 0])
-        };
-        sum += val as u32;
-    }
+/// Why [`handle_transmitter`] stopped reading from a transmitter.
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// The transmitter closed the connection between frames.
+    Closed,
+    /// Reading failed, or the stream ended part way through a frame.
+    ReadFailed(io::ErrorKind),
+    /// A header failed validation, or its fragment took its message over
+    /// [`TransmitterPolicy::max_message_size`] under [`OversizePolicy::Reject`].
+    InvalidHeader(CtmpError),
+    /// The transmitter reached [`TransmitterPolicy::max_session`].
+    SessionExpired,
+    /// The transmitter's next frame would have taken it over
+    /// [`TransmitterPolicy::max_bytes_per_session`].
+    QuotaExceeded,
+}
 
-    // Sum payload as u16 chunks
-    for chunk in payload.chunks(2) {
-        let val = if chunk.len() == 2 {
-            u16::from_be_bytes([chunk[0], chunk[1]])
-        } else {
-            u16::from_be_bytes([chunk[0], 0])
-        };
-        sum += val as u32;
+impl DisconnectReason {
+    /// Which family the reason belongs to.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::net::TcpStream;
+    /// # use std::sync::{Arc, Mutex};
+    /// # use coretech_wirestorm::{build_frame, handle_transmitter, Destinations, DisconnectKind, TransmitterPolicy};
+    /// let destinations = Destinations::<TcpStream>::default();
+    /// let run = |input: &[u8]| {
+    ///     let slot = Arc::new(Mutex::new(None::<TcpStream>));
+    ///     handle_transmitter(input, None, destinations.clone(), slot, TransmitterPolicy::default()).kind()
+    /// };
+    ///
+    /// // the connection drops part way through a frame
+    /// let frame = build_frame(b"hello", false).unwrap();
+    /// assert_eq!(run(&frame[..10]), DisconnectKind::Transport);
+    /// // a header with the wrong magic byte
+    /// assert_eq!(run(&[0x00, 0, 0, 5, 0, 0, 0, 0]), DisconnectKind::Protocol);
+    ///
+    /// let metrics = destinations.metrics();
+    /// assert_eq!(metrics.transport_disconnects.get(), 1);
+    /// assert_eq!(metrics.protocol_disconnects.get(), 1);
+    /// ```
+    pub fn kind(&self) -> DisconnectKind {
+        match self {
+            DisconnectReason::Closed => DisconnectKind::Clean,
+            DisconnectReason::ReadFailed(_) => DisconnectKind::Transport,
+            DisconnectReason::InvalidHeader(_) => DisconnectKind::Protocol,
+            DisconnectReason::SessionExpired | DisconnectReason::QuotaExceeded => DisconnectKind::Policy,
+        }
     }
+}
 
-    // Fold carry bits
-    sum = (sum & 0xFFFF) + (sum >> 16);
+/// The family of a [`DisconnectReason`], for telling network trouble apart from
+/// transmitters that misbehave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectKind {
+    /// The transmitter closed the connection between frames.
+    Clean,
+    /// The connection failed under the transmitter: a read error, or the stream ending
+    /// part way through a frame. Counted in [`Metrics::transport_disconnects`].
+    Transport,
+    /// The transmitter sent something that is not CTMP. Counted in
+    /// [`Metrics::protocol_disconnects`].
+    Protocol,
+    /// The relay ended the session for reaching a limit in its [`TransmitterPolicy`].
+    Policy,
+}
 
-    // Convert to one's complement
-    !(sum as u16)
+/// A frame picked out by [`TransmitterPolicy::log_every_nth_frame`].
+///
+/// Frames are sampled as they are read, before the relay decides whether to relay
+/// them, so a sample may be of a frame that is then dropped.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::net::TcpStream;
+/// # use std::sync::{Arc, Mutex};
+/// # use coretech_wirestorm::{build_frame, handle_transmitter, Destinations, TransmitterPolicy};
+/// let mut input = build_frame(b"plain", false).unwrap();
+/// input.extend(build_frame(b"secret", true).unwrap());
+/// let mut tampered = build_frame(b"secret", true).unwrap();
+/// tampered[4] ^= 0xFF;
+/// input.extend(tampered);
+///
+/// let samples = Arc::new(Mutex::new(Vec::new()));
+/// let log = Arc::clone(&samples);
+/// let destinations = Destinations::<TcpStream>::default()
+///     .with_frame_log(move |sample| log.lock().unwrap().push(*sample));
+/// let policy = TransmitterPolicy { log_every_nth_frame: Some(1), ..TransmitterPolicy::default() };
+/// handle_transmitter(&input[..], None, destinations, Arc::new(Mutex::new(None::<TcpStream>)), policy);
+///
+/// let verified: Vec<_> = samples.lock().unwrap().iter().map(|s| (s.sensitive, s.checksum_verified)).collect();
+/// assert_eq!(verified, [(false, false), (true, true), (true, false)]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSample {
+    /// The frame's position in its transmitter's session, counting from 1.
+    pub index: u64,
+    /// The payload length.
+    pub length: usize,
+    /// Whether the sensitive flag was set.
+    pub sensitive: bool,
+    /// Whether the frame's checksum matched, as the transmitter sent it. Only sensitive
+    /// frames carry a checksum, so this is always `false` for the rest. A sampled frame
+    /// is checked for its sample even if the
+    /// [verification budget](Destinations::with_checksum_limit) then drops it.
+    pub checksum_verified: bool,
+    /// The transmitter's address, if known.
+    pub peer: Option<SocketAddr>,
 }
 
 //this function will handle the transmitter
 /// Handles a transmitter client, reading messages and broadcasting them.
 ///
 /// Reads headers and payloads from the source client, validates them, and broadcasts valid messages to all destinations.
-/// If a sensitive message fails checksum validation, it is dropped.
+/// If a sensitive message fails checksum validation, using the algorithm in `policy`, or
+/// its payload is over the policy's limit, it is dropped.
+/// With [loop detection](Destinations::with_loop_detection) enabled, frames are tagged
+/// with a routing id and frames that have looped back are dropped.
+///
+/// A frame with the continued bit (`0x08`) set is a fragment of a message larger than
+/// one frame, and the message goes on to the next frame; the first frame without the
+/// bit ends it. With a [message size limit](TransmitterPolicy::max_message_size), a
+/// message's fragments are held until its last one arrives and then broadcast back to
+/// back, and a message over the limit is dropped whole, so receivers only ever see
+/// complete messages. Fragments held when the transmitter disconnects are dropped.
+/// Without a limit, fragments are relayed as they arrive, like any other frame.
+///
+/// A sensitive frame's checksum is only ever trusted as the transmitter sent it: it is
+/// verified before anything else touches the frame. A frame the relay does not change
+/// goes out byte for byte as it came in, with that original checksum. One the relay
+/// does change, such as by tagging it, has its checksum recomputed over the changed
+/// header, so receivers can verify every sensitive frame they get, but what they are
+/// verifying is then the relay's word that the frame arrived intact.
+///
+/// With [`TransmitterPolicy::trailing_crc`], each payload is followed by a CRC outside
+/// its declared length. It is read and checked straight after the payload and never
+/// relayed, and a frame whose CRC does not match is dropped.
+///
+/// A frame that would take the session's broadcast payload bytes over the quota in
+/// `policy` is dropped and the transmitter disconnected. Frames are only counted
+/// against the quota as they are broadcast, so ones dropped for any other reason,
+/// including fragments of a message over the size limit, never use it up.
+///
+/// The session limit in `policy` is checked between frames, and a frame still arriving
+/// when the session ends is abandoned unrelayed, ending the session with
+/// [`DisconnectReason::SessionExpired`] all the same. A transmitter
+/// that goes quiet is only noticed if reads on `stream` time out, so give it a read
+/// timeout when a limit is set; timeouts are otherwise treated as waiting for more data.
+///
+/// With [`TransmitterPolicy::log_every_nth_frame`] set, every Nth frame read from this
+/// transmitter, whether or not it is then relayed, is logged or handed to the
+/// [frame log](Destinations::with_frame_log) as a [`FrameSample`].
 ///
 /// # Arguments
-/// * `stream` - The TCP stream for the transmitter client.
+/// * `stream` - The stream for the transmitter client, normally a `TcpStream`.
+/// * `peer` - The transmitter's address, if it has one, for logging.
 /// * `destinations` - Shared list of destination clients.
 /// * `active_source` - Shared state for the active source client.
-pub fn handle_transmitter(
-    stream: TcpStream,
-    destinations: Arc<Mutex<Vec<TcpStream>>>,
-    active_source: Arc<Mutex<Option<TcpStream>>>,
-) {
-    let mut buf_reader = BufReader::new(&stream);
+/// * `policy` - Limits on the transmitter's session, and how it is read.
+///
+/// # Returns
+/// Why the transmitter was disconnected.
+pub fn handle_transmitter<R: Read, S, T: Transport>(
+    stream: R,
+    peer: Option<SocketAddr>,
+    destinations: Destinations<T>,
+    active_source: Arc<Mutex<Option<S>>>,
+    policy: TransmitterPolicy,
+) -> DisconnectReason {
+    let metrics = Arc::clone(&destinations.metrics);
+    handle_transmitter_session(stream, peer, destinations, active_source, policy, &metrics.transmitter_session)
+}
+
+/// Like [`handle_transmitter`], but counts the session in `session` rather than in the
+/// [shared transmitter session](Metrics::transmitter_session), so transmitters handled
+/// at the same time each keep their own counts.
+///
+/// `session` is [started](SessionStats::start) when the transmitter is first read and
+/// ended when it disconnects, and keeps its counts after that, so the caller can see
+/// how the session went, such as how many of its frames were rejected.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::net::TcpStream;
+/// # use std::sync::{Arc, Mutex};
+/// # use coretech_wirestorm::metrics::SessionStats;
+/// # use coretech_wirestorm::testing::build_frame_with_checksum;
+/// # use coretech_wirestorm::{build_frame, handle_transmitter_session, Destinations, TransmitterPolicy};
+/// let mut input = build_frame(b"good", false).unwrap();
+/// input.extend(build_frame_with_checksum(b"bad", true, 0).unwrap());
+/// let destinations = Destinations::<TcpStream>::default();
+/// let session = SessionStats::default();
+/// let slot = Arc::new(Mutex::new(None::<TcpStream>));
+/// handle_transmitter_session(&input[..], None, destinations.clone(), slot, TransmitterPolicy::default(), &session);
+///
+/// assert_eq!(session.frames.get(), 1);
+/// assert_eq!(session.checksum_failures.get(), 1);
+/// assert!(destinations.metrics().transmitter_session.snapshot().is_none());
+/// ```
+pub fn handle_transmitter_session<R: Read, S, T: Transport>(
+    stream: R,
+    peer: Option<SocketAddr>,
+    destinations: Destinations<T>,
+    active_source: Arc<Mutex<Option<S>>>,
+    policy: TransmitterPolicy,
+    session: &SessionStats,
+) -> DisconnectReason {
+    let mut buf_reader = BufReader::with_capacity(policy.read_buffer_size, stream);
     let mut header = [0u8; CTMP_HEADER_LEN];
+    let session_start = Instant::now();
+    // a frame still arriving when the session ends is abandoned rather than waited for
+    let deadline = policy.max_session.map(|max| session_start + max);
+    session.start();
+    let mut session_bytes: u64 = 0;
+    let mut frames_read: u64 = 0;
+    // what the transmitter called itself, if its first frame was a label
+    let mut label = None;
+    // fragments of the message being assembled, and its size so far
+    let mut held: Vec<([u8; CTMP_HEADER_LEN], Vec<u8>)> = Vec::new();
+    let mut message_len = 0;
+    let mut oversized = false;
+
+    let reason = loop {
+        if policy.session_expired(session_start.elapsed()) {
+            eprintln!("Transmitter session expired");
+            break DisconnectReason::SessionExpired;
+        }
 
-    loop {
+        // Wait for the next frame to start, waking on read timeouts to check the session limit
+        match buf_reader.fill_buf() {
+            Ok([]) => break DisconnectReason::Closed,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if is_timeout(&e) && policy.max_session.is_some() => continue,
+            Err(e) => {
+                eprintln!("Failed to read header: {}", e);
+                break DisconnectReason::ReadFailed(e.kind());
+            }
+        }
 
         // Read the fixed-size header
-        if let Err(e) = buf_reader.read_exact(&mut header) {
-            eprintln!("Failed to read header: {}", e);
-            break;
+        if let Err(e) = read_full(&mut buf_reader, &mut header, deadline) {
+            break read_failed("header", e, deadline);
         }
         
         let (length, sensitive) = match validate_header(&header) {
             Ok(result) => result,
             Err(e) => {
                 eprintln!("Error validating header: {}", e);
-                break;
+                destinations.metrics.frames_rejected.increment();
+                session.rejected.increment();
+                break DisconnectReason::InvalidHeader(e);
             }
         };
 
         let mut payload = vec![0u8; length as usize];
-        if let Err(e) = buf_reader.read_exact(&mut payload) {
-            eprintln!("Failed to read payload: {}", e);
-            break;
+        if let Err(e) = read_full(&mut buf_reader, &mut payload, deadline) {
+            break read_failed("payload", e, deadline);
+        }
+
+        frames_read += 1;
+        destinations.metrics.max_payload_seen.record(payload.len() as u64);
+        // A sampled frame's checksum is checked for the log, and the result kept for later
+        let sampled_verification = policy
+            .log_every_nth_frame
+            .is_some_and(|n| frames_read.is_multiple_of(n))
+            .then(|| sensitive && policy.checksum.verify(&header, &payload));
+        if let Some(checksum_verified) = sampled_verification {
+            let sample = FrameSample { index: frames_read, length: payload.len(), sensitive, checksum_verified, peer };
+            match &destinations.frame_log {
+                Some(log) => log(&sample),
+                None => eprintln!("Sampled frame {:?}", sample),
+            }
+        }
+
+        // Some producers append a CRC outside the declared length; it is never relayed
+        if let Some(crc) = policy.trailing_crc {
+            let mut trailer = [0u8; 4];
+            let trailer = &mut trailer[..crc.size()];
+            if let Err(e) = read_full(&mut buf_reader, trailer, deadline) {
+                break read_failed("trailing CRC", e, deadline);
+            }
+            if !crc.verify(&payload, trailer) {
+                eprintln!("Invalid trailing {} for message, dropping", crc);
+                destinations.metrics.frames_rejected.increment();
+                session.rejected.increment();
+                continue;
+            }
+        }
+
+        if !policy.allows_payload(payload.len()) {
+            eprintln!("Payload of {} bytes is over the listener's limit, dropping", payload.len());
+            destinations.metrics.frames_rejected.increment();
+            session.rejected.increment();
+            continue;
         }
 
-        let checksum_in_msg = u16::from_be_bytes([header[4], header[5]]);
+        // Control frames come from the relay, never from a transmitter, except for the
+        // label a transmitter may send first
+        if frames_read == 1
+            && let Some(name) = frame::frame_label(&header, &payload)
+        {
+            eprintln!("Transmitter {} is labelled {:?}", display_peer(peer), name);
+            session.set_label(name.clone());
+            label = Some(name);
+            continue;
+        }
+        if header[1] & CTMP_CONTROL_FLAG != 0 {
+            eprintln!("Control frame from transmitter, dropping");
+            destinations.metrics.frames_rejected.increment();
+            session.rejected.increment();
+            continue;
+        }
+        // Only the relay compresses frames, and only for receivers that asked
+        if header[1] & CTMP_COMPRESSED_FLAG != 0 {
+            eprintln!("Compressed frame from transmitter, dropping");
+            destinations.metrics.frames_rejected.increment();
+            session.rejected.increment();
+            continue;
+        }
 
+        // Only frames that got this far spend from the verification budget, and
+        // sensitive frames over it are never checked
+        if sensitive
+            && destinations.checksum_budget.as_ref().is_some_and(|budget| !budget.spend(&destinations.metrics))
+        {
+            eprintln!("Checksum verification budget used up, dropping sensitive message");
+            destinations.metrics.frames_rejected.increment();
+            session.rejected.increment();
+            continue;
+        }
         // If sensitive, validate checksum
-        if sensitive {
+        if sensitive && !sampled_verification.unwrap_or_else(|| policy.checksum.verify(&header, &payload)) {
+            eprintln!("Invalid checksum for sensitive message, dropping");
+            destinations.metrics.frames_rejected.increment();
+            session.checksum_failures.increment();
+            continue;
+        }
 
-            let checksum_computed = verify_checksum(&header, &payload);
-            if checksum_computed != checksum_in_msg {
-                eprintln!("Invalid checksum for sensitive message, dropping");
-                continue;
+        // Set by anything below that changes the header, so its checksum is resealed
+        let mut modified = false;
+
+        if let Some(guard) = &destinations.loop_guard {
+            let admission = guard.lock().unwrap_or_else(|e| e.into_inner()).admit(&mut header);
+            match admission {
+                Admission::Duplicate(id) => {
+                    eprintln!("Frame with routing id {} seen before, dropping looped frame", id);
+                    destinations.metrics.frames_rejected.increment();
+                    session.rejected.increment();
+                    continue;
+                }
+                Admission::Tagged(_) => modified = true,
+                Admission::Forwarded(_) => {}
+            }
+        }
+
+        if modified && sensitive {
+            let checksum = policy.checksum.frame_checksum(&header, &payload);
+            header[4..6].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        // With a message size limit, fragments are held until their message is complete
+        if let Some(max) = policy.max_message_size {
+            message_len += payload.len();
+            if message_len > max && !oversized {
+                eprintln!("Message over {} bytes, dropping all of its fragments", max);
+                destinations.metrics.frames_rejected.add(held.len() as u64);
+                session.rejected.add(held.len() as u64);
+                held.clear();
+                oversized = true;
+                if policy.oversized_messages == OversizePolicy::Reject {
+                    destinations.metrics.frames_rejected.increment();
+                    session.rejected.increment();
+                    break DisconnectReason::InvalidHeader(CtmpError::ReassemblyTooLarge { limit: max, size: message_len });
+                }
             }
+            let continued = header[1] & CTMP_CONTINUED_FLAG != 0;
+            if oversized {
+                destinations.metrics.frames_rejected.increment();
+                session.rejected.increment();
+            } else {
+                held.push((header, payload));
+            }
+            if !continued {
+                let mut fragments = held.drain(..);
+                let over = fragments.by_ref().any(|(header, payload)| {
+                    let over = charge_quota(&mut session_bytes, payload.len(), &policy, &destinations, session);
+                    if !over {
+                        relay_frame(&header, &payload, &destinations, session);
+                    }
+                    over
+                });
+                // the rest of a message cut off by the quota is never relayed
+                let unsent = fragments.len() as u64;
+                drop(fragments);
+                if over {
+                    destinations.metrics.frames_rejected.add(unsent);
+                    session.rejected.add(unsent);
+                    break DisconnectReason::QuotaExceeded;
+                }
+                message_len = 0;
+                oversized = false;
+            }
+            continue;
+        }
+
+        if charge_quota(&mut session_bytes, payload.len(), &policy, &destinations, session) {
+            break DisconnectReason::QuotaExceeded;
         }
+        relay_frame(&header, &payload, &destinations, session);
+    };
+
+    if !held.is_empty() {
+        eprintln!("Transmitter left part way through a message, dropping {} fragments", held.len());
+        destinations.metrics.frames_rejected.add(held.len() as u64);
+        session.rejected.add(held.len() as u64);
+    }
 
-        broadcast_message(&header, &payload, destinations.clone());
+    match reason.kind() {
+        DisconnectKind::Transport => destinations.metrics.transport_disconnects.increment(),
+        DisconnectKind::Protocol => destinations.metrics.protocol_disconnects.increment(),
+        DisconnectKind::Clean | DisconnectKind::Policy => {}
     }
 
     // Clear active source when done
@@ -374,5 +2394,68 @@ pub fn handle_transmitter(
         .lock()
         .unwrap_or_else(|_| panic!("Failed to lock active source mutex"));
     *active = None;
-    eprintln!("Source client disconnected");
+    session.end();
+    eprintln!("Source client {} disconnected", display_client(peer, label.as_deref()));
+    reason
+}
+
+// Counts a frame about to be relayed towards the session's quota. If it would go over,
+// the frame is counted as rejected instead and this returns true, to disconnect.
+fn charge_quota<T: Transport>(
+    session_bytes: &mut u64,
+    len: usize,
+    policy: &TransmitterPolicy,
+    destinations: &Destinations<T>,
+    session: &SessionStats,
+) -> bool {
+    *session_bytes += len as u64;
+    if !policy.over_quota(*session_bytes) {
+        return false;
+    }
+    eprintln!("Transmitter went over its session quota after {} bytes, disconnecting", session_bytes);
+    destinations.metrics.frames_rejected.increment();
+    session.rejected.increment();
+    destinations.metrics.transmitters_over_quota.increment();
+    true
+}
+
+// Broadcasts a transmitter's frame and counts it towards the transmitter's session.
+fn relay_frame<T: Transport>(header: &[u8], payload: &[u8], destinations: &Destinations<T>, session: &SessionStats) {
+    broadcast_message(header, payload, destinations);
+    session.frames.increment();
+    session.bytes.add(payload.len() as u64);
+}
+
+// Why a session ends on a failed read. A frame cut off by the session limit expires the
+// session rather than failing it, since `read_full` only times out at the deadline.
+fn read_failed(what: &str, e: io::Error, deadline: Option<Instant>) -> DisconnectReason {
+    if e.kind() == io::ErrorKind::TimedOut && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        eprintln!("Transmitter session expired while reading {}, abandoning the frame", what);
+        return DisconnectReason::SessionExpired;
+    }
+    eprintln!("Failed to read {}: {}", what, e);
+    DisconnectReason::ReadFailed(e.kind())
+}
+
+// Whether a read failed only because its timeout passed.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Like `read_exact`, but keeps waiting through read timeouts so a frame that has
+// started arriving is not cut short, until `deadline`, if there is one, passes; then
+// it fails with `TimedOut`.
+pub(crate) fn read_full<R: Read>(reader: &mut R, mut buf: &mut [u8], deadline: Option<Instant>) -> io::Result<()> {
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if is_timeout(&e) && deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted || is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
 }
\ No newline at end of file
@@ -1,84 +1,38 @@
-use std::{net::{TcpListener, TcpStream}, sync::{Arc, Mutex}, thread};
-// Import custom thread pool and destination management from the library.
-use coretech_wirestorm::{Destinations, ThreadPool,handle_transmitter}; 
-
-const THREAD_COUNT: usize = 2; // Number of threads in the thread pool
-const SRC_PORT: u16 = 33333; // Source (transmitter) port
-const DEST_PORT: u16 = 44444; // Destination (receiver) port
-const IP_ADDRESS: &str = "127.0.0.1"; // IP address for the listeners
+use std::process;
+// Import the server and its configuration from the library.
+use coretech_wirestorm::{version, Config, Server};
 
 // Entry point for the server application.
-// Sets up listeners, thread pool, and manages client connections.
+// Reads the configuration, binds the listeners and serves until killed.
 fn main() {
-    // Bind the main TCP listener for source (transmitter) clients.
-    let listener = TcpListener::bind(format!("{}:{}", IP_ADDRESS, SRC_PORT))
-        .unwrap_or_else(|e| {
-            panic!("Failed to bind to port {}: {}", SRC_PORT, e);
-        });
-
-    // Create a thread pool for handling transmitter connections.
-    let pool = ThreadPool::new(THREAD_COUNT);
-    // Shared state for the currently active transmitter connection.
-    let active_source = Arc::new(Mutex::new(None::<TcpStream>));
-    // Manages all receiver clients.
-    let destinations = Destinations::new();
-    // Clone the inner Arc<Mutex<Vec<TcpStream>>> for use in destination thread.
-    let dest_clone = destinations.clone_inner();
-
-    // Spawn a thread to handle incoming destination (receiver) client connections.
-    // Each new connection is added to the shared destinations list.
-    thread::spawn(move || {
-        let dest_listener = TcpListener::bind(format!("{}:{}", IP_ADDRESS, DEST_PORT))
-            .unwrap_or_else(|e| panic!("Failed to bind to port {}: {e}", DEST_PORT));
+    // Report the build and exit, before anything else is parsed.
+    if std::env::args().skip(1).any(|arg| arg == "--version") {
+        println!("coretech-wirestorm {}", version());
+        return;
+    }
 
-        for stream in dest_listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    eprintln!("New destination client connected");
-                    dest_clone
-                        .lock()
-                        .unwrap_or_else(|_| panic!("Failed to lock destinations mutex"))
-                        .push(stream);
-                }
-                Err(e) => eprintln!("Destination connection error: {e}"),
-            }
-        }
+    // Read the configuration from the command line, falling back to the defaults.
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(2);
     });
 
-    // Accept incoming transmitter (source) connections.
-    // Only one transmitter is allowed at a time; others are rejected.
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let dests_clone = destinations.clone_inner();
-                let active_clone = Arc::clone(&active_source);
-
-                // Scope for locking and checking the active transmitter.
-                {
-                    let mut active = active_clone
-                        .lock()
-                        .unwrap_or_else(|_| panic!("Failed to lock active_source mutex"));
+    eprintln!("Starting coretech-wirestorm {}", version());
 
-                    // If a transmitter is already active, reject the new connection.
-                    if active.is_some() {
-                        eprintln!("Source client already connected, ignoring new connection");
-                        continue;
-                    }
+    // A planned recycle reports its own exit status to the supervisor.
+    let recycle_exit_code = config.max_uptime_exit_code;
 
-                    // Set the active transmitter to the new stream.
-                    *active = Some(
-                        stream
-                            .try_clone()
-                            .unwrap_or_else(|_| panic!("Failed to clone source stream")),
-                    );
-                }
+    // Bind the source and destination listeners, plus the multicast socket if configured.
+    let server = Server::bind(config).unwrap_or_else(|e| {
+        eprintln!("Failed to start server: {e}");
+        process::exit(1);
+    });
 
-                // Send the transmitter connection to the thread pool for handling.
-                pool.execute(move || {
-                    handle_transmitter(stream, dests_clone, active_clone);
-                });
-            }
-            Err(e) => eprintln!("Source connection error: {e}"),
-        }
+    // Accept transmitters and receivers until the process is stopped, or has been up
+    // for --max-uptime-ms.
+    if let Err(e) = server.run() {
+        eprintln!("Server error: {e}");
+        process::exit(1);
     }
-}
\ No newline at end of file
+    process::exit(recycle_exit_code);
+}
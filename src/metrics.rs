@@ -0,0 +1,571 @@
+//! Measurements of what the relay is doing, for diagnosing it while it runs.
+//!
+//! A [`Metrics`] is shared behind an `Arc` by everything that records into it. Every
+//! measurement is an atomic, so recording never takes a lock and never blocks the
+//! relay; reading one while it is being updated gives a value that is at most a few
+//! samples out of date.
+//!
+//! The [`Counter`]s directly on [`Metrics`] are lifetime counters: they only go up,
+//! and [`Metrics::save`] and [`Metrics::load`] carry them over from one run of the
+//! server to the next. The [`Gauge`]s, histograms and the
+//! [transmitter session](Metrics::transmitter_session) describe the current run only
+//! and start empty every time.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::server::RejectReason;
+
+/// Upper bounds, in microseconds, of the buckets used for latency histograms.
+pub const LATENCY_BUCKETS_US: &[u64] = &[10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+/// Upper bounds of the buckets used for the number of receivers a frame reaches.
+pub const FANOUT_BUCKETS: &[u64] = &[0, 1, 2, 5, 10, 20, 50, 100, 500, 1_000];
+
+/// Everything the relay measures.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Frames broadcast to receivers.
+    pub frames_relayed: Counter,
+    /// Frames from transmitters that were not relayed: bad checksums, control frames,
+    /// frames that looped back, frames over a session quota and frames whose header
+    /// ended the connection.
+    pub frames_rejected: Counter,
+    /// Frames a receiver missed because it was not keeping up, summed over receivers.
+    pub frames_dropped: Counter,
+    /// Frames a receiver was never sent because they waited in its queue past the
+    /// [frame TTL](crate::DeliveryPolicy::frame_ttl), summed over receivers.
+    pub frames_expired: Counter,
+    /// Receivers removed because writing to them failed, or to make room for a new one
+    /// under [`CapacityPolicy::EvictOldest`](crate::CapacityPolicy::EvictOldest).
+    pub destinations_evicted: Counter,
+    /// Receivers turned away because
+    /// [`max_destinations`](crate::DeliveryPolicy::max_destinations) were already connected.
+    pub destinations_rejected: Counter,
+    /// Transmitter connections refused, for any [`RejectReason`]. Each refusal is also
+    /// counted under its reason, which [`rejections`](Self::rejections) looks up.
+    pub transmitters_rejected: Counter,
+    /// Transmitters refused for [`RejectReason::AlreadyActive`].
+    pub rejected_already_active: Counter,
+    /// Transmitters refused for [`RejectReason::Quarantined`].
+    pub rejected_quarantined: Counter,
+    /// Transmitters refused for [`RejectReason::UnknownPeer`].
+    pub rejected_unknown_peer: Counter,
+    /// Transmitters refused for [`RejectReason::PoolFull`].
+    pub rejected_pool_full: Counter,
+    /// Transmitters disconnected for going over their
+    /// [session quota](crate::TransmitterPolicy::max_bytes_per_session).
+    pub transmitters_over_quota: Counter,
+    /// Transmitters whose connection failed under them, a
+    /// [transport](crate::DisconnectKind::Transport) disconnect.
+    pub transport_disconnects: Counter,
+    /// Transmitters disconnected for breaking the protocol, a
+    /// [protocol](crate::DisconnectKind::Protocol) disconnect. A steady rate of these
+    /// points at a buggy or hostile transmitter rather than the network.
+    pub protocol_disconnects: Counter,
+    /// Checksum verifications of sensitive frames that went over the
+    /// [verification budget](crate::Destinations::with_checksum_limit), whether the
+    /// transmitter was made to wait for them or the frame was dropped unverified.
+    pub checksums_throttled: Counter,
+    /// Microseconds transmitter jobs waited in the thread pool's queue before a worker took them.
+    pub pool_queue_wait: Histogram,
+    /// Microseconds transmitter jobs spent running on a worker.
+    pub pool_exec_time: Histogram,
+    /// Transmitter jobs waiting in the thread pool's queue for a worker, as of the last
+    /// job sent or started. See [`ThreadPool::pending_jobs`](crate::ThreadPool::pending_jobs).
+    pub pool_pending_jobs: Gauge,
+    /// How many receivers each broadcast frame was written to in full.
+    pub fanout: Histogram,
+    /// Bytes waiting in receivers' queues, summed over every receiver, including the
+    /// whole of any frame left partly written. See
+    /// [`Destination::queued_bytes`](crate::Destination::queued_bytes) for each one's share.
+    pub queued_bytes: Gauge,
+    /// Broadcasts running at once. Only kept up to date with a
+    /// [broadcast limit](crate::Destinations::with_broadcast_limit), which it never exceeds.
+    pub broadcasts_in_flight: Gauge,
+    /// The largest payload read from any transmitter, in bytes, whether or not the frame
+    /// was then relayed. For sizing [`max_payload`](crate::TransmitterPolicy::max_payload)
+    /// and buffers; [reset](HighWater::reset) it to start measuring afresh.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::net::TcpStream;
+    /// # use std::sync::{Arc, Mutex};
+    /// # use coretech_wirestorm::{build_frame, handle_transmitter, Destinations, TransmitterPolicy};
+    /// let mut input = Vec::new();
+    /// for size in [10, 500, 200] {
+    ///     input.extend(build_frame(&vec![1; size], false).unwrap());
+    /// }
+    /// let destinations = Destinations::new();
+    /// handle_transmitter(&input[..], None, destinations.clone(), Arc::new(Mutex::new(None::<TcpStream>)), TransmitterPolicy::default());
+    ///
+    /// let seen = &destinations.metrics().max_payload_seen;
+    /// assert_eq!(seen.get(), 500);
+    /// assert_eq!(seen.reset(), 500);
+    /// assert_eq!(seen.get(), 0);
+    /// ```
+    pub max_payload_seen: HighWater,
+    /// The current transmitter's session, for transmitters run with
+    /// [`handle_transmitter`](crate::handle_transmitter). A server counts each of its
+    /// sessions on its own instead; see
+    /// [`ServerHandle::transmitter_session`](crate::ServerHandle::transmitter_session).
+    pub transmitter_session: SessionStats,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            frames_relayed: Counter::default(),
+            frames_rejected: Counter::default(),
+            frames_dropped: Counter::default(),
+            frames_expired: Counter::default(),
+            destinations_evicted: Counter::default(),
+            destinations_rejected: Counter::default(),
+            transmitters_rejected: Counter::default(),
+            rejected_already_active: Counter::default(),
+            rejected_quarantined: Counter::default(),
+            rejected_unknown_peer: Counter::default(),
+            rejected_pool_full: Counter::default(),
+            transmitters_over_quota: Counter::default(),
+            transport_disconnects: Counter::default(),
+            protocol_disconnects: Counter::default(),
+            checksums_throttled: Counter::default(),
+            pool_queue_wait: Histogram::new(LATENCY_BUCKETS_US),
+            pool_exec_time: Histogram::new(LATENCY_BUCKETS_US),
+            pool_pending_jobs: Gauge::default(),
+            fanout: Histogram::new(FANOUT_BUCKETS),
+            queued_bytes: Gauge::default(),
+            broadcasts_in_flight: Gauge::default(),
+            max_payload_seen: HighWater::default(),
+            transmitter_session: SessionStats::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Creates a set of metrics with nothing recorded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The lifetime counters, by the names they are saved under.
+    fn lifetime_counters(&self) -> [(&'static str, &Counter); 15] {
+        [
+            ("frames_relayed", &self.frames_relayed),
+            ("frames_rejected", &self.frames_rejected),
+            ("frames_dropped", &self.frames_dropped),
+            ("frames_expired", &self.frames_expired),
+            ("destinations_evicted", &self.destinations_evicted),
+            ("destinations_rejected", &self.destinations_rejected),
+            ("transmitters_rejected", &self.transmitters_rejected),
+            ("rejected_already_active", &self.rejected_already_active),
+            ("rejected_quarantined", &self.rejected_quarantined),
+            ("rejected_unknown_peer", &self.rejected_unknown_peer),
+            ("rejected_pool_full", &self.rejected_pool_full),
+            ("transmitters_over_quota", &self.transmitters_over_quota),
+            ("transport_disconnects", &self.transport_disconnects),
+            ("protocol_disconnects", &self.protocol_disconnects),
+            ("checksums_throttled", &self.checksums_throttled),
+        ]
+    }
+
+    /// The counter of transmitters refused for `reason`.
+    pub fn rejections(&self, reason: RejectReason) -> &Counter {
+        match reason {
+            RejectReason::AlreadyActive => &self.rejected_already_active,
+            RejectReason::Quarantined => &self.rejected_quarantined,
+            RejectReason::UnknownPeer => &self.rejected_unknown_peer,
+            RejectReason::PoolFull => &self.rejected_pool_full,
+        }
+    }
+
+    /// Writes the lifetime counters to `path`, one `name value` line each.
+    ///
+    /// The file is written alongside and then renamed over `path`, so a crash part way
+    /// through leaves the previous save in place.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents: String = self
+            .lifetime_counters()
+            .iter()
+            .map(|(name, counter)| format!("{} {}\n", name, counter.get()))
+            .collect();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Creates a set of metrics whose lifetime counters start from those saved in
+    /// `path` by [`save`](Self::save). Everything else starts empty.
+    ///
+    /// Names the file has that these metrics do not are ignored, and counters the file
+    /// has no line for start at zero, so files from older and newer builds both load.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Metrics)` - The restored metrics.
+    /// * `Err(io::Error)` - If the file could not be read, or an `InvalidData` error if
+    ///   a line is not a name followed by a count.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use coretech_wirestorm::Metrics;
+    /// let path = std::env::temp_dir().join(format!("wirestorm-metrics-{}", std::process::id()));
+    /// let metrics = Metrics::new();
+    /// metrics.frames_relayed.add(41);
+    /// metrics.pool_exec_time.record(5);
+    /// metrics.save(&path).unwrap();
+    ///
+    /// let restored = Metrics::load(&path).unwrap();
+    /// restored.frames_relayed.increment();
+    /// assert_eq!(restored.frames_relayed.get(), 42);
+    /// assert_eq!(restored.pool_exec_time.snapshot().count, 0);
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn load(path: &Path) -> io::Result<Metrics> {
+        let metrics = Metrics::new();
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(name, value)| Some((name, value.trim().parse::<u64>().ok()?)));
+            let Some((name, value)) = parsed else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad metrics line: {:?}", line)));
+            };
+            if let Some((_, counter)) = metrics.lifetime_counters().iter().find(|(known, _)| *known == name) {
+                counter.add(value);
+            }
+        }
+        Ok(metrics)
+    }
+
+    /// A copy of every measurement.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            frames_relayed: self.frames_relayed.get(),
+            frames_rejected: self.frames_rejected.get(),
+            frames_dropped: self.frames_dropped.get(),
+            frames_expired: self.frames_expired.get(),
+            destinations_evicted: self.destinations_evicted.get(),
+            destinations_rejected: self.destinations_rejected.get(),
+            transmitters_rejected: self.transmitters_rejected.get(),
+            rejected_already_active: self.rejected_already_active.get(),
+            rejected_quarantined: self.rejected_quarantined.get(),
+            rejected_unknown_peer: self.rejected_unknown_peer.get(),
+            rejected_pool_full: self.rejected_pool_full.get(),
+            transmitters_over_quota: self.transmitters_over_quota.get(),
+            transport_disconnects: self.transport_disconnects.get(),
+            protocol_disconnects: self.protocol_disconnects.get(),
+            checksums_throttled: self.checksums_throttled.get(),
+            pool_queue_wait: self.pool_queue_wait.snapshot(),
+            pool_exec_time: self.pool_exec_time.snapshot(),
+            pool_pending_jobs: self.pool_pending_jobs.get(),
+            fanout: self.fanout.snapshot(),
+            queued_bytes: self.queued_bytes.get(),
+            broadcasts_in_flight: self.broadcasts_in_flight.get(),
+            max_payload_seen: self.max_payload_seen.get(),
+            transmitter_session: self.transmitter_session.snapshot(),
+        }
+    }
+}
+
+/// A point-in-time copy of [`Metrics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// See [`Metrics::frames_relayed`].
+    pub frames_relayed: u64,
+    /// See [`Metrics::frames_rejected`].
+    pub frames_rejected: u64,
+    /// See [`Metrics::frames_dropped`].
+    pub frames_dropped: u64,
+    /// See [`Metrics::frames_expired`].
+    pub frames_expired: u64,
+    /// See [`Metrics::destinations_evicted`].
+    pub destinations_evicted: u64,
+    /// See [`Metrics::destinations_rejected`].
+    pub destinations_rejected: u64,
+    /// See [`Metrics::transmitters_rejected`].
+    pub transmitters_rejected: u64,
+    /// See [`Metrics::rejected_already_active`].
+    pub rejected_already_active: u64,
+    /// See [`Metrics::rejected_quarantined`].
+    pub rejected_quarantined: u64,
+    /// See [`Metrics::rejected_unknown_peer`].
+    pub rejected_unknown_peer: u64,
+    /// See [`Metrics::rejected_pool_full`].
+    pub rejected_pool_full: u64,
+    /// See [`Metrics::transmitters_over_quota`].
+    pub transmitters_over_quota: u64,
+    /// See [`Metrics::transport_disconnects`].
+    pub transport_disconnects: u64,
+    /// See [`Metrics::protocol_disconnects`].
+    pub protocol_disconnects: u64,
+    /// See [`Metrics::checksums_throttled`].
+    pub checksums_throttled: u64,
+    /// See [`Metrics::pool_queue_wait`].
+    pub pool_queue_wait: HistogramSnapshot,
+    /// See [`Metrics::pool_exec_time`].
+    pub pool_exec_time: HistogramSnapshot,
+    /// See [`Metrics::pool_pending_jobs`].
+    pub pool_pending_jobs: u64,
+    /// See [`Metrics::fanout`].
+    pub fanout: HistogramSnapshot,
+    /// See [`Metrics::queued_bytes`].
+    pub queued_bytes: u64,
+    /// See [`Metrics::broadcasts_in_flight`].
+    pub broadcasts_in_flight: u64,
+    /// See [`Metrics::max_payload_seen`].
+    pub max_payload_seen: u64,
+    /// See [`Metrics::transmitter_session`]. `None` while no transmitter is connected.
+    pub transmitter_session: Option<SessionSnapshot>,
+}
+
+/// A count that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Adds one.
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Adds `n`.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The current count.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A level that goes up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    /// Replaces the level.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// The current level.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The largest value recorded since it was last reset.
+#[derive(Debug, Default)]
+pub struct HighWater(AtomicU64);
+
+impl HighWater {
+    /// Raises the mark to `value` if it is higher.
+    pub fn record(&self, value: u64) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// The largest value recorded, or zero if there has been none.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears the mark, returning what it was.
+    pub fn reset(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Counts for one transmitter session, reset when the next one starts.
+///
+/// [`handle_transmitter`](crate::handle_transmitter) starts a session when it begins
+/// reading and ends it when the transmitter disconnects. A server only has one
+/// transmitter at a time unless [`Config::allow_multiple_sources`](crate::Config::allow_multiple_sources)
+/// is set; if several sessions do overlap, the counts mix.
+#[derive(Debug)]
+pub struct SessionStats {
+    active: AtomicBool,
+    // when the session started, in nanoseconds since `epoch`
+    epoch: Instant,
+    started: AtomicU64,
+    /// Frames broadcast.
+    pub frames: Counter,
+    /// Payload bytes broadcast.
+    pub bytes: Counter,
+    /// Sensitive frames dropped for a bad checksum.
+    pub checksum_failures: Counter,
+    /// Frames dropped for any other reason.
+    pub rejected: Counter,
+    label: Mutex<Option<String>>,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        SessionStats {
+            active: AtomicBool::new(false),
+            epoch: Instant::now(),
+            started: AtomicU64::new(0),
+            frames: Counter::default(),
+            bytes: Counter::default(),
+            checksum_failures: Counter::default(),
+            rejected: Counter::default(),
+            label: Mutex::new(None),
+        }
+    }
+}
+
+impl SessionStats {
+    /// Zeroes the counts and starts timing a new session.
+    pub fn start(&self) {
+        for counter in [&self.frames, &self.bytes, &self.checksum_failures, &self.rejected] {
+            counter.0.store(0, Ordering::Relaxed);
+        }
+        *self.label.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        let started = u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.started.store(started, Ordering::Relaxed);
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// Whether the session has started and not yet ended.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Marks the session as over. Its counts are kept until the next one starts.
+    pub fn end(&self) {
+        self.active.store(false, Ordering::Release);
+    }
+
+    /// Records the [label](crate::CtmpFrame::label) the transmitter gave itself.
+    pub fn set_label(&self, label: String) {
+        *self.label.lock().unwrap_or_else(|e| e.into_inner()) = Some(label);
+    }
+
+    /// The label the session's transmitter gave itself, if it sent one.
+    pub fn label(&self) -> Option<String> {
+        self.label.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The current session's counts, or `None` if no session is running.
+    pub fn snapshot(&self) -> Option<SessionSnapshot> {
+        if !self.is_active() {
+            return None;
+        }
+        let started = Duration::from_nanos(self.started.load(Ordering::Relaxed));
+        Some(SessionSnapshot {
+            duration: self.epoch.elapsed().saturating_sub(started),
+            frames: self.frames.get(),
+            bytes: self.bytes.get(),
+            checksum_failures: self.checksum_failures.get(),
+            rejected: self.rejected.get(),
+        })
+    }
+}
+
+/// A point-in-time copy of a running [`SessionStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    /// How long the session has been running.
+    pub duration: Duration,
+    /// See [`SessionStats::frames`].
+    pub frames: u64,
+    /// See [`SessionStats::bytes`].
+    pub bytes: u64,
+    /// See [`SessionStats::checksum_failures`].
+    pub checksum_failures: u64,
+    /// See [`SessionStats::rejected`].
+    pub rejected: u64,
+}
+
+/// A distribution of recorded values, counted into fixed buckets.
+///
+/// Each bucket counts values up to and including its bound and above the previous
+/// one; one extra bucket counts everything above the last bound.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram with the given ascending bucket bounds.
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Records one value.
+    pub fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Records a duration in microseconds.
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(u64::try_from(duration.as_micros()).unwrap_or(u64::MAX));
+    }
+
+    /// The values recorded so far.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds,
+            buckets: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of a [`Histogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// The upper bound of every bucket but the last.
+    pub bounds: &'static [u64],
+    /// How many values fell into each bucket; one longer than `bounds`.
+    pub buckets: Vec<u64>,
+    /// How many values were recorded.
+    pub count: u64,
+    /// The sum of every recorded value.
+    pub sum: u64,
+    /// The largest recorded value, or `0` if none were.
+    pub max: u64,
+}
+
+impl MetricsSnapshot {
+    /// How many transmitters had been refused for `reason`. See [`Metrics::rejections`].
+    pub fn rejections(&self, reason: RejectReason) -> u64 {
+        match reason {
+            RejectReason::AlreadyActive => self.rejected_already_active,
+            RejectReason::Quarantined => self.rejected_quarantined,
+            RejectReason::UnknownPeer => self.rejected_unknown_peer,
+            RejectReason::PoolFull => self.rejected_pool_full,
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    /// The mean of the recorded values, or `0` if none were.
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+}
@@ -0,0 +1,77 @@
+//! UDP multicast as an alternate destination transport.
+//!
+//! Instead of writing every frame to each TCP receiver, the server can send it
+//! once to a multicast group that any number of receivers have joined. UDP gives
+//! no delivery or ordering guarantees, and a frame must fit in one datagram, so
+//! frames above the configured MTU are skipped for this transport.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+use crate::config::MulticastConfig;
+
+/// Sends frames to a UDP multicast group.
+pub struct MulticastDestination {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    mtu: usize,
+}
+
+impl MulticastDestination {
+    /// Opens a sending socket for the configured group.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The group, interface, MTU and TTL to use.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(MulticastDestination)` - A sender ready for [`send`](Self::send).
+    /// * `Err(io::Error)` - If the socket could not be created or configured.
+    pub fn new(config: &MulticastConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(config.interface, 0))?;
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        // let receivers on the same host see the frames
+        socket.set_multicast_loop_v4(true)?;
+        Ok(MulticastDestination {
+            socket,
+            group: config.group,
+            mtu: config.mtu,
+        })
+    }
+
+    /// Sends one encoded frame to the group as a single datagram.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The full frame, header and payload.
+    ///
+    /// # Returns
+    ///
+    /// * `Err(io::Error)` of kind `InvalidInput` if the frame is larger than the MTU,
+    ///   or any error from the socket.
+    pub fn send(&self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes exceeds multicast MTU of {}", frame.len(), self.mtu),
+            ));
+        }
+        self.socket.send_to(frame, self.group)?;
+        Ok(())
+    }
+}
+
+/// Opens a socket that receives frames sent to a multicast group.
+///
+/// Each datagram read from the socket holds exactly one frame.
+///
+/// # Arguments
+///
+/// * `group` - The multicast group and port to join.
+/// * `interface` - The local interface to join on. `UNSPECIFIED` lets the OS choose.
+pub fn join(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()))?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    Ok(socket)
+}
@@ -0,0 +1,98 @@
+//! Turning away transmitters that keep sending bad frames.
+//!
+//! Every frame the relay rejects from a transmitter is an offense against the
+//! transmitter's IP address. Once an address has committed
+//! [`max_offenses`](QuarantinePolicy::max_offenses) within the policy's window, new
+//! connections from it are refused until the cooldown has passed.
+//!
+//! Times are passed in rather than read from the clock, so the bookkeeping can be
+//! driven through any sequence of instants.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::config::QuarantinePolicy;
+use crate::server::RejectReason;
+
+/// Offenses and quarantines by IP address.
+#[derive(Debug)]
+pub struct Quarantine {
+    policy: QuarantinePolicy,
+    // when each recent offense happened, oldest first
+    offenses: HashMap<IpAddr, VecDeque<Instant>>,
+    // when each quarantine ends
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl Quarantine {
+    /// Creates an empty quarantine applying `policy`.
+    pub fn new(policy: QuarantinePolicy) -> Self {
+        Quarantine { policy, offenses: HashMap::new(), until: HashMap::new() }
+    }
+
+    /// Records `count` offenses by `ip` at `now`.
+    ///
+    /// # Returns
+    ///
+    /// Whether this put `ip` into quarantine.
+    pub fn record(&mut self, ip: IpAddr, count: u64, now: Instant) -> bool {
+        if count == 0 || self.is_quarantined(ip, now) {
+            return false;
+        }
+        let window = self.policy.window;
+        let offenses = self.offenses.entry(ip).or_default();
+        // only the most recent `max_offenses` can matter
+        let keep = self.policy.max_offenses as usize;
+        for _ in 0..count.min(keep as u64) {
+            offenses.push_back(now);
+        }
+        while offenses.len() > keep {
+            offenses.pop_front();
+        }
+        offenses.retain(|&at| now.saturating_duration_since(at) < window);
+
+        if offenses.len() < keep {
+            return false;
+        }
+        self.offenses.remove(&ip);
+        self.until.insert(ip, now + self.policy.cooldown);
+        true
+    }
+
+    /// Whether connections from `ip` are refused at `now`.
+    pub fn is_quarantined(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.until.get(&ip) {
+            Some(&until) if now < until => true,
+            Some(_) => {
+                self.until.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Decides whether a new connection from `ip` is accepted at `now`, forgetting
+    /// anything that has expired first.
+    ///
+    /// A connection whose address could not be read is refused, since there is no way
+    /// to tell whether it is quarantined.
+    pub fn admit(&mut self, ip: Option<IpAddr>, now: Instant) -> Result<(), RejectReason> {
+        self.prune(now);
+        match ip {
+            None => Err(RejectReason::UnknownPeer),
+            Some(ip) if self.is_quarantined(ip, now) => Err(RejectReason::Quarantined),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Forgets offenses that have left the window and quarantines that have ended.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.policy.window;
+        self.until.retain(|_, until| now < *until);
+        self.offenses.retain(|_, offenses| {
+            offenses.retain(|&at| now.saturating_duration_since(at) < window);
+            !offenses.is_empty()
+        });
+    }
+}
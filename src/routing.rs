@@ -0,0 +1,101 @@
+//! Breaking broadcast loops between chained relays.
+//!
+//! A relay can be fed by a receiver of another relay, and if that chain ever leads
+//! back to itself every frame would circulate forever. With loop detection enabled,
+//! the relay tags each frame it accepts with a 16-bit routing id, carried in the
+//! header's padding bytes with the routed flag (`0x10`) set in the options byte. A
+//! frame that arrives already tagged keeps its id, so every relay in a chain knows
+//! it by the same one, and a frame whose id a relay has seen recently is one it has
+//! already broadcast.
+//!
+//! Ids are assigned from a counter that starts at a random value, so relays in the
+//! same chain are unlikely to hand out overlapping ids. The window of remembered ids
+//! should stay well below the 65536 ids available, or frames that merely share an id
+//! with an older one are dropped too.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+
+use crate::{CTMP_HEADER_LEN, CTMP_ROUTED_FLAG};
+
+/// The routing id carried in `header`, if it has one.
+pub fn routing_id(header: &[u8]) -> Option<u16> {
+    (header[1] & CTMP_ROUTED_FLAG != 0).then(|| u16::from_be_bytes([header[6], header[7]]))
+}
+
+/// What [`LoopGuard::admit`] decided about a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The frame had no routing id and was given this one.
+    Tagged(u16),
+    /// The frame already had this routing id and has not been seen before.
+    Forwarded(u16),
+    /// The frame has this routing id, which was seen recently; it has looped.
+    Duplicate(u16),
+}
+
+/// Remembers the routing ids of recent frames.
+#[derive(Debug)]
+pub struct LoopGuard {
+    window: usize,
+    next_id: u16,
+    seen: HashSet<u16>,
+    // the ids in `seen`, oldest first
+    order: VecDeque<u16>,
+}
+
+impl LoopGuard {
+    /// Creates a guard that remembers the last `window` routing ids.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is zero or larger than the number of possible ids.
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0 && window <= u16::MAX as usize, "loop detection window must be 1..=65535");
+        LoopGuard {
+            window,
+            next_id: RandomState::new().build_hasher().finish() as u16,
+            seen: HashSet::with_capacity(window),
+            order: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Checks a frame's header, tagging it with a routing id if it has none.
+    ///
+    /// A tagged header's padding bytes change, so a sensitive frame's checksum must be
+    /// recomputed afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The frame header, updated in place when tagged.
+    pub fn admit(&mut self, header: &mut [u8; CTMP_HEADER_LEN]) -> Admission {
+        match routing_id(header) {
+            Some(id) if self.seen.contains(&id) => Admission::Duplicate(id),
+            Some(id) => {
+                self.remember(id);
+                Admission::Forwarded(id)
+            }
+            None => {
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                header[1] |= CTMP_ROUTED_FLAG;
+                header[6..8].copy_from_slice(&id.to_be_bytes());
+                self.remember(id);
+                Admission::Tagged(id)
+            }
+        }
+    }
+
+    fn remember(&mut self, id: u16) {
+        if self.order.len() == self.window
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        // a tag we hand out may equal a forwarded id already in the window
+        if self.seen.insert(id) {
+            self.order.push_back(id);
+        }
+    }
+}
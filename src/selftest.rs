@@ -0,0 +1,95 @@
+//! A startup check of the relay's framing and checksum logic.
+//!
+//! Before accepting connections, the server round-trips a set of frames it builds
+//! itself through [`build_frame`] and [`read_frame`], and checks that the configured
+//! checksum algorithm both accepts an intact frame and catches a corrupted one. A
+//! miscompiled checksum or a broken validator then stops the server at startup
+//! instead of silently relaying, or dropping, every sensitive frame in production.
+//! It is on by default; see [`Config::self_test`](crate::Config::self_test).
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::{build_frame, read_frame, CTMP_HEADER_LEN};
+
+// Payload sizes covering odd and even lengths and the largest a header can declare.
+const PAYLOAD_SIZES: &[usize] = &[1, 2, 3, 255, 4096, u16::MAX as usize];
+
+/// Runs the self-test with the checksum algorithm transmitters are verified with.
+///
+/// Besides the round trips, the algorithm must give its published check value for a
+/// reference input.
+///
+/// # Returns
+///
+/// A description of the first check that failed, if any did.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::checksum::ChecksumAlgorithm;
+/// # use coretech_wirestorm::selftest;
+/// assert_eq!(selftest::run(ChecksumAlgorithm::OnesComplement), Ok(()));
+/// assert_eq!(selftest::run(ChecksumAlgorithm::Crc16), Ok(()));
+/// ```
+pub fn run(checksum: ChecksumAlgorithm) -> Result<(), String> {
+    let (input, expected): (&[u8], u16) = match checksum {
+        // RFC 1071, section 3
+        ChecksumAlgorithm::OnesComplement => (&[0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7], 0x220D),
+        ChecksumAlgorithm::Crc16 => (b"123456789", 0x29B1),
+    };
+    let computed = checksum.checksum(input);
+    if computed != expected {
+        return Err(format!("{} gives {:#06x} for its check input, not {:#06x}", checksum, computed, expected));
+    }
+    run_with(|header, payload| checksum.frame_checksum(header, payload))
+}
+
+/// Runs the framing round trips, checking sensitive frames with `checksum`.
+///
+/// `checksum` computes a frame's checksum from its header and payload, ignoring the
+/// header's checksum bytes, as [`ChecksumAlgorithm::frame_checksum`] does. Each
+/// sensitive frame is stamped with it and must then verify, and must stop verifying
+/// once a bit of its payload is flipped.
+///
+/// # Returns
+///
+/// A description of the first check that failed, if any did.
+///
+/// # Examples
+///
+/// ```rust
+/// # use coretech_wirestorm::selftest;
+/// // a validator that waves every frame through
+/// let error = selftest::run_with(|_, _| 0).unwrap_err();
+/// assert!(error.contains("does not catch a corrupted payload"), "{}", error);
+/// ```
+pub fn run_with(checksum: impl Fn(&[u8], &[u8]) -> u16) -> Result<(), String> {
+    for &size in PAYLOAD_SIZES {
+        for sensitive in [false, true] {
+            let case = format!("{} byte {} frame", size, if sensitive { "sensitive" } else { "plain" });
+            let payload: Vec<u8> = (0..size).map(|i| (i * 31 + size) as u8).collect();
+
+            let bytes = build_frame(&payload, sensitive).map_err(|e| format!("{}: building failed: {}", case, e))?;
+            let frame = read_frame(&mut &bytes[..]).map_err(|e| format!("{}: reading back failed: {}", case, e))?;
+            if frame.payload() != payload.as_slice() || frame.is_sensitive() != sensitive || frame.to_bytes() != bytes {
+                return Err(format!("{}: read back differently from how it was built", case));
+            }
+            if !sensitive {
+                continue;
+            }
+
+            let mut header = [0u8; CTMP_HEADER_LEN];
+            header.copy_from_slice(&bytes[..CTMP_HEADER_LEN]);
+            let stamped = checksum(&header, &payload);
+            header[4..6].copy_from_slice(&stamped.to_be_bytes());
+            if checksum(&header, &payload) != stamped {
+                return Err(format!("{}: the checksum does not verify an intact frame", case));
+            }
+            let mut corrupted = payload;
+            corrupted[size / 2] ^= 0x01;
+            if checksum(&header, &corrupted) == stamped {
+                return Err(format!("{}: the checksum does not catch a corrupted payload", case));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1497 @@
+//! The relay server: listeners, accept loops and shutdown.
+//!
+//! [`Server::bind`] opens the listeners described by a [`Config`]. The server can then
+//! either [`run`](Server::run) on the current thread, which is what the binary does,
+//! or be [`spawn`](Server::spawn)ed onto background threads, returning a
+//! [`ServerHandle`] that can stop it again. Embedders and tests use the latter.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Seek};
+use std::{fmt, io};
+use std::path::Path;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::activation;
+use crate::bridge::LengthPrefixedSource;
+use crate::config::PreloadConfig;
+use crate::metrics::{MetricsSnapshot, SessionSnapshot, SessionStats};
+use crate::quarantine::Quarantine;
+use crate::selftest;
+use crate::{broadcast_message, display_peer, handle_transmitter_session, peer_of, read_frame, AutoscalePolicy, BridgeConfig, Config,
+    CtmpError, Destinations, DrainReport, FrameSample, Metrics, MulticastDestination, ThreadPool, TransmitterPolicy,
+    Transport, CTMP_MAX_PAYLOAD_SIZE};
+
+// How often lifetime counters are saved to the metrics file, if there is one.
+const METRICS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often an idle transmitter's read wakes up to check its session limit.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How often a sleeping background thread checks whether the server is shutting down.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How often an idle accept loop checks for a new connection, or for shutdown.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a transmitter connection was refused.
+///
+/// Refused connections are closed straight away and counted in
+/// [`Metrics::transmitters_rejected`], and by reason in [`Metrics::rejections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// Another transmitter is connected, and only one may be at a time. Never given
+    /// with [`Config::allow_multiple_sources`].
+    AlreadyActive,
+    /// The transmitter's address is in [quarantine](crate::quarantine).
+    Quarantined,
+    /// Quarantine is enabled but the transmitter's address could not be read, so it
+    /// cannot be checked.
+    UnknownPeer,
+    /// Every pool thread is handling a transmitter. Only given with
+    /// [`Config::reject_when_pool_full`].
+    PoolFull,
+}
+
+impl RejectReason {
+    /// Every reason, in the order the stats line and
+    /// [`rejections_json`](ServerStatus::rejections_json) list them.
+    pub const ALL: [RejectReason; 4] =
+        [RejectReason::AlreadyActive, RejectReason::Quarantined, RejectReason::UnknownPeer, RejectReason::PoolFull];
+
+    /// Returns a short, stable name for the reason.
+    pub fn name(self) -> &'static str {
+        match self {
+            RejectReason::AlreadyActive => "already_active",
+            RejectReason::Quarantined => "quarantined",
+            RejectReason::UnknownPeer => "unknown_peer",
+            RejectReason::PoolFull => "pool_full",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::AlreadyActive => write!(f, "another transmitter is already connected"),
+            RejectReason::Quarantined => write!(f, "its address is quarantined"),
+            RejectReason::UnknownPeer => write!(f, "its address could not be read"),
+            RejectReason::PoolFull => write!(f, "no pool thread is free to handle it"),
+        }
+    }
+}
+
+/// Which side of the relay a connection was accepted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// A client sending frames to be broadcast.
+    Transmitter,
+    /// A client receiving the broadcast frames.
+    Destination,
+}
+
+impl Role {
+    /// Returns a short, stable name for the role.
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Transmitter => "transmitter",
+            Role::Destination => "destination",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Applies the setup every accepted connection gets, whatever its role, and logs it.
+///
+/// Frames are written whole, so Nagle's algorithm is turned off on both sides: it
+/// would only delay broadcasts to receivers and rejections sent to transmitters.
+/// Each accept loop then goes on to admit the connection in its own way.
+///
+/// # Returns
+///
+/// The connection's peer address, if it could be read.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::net::{TcpListener, TcpStream};
+/// # use coretech_wirestorm::server::{prepare_connection, Role};
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// for role in [Role::Transmitter, Role::Destination] {
+///     let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+///     let (stream, _) = listener.accept().unwrap();
+///     assert!(!stream.nodelay().unwrap());
+///
+///     let peer = prepare_connection(&stream, role);
+///     assert_eq!(peer, Some(client.local_addr().unwrap()));
+///     assert!(stream.nodelay().unwrap());
+/// }
+/// ```
+pub fn prepare_connection(stream: &TcpStream, role: Role) -> Option<SocketAddr> {
+    let peer = peer_of(stream);
+    eprintln!("New {} client connected from {}", role, display_peer(peer));
+    configure_connection(stream, role);
+    peer
+}
+
+/// Checks a newly accepted transmitter connection against `quarantine`, as the accept
+/// loop does before handling it.
+///
+/// A connection whose address cannot be read is refused with
+/// [`RejectReason::UnknownPeer`], since it cannot be checked. A refused connection is
+/// logged and counted in `metrics`, under its reason, and should be dropped.
+pub fn admit_transmitter<T: Transport + ?Sized>(stream: &T, quarantine: &Mutex<Quarantine>, metrics: &Metrics) -> bool {
+    let peer = peer_of(stream);
+    let mut quarantine = quarantine.lock().unwrap_or_else(|e| e.into_inner());
+    match quarantine.admit(peer.map(|addr| addr.ip()), Instant::now()) {
+        Ok(()) => true,
+        Err(reason) => {
+            reject(reason, peer, metrics);
+            false
+        }
+    }
+}
+
+// Applies the socket options shared by both roles, without logging the connection.
+pub(crate) fn configure_connection(stream: &TcpStream, role: Role) {
+    if let Err(e) = stream.set_nodelay(true) {
+        eprintln!("Failed to set TCP_NODELAY on {}: {}", role, e);
+    }
+}
+
+/// Decides when an idle server sends its receivers a
+/// [keepalive](crate::CtmpFrame::keepalive).
+///
+/// A keepalive is due once a whole interval has passed without a frame being broadcast,
+/// and again after every further interval that stays quiet. Activity is noticed by the
+/// destinations' [`generation`](Destinations::generation) moving on. Times are passed in
+/// rather than read from the clock, so any sequence of instants can be checked.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::time::{Duration, Instant};
+/// # use coretech_wirestorm::server::KeepaliveTimer;
+/// let start = Instant::now();
+/// let second = Duration::from_secs(1);
+/// let mut timer = KeepaliveTimer::new(second, 0, start);
+///
+/// assert!(!timer.due(0, start + second / 2));
+/// assert!(timer.due(0, start + second));
+/// // a frame was broadcast, so the wait starts again
+/// assert!(!timer.due(1, start + second * 3 / 2));
+/// assert!(!timer.due(1, start + second * 2));
+/// assert!(timer.due(1, start + second * 5 / 2));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveTimer {
+    interval: Duration,
+    generation: u64,
+    // when a frame was last seen to be broadcast, or the last keepalive was due
+    quiet_since: Instant,
+}
+
+impl KeepaliveTimer {
+    /// Starts timing at `now`, with `generation` the destinations' current generation.
+    pub fn new(interval: Duration, generation: u64, now: Instant) -> Self {
+        KeepaliveTimer { interval, generation, quiet_since: now }
+    }
+
+    /// Whether a keepalive should be sent at `now`, given the destinations' current
+    /// generation. Once it says so, the next one is a full interval away.
+    pub fn due(&mut self, generation: u64, now: Instant) -> bool {
+        if generation != self.generation {
+            self.generation = generation;
+            self.quiet_since = now;
+            return false;
+        }
+        if now.saturating_duration_since(self.quiet_since) < self.interval {
+            return false;
+        }
+        self.quiet_since = now;
+        true
+    }
+}
+
+/// Decides when a server with a [`max_uptime`](Config::max_uptime) is due to shut
+/// itself down. Like [`KeepaliveTimer`], it is given the time rather than reading the
+/// clock.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::time::{Duration, Instant};
+/// # use coretech_wirestorm::server::UptimeLimit;
+/// let started = Instant::now();
+/// let hour = Duration::from_secs(3600);
+/// let limit = UptimeLimit::new(hour * 24, started);
+///
+/// assert!(!limit.expired(started + hour * 23));
+/// assert_eq!(limit.remaining(started + hour * 23), hour);
+/// assert!(limit.expired(started + hour * 24));
+/// assert_eq!(limit.remaining(started + hour * 25), Duration::ZERO);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct UptimeLimit {
+    max: Duration,
+    started: Instant,
+}
+
+impl UptimeLimit {
+    /// A limit of `max` on a server started at `started`.
+    pub fn new(max: Duration, started: Instant) -> Self {
+        UptimeLimit { max, started }
+    }
+
+    /// Whether the server has been up for its whole allowance at `now`.
+    pub fn expired(&self, now: Instant) -> bool {
+        self.remaining(now).is_zero()
+    }
+
+    /// How much longer the server may stay up, as of `now`.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.max.saturating_sub(now.saturating_duration_since(self.started))
+    }
+}
+
+/// A relay server whose listeners are bound but not yet accepting.
+pub struct Server {
+    config: Config,
+    source_listener: TcpListener,
+    destination_listener: Option<TcpListener>,
+    bridge_listener: Option<TcpListener>,
+    // one per `config.extra_sources`, in the same order
+    extra_source_listeners: Vec<TcpListener>,
+    preload_file: Option<File>,
+    destinations: Destinations,
+}
+
+impl Server {
+    /// Binds the listeners and opens the multicast socket described by `config`.
+    ///
+    /// If socket activation is enabled and the supervisor passed listeners in, those
+    /// are adopted instead of binding the configured addresses.
+    ///
+    /// Binding to port `0` picks a free port; the chosen addresses are available from
+    /// [`source_addr`](Self::source_addr) and [`destination_addr`](Self::destination_addr).
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The server configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Server)` - A server ready to run.
+    /// * `Err(io::Error)` - If a listener could not be bound, the multicast socket opened
+    ///   or the preload file opened, or if the metrics file exists but cannot be loaded.
+    ///   With [`Config::self_test`], an `InvalidData` error if the self-test fails.
+    ///   An `InvalidInput` error if a listener's
+    ///   [`max_payload`](crate::TransmitterPolicy::max_payload) is over
+    ///   [`CTMP_MAX_PAYLOAD_SIZE`].
+    pub fn bind(config: Config) -> io::Result<Server> {
+        // A limit above the ceiling would be clamped without anyone being told.
+        let policies = [&config.transmitter].into_iter();
+        for policy in policies.chain(config.extra_sources.iter().map(|source| &source.policy)) {
+            if let Some(max) = policy.max_payload.filter(|&max| max > CTMP_MAX_PAYLOAD_SIZE) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("max_payload of {} exceeds the ceiling of {}", max, CTMP_MAX_PAYLOAD_SIZE),
+                ));
+            }
+        }
+        // Every algorithm a listener verifies with must work before anything is accepted.
+        if config.self_test {
+            let algorithms = [config.transmitter.checksum].into_iter();
+            for checksum in algorithms.chain(config.extra_sources.iter().map(|source| source.policy.checksum)) {
+                selftest::run(checksum).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Startup self-test failed: {}", e))
+                })?;
+            }
+        }
+        // Prefer listeners handed over by a supervisor, so restarts never drop connections.
+        let inherited = match config.socket_activation {
+            true => activation::inherited_listeners()?,
+            false => None,
+        };
+        let (source_listener, inherited_destination) = match inherited {
+            Some(inherited) => {
+                eprintln!("Adopted inherited listener on {}", inherited.source.local_addr()?);
+                (inherited.source, inherited.destination)
+            }
+            // Bind the main TCP listener for source (transmitter) clients.
+            None => (TcpListener::bind(&config.source_addr)?, None),
+        };
+        // Destination (receiver) clients are optional when broadcasting over multicast only.
+        let destination_listener = match (config.tcp_destinations, inherited_destination) {
+            (false, _) => None,
+            (true, Some(listener)) => {
+                eprintln!("Adopted inherited listener on {}", listener.local_addr()?);
+                Some(listener)
+            }
+            (true, None) => Some(TcpListener::bind(&config.destination_addr)?),
+        };
+
+        // Carry the lifetime counters on from the last run; a missing file is a first run.
+        let metrics = match &config.metrics_file {
+            Some(path) => match Metrics::load(path) {
+                Ok(metrics) => metrics,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Metrics::new(),
+                Err(e) => {
+                    return Err(io::Error::new(e.kind(), format!("Failed to load {}: {}", path.display(), e)));
+                }
+            },
+            None => Metrics::new(),
+        };
+
+        // Manages all receiver clients, plus the multicast group if one is configured.
+        let destinations = match &config.multicast {
+            Some(multicast) => {
+                eprintln!("Broadcasting to multicast group {}", multicast.group);
+                Destinations::new().with_multicast(MulticastDestination::new(multicast)?)
+            }
+            None => Destinations::new(),
+        }
+        .with_policy(config.delivery)
+        .with_metrics(Arc::new(metrics));
+        let destinations = match config.loop_detection {
+            Some(window) => destinations.with_loop_detection(window),
+            None => destinations,
+        };
+        let destinations = match config.max_concurrent_broadcasts {
+            Some(max) => destinations.with_broadcast_limit(max),
+            None => destinations,
+        };
+        let destinations = match config.max_checksums_per_second {
+            Some(max) => destinations.with_checksum_limit(max, config.checksum_overload),
+            None => destinations,
+        };
+
+        // Length-prefixed producers get their own listener, if configured.
+        let bridge_listener = match &config.bridge {
+            Some(bridge) => Some(TcpListener::bind(&bridge.addr)?),
+            None => None,
+        };
+
+        // And so do transmitters that get a policy of their own.
+        let extra_source_listeners = config
+            .extra_sources
+            .iter()
+            .map(|source| TcpListener::bind(&source.addr))
+            .collect::<io::Result<_>>()?;
+
+        // Open the capture now, so a missing file stops the server starting.
+        let preload_file = match &config.preload {
+            Some(preload) => Some(File::open(&preload.path)?),
+            None => None,
+        };
+
+        Ok(Server {
+            config,
+            source_listener,
+            destination_listener,
+            bridge_listener,
+            extra_source_listeners,
+            preload_file,
+            destinations,
+        })
+    }
+
+    /// The address transmitters connect to.
+    pub fn source_addr(&self) -> io::Result<SocketAddr> {
+        self.source_listener.local_addr()
+    }
+
+    /// The address receivers connect to, if TCP destinations are enabled.
+    pub fn destination_addr(&self) -> Option<SocketAddr> {
+        self.destination_listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// The address length-prefixed producers connect to, if the bridge is enabled.
+    pub fn bridge_addr(&self) -> Option<SocketAddr> {
+        self.bridge_listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// The addresses of the extra transmitter listeners, in the order they were configured.
+    pub fn extra_source_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.extra_source_listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// The set of connected receivers.
+    pub fn destinations(&self) -> &Destinations {
+        &self.destinations
+    }
+
+    /// What the server has measured.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        self.destinations.metrics()
+    }
+
+    /// Hands sampled frames to `callback` instead of logging them. See
+    /// [`TransmitterPolicy::log_every_nth_frame`].
+    pub fn with_frame_log(mut self, callback: impl Fn(&FrameSample) + Send + Sync + 'static) -> Self {
+        self.destinations = self.destinations.with_frame_log(callback);
+        self
+    }
+
+    /// Runs the server on background threads.
+    ///
+    /// # Returns
+    ///
+    /// A handle for inspecting and stopping the server.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let started = Instant::now();
+        let config_hash = {
+            let mut hasher = DefaultHasher::new();
+            self.config.hash(&mut hasher);
+            hasher.finish()
+        };
+        let source_addr = self.source_addr()?;
+        let destination_addr = self.destination_addr();
+        let bridge_addr = self.bridge_addr();
+        let extra_source_addrs = self.extra_source_addrs()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicUsize::new(0));
+        // Shared state for the currently active transmitter connection.
+        let active_source = Arc::new(Mutex::new(None::<TcpStream>));
+        // Every transmitter's session, with its slot: the active source's, or when several
+        // may be connected at once, one of their own.
+        let sessions = Arc::new(Mutex::new(Vec::new()));
+        let mut threads = Vec::new();
+
+        // Spawn a thread to handle incoming destination (receiver) client connections.
+        if let Some(listener) = self.destination_listener {
+            let destinations = self.destinations.clone();
+            let shutdown = Arc::clone(&shutdown);
+            let max_handshakes = self.config.max_handshakes;
+            let label_wait = self.config.destination_label_wait;
+            threads.push(spawn_tracked("wirestorm-destinations", &running, move || {
+                accept_destinations(listener, destinations, max_handshakes, label_wait, shutdown)
+            })?);
+        }
+
+        // And, if asked for, one that logs a summary of the metrics at a fixed interval.
+        if let Some(interval) = self.config.stats_log_interval {
+            let destinations = self.destinations.clone();
+            let shutdown = Arc::clone(&shutdown);
+            threads.push(spawn_tracked("wirestorm-stats", &running, move || {
+                log_stats(destinations, interval, shutdown)
+            })?);
+        }
+
+        // And one that keeps the metrics file up to date.
+        if let Some(path) = self.config.metrics_file.clone() {
+            let metrics = Arc::clone(self.destinations.metrics());
+            let shutdown = Arc::clone(&shutdown);
+            threads.push(spawn_tracked("wirestorm-metrics", &running, move || {
+                save_metrics(&metrics, &path, &shutdown)
+            })?);
+        }
+
+        // And one that keeps idle receiver connections alive.
+        if let Some(interval) = self.config.keepalive_interval {
+            let destinations = self.destinations.clone();
+            let shutdown = Arc::clone(&shutdown);
+            threads.push(spawn_tracked("wirestorm-keepalive", &running, move || {
+                send_keepalives(destinations, interval, shutdown)
+            })?);
+        }
+
+        // And one that flushes receivers, if they are not flushed after every frame.
+        if let Some(interval) = self.config.delivery.flush_interval {
+            let destinations = self.destinations.clone();
+            let shutdown = Arc::clone(&shutdown);
+            threads.push(spawn_tracked("wirestorm-flush", &running, move || {
+                while sleep_unless_shutdown(interval, &shutdown) {
+                    destinations.flush();
+                }
+            })?);
+        }
+
+        // And one that broadcasts a capture file, if there is one.
+        if let (Some(file), Some(config)) = (self.preload_file, self.config.preload.clone()) {
+            let destinations = self.destinations.clone();
+            let shutdown = Arc::clone(&shutdown);
+            threads.push(spawn_tracked("wirestorm-preload", &running, move || {
+                preload(file, config, destinations, shutdown)
+            })?);
+        }
+
+        // And one for transmitter (source) connections, which owns the thread pool.
+        let sources = Sources {
+            thread_count: self.config.thread_count,
+            autoscale: self.config.autoscale,
+            policy: self.config.transmitter,
+            destinations: self.destinations.clone(),
+            active_source: Arc::clone(&active_source),
+            allow_multiple: self.config.allow_multiple_sources,
+            reject_when_full: self.config.reject_when_pool_full,
+            sessions: Arc::clone(&sessions),
+            metrics: Arc::clone(self.destinations.metrics()),
+            quarantine: self.config.quarantine.map(|policy| Arc::new(Mutex::new(Quarantine::new(policy)))),
+            shutdown: Arc::clone(&shutdown),
+        };
+        {
+            let sources = sources.clone();
+            threads.push(spawn_tracked("wirestorm-sources", &running, move || {
+                accept_transmitters(self.source_listener, None, sources)
+            })?);
+        }
+
+        // Extra transmitter listeners do too, each with its own policy.
+        for (listener, source) in self.extra_source_listeners.into_iter().zip(&self.config.extra_sources) {
+            let sources = Sources { policy: source.policy, ..sources.clone() };
+            threads.push(spawn_tracked("wirestorm-extra-sources", &running, move || {
+                accept_transmitters(listener, None, sources)
+            })?);
+        }
+
+        // Bridged producers share the active source slot with ordinary transmitters.
+        if let (Some(listener), Some(bridge)) = (self.bridge_listener, self.config.bridge) {
+            // frames wrapped by the bridge never carry a trailing CRC
+            let policy = TransmitterPolicy { trailing_crc: None, ..sources.policy };
+            let sources = Sources { policy, ..sources };
+            threads.push(spawn_tracked("wirestorm-bridge", &running, move || {
+                accept_transmitters(listener, Some(bridge), sources)
+            })?);
+        }
+
+        Ok(ServerHandle {
+            source_addr,
+            destination_addr,
+            bridge_addr,
+            extra_source_addrs,
+            destinations: self.destinations,
+            active_source,
+            sessions,
+            started,
+            config_hash,
+            shutdown,
+            running,
+            threads,
+        })
+    }
+
+    /// Runs the server on the current thread until it fails to start.
+    ///
+    /// With a [`max_uptime`](Config::max_uptime), the server is shut down once it has
+    /// been up that long, exactly as by [`ServerHandle::shutdown`], and this returns.
+    /// Otherwise it never returns once the listeners are accepting.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::time::{Duration, Instant};
+    /// # use coretech_wirestorm::{Config, Server};
+    /// let config = Config {
+    ///     source_addr: "127.0.0.1:0".into(),
+    ///     destination_addr: "127.0.0.1:0".into(),
+    ///     max_uptime: Some(Duration::from_millis(200)),
+    ///     ..Config::default()
+    /// };
+    /// let started = Instant::now();
+    /// Server::bind(config).unwrap().run().unwrap();
+    /// assert!(started.elapsed() >= Duration::from_millis(200));
+    /// ```
+    pub fn run(self) -> io::Result<()> {
+        let max_uptime = self.config.max_uptime;
+        let mut handle = self.spawn()?;
+        let Some(max_uptime) = max_uptime else {
+            handle.join();
+            return Ok(());
+        };
+        let limit = UptimeLimit::new(max_uptime, handle.started);
+        while !limit.expired(Instant::now()) {
+            thread::sleep(limit.remaining(Instant::now()));
+        }
+        eprintln!("Up for the maximum of {:?}, shutting down", max_uptime);
+        handle.shutdown();
+        Ok(())
+    }
+}
+
+/// A running server, returned by [`Server::spawn`].
+pub struct ServerHandle {
+    source_addr: SocketAddr,
+    destination_addr: Option<SocketAddr>,
+    bridge_addr: Option<SocketAddr>,
+    extra_source_addrs: Vec<SocketAddr>,
+    destinations: Destinations,
+    active_source: SourceSlot,
+    sessions: Arc<Mutex<Vec<Session>>>,
+    started: Instant,
+    config_hash: u64,
+    shutdown: Arc<AtomicBool>,
+    running: Arc<AtomicUsize>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address transmitters connect to.
+    pub fn source_addr(&self) -> SocketAddr {
+        self.source_addr
+    }
+
+    /// The address receivers connect to, if TCP destinations are enabled.
+    pub fn destination_addr(&self) -> Option<SocketAddr> {
+        self.destination_addr
+    }
+
+    /// The address length-prefixed producers connect to, if the bridge is enabled.
+    pub fn bridge_addr(&self) -> Option<SocketAddr> {
+        self.bridge_addr
+    }
+
+    /// The addresses of the extra transmitter listeners, in the order they were configured.
+    pub fn extra_source_addrs(&self) -> &[SocketAddr] {
+        &self.extra_source_addrs
+    }
+
+    /// The set of connected receivers.
+    pub fn destinations(&self) -> &Destinations {
+        &self.destinations
+    }
+
+    /// What the server has measured.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        self.destinations.metrics()
+    }
+
+    /// Whether a transmitter is currently connected.
+    pub fn has_transmitter(&self) -> bool {
+        self.transmitter_count() > 0
+    }
+
+    /// Number of transmitters currently connected. Only ever more than one with
+    /// [`Config::allow_multiple_sources`].
+    pub fn transmitter_count(&self) -> usize {
+        let single = usize::from(self.active_source.lock().is_ok_and(|active| active.is_some()));
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let others = sessions.iter().filter(|session| !Arc::ptr_eq(&session.slot, &self.active_source));
+        single + others.filter(|session| slot_occupied(&session.slot)).count()
+    }
+
+    // The counts of the most recently connected transmitter still connected.
+    fn latest_session(&self) -> Option<Arc<SessionStats>> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.iter().rev().find(|session| session.stats.is_active()).map(|session| Arc::clone(&session.stats))
+    }
+
+    /// A snapshot of the receivers and metrics taken together.
+    ///
+    /// The receivers are locked while the metrics are copied, and every broadcast and
+    /// eviction updates the metrics under the same lock, so the two always agree: no
+    /// frame is counted as relayed without the receivers it reached being listed.
+    pub fn status(&self) -> ServerStatus {
+        let transmitter_connected = self.has_transmitter();
+        let receivers = self.destinations.clone_inner();
+        let receivers = receivers.lock().unwrap_or_else(|e| e.into_inner());
+        ServerStatus {
+            uptime: self.started.elapsed(),
+            version: crate::version(),
+            config_hash: self.config_hash,
+            transmitter_connected,
+            transmitter_label: self.latest_session().and_then(|stats| stats.label()),
+            generation: self.destinations.generation(),
+            destinations: receivers
+                .iter()
+                .map(|dest| DestinationStatus {
+                    id: dest.id(),
+                    peer_addr: dest.peer_addr(),
+                    label: dest.label().map(str::to_string),
+                    compressed: dest.compressed(),
+                    joined_at: dest.joined_at(),
+                    strikes: dest.strikes(),
+                    frames_dropped: dest.frames_dropped(),
+                    frames_expired: dest.frames_expired(),
+                    queued_frames: dest.queued_frames(),
+                    queued_bytes: dest.queued_bytes(),
+                })
+                .collect(),
+            metrics: MetricsSnapshot { transmitter_session: self.transmitter_session(), ..self.metrics().snapshot() },
+        }
+    }
+
+    /// Counts for the connected transmitter's session, or `None` if none is connected.
+    /// With [`Config::allow_multiple_sources`], for whichever connected last.
+    ///
+    /// Each session is counted on its own, rather than in
+    /// [`Metrics::transmitter_session`], which the server leaves empty.
+    pub fn transmitter_session(&self) -> Option<SessionSnapshot> {
+        self.latest_session().and_then(|stats| stats.snapshot())
+    }
+
+    /// Counts for every connected transmitter's session, oldest first. At most one
+    /// unless [`Config::allow_multiple_sources`] is set.
+    pub fn transmitter_sessions(&self) -> Vec<SessionSnapshot> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.iter().filter_map(|session| session.stats.snapshot()).collect()
+    }
+
+    /// Number of accept threads still running.
+    ///
+    /// The transmitter accept thread joins the thread pool's workers before it exits,
+    /// so zero means every server thread has stopped. Always zero once
+    /// [`shutdown`](Self::shutdown) has returned.
+    pub fn running_threads(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting, disconnects every client and waits for all server threads to exit.
+    ///
+    /// Receivers are closed with [`Destinations::clear`], so a
+    /// [`drain_timeout`](crate::DeliveryPolicy::drain_timeout) bounds how long each one
+    /// can hold up shutdown.
+    ///
+    /// # Returns
+    ///
+    /// How many frames each receiver was left without. Empty if the server had
+    /// already been shut down.
+    pub fn shutdown(&mut self) -> Vec<DrainReport> {
+        // the accept loops poll, so they see the flag without being woken
+        self.shutdown.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            if let Err(e) = thread.join() {
+                eprintln!("Server thread failed to join: {:?}", e);
+            }
+        }
+        let reports = self.destinations.clear();
+        for report in reports.iter().filter(|report| report.undrained > 0) {
+            eprintln!("Closed receiver {} with {} frames undrained", display_peer(report.peer), report.undrained);
+        }
+        reports
+    }
+
+    /// Blocks until the server stops.
+    pub fn join(mut self) {
+        for thread in self.threads.drain(..) {
+            if let Err(e) = thread.join() {
+                eprintln!("Server thread failed to join: {:?}", e);
+            }
+        }
+    }
+}
+
+/// A consistent view of a running server, returned by [`ServerHandle::status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    /// How long ago the server was spawned.
+    pub uptime: Duration,
+    /// The [`version`](crate::version) of the running build.
+    pub version: &'static str,
+    /// A hash of the [`Config`] the server was started with, for telling whether two
+    /// servers, or one server before and after a restart, run the same configuration.
+    /// Only comparable between builds of the same binary.
+    pub config_hash: u64,
+    /// Whether a transmitter was connected just before the snapshot was taken.
+    pub transmitter_connected: bool,
+    /// The [label](crate::CtmpFrame::label) the connected transmitter gave itself. With
+    /// [`Config::allow_multiple_sources`], the label of whichever connected last.
+    pub transmitter_label: Option<String>,
+    /// The [`generation`](Destinations::generation) at the time of the snapshot.
+    pub generation: u64,
+    /// Every connected receiver, in delivery order.
+    pub destinations: Vec<DestinationStatus>,
+    /// The server's metrics at the time of the snapshot.
+    pub metrics: MetricsSnapshot,
+}
+
+impl ServerStatus {
+    /// Number of connected receivers.
+    pub fn destination_count(&self) -> usize {
+        self.destinations.len()
+    }
+
+    /// The receivers with the most bytes queued, largest first, at most `limit` of
+    /// them. Receivers with nothing queued are left out, so this stays short however
+    /// many receivers are connected and picks out the slow ones holding memory.
+    pub fn largest_backlogs(&self, limit: usize) -> Vec<&DestinationStatus> {
+        let mut backlogged: Vec<_> = self.destinations.iter().filter(|dest| dest.queued_bytes > 0).collect();
+        backlogged.sort_by_key(|dest| std::cmp::Reverse(dest.queued_bytes));
+        backlogged.truncate(limit);
+        backlogged
+    }
+
+    /// Renders the connected receivers as a single JSON object, for external tooling
+    /// such as load balancers that need to know the current receivers.
+    ///
+    /// At most `limit` receivers are listed, in delivery order, so the output stays
+    /// bounded however many are connected. `total` says how many there are in all and
+    /// `listed` how many follow. Each receiver is listed with its
+    /// [`id`](crate::Destination::id). Receivers without an address or label have
+    /// `null`. Receivers cannot be paused in this relay, so there is no paused state to
+    /// report.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// # use coretech_wirestorm::{Config, CtmpClient, Server};
+    /// let config = Config {
+    ///     source_addr: "127.0.0.1:0".into(),
+    ///     destination_addr: "127.0.0.1:0".into(),
+    ///     destination_label_wait: Some(Duration::from_millis(200)),
+    ///     ..Config::default()
+    /// };
+    /// let mut server = Server::bind(config).and_then(Server::spawn).unwrap();
+    /// let addr = server.destination_addr().unwrap();
+    /// let mut edge = CtmpClient::connect_destination(addr).unwrap();
+    /// edge.send_label("edge \"1\"").unwrap();
+    /// let _plain = CtmpClient::connect_destination(addr).unwrap();
+    /// while server.status().destination_count() < 2 {
+    ///     thread::sleep(Duration::from_millis(10));
+    /// }
+    ///
+    /// let status = server.status();
+    /// let json = status.destinations_json(10);
+    /// assert!(json.starts_with(r#"{"total":2,"listed":2,"destinations":[{"id":"#));
+    /// assert!(json.contains(r#","addr":"127.0.0.1:"#));
+    /// assert!(json.contains(r#""label":"edge \"1\"","compressed":false,"joined_at":0,"strikes":0,"#));
+    /// assert!(json.contains(r#""label":null"#));
+    /// assert!(status.destinations_json(1).starts_with(r#"{"total":2,"listed":1,"#));
+    /// server.shutdown();
+    /// ```
+    pub fn destinations_json(&self, limit: usize) -> String {
+        let listed: Vec<_> = self
+            .destinations
+            .iter()
+            .take(limit)
+            .map(|dest| {
+                format!(
+                    concat!(
+                        "{{\"id\":{},\"addr\":{},\"label\":{},\"compressed\":{},\"joined_at\":{},\"strikes\":{},",
+                        "\"queued_frames\":{},\"queued_bytes\":{},\"frames_dropped\":{},\"frames_expired\":{}}}"
+                    ),
+                    dest.id,
+                    dest.peer_addr.map_or_else(|| "null".to_string(), |addr| json_string(&addr.to_string())),
+                    dest.label.as_deref().map_or_else(|| "null".to_string(), json_string),
+                    dest.compressed,
+                    dest.joined_at,
+                    dest.strikes,
+                    dest.queued_frames,
+                    dest.queued_bytes,
+                    dest.frames_dropped,
+                    dest.frames_expired,
+                )
+            })
+            .collect();
+        format!(
+            "{{\"total\":{},\"listed\":{},\"destinations\":[{}]}}",
+            self.destinations.len(),
+            listed.len(),
+            listed.join(",")
+        )
+    }
+
+    /// Renders how many transmitters have been refused, in all and for each
+    /// [`RejectReason`] by its [name](RejectReason::name), as a single JSON object.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::net::TcpStream;
+    /// # use std::time::Duration;
+    /// # use std::thread;
+    /// # use coretech_wirestorm::{Config, Server};
+    /// let config = Config {
+    ///     source_addr: "127.0.0.1:0".into(),
+    ///     destination_addr: "127.0.0.1:0".into(),
+    ///     ..Config::default()
+    /// };
+    /// let mut server = Server::bind(config).and_then(Server::spawn).unwrap();
+    /// let addr = server.source_addr();
+    /// let _first = TcpStream::connect(addr).unwrap();
+    /// while server.transmitter_session().is_none() {
+    ///     thread::sleep(Duration::from_millis(10));
+    /// }
+    /// let _second = TcpStream::connect(addr).unwrap();
+    /// while server.metrics().transmitters_rejected.get() == 0 {
+    ///     thread::sleep(Duration::from_millis(10));
+    /// }
+    ///
+    /// assert_eq!(
+    ///     server.status().rejections_json(),
+    ///     r#"{"total":1,"already_active":1,"quarantined":0,"unknown_peer":0,"pool_full":0}"#
+    /// );
+    /// server.shutdown();
+    /// ```
+    pub fn rejections_json(&self) -> String {
+        let reasons: String = RejectReason::ALL
+            .iter()
+            .map(|&reason| format!(",\"{}\":{}", reason.name(), self.metrics.rejections(reason)))
+            .collect();
+        format!("{{\"total\":{}{}}}", self.metrics.transmitters_rejected, reasons)
+    }
+}
+
+// Quotes a string for JSON, escaping whatever a receiver may have put in its label.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// One receiver in a [`ServerStatus`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationStatus {
+    /// See [`Destination::id`](crate::Destination::id).
+    pub id: u64,
+    /// See [`Destination::peer_addr`](crate::Destination::peer_addr).
+    pub peer_addr: Option<SocketAddr>,
+    /// See [`Destination::label`](crate::Destination::label).
+    pub label: Option<String>,
+    /// See [`Destination::compressed`](crate::Destination::compressed).
+    pub compressed: bool,
+    /// See [`Destination::joined_at`](crate::Destination::joined_at).
+    pub joined_at: u64,
+    /// See [`Destination::strikes`](crate::Destination::strikes).
+    pub strikes: u32,
+    /// See [`Destination::frames_dropped`](crate::Destination::frames_dropped).
+    pub frames_dropped: u64,
+    /// See [`Destination::frames_expired`](crate::Destination::frames_expired).
+    pub frames_expired: u64,
+    /// See [`Destination::queued_frames`](crate::Destination::queued_frames).
+    pub queued_frames: usize,
+    /// See [`Destination::queued_bytes`](crate::Destination::queued_bytes).
+    pub queued_bytes: usize,
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if !self.threads.is_empty() {
+            self.shutdown();
+        }
+    }
+}
+
+// Accepts receivers until shutdown, adding each to the shared destinations list.
+// With a handshake limit, or a wait for labels, each is added on its own thread while a
+// permit is held, and the loop waits for every handshake to finish before returning.
+fn accept_destinations(
+    listener: TcpListener,
+    destinations: Destinations,
+    max_handshakes: Option<usize>,
+    label_wait: Option<Duration>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let handshakes = (max_handshakes.is_some() || label_wait.is_some())
+        .then(|| Arc::new(Semaphore::new(max_handshakes.unwrap_or(usize::MAX))));
+    let turn_away = |stream: TcpStream| {
+        eprintln!("Closing destination client {} that connected during shutdown", display_peer(peer_of(&stream)));
+        destinations.turn_away(stream);
+    };
+    for stream in incoming_until_shutdown(&listener, &shutdown, turn_away) {
+        match stream {
+            Ok(stream) => {
+                prepare_connection(&stream, Role::Destination);
+                let Some(handshakes) = &handshakes else {
+                    destinations.add(stream);
+                    continue;
+                };
+                let permit = Semaphore::acquire(handshakes);
+                let destinations = destinations.clone();
+                let spawned = thread::Builder::new().name("wirestorm-handshake".into()).spawn(move || {
+                    let _permit = permit;
+                    let (label, compress) = label_wait.map_or((None, false), |wait| read_hello(&stream, wait));
+                    destinations.add_negotiated(stream, label, compress);
+                });
+                if let Err(e) = spawned {
+                    eprintln!("Failed to spawn handshake thread: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Destination connection error: {e}"),
+        }
+    }
+    if let Some(handshakes) = handshakes {
+        handshakes.wait_idle();
+    }
+}
+
+// Waits up to `wait` for a new receiver to name itself with a label frame, returning
+// the label and whether it asked for compression first.
+fn read_hello(stream: &TcpStream, wait: Duration) -> (Option<String>, bool) {
+    let peer = display_peer(peer_of(stream));
+    let deadline = Instant::now() + wait;
+    let mut reader = stream;
+    let mut compress = false;
+    let label = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break None;
+        }
+        if let Err(e) = stream.set_read_timeout(Some(remaining)) {
+            eprintln!("Failed to wait for a label from receiver {}: {}", peer, e);
+            break None;
+        }
+        match read_frame(&mut reader) {
+            Ok(frame) if frame.is_compression_request() && !compress => {
+                eprintln!("Receiver {} asked for compressed frames", peer);
+                compress = true;
+            }
+            Ok(frame) => {
+                let label = frame.label_name();
+                match &label {
+                    Some(label) => eprintln!("Receiver {} is labelled {:?}", peer, label),
+                    None => eprintln!("Receiver {} sent a frame that is not a label, ignoring it", peer),
+                }
+                break label;
+            }
+            Err(CtmpError::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                break None;
+            }
+            Err(e) => {
+                eprintln!("Failed to read a label from receiver {}: {}", peer, e);
+                break None;
+            }
+        }
+    };
+    if let Err(e) = stream.set_read_timeout(None) {
+        eprintln!("Failed to clear receiver {}'s read timeout: {}", peer, e);
+    }
+    (label, compress)
+}
+
+// Counts permits for a bounded number of concurrent tasks.
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+    permits: usize,
+}
+
+// A held permit, returned to its semaphore when dropped.
+struct Permit(Arc<Semaphore>);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore { available: Mutex::new(permits), released: Condvar::new(), permits }
+    }
+
+    // Blocks until a permit is free and takes it.
+    fn acquire(this: &Arc<Semaphore>) -> Permit {
+        let available = this.available.lock().unwrap_or_else(|e| e.into_inner());
+        let mut available = this
+            .released
+            .wait_while(available, |available| *available == 0)
+            .unwrap_or_else(|e| e.into_inner());
+        *available -= 1;
+        Permit(Arc::clone(this))
+    }
+
+    // Blocks until every permit has been returned.
+    fn wait_idle(&self) {
+        let available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        let _idle = self
+            .released
+            .wait_while(available, |available| *available < self.permits)
+            .unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.released.notify_all();
+    }
+}
+
+// Where a connected transmitter's stream is kept, so it can be shut down; emptied
+// when the transmitter disconnects.
+type SourceSlot = Arc<Mutex<Option<TcpStream>>>;
+
+fn slot_occupied(slot: &SourceSlot) -> bool {
+    slot.lock().is_ok_and(|stream| stream.is_some())
+}
+
+// A transmitter's slot and the counts for its session, kept after it disconnects
+// until the next transmitter is accepted.
+struct Session {
+    slot: SourceSlot,
+    stats: Arc<SessionStats>,
+}
+
+// Records a newly accepted transmitter's session, forgetting those that have ended and
+// any earlier one in the same slot.
+fn register(sessions: &Mutex<Vec<Session>>, slot: &SourceSlot) -> Arc<SessionStats> {
+    let stats = Arc::new(SessionStats::default());
+    let mut sessions = sessions.lock().unwrap_or_else(|e| e.into_inner());
+    sessions.retain(|session| !Arc::ptr_eq(&session.slot, slot) && slot_occupied(&session.slot));
+    sessions.push(Session { slot: Arc::clone(slot), stats: Arc::clone(&stats) });
+    stats
+}
+
+// What every transmitter accept loop shares with the rest of the server.
+#[derive(Clone)]
+struct Sources {
+    thread_count: usize,
+    autoscale: Option<AutoscalePolicy>,
+    policy: TransmitterPolicy,
+    destinations: Destinations,
+    // the one transmitter allowed, unless `allow_multiple`, when each has a slot in `sessions`
+    active_source: SourceSlot,
+    allow_multiple: bool,
+    // refuse transmitters the pool has no free thread for, rather than holding them
+    reject_when_full: bool,
+    sessions: Arc<Mutex<Vec<Session>>>,
+    metrics: Arc<Metrics>,
+    quarantine: Option<Arc<Mutex<Quarantine>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+// Logs and counts a refused transmitter connection, which is closed when dropped.
+fn reject(reason: RejectReason, peer: Option<SocketAddr>, metrics: &Metrics) {
+    eprintln!("Rejecting transmitter connection from {}: {}", display_peer(peer), reason);
+    metrics.transmitters_rejected.increment();
+    metrics.rejections(reason).increment();
+}
+
+// Accepts transmitters until shutdown.
+// Addresses in quarantine are rejected, and offenses are recorded when a transmitter disconnects.
+// Only one transmitter is allowed at a time, and others are rejected, unless `allow_multiple` is set.
+// A transmitter accepted while every pool thread is busy is held, and logged as waiting,
+// until one frees up, or refused if `reject_when_full` is set.
+// With a bridge config, connections speak length-prefixed framing and are wrapped into CTMP.
+fn accept_transmitters(listener: TcpListener, bridge: Option<BridgeConfig>, sources: Sources) {
+    let Sources {
+        thread_count,
+        autoscale,
+        policy,
+        destinations,
+        active_source,
+        allow_multiple,
+        reject_when_full,
+        sessions,
+        metrics,
+        quarantine,
+        shutdown,
+    } = sources;
+
+    // Create a thread pool for handling transmitter connections.
+    let pool = match autoscale {
+        Some(autoscale) => ThreadPool::autoscaling(autoscale),
+        None => ThreadPool::new(thread_count),
+    }
+    .with_metrics(Arc::clone(&metrics));
+
+    let turn_away = |stream: TcpStream| {
+        eprintln!("Closing transmitter connection from {} that arrived during shutdown", display_peer(peer_of(&stream)));
+    };
+    for stream in incoming_until_shutdown(&listener, &shutdown, turn_away) {
+        match stream {
+            Ok(stream) => {
+                let peer = prepare_connection(&stream, Role::Transmitter);
+                let dests_clone = destinations.clone();
+                let ip = peer.map(|addr| addr.ip());
+
+                if let Some(quarantine) = &quarantine
+                    && !admit_transmitter(&stream, quarantine, &metrics)
+                {
+                    continue;
+                }
+
+                let slot = if allow_multiple {
+                    match stream.try_clone() {
+                        Ok(clone) => Arc::new(Mutex::new(Some(clone))),
+                        Err(e) => {
+                            eprintln!("Dropping transmitter {}: failed to clone its stream: {}", display_peer(peer), e);
+                            continue;
+                        }
+                    }
+                } else {
+                    // Scope for locking and checking the active transmitter.
+                    let mut active = active_source
+                        .lock()
+                        .unwrap_or_else(|_| panic!("Failed to lock active_source mutex"));
+
+                    // If a transmitter is already active, reject the new connection.
+                    if active.is_some() {
+                        reject(RejectReason::AlreadyActive, peer, &metrics);
+                        continue;
+                    }
+
+                    // Set the active transmitter to the new stream, leaving the slot free if it
+                    // cannot be kept.
+                    match stream.try_clone() {
+                        Ok(clone) => *active = Some(clone),
+                        Err(e) => {
+                            eprintln!("Dropping transmitter {}: failed to clone its stream: {}", display_peer(peer), e);
+                            continue;
+                        }
+                    }
+                    Arc::clone(&active_source)
+                };
+                let session = register(&sessions, &slot);
+
+                // Tell a transmitter that cannot be serviced straight away what is happening
+                // to it, rather than leaving it accepted but unread.
+                let held = pool.is_saturated().then(Instant::now);
+                if held.is_some() {
+                    if reject_when_full {
+                        *slot.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                        reject(RejectReason::PoolFull, peer, &metrics);
+                        continue;
+                    }
+                    eprintln!(
+                        "Transmitter {} queued, awaiting a free worker (all {} busy)",
+                        display_peer(peer),
+                        pool.size()
+                    );
+                }
+
+                // Wake idle reads periodically so an expired session is noticed.
+                if let Some(max_session) = policy.max_session
+                    && let Err(e) = stream.set_read_timeout(Some(max_session.min(SESSION_CHECK_INTERVAL)))
+                {
+                    eprintln!("Failed to set transmitter read timeout: {}", e);
+                }
+
+                // Send the transmitter connection to the thread pool for handling.
+                let bridge = bridge.clone();
+                let quarantine = quarantine.clone();
+                pool.execute(move || {
+                    if let Some(held) = held {
+                        eprintln!("Transmitter {} got a worker after {:?}", display_peer(peer), held.elapsed());
+                    }
+                    let reason = match bridge {
+                        Some(bridge) => {
+                            let source = LengthPrefixedSource::new(stream, &bridge);
+                            handle_transmitter_session(source, peer, dests_clone, slot, policy, &session)
+                        }
+                        None => handle_transmitter_session(stream, peer, dests_clone, slot, policy, &session),
+                    };
+                    eprintln!("Transmitter {} disconnected: {:?}", display_peer(peer), reason);
+
+                    if let (Some(quarantine), Some(ip)) = (quarantine, ip) {
+                        // only this transmitter's own rejections, whoever else is connected
+                        let offenses = session.rejected.get() + session.checksum_failures.get();
+                        let mut quarantine = quarantine.lock().unwrap_or_else(|e| e.into_inner());
+                        if quarantine.record(ip, offenses, Instant::now()) {
+                            eprintln!("Quarantining transmitter address {}", ip);
+                        }
+                    }
+                });
+            }
+            Err(e) => eprintln!("Source connection error: {e}"),
+        }
+    }
+
+    // Unblock every connected transmitter's read so the pool can join its workers.
+    let sessions = sessions.lock().unwrap_or_else(|e| e.into_inner());
+    let slots: Vec<_> = sessions.iter().map(|session| Arc::clone(&session.slot)).collect();
+    drop(sessions);
+    for slot in slots.iter().chain([&active_source]) {
+        if let Ok(active) = slot.lock()
+            && let Some(stream) = active.as_ref()
+        {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+    drop(pool);
+}
+
+// Broadcasts the frames in a capture file once a receiver has connected, repeating
+// until shutdown if asked to.
+fn preload(file: File, config: PreloadConfig, destinations: Destinations, shutdown: Arc<AtomicBool>) {
+    while destinations.is_empty() {
+        if !sleep_unless_shutdown(SHUTDOWN_CHECK_INTERVAL, &shutdown) {
+            return;
+        }
+    }
+
+    let mut reader = BufReader::new(file);
+    loop {
+        let frames = match preload_pass(&mut reader, &config, &destinations, &shutdown) {
+            Ok(frames) => frames,
+            Err(e) => {
+                eprintln!("Stopped preloading {}: {}", config.path.display(), e);
+                return;
+            }
+        };
+        eprintln!("Preloaded {} frames from {}", frames, config.path.display());
+        // a capture with nothing to send would only spin
+        if !config.repeat || frames == 0 || shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = reader.rewind() {
+            eprintln!("Failed to rewind {}: {}", config.path.display(), e);
+            return;
+        }
+    }
+}
+
+// Broadcasts each frame of the capture once, returning how many were sent.
+// Frames with a bad checksum and control frames are skipped, like a transmitter's.
+fn preload_pass(
+    reader: &mut BufReader<File>,
+    config: &PreloadConfig,
+    destinations: &Destinations,
+    shutdown: &AtomicBool,
+) -> Result<u64, CtmpError> {
+    let mut frames = 0;
+    while !reader.fill_buf()?.is_empty() {
+        match read_frame(reader) {
+            Ok(frame) if frame.is_control() => continue,
+            Ok(frame) => broadcast_message(frame.header(), frame.payload(), destinations),
+            Err(e @ CtmpError::ChecksumMismatch { .. }) => {
+                eprintln!("Skipping preloaded frame: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        frames += 1;
+        if !sleep_unless_shutdown(config.interval, shutdown) {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+// Sends receivers a keepalive whenever nothing has been broadcast for `interval`, until shutdown.
+// Checking four times an interval means one goes out at most a quarter interval late.
+fn send_keepalives(destinations: Destinations, interval: Duration, shutdown: Arc<AtomicBool>) {
+    let mut timer = KeepaliveTimer::new(interval, destinations.generation(), Instant::now());
+    let check = (interval / 4).max(Duration::from_millis(1));
+    while sleep_unless_shutdown(check, &shutdown) {
+        if timer.due(destinations.generation(), Instant::now()) {
+            destinations.keepalive();
+        }
+    }
+}
+
+// Saves the lifetime counters every `METRICS_SAVE_INTERVAL`, and once more at shutdown.
+fn save_metrics(metrics: &Metrics, path: &Path, shutdown: &AtomicBool) {
+    loop {
+        let running = sleep_unless_shutdown(METRICS_SAVE_INTERVAL, shutdown);
+        if let Err(e) = metrics.save(path) {
+            eprintln!("Failed to save metrics to {}: {}", path.display(), e);
+        }
+        if !running {
+            return;
+        }
+    }
+}
+
+// Logs a one-line summary every `interval` until shutdown.
+fn log_stats(destinations: Destinations, interval: Duration, shutdown: Arc<AtomicBool>) {
+    let mut last = destinations.metrics().snapshot();
+    let mut last_at = Instant::now();
+    while sleep_unless_shutdown(interval, &shutdown) {
+        let now = Instant::now();
+        let current = destinations.metrics().snapshot();
+        eprintln!("{}", stats_line(destinations.len(), &last, &current, now - last_at));
+        last = current;
+        last_at = now;
+    }
+}
+
+// Summarises the metrics, with the frame rate measured between two snapshots.
+fn stats_line(destinations: usize, before: &MetricsSnapshot, after: &MetricsSnapshot, elapsed: Duration) -> String {
+    let relayed = after.frames_relayed - before.frames_relayed;
+    let per_sec = relayed as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    format!(
+        "stats: destinations={} frames_per_sec={:.1} relayed={} rejected={} dropped={} evicted={} transmitters_rejected={}{} queued_bytes={}",
+        destinations,
+        per_sec,
+        after.frames_relayed,
+        after.frames_rejected,
+        after.frames_dropped,
+        after.destinations_evicted,
+        after.transmitters_rejected,
+        RejectReason::ALL
+            .iter()
+            .map(|&reason| format!(" rejected_{}={}", reason.name(), after.rejections(reason)))
+            .collect::<String>(),
+        after.queued_bytes,
+    )
+}
+
+// Sleeps for `duration`, returning false as soon as the server starts shutting down.
+fn sleep_unless_shutdown(duration: Duration, shutdown: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(SHUTDOWN_CHECK_INTERVAL));
+    }
+}
+
+// Spawns a named thread counted in `running` until it exits.
+fn spawn_tracked<F>(name: &str, running: &Arc<AtomicUsize>, f: F) -> io::Result<thread::JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    struct Running(Arc<AtomicUsize>);
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    running.fetch_add(1, Ordering::SeqCst);
+    let guard = Running(Arc::clone(running));
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let _guard = guard;
+        f();
+    })
+}
+
+// Yields connections from `listener` until shutdown. The listener is polled, so the
+// loop notices shutdown promptly even when no one connects. A connection accepted once
+// shutdown has begun, and any still waiting in the backlog, is handed to `turn_away`
+// instead, so it is closed deliberately rather than registered with a server that is
+// stopping.
+fn incoming_until_shutdown<'a>(
+    listener: &'a TcpListener,
+    shutdown: &'a AtomicBool,
+    mut turn_away: impl FnMut(TcpStream) + 'a,
+) -> impl Iterator<Item = io::Result<TcpStream>> + 'a {
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Failed to make listener non-blocking, shutdown will wait for a connection: {}", e);
+    }
+    std::iter::from_fn(move || {
+        loop {
+            let accepted = listener.accept();
+            if shutdown.load(Ordering::SeqCst) {
+                let mut accepted = accepted;
+                while let Ok((stream, _)) = accepted {
+                    let _ = stream.set_nonblocking(false);
+                    turn_away(stream);
+                    accepted = listener.accept();
+                }
+                return None;
+            }
+            match accepted {
+                // accepted sockets inherit non-blocking mode on some platforms
+                Ok((stream, _)) => return Some(stream.set_nonblocking(false).map(|()| stream)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    })
+}
@@ -0,0 +1,305 @@
+//! In-memory streams for exercising the relay without sockets.
+//!
+//! [`duplex`] returns two connected [`DuplexStream`]s: bytes written to one are read
+//! from the other. They implement `Read`, `Write` and [`Transport`], so one end can be
+//! handed to [`handle_transmitter`](crate::handle_transmitter) as a source or added to
+//! [`Destinations`](crate::Destinations) as a receiver while the test drives the other
+//! end. Short writes, full buffers and end of stream can be triggered on demand, which
+//! makes the awkward paths deterministic.
+//!
+//! [`build_frame_with_checksum`] produces frames with a chosen, usually wrong, checksum
+//! for exercising the relay's rejection path.
+//!
+//! Only built with the `testing` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # use coretech_wirestorm::{broadcast_message, Destinations, testing::{duplex, DuplexStream}};
+//! # use std::io::Read;
+//! let destinations = Destinations::<DuplexStream>::default();
+//! let (server_end, mut receiver) = duplex();
+//! destinations.add(server_end);
+//!
+//! broadcast_message(&[0xCC, 0, 0, 2, 0, 0, 0, 0], b"hi", &destinations);
+//!
+//! let mut frame = [0u8; 10];
+//! receiver.read_exact(&mut frame).unwrap();
+//! assert_eq!(&frame[8..], b"hi");
+//! ```
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{build_frame, CtmpError, Transport};
+
+/// Encodes `payload` like [`build_frame`], then stamps `checksum` into the header.
+///
+/// Unlike `build_frame`, the checksum is not computed, so a sensitive frame built
+/// here with anything but the correct value is one the relay must drop. Stamping a
+/// non-zero checksum on a non-sensitive frame produces a header the relay rejects
+/// for bad padding.
+///
+/// # Arguments
+///
+/// * `payload` - The message body.
+/// * `sensitive` - Whether to set the sensitive flag.
+/// * `checksum` - The value to place in the checksum field.
+pub fn build_frame_with_checksum(payload: &[u8], sensitive: bool, checksum: u16) -> Result<Vec<u8>, CtmpError> {
+    let mut frame = build_frame(payload, sensitive)?;
+    frame[4..6].copy_from_slice(&checksum.to_be_bytes());
+    Ok(frame)
+}
+
+/// Creates a connected pair of in-memory streams.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Pipe::default());
+    let b_to_a = Arc::new(Pipe::default());
+    (
+        DuplexStream::new(Arc::clone(&b_to_a), Arc::clone(&a_to_b)),
+        DuplexStream::new(a_to_b, b_to_a),
+    )
+}
+
+/// One direction of a duplex pair.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    // no more bytes will be written (writer closed) or read (reader dropped)
+    closed: bool,
+    capacity: Option<usize>,
+    max_write: Option<usize>,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    // Waits on the condvar until `blocked` is false, the deadline passes or the pipe closes.
+    fn wait_while<'a>(
+        &self,
+        mut state: MutexGuard<'a, PipeState>,
+        deadline: Option<Instant>,
+        blocked: impl Fn(&PipeState) -> bool,
+    ) -> io::Result<MutexGuard<'a, PipeState>> {
+        while blocked(&state) && !state.closed {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        Ok(state)
+    }
+}
+
+/// One end of an in-memory connection created by [`duplex`].
+///
+/// Dropping an end behaves like closing a socket: the peer reads end of stream once
+/// it has drained what was already written, and the peer's writes fail with
+/// `BrokenPipe`.
+pub struct DuplexStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>,
+    peer_addr: Cell<Option<SocketAddr>>,
+}
+
+impl DuplexStream {
+    fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>) -> Self {
+        DuplexStream {
+            incoming,
+            outgoing,
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None),
+            peer_addr: Cell::new(None),
+        }
+    }
+
+    /// Limits how many bytes this end may have written but not yet read by the peer.
+    ///
+    /// Writes block once the limit is reached, failing with `WouldBlock` if a write
+    /// timeout is set, just as a socket with a full send buffer does. `None` removes
+    /// the limit.
+    ///
+    /// A capacity that is never read down is a stalled receiver:
+    ///
+    /// ```rust
+    /// # use std::time::{Duration, Instant};
+    /// # use coretech_wirestorm::{broadcast_message, DeliveryPolicy, Destinations, testing::{duplex, DuplexStream}};
+    /// let policy = DeliveryPolicy {
+    ///     write_timeout: Some(Duration::from_millis(5)),
+    ///     max_strikes: Some(100),
+    ///     queue_depth: 10,
+    ///     drain_timeout: Some(Duration::from_millis(100)),
+    ///     ..DeliveryPolicy::default()
+    /// };
+    /// let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    /// let (server_end, _receiver) = duplex();
+    /// server_end.set_capacity(Some(0));
+    /// destinations.add(server_end);
+    /// for _ in 0..3 {
+    ///     broadcast_message(&[0xCC, 0, 0, 2, 0, 0, 0, 0], b"hi", &destinations);
+    /// }
+    ///
+    /// let started = Instant::now();
+    /// let reports = destinations.clear();
+    /// assert!(started.elapsed() < Duration::from_secs(1));
+    /// assert_eq!(reports[0].undrained, 3);
+    /// ```
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.outgoing.lock().capacity = capacity;
+        self.outgoing.changed.notify_all();
+    }
+
+    /// Limits how many bytes a single `write` call on this end accepts, to simulate short writes.
+    ///
+    /// `Some(0)` makes every write return `Ok(0)`, as a stalled destination might.
+    pub fn set_max_write(&self, max_write: Option<usize>) {
+        self.outgoing.lock().max_write = max_write;
+    }
+
+    /// Bounds how long a read on this end may block, failing with `WouldBlock` after it.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        self.read_timeout.set(timeout);
+    }
+
+    /// Sets the address [`Transport::peer_addr`] reports for this end, so it can be told
+    /// apart from other receivers in a [`Destinations`](crate::Destinations). Must be
+    /// called before the stream is added.
+    ///
+    /// Until it is set, `peer_addr` fails, as it does on a socket that was reset before
+    /// it was accepted, and the stream is refused by anything that checks addresses.
+    ///
+    /// ```rust
+    /// # use std::time::{Duration, Instant};
+    /// # use coretech_wirestorm::{peer_of, QuarantinePolicy, quarantine::Quarantine, server::RejectReason, testing::duplex};
+    /// let (stream, _peer) = duplex();
+    /// let policy = QuarantinePolicy {
+    ///     max_offenses: 1,
+    ///     window: Duration::from_secs(60),
+    ///     cooldown: Duration::from_secs(60),
+    /// };
+    /// let mut quarantine = Quarantine::new(policy);
+    /// let ip = peer_of(&stream).map(|addr| addr.ip());
+    /// assert_eq!(quarantine.admit(ip, Instant::now()), Err(RejectReason::UnknownPeer));
+    ///
+    /// stream.set_peer_addr("127.0.0.1:5000".parse().unwrap());
+    /// let ip = peer_of(&stream).map(|addr| addr.ip());
+    /// assert_eq!(quarantine.admit(ip, Instant::now()), Ok(()));
+    /// ```
+    pub fn set_peer_addr(&self, addr: SocketAddr) {
+        self.peer_addr.set(Some(addr));
+    }
+
+    /// Signals end of stream to the peer without dropping this end.
+    ///
+    /// The peer reads whatever was already written, then `Ok(0)`. Further writes on
+    /// this end fail with `BrokenPipe`.
+    pub fn close_write(&self) {
+        self.outgoing.close();
+    }
+
+    /// Number of bytes written by the peer that this end has not read yet.
+    pub fn available(&self) -> usize {
+        self.incoming.lock().buf.len()
+    }
+
+    /// Takes every byte currently waiting to be read, without blocking.
+    pub fn read_available(&mut self) -> Vec<u8> {
+        let bytes = self.incoming.lock().buf.drain(..).collect();
+        self.incoming.changed.notify_all();
+        bytes
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.read_timeout.get().map(|timeout| Instant::now() + timeout);
+        let mut state = self
+            .incoming
+            .wait_while(self.incoming.lock(), deadline, |state| state.buf.is_empty())?;
+
+        let n = buf.len().min(state.buf.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *slot = byte;
+        }
+        drop(state);
+        self.incoming.changed.notify_all();
+        Ok(n)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.write_timeout.get().map(|timeout| Instant::now() + timeout);
+        let mut state = self.outgoing.wait_while(self.outgoing.lock(), deadline, |state| {
+            state.capacity.is_some_and(|capacity| state.buf.len() >= capacity)
+        })?;
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let room = state.capacity.map_or(usize::MAX, |capacity| capacity - state.buf.len());
+        let n = buf.len().min(room).min(state.max_write.unwrap_or(usize::MAX));
+        state.buf.extend(&buf[..n]);
+        drop(state);
+        self.outgoing.changed.notify_all();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for DuplexStream {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.write_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer_addr
+            .get()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no peer address set"))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.outgoing.close();
+        self.incoming.close();
+    }
+}
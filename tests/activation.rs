@@ -0,0 +1,83 @@
+//! Adopting a listener passed in by socket activation.
+//!
+//! A supervisor hands listeners over at file descriptor 3 onwards, which the test
+//! process may already be using, so the test re-runs itself as a child with the
+//! listener placed there, as a supervisor would start the server.
+
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::activation::LISTEN_FDS_START;
+use coretech_wirestorm::{Config, CtmpClient, Server};
+
+unsafe extern "C" {
+    fn dup2(old: i32, new: i32) -> i32;
+}
+
+// Set in the child, which holds the inherited listener.
+const CHILD: &str = "WIRESTORM_ACTIVATION_CHILD";
+
+#[test]
+fn an_inherited_listener_is_adopted_and_used() {
+    match env::var(CHILD) {
+        Ok(addr) => adopt_and_relay(&addr),
+        Err(_) => run_child(),
+    }
+}
+
+fn run_child() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // a duplicate, in case the listener itself is at the target descriptor, where dup2
+    // would leave it to be closed on exec
+    let duplicate = listener.try_clone().unwrap();
+    let fd = duplicate.as_raw_fd();
+    let mut child = Command::new(env::current_exe().unwrap());
+    child
+        .args(["--exact", "an_inherited_listener_is_adopted_and_used", "--test-threads=1"])
+        .env(CHILD, listener.local_addr().unwrap().to_string());
+    // SAFETY: dup2 is async-signal-safe, and the duplicate is left without close-on-exec.
+    unsafe {
+        child.pre_exec(move || match dup2(fd, LISTEN_FDS_START) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let output = child.output().unwrap();
+    let log = String::from_utf8_lossy(&[output.stdout, output.stderr].concat()).into_owned();
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("1 passed"), "the child ran no test: {}", log);
+}
+
+fn adopt_and_relay(addr: &str) {
+    // SAFETY: the only test in this process, and no other thread reads the environment yet.
+    unsafe {
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", "1");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+    let config = Config {
+        // fails to bind if it is used instead of the inherited listener
+        source_addr: "203.0.113.1:1".into(),
+        destination_addr: "127.0.0.1:0".into(),
+        ..Config::default()
+    };
+    let server = Server::bind(config).and_then(Server::spawn).unwrap();
+    assert_eq!(server.source_addr().to_string(), addr);
+
+    let mut receiver = CtmpClient::connect_destination(server.destination_addr().unwrap()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.destinations().is_empty() {
+        assert!(Instant::now() < deadline, "receiver never registered");
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut transmitter = CtmpClient::connect_source(server.source_addr()).unwrap();
+    transmitter.send(b"through the inherited socket", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"through the inherited socket");
+}
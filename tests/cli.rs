@@ -0,0 +1,68 @@
+//! The server's command line, and the binary run as a child process.
+
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::{Config, CTMP_MAX_PAYLOAD_SIZE};
+
+// Runs the server on ephemeral ports with `args`, until it has been up for `uptime_ms`.
+fn run_server(args: &[&str], uptime_ms: u64) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_coretech-wirestorm"))
+        .args(["--source", "127.0.0.1:0", "--destination", "127.0.0.1:0", "--no-socket-activation"])
+        .args(["--max-uptime-ms", &uptime_ms.to_string()])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn a_stats_line_is_logged_every_interval() {
+    let output = run_server(&["--stats-log-interval-ms", "50"], 400);
+    assert!(output.status.success());
+
+    let log = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = log.lines().filter(|line| line.starts_with("stats: ")).collect();
+    assert!((3..=8).contains(&lines.len()), "{}", log);
+    assert!(lines[0].starts_with("stats: destinations=0 frames_per_sec=0.0 relayed=0 rejected=0"), "{}", lines[0]);
+    let by_reason = "rejected_already_active=0 rejected_quarantined=0 rejected_unknown_peer=0 rejected_pool_full=0";
+    assert!(lines[0].contains(by_reason), "{}", lines[0]);
+}
+
+#[test]
+fn no_stats_lines_are_logged_by_default() {
+    let output = run_server(&[], 200);
+    assert!(output.status.success());
+
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(!log.lines().any(|line| line.starts_with("stats: ")), "{}", log);
+}
+
+#[test]
+fn a_recycle_at_the_uptime_limit_exits_with_the_chosen_status() {
+    let started = Instant::now();
+    let output = run_server(&["--max-uptime-exit-code", "75"], 200);
+
+    assert_eq!(output.status.code(), Some(75), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+// Parses `args` as the server's command line.
+fn parse(args: &[&str]) -> Result<Config, String> {
+    Config::from_args(args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn payload_limits_over_the_ceiling_are_refused() {
+    let error = parse(&["--max-payload", "65537"]).unwrap_err();
+    assert_eq!(error, "--max-payload cannot exceed 65536");
+    let error = parse(&["--extra-source", "127.0.0.1:0", "--extra-source-max-payload", "65537"]).unwrap_err();
+    assert_eq!(error, "--extra-source-max-payload cannot exceed 65536");
+}
+
+#[test]
+fn payload_limits_at_the_ceiling_are_accepted() {
+    let config = parse(&["--max-payload", "65536"]).unwrap();
+    assert_eq!(config.transmitter.max_payload, Some(CTMP_MAX_PAYLOAD_SIZE));
+    let config = parse(&["--extra-source", "127.0.0.1:0", "--extra-source-max-payload", "65536"]).unwrap();
+    assert_eq!(config.extra_sources[0].policy.max_payload, Some(CTMP_MAX_PAYLOAD_SIZE));
+}
@@ -0,0 +1,123 @@
+//! Both halves of [`CtmpClient`] against an in-process [`Server`].
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::client::ClientOptions;
+use coretech_wirestorm::{Config, CtmpClient, Server, ServerHandle, CTMP_MAX_PAYLOAD_SIZE};
+
+fn start_server() -> ServerHandle {
+    let config = Config {
+        source_addr: "127.0.0.1:0".into(),
+        destination_addr: "127.0.0.1:0".into(),
+        ..Config::default()
+    };
+    Server::bind(config).and_then(Server::spawn).unwrap()
+}
+
+// Waits until the server has `count` receivers registered.
+fn wait_for_destinations(server: &ServerHandle, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.destinations().len() != count {
+        assert!(Instant::now() < deadline, "server never had {} receivers", count);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn frames_sent_are_received_and_verified() {
+    let server = start_server();
+    let mut receiver = CtmpClient::connect_destination(server.destination_addr().unwrap()).unwrap();
+    wait_for_destinations(&server, 1);
+    let mut sender = CtmpClient::connect_source(server.source_addr()).unwrap();
+
+    sender.send(b"plain", false).unwrap();
+    sender.send(b"secret", true).unwrap();
+
+    let frame = receiver.recv().unwrap();
+    assert_eq!((frame.payload(), frame.is_sensitive()), (&b"plain"[..], false));
+    let frame = receiver.recv().unwrap();
+    assert_eq!((frame.payload(), frame.is_sensitive()), (&b"secret"[..], true));
+}
+
+#[test]
+fn messages_larger_than_a_frame_arrive_whole() {
+    let server = start_server();
+    let mut receiver = CtmpClient::connect_destination(server.destination_addr().unwrap()).unwrap();
+    wait_for_destinations(&server, 1);
+    let mut sender = CtmpClient::connect_source(server.source_addr()).unwrap();
+
+    let message: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+    sender.send_message(&message, true).unwrap();
+    sender.send(b"after", false).unwrap();
+
+    assert_eq!(receiver.recv_message().unwrap(), message);
+    assert_eq!(receiver.recv_message().unwrap(), b"after");
+}
+
+#[test]
+fn payloads_over_the_limit_are_refused_before_sending() {
+    let server = start_server();
+    let mut sender = CtmpClient::connect_source(server.source_addr()).unwrap();
+
+    let error = sender.send(&vec![0; CTMP_MAX_PAYLOAD_SIZE + 1], false).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let error = sender.send(&[], false).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn a_quiet_relay_times_out_a_read() {
+    let server = start_server();
+    let client = CtmpClient::new(ClientOptions {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..ClientOptions::default()
+    });
+    let mut receiver = client.destination(server.destination_addr().unwrap()).unwrap();
+
+    let error = receiver.recv().unwrap_err();
+    assert!(matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{:?}", error);
+}
+
+#[test]
+fn a_reconnected_receiver_gets_later_frames() {
+    let server = start_server();
+    let mut receiver = CtmpClient::connect_destination(server.destination_addr().unwrap()).unwrap();
+    wait_for_destinations(&server, 1);
+    let mut sender = CtmpClient::connect_source(server.source_addr()).unwrap();
+
+    receiver.reconnect().unwrap();
+    // the old connection is only pruned once a write to it fails
+    while server.destinations().len() != 1 || server.metrics().destinations_evicted.get() == 0 {
+        sender.send(b"while reconnecting", false).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+    sender.send(b"after reconnecting", false).unwrap();
+
+    let frame = receiver.find(|frame| frame.as_ref().unwrap().payload() == b"after reconnecting");
+    assert!(frame.is_some());
+}
+
+#[test]
+fn a_refused_sender_gets_through_by_reconnecting() {
+    let server = start_server();
+    let mut receiver = CtmpClient::connect_destination(server.destination_addr().unwrap()).unwrap();
+    wait_for_destinations(&server, 1);
+    let active = CtmpClient::connect_source(server.source_addr()).unwrap();
+    while !server.has_transmitter() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut refused = CtmpClient::connect_source(server.source_addr()).unwrap();
+    while server.metrics().transmitters_rejected.get() == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    drop(active);
+    while server.has_transmitter() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    refused.reconnect().unwrap();
+    refused.send(b"second try", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"second try");
+}
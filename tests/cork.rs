@@ -0,0 +1,45 @@
+//! Corked TCP receivers, which only exist on Linux.
+
+#![cfg(all(feature = "testing", target_os = "linux"))]
+
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::TestServer;
+use coretech_wirestorm::Config;
+
+fn corked(flush_interval: Duration) -> Config {
+    let mut config = Config::default();
+    config.delivery.cork = true;
+    config.delivery.flush_interval = Some(flush_interval);
+    config
+}
+
+#[test]
+fn frames_arrive_intact_and_in_order_under_corking() {
+    let server = TestServer::start(corked(Duration::from_millis(20)));
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+
+    for i in 0..200u16 {
+        transmitter.send(&i.to_be_bytes(), i % 2 == 0).unwrap();
+    }
+    for i in 0..200u16 {
+        let frame = receiver.recv().unwrap();
+        assert_eq!(frame.payload(), i.to_be_bytes());
+        assert_eq!(frame.is_sensitive(), i % 2 == 0);
+    }
+    assert_eq!(server.handle().metrics().frames_rejected.get(), 0);
+}
+
+#[test]
+fn a_lone_corked_frame_is_pushed_out_by_the_flush() {
+    let server = TestServer::start(corked(Duration::from_millis(20)));
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+
+    let sent = Instant::now();
+    transmitter.send(b"alone", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"alone");
+    // well within the kernel's own 200ms cork limit
+    assert!(sent.elapsed() < Duration::from_millis(150), "{:?}", sent.elapsed());
+}
@@ -0,0 +1,288 @@
+//! How frames are written to receivers that do not keep up.
+
+#![cfg(feature = "testing")]
+
+use std::io::{self, BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::testing::{duplex, DuplexStream};
+use coretech_wirestorm::{
+    broadcast_message, build_frame, read_frame, Config, CtmpClient, DeliveryPolicy, Destinations, Server, Transport,
+};
+
+// Broadcasts `payload` as a plain frame.
+fn broadcast(payload: &[u8], destinations: &Destinations<DuplexStream>) {
+    let frame = build_frame(payload, false).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], destinations);
+}
+
+// Adds a receiver at `127.0.0.1:port` that can hold `capacity` bytes unread, returning
+// the end the test reads from.
+fn add_receiver(destinations: &Destinations<DuplexStream>, port: u16, capacity: Option<usize>) -> DuplexStream {
+    let (server_end, receiver) = duplex();
+    server_end.set_peer_addr(([127, 0, 0, 1], port).into());
+    server_end.set_capacity(capacity);
+    destinations.add(server_end);
+    receiver
+}
+
+// Reads whatever arrives on `receiver`, `chunk` bytes at most every `interval`, until
+// `stop` is set, returning everything read.
+fn read_slowly(
+    mut receiver: DuplexStream,
+    chunk: usize,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut received = Vec::new();
+        while !stop.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            let available = receiver.available().min(chunk);
+            let mut buf = vec![0u8; available];
+            std::io::Read::read_exact(&mut receiver, &mut buf).unwrap();
+            received.extend(buf);
+        }
+        received.extend(receiver.read_available());
+        received
+    })
+}
+
+#[test]
+fn large_frames_get_longer_to_reach_a_slow_receiver() {
+    let policy = DeliveryPolicy {
+        write_timeout: Some(Duration::from_millis(20)),
+        write_timeout_per_kib: Duration::from_millis(5),
+        ..DeliveryPolicy::default()
+    };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    let slow = add_receiver(&destinations, 1, Some(4096));
+    let _stalled = add_receiver(&destinations, 2, Some(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    // about 80ms for the whole frame, far over the base timeout but within its allowance
+    let reader = read_slowly(slow, 4096, Duration::from_millis(5), Arc::clone(&stop));
+
+    let payload = vec![7u8; 64_000];
+    broadcast(&payload, &destinations);
+
+    let receivers = destinations.clone_inner();
+    let peers: Vec<_> = receivers.lock().unwrap().iter().map(|dest| dest.peer_addr()).collect();
+    assert_eq!(peers, [Some(([127, 0, 0, 1], 1).into())]);
+    assert_eq!(destinations.metrics().destinations_evicted.get(), 1);
+
+    stop.store(true, Ordering::SeqCst);
+    let received = reader.join().unwrap();
+    assert_eq!(read_frame(&mut &received[..]).unwrap().payload(), payload);
+}
+
+// A receiver that takes a KiB at a time, slowly, and never times a write out.
+struct Trickle(Arc<Mutex<usize>>);
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(5));
+        let n = buf.len().min(1024);
+        *self.0.lock().unwrap() += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Trickle {}
+
+#[test]
+fn a_chunked_frame_is_given_up_on_within_its_first_chunk() {
+    let policy = DeliveryPolicy {
+        write_timeout: Some(Duration::from_millis(1)),
+        write_chunk_size: Some(4096),
+        ..DeliveryPolicy::default()
+    };
+    let received = Arc::new(Mutex::new(0));
+    let destinations = Destinations::default().with_policy(policy);
+    destinations.add(Trickle(Arc::clone(&received)));
+
+    let frame = build_frame(&[0; 60_000], false).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], &destinations);
+
+    // evicted within its first chunk, not after the whole frame trickled out
+    assert!(destinations.is_empty());
+    assert!(*received.lock().unwrap() < 4096);
+}
+
+// Reads every whole frame waiting on `receiver`, returning their payloads.
+fn payloads(receiver: &mut DuplexStream) -> Vec<Vec<u8>> {
+    let bytes = receiver.read_available();
+    let mut reader = &bytes[..];
+    let mut payloads = Vec::new();
+    while !reader.is_empty() {
+        payloads.push(read_frame(&mut reader).unwrap().into_payload());
+    }
+    payloads
+}
+
+// A policy under which a receiver that cannot take a frame is never evicted, only queued for.
+fn patient(queue_depth: usize) -> DeliveryPolicy {
+    DeliveryPolicy {
+        write_timeout: Some(Duration::from_millis(1)),
+        max_strikes: Some(u32::MAX),
+        queue_depth,
+        ..DeliveryPolicy::default()
+    }
+}
+
+#[test]
+fn a_full_queue_drops_its_oldest_frames() {
+    let destinations = Destinations::<DuplexStream>::default().with_policy(patient(3));
+    let mut receiver = add_receiver(&destinations, 1, Some(0));
+
+    for i in 0..10u8 {
+        broadcast(&[i], &destinations);
+    }
+    let receivers = destinations.clone_inner();
+    assert_eq!(receivers.lock().unwrap()[0].queued_frames(), 3);
+    assert_eq!(receivers.lock().unwrap()[0].frames_dropped(), 7);
+
+    receivers.lock().unwrap()[0].stream().set_capacity(None);
+    broadcast(&[10], &destinations);
+
+    assert_eq!(payloads(&mut receiver), [[7], [8], [9], [10]]);
+    assert_eq!(receivers.lock().unwrap()[0].frames_dropped(), 7);
+    assert_eq!(destinations.metrics().frames_dropped.get(), 7);
+}
+
+#[test]
+fn control_frames_overtake_queued_data() {
+    let destinations = Destinations::<DuplexStream>::default().with_policy(patient(10));
+    let mut receiver = add_receiver(&destinations, 1, Some(0));
+
+    for i in 0..3u8 {
+        broadcast(&[i], &destinations);
+    }
+    destinations.keepalive();
+    let receivers = destinations.clone_inner();
+    receivers.lock().unwrap()[0].stream().set_capacity(None);
+    broadcast(&[3], &destinations);
+
+    let bytes = receiver.read_available();
+    let mut reader = &bytes[..];
+    assert!(read_frame(&mut reader).unwrap().is_keepalive());
+    // and the data frames still arrive in the order they were sent
+    for i in 0..4u8 {
+        assert_eq!(read_frame(&mut reader).unwrap().payload(), [i]);
+    }
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn receivers_skip_frames_once_queue_memory_runs_out() {
+    let frame_len = build_frame(&[0], false).unwrap().len();
+    let policy = DeliveryPolicy { queue_memory: Some(5 * frame_len), ..patient(100) };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    let mut fast = add_receiver(&destinations, 1, None);
+    let _stalled = [add_receiver(&destinations, 2, Some(0)), add_receiver(&destinations, 3, Some(0))];
+
+    for i in 0..10u8 {
+        broadcast(&[i], &destinations);
+    }
+
+    assert_eq!(payloads(&mut fast), (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    let receivers = destinations.clone_inner();
+    let receivers = receivers.lock().unwrap();
+    assert_eq!(receivers.len(), 3);
+    assert_eq!(receivers[0].frames_dropped(), 0);
+    let queued: usize = receivers[1..].iter().map(|receiver| receiver.queued_frames()).sum();
+    assert_eq!(queued, 5);
+    assert_eq!(receivers[1..].iter().map(|receiver| receiver.queued_bytes()).sum::<usize>(), 5 * frame_len);
+    for receiver in &receivers[1..] {
+        assert_eq!(receiver.queued_frames() as u64 + receiver.frames_dropped(), 10);
+    }
+    assert_eq!(destinations.metrics().frames_dropped.get(), 15);
+}
+
+#[test]
+fn a_buffered_receiver_gets_a_single_frame_without_waiting_for_more() {
+    let destinations = Destinations::<BufWriter<DuplexStream>>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(BufWriter::new(server_end));
+
+    let frame = build_frame(b"alone", false).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], &destinations);
+
+    assert_eq!(receiver.read_available(), frame);
+}
+
+#[test]
+fn with_a_flush_interval_frames_wait_for_the_flush() {
+    let policy = DeliveryPolicy { flush_interval: Some(Duration::from_secs(60)), ..DeliveryPolicy::default() };
+    let destinations = Destinations::<BufWriter<DuplexStream>>::default().with_policy(policy);
+    let (server_end, mut receiver) = duplex();
+    destinations.add(BufWriter::new(server_end));
+
+    let frame = build_frame(b"held", false).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], &destinations);
+    assert_eq!(receiver.available(), 0);
+
+    destinations.flush();
+    assert_eq!(receiver.read_available(), frame);
+}
+
+#[test]
+fn a_receiver_whose_writes_return_zero_gets_strikes_without_a_busy_loop() {
+    let policy = DeliveryPolicy { max_strikes: Some(3), ..DeliveryPolicy::default() };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    let (server_end, _receiver) = duplex();
+    server_end.set_max_write(Some(0));
+    destinations.add(server_end);
+
+    let started = Instant::now();
+    for expected in [1, 2] {
+        broadcast(b"nowhere to go", &destinations);
+        assert_eq!(destinations.len(), 1);
+        assert_eq!(destinations.clone_inner().lock().unwrap()[0].strikes(), expected);
+    }
+    broadcast(b"nowhere to go", &destinations);
+    assert!(destinations.is_empty());
+    assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+    assert_eq!(destinations.metrics().destinations_evicted.get(), 1);
+}
+
+#[test]
+fn shutdown_gives_up_on_a_stalled_receiver_at_the_drain_timeout() {
+    let drain_timeout = Duration::from_millis(200);
+    let config = Config {
+        source_addr: "127.0.0.1:0".into(),
+        destination_addr: "127.0.0.1:0".into(),
+        delivery: DeliveryPolicy { drain_timeout: Some(drain_timeout), ..patient(1000) },
+        ..Config::default()
+    };
+    let mut server = Server::bind(config).and_then(Server::spawn).unwrap();
+    // never reads, so once the socket buffers fill frames queue up for it
+    let _stalled = TcpStream::connect(server.destination_addr().unwrap()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.destinations().is_empty() {
+        assert!(Instant::now() < deadline, "the receiver was never registered");
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let mut transmitter = CtmpClient::connect_source(server.source_addr()).unwrap();
+    let payload = vec![7; 60_000];
+    let queued = || server.status().destinations.first().map_or(0, |dest| dest.queued_frames);
+    while queued() < 10 {
+        assert!(Instant::now() < deadline, "no frames were queued");
+        transmitter.send(&payload, false).unwrap();
+    }
+
+    let started = Instant::now();
+    let reports = server.shutdown();
+    let took = started.elapsed();
+    assert!(took < drain_timeout + Duration::from_millis(300), "{:?}", took);
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].undrained > 0, "{:?}", reports);
+}
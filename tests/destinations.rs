@@ -0,0 +1,178 @@
+//! Managing the set of receivers frames are broadcast to.
+
+#![cfg(feature = "testing")]
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use coretech_wirestorm::metrics::FANOUT_BUCKETS;
+use coretech_wirestorm::testing::{duplex, DuplexStream};
+use coretech_wirestorm::{
+    broadcast_message, build_frame, handle_transmitter, read_frame, DeliveryPolicy, Destinations, TransmitterPolicy,
+};
+
+#[test]
+fn frames_after_a_swap_go_to_the_new_set() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (old_end, mut old) = duplex();
+    destinations.add(old_end);
+    let (source, mut transmitter) = duplex();
+    let session = {
+        let destinations = destinations.clone();
+        let slot = Arc::new(Mutex::new(None::<DuplexStream>));
+        thread::spawn(move || handle_transmitter(source, None, destinations, slot, TransmitterPolicy::default()))
+    };
+
+    transmitter.write_all(&build_frame(b"before", false).unwrap()).unwrap();
+    assert_eq!(read_frame(&mut old).unwrap().payload(), b"before");
+
+    let (first_end, mut first) = duplex();
+    let (second_end, mut second) = duplex();
+    let replaced = destinations.replace_all([first_end, second_end]);
+    assert_eq!(replaced.len(), 1);
+    transmitter.write_all(&build_frame(b"after", false).unwrap()).unwrap();
+
+    assert_eq!(read_frame(&mut first).unwrap().payload(), b"after");
+    assert_eq!(read_frame(&mut second).unwrap().payload(), b"after");
+    drop(transmitter);
+    session.join().unwrap();
+    assert_eq!(old.available(), 0);
+    assert_eq!(destinations.len(), 2);
+}
+
+#[test]
+fn pending_frames_can_be_taken_from_a_stalled_receiver() {
+    let policy = DeliveryPolicy {
+        write_timeout: Some(Duration::from_millis(1)),
+        max_strikes: Some(u32::MAX),
+        queue_depth: 10,
+        ..DeliveryPolicy::default()
+    };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    let addr = ([127, 0, 0, 1], 9000).into();
+    let (server_end, mut receiver) = duplex();
+    server_end.set_peer_addr(addr);
+    // room for the start of the first frame only
+    server_end.set_capacity(Some(4));
+    destinations.add(server_end);
+
+    let frames: Vec<_> = (0..3u8).map(|i| build_frame(&[i; 10], false).unwrap()).collect();
+    for frame in &frames {
+        broadcast_message(&frame[..8], &frame[8..], &destinations);
+    }
+
+    let pending = destinations.take_pending(addr);
+    let pending: Vec<_> = pending.iter().map(|frame| frame.as_slice()).collect();
+    assert_eq!(pending, frames.iter().map(Vec::as_slice).collect::<Vec<_>>());
+    assert!(destinations.is_empty());
+    assert_eq!(receiver.read_available(), frames[0][..4]);
+    assert!(destinations.take_pending(addr).is_empty());
+}
+
+#[test]
+fn a_receiver_added_during_broadcasts_misses_no_frame_after_its_first() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (busy_end, mut busy) = duplex();
+    destinations.add(busy_end);
+    let broadcaster = {
+        let destinations = destinations.clone();
+        // keeps going until well after the late receiver has joined
+        thread::spawn(move || {
+            let mut after_join = 0;
+            let mut sent = 0u32;
+            while after_join < 100 {
+                let frame = build_frame(&sent.to_be_bytes(), false).unwrap();
+                broadcast_message(&frame[..8], &frame[8..], &destinations);
+                sent += 1;
+                if destinations.len() == 2 {
+                    after_join += 1;
+                }
+            }
+            sent
+        })
+    };
+
+    // joins while frames are being broadcast, often with one in progress
+    thread::sleep(Duration::from_millis(1));
+    let (late_end, mut late) = duplex();
+    destinations.add(late_end);
+    let sent = broadcaster.join().unwrap();
+
+    let bytes = late.read_available();
+    let mut reader = &bytes[..];
+    let mut received = Vec::new();
+    while !reader.is_empty() {
+        let payload = read_frame(&mut reader).unwrap().into_payload();
+        received.push(u32::from_be_bytes(payload.try_into().unwrap()));
+    }
+    let first = received[0];
+    assert_eq!(received, (first..sent).collect::<Vec<_>>());
+    assert!(busy.read_available().len() > bytes.len());
+}
+
+#[test]
+fn destinations_built_from_streams_get_every_frame() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut clients = Vec::new();
+    let mut accepted = Vec::new();
+    for _ in 0..2 {
+        clients.push(TcpStream::connect(addr).unwrap());
+        accepted.push(listener.accept().unwrap().0);
+    }
+    let policy = DeliveryPolicy { write_timeout: Some(Duration::from_millis(250)), ..DeliveryPolicy::default() };
+    let destinations = Destinations::from_streams(accepted).with_policy(policy);
+
+    let frame = build_frame(b"to both", true).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], &destinations);
+
+    for client in &mut clients {
+        assert_eq!(read_frame(client).unwrap().payload(), b"to both");
+    }
+    let receivers = destinations.clone_inner();
+    for receiver in receivers.lock().unwrap().iter() {
+        assert!(receiver.stream().nodelay().unwrap());
+        // the kernel rounds timeouts to its clock tick
+        let timeout = receiver.stream().write_timeout().unwrap().unwrap();
+        assert!(timeout >= Duration::from_millis(250) && timeout < Duration::from_millis(300), "{:?}", timeout);
+    }
+}
+
+#[test]
+fn the_fanout_histogram_counts_receivers_reached_per_frame() {
+    let policy = DeliveryPolicy {
+        write_timeout: Some(Duration::from_millis(1)),
+        max_strikes: Some(u32::MAX),
+        ..DeliveryPolicy::default()
+    };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+    // a receiver that takes nothing is not reached, so never counts
+    let (stalled_end, _stalled) = duplex();
+    stalled_end.set_capacity(Some(0));
+    destinations.add(stalled_end);
+    let mut receivers = Vec::new();
+    let frame = build_frame(b"fan out", false).unwrap();
+    for count in [0, 1, 3, 8] {
+        while receivers.len() < count {
+            let (server_end, receiver) = duplex();
+            destinations.add(server_end);
+            receivers.push(receiver);
+        }
+        broadcast_message(&frame[..8], &frame[8..], &destinations);
+    }
+
+    let fanout = destinations.metrics().fanout.snapshot();
+    assert_eq!(fanout.bounds, FANOUT_BUCKETS);
+    assert_eq!(fanout.count, 4);
+    assert_eq!(fanout.sum, 12);
+    assert_eq!(fanout.max, 8);
+    // one frame each in the buckets up to 0, 1, 5 and 10
+    let mut expected = vec![0; FANOUT_BUCKETS.len() + 1];
+    for bucket in [0, 1, 3, 4] {
+        expected[bucket] = 1;
+    }
+    assert_eq!(fanout.buckets, expected);
+}
@@ -0,0 +1,53 @@
+//! Reading CTMP frames off a stream.
+
+use std::error::Error;
+use std::io::{self, ErrorKind, Read};
+
+use coretech_wirestorm::{build_frame, read_frame, CtmpError};
+
+// A reader that hands out the start of a frame and then fails.
+struct FailingReader {
+    start: Vec<u8>,
+}
+
+impl Read for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start.is_empty() {
+            return Err(io::Error::new(ErrorKind::ConnectionReset, "reset by peer"));
+        }
+        let n = buf.len().min(self.start.len());
+        buf[..n].copy_from_slice(&self.start[..n]);
+        self.start.drain(..n);
+        Ok(n)
+    }
+}
+
+#[test]
+fn a_read_error_surfaces_as_ctmp_error_io() {
+    let frame = build_frame(b"interrupted", true).unwrap();
+    let mut reader = FailingReader { start: frame[..10].to_vec() };
+
+    let err = read_frame(&mut reader).unwrap_err();
+    assert!(matches!(&err, CtmpError::Io(e) if e.kind() == ErrorKind::ConnectionReset), "{:?}", err);
+    assert!(err.source().is_some());
+    // and converts back to the same io::Error for io::Result callers
+    assert_eq!(io::Error::from(err).kind(), ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn a_stream_ending_mid_frame_is_an_io_error() {
+    let frame = build_frame(b"cut short", false).unwrap();
+
+    let err = read_frame(&mut &frame[..frame.len() - 1]).unwrap_err();
+    assert!(matches!(&err, CtmpError::Io(e) if e.kind() == ErrorKind::UnexpectedEof), "{:?}", err);
+}
+
+#[test]
+fn protocol_errors_are_not_io_errors() {
+    let mut frame = build_frame(b"bad magic", false).unwrap();
+    frame[0] = 0xAB;
+
+    let err = read_frame(&mut &frame[..]).unwrap_err();
+    assert!(matches!(err, CtmpError::InvalidMagic(0xAB)), "{:?}", err);
+    assert_eq!(io::Error::from(err).kind(), ErrorKind::InvalidData);
+}
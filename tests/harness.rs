@@ -0,0 +1,139 @@
+//! End-to-end tests of the relay through the [`TestServer`] harness.
+
+#![cfg(feature = "testing")]
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use coretech_wirestorm::harness::{Pace, Scenario, TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::testing::build_frame_with_checksum;
+use coretech_wirestorm::{Config, CtmpFrame};
+
+#[test]
+fn relays_frames_to_every_receiver() {
+    let server = TestServer::start(Config::default());
+    let mut first = server.receiver();
+    let mut second = server.receiver();
+    let mut transmitter = server.transmitter();
+
+    transmitter.send(b"plain", false).unwrap();
+    transmitter.send(b"sensitive", true).unwrap();
+
+    for receiver in [&mut first, &mut second] {
+        let frame = receiver.recv().unwrap();
+        assert_eq!(frame.payload(), b"plain");
+        assert!(!frame.is_sensitive());
+        let frame = receiver.recv().unwrap();
+        assert_eq!(frame.payload(), b"sensitive");
+        assert!(frame.is_sensitive());
+    }
+    assert_eq!(server.handle().metrics().frames_relayed.get(), 2);
+}
+
+#[test]
+fn drops_a_sensitive_frame_with_a_corrupted_checksum() {
+    let server = TestServer::start(Config::default());
+    let mut receiver = server.receiver();
+
+    let good = CtmpFrame::new(b"intact".to_vec(), true).unwrap();
+    let corrupted = build_frame_with_checksum(b"corrupted", true, good.checksum()).unwrap();
+    let scenario = Scenario::new().send(&corrupted).send(&good.to_bytes());
+    let _transmitter = server.play(&scenario);
+
+    // the corrupted frame never arrives, so the intact one is the first through
+    assert_eq!(receiver.recv().unwrap().payload(), b"intact");
+    let metrics = server.handle().metrics();
+    assert_eq!(metrics.frames_rejected.get(), 1);
+    assert_eq!(metrics.frames_relayed.get(), 1);
+}
+
+#[test]
+fn rejects_a_second_transmitter() {
+    let server = TestServer::start(Config::default());
+    let mut receiver = server.receiver();
+    let mut first = server.transmitter();
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+
+    let mut second = server.transmitter();
+    let rejected = &server.handle().metrics().transmitters_rejected;
+    assert!(wait_for(|| rejected.get() == 1));
+    // frames from the refused connection go nowhere, while the first keeps relaying
+    let _ = second.send(b"ignored", false);
+    first.send(b"accepted", false).unwrap();
+
+    assert_eq!(receiver.recv().unwrap().payload(), b"accepted");
+    assert_eq!(server.handle().transmitter_count(), 1);
+    assert_eq!(server.handle().metrics().frames_relayed.get(), 1);
+}
+
+#[test]
+fn prunes_a_receiver_that_disconnects() {
+    let server = TestServer::start(Config::default());
+    let mut staying = server.receiver();
+    let leaving = server.receiver();
+    let mut transmitter = server.transmitter();
+
+    drop(leaving);
+    // the relay only notices the closed connection when a write to it fails
+    let mut sent = 0;
+    while server.handle().destinations().len() > 1 && sent < 100 {
+        transmitter.send(b"tick", false).unwrap();
+        sent += 1;
+        let _ = server.wait_for_destinations(1, Duration::from_millis(20));
+    }
+
+    assert_eq!(server.handle().destinations().len(), 1);
+    assert_eq!(server.handle().metrics().destinations_evicted.get(), 1);
+    for _ in 0..sent {
+        assert_eq!(staying.recv().unwrap().payload(), b"tick");
+    }
+}
+
+#[test]
+fn shuts_down_every_thread_with_clients_still_connected() {
+    let config = Config {
+        keepalive_interval: Some(Duration::from_millis(20)),
+        stats_log_interval: Some(Duration::from_millis(20)),
+        destination_label_wait: Some(Duration::from_millis(50)),
+        max_handshakes: Some(2),
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let _receiver = server.receiver();
+    let _stalled = server.slow_receiver(Pace::StopAfter(0));
+    let mut transmitter = server.transmitter();
+    transmitter.send(b"hello", false).unwrap();
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+    // a receiver still in its handshake when the server stops
+    let _handshaking = TcpStream::connect(server.destination_addr()).unwrap();
+
+    assert!(server.handle().running_threads() > 0);
+    // dropping the server panics if any of its threads outlive the shutdown
+    drop(server);
+}
+
+#[test]
+fn shutdown_waits_for_a_transmitter_mid_frame() {
+    let mut config = Config::default();
+    config.transmitter.max_session = Some(Duration::from_secs(60));
+    let server = TestServer::start(config);
+    let mut transmitter = server.play(&Scenario::new());
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+    let frame = CtmpFrame::new(b"half".to_vec(), false).unwrap().to_bytes();
+    transmitter.write_all(&frame[..6]).unwrap();
+
+    drop(server);
+}
+
+// Polls `condition` until it holds, for up to the harness's default timeout.
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = std::time::Instant::now() + DEFAULT_TIMEOUT;
+    while !condition() {
+        if std::time::Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
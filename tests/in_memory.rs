@@ -0,0 +1,120 @@
+//! Frame round trips through the relay entirely in memory, over [`duplex`] streams.
+
+#![cfg(feature = "testing")]
+
+use std::io::{ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use coretech_wirestorm::testing::{build_frame_with_checksum, duplex, DuplexStream};
+use coretech_wirestorm::{
+    build_frame, handle_transmitter, read_frame, Destinations, DisconnectReason, TransmitterPolicy,
+};
+
+// Runs a transmitter session over `input`, closed once it has all been written.
+fn relay(input: &[u8], destinations: &Destinations<DuplexStream>) -> DisconnectReason {
+    let (server_end, mut transmitter) = duplex();
+    transmitter.write_all(input).unwrap();
+    transmitter.close_write();
+    let slot = Arc::new(Mutex::new(None::<DuplexStream>));
+    handle_transmitter(server_end, None, destinations.clone(), slot, TransmitterPolicy::default())
+}
+
+#[test]
+fn frames_make_the_round_trip() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+
+    let mut input = build_frame(b"plain", false).unwrap();
+    input.extend(build_frame(b"secret", true).unwrap());
+    assert!(matches!(relay(&input, &destinations), DisconnectReason::Closed));
+
+    assert_eq!(read_frame(&mut receiver).unwrap().payload(), b"plain");
+    let frame = read_frame(&mut receiver).unwrap();
+    assert_eq!((frame.payload(), frame.is_sensitive()), (&b"secret"[..], true));
+    assert_eq!(receiver.available(), 0);
+}
+
+#[test]
+fn a_corrupted_sensitive_frame_is_dropped() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+
+    let mut input = build_frame_with_checksum(b"corrupted", true, 0xBEEF).unwrap();
+    input.extend(build_frame(b"intact", true).unwrap());
+    relay(&input, &destinations);
+
+    assert_eq!(read_frame(&mut receiver).unwrap().payload(), b"intact");
+    assert_eq!(receiver.available(), 0);
+    assert_eq!(destinations.metrics().frames_rejected.get(), 1);
+}
+
+#[test]
+fn short_writes_still_deliver_whole_frames() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    server_end.set_max_write(Some(3));
+    destinations.add(server_end);
+
+    let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    relay(&build_frame(&payload, true).unwrap(), &destinations);
+
+    assert_eq!(read_frame(&mut receiver).unwrap().payload(), payload);
+    assert_eq!(destinations.len(), 1);
+}
+
+#[test]
+fn a_frame_cut_off_by_end_of_stream_is_never_relayed() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, receiver) = duplex();
+    destinations.add(server_end);
+
+    let mut input = build_frame(b"whole", false).unwrap();
+    let cut = build_frame(b"cut off", false).unwrap();
+    input.extend(&cut[..cut.len() - 3]);
+    let reason = relay(&input, &destinations);
+
+    assert!(matches!(reason, DisconnectReason::ReadFailed(ErrorKind::UnexpectedEof)), "{:?}", reason);
+    assert_eq!(receiver.available(), build_frame(b"whole", false).unwrap().len());
+    assert_eq!(destinations.metrics().frames_relayed.get(), 1);
+    assert_eq!(destinations.metrics().transport_disconnects.get(), 1);
+}
+
+#[test]
+fn a_receiver_that_closes_is_evicted() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (closed_end, closed) = duplex();
+    let (open_end, mut open) = duplex();
+    destinations.add(closed_end);
+    destinations.add(open_end);
+    drop(closed);
+
+    relay(&build_frame(b"after", false).unwrap(), &destinations);
+
+    assert_eq!(destinations.len(), 1);
+    assert_eq!(destinations.metrics().destinations_evicted.get(), 1);
+    assert_eq!(read_frame(&mut open).unwrap().payload(), b"after");
+}
+
+#[test]
+fn a_transmitter_can_be_driven_from_another_thread() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source_end, mut transmitter) = duplex();
+
+    let session = {
+        let destinations = destinations.clone();
+        let slot = Arc::new(Mutex::new(None::<DuplexStream>));
+        thread::spawn(move || handle_transmitter(source_end, None, destinations, slot, TransmitterPolicy::default()))
+    };
+    for i in 0..10u8 {
+        transmitter.write_all(&build_frame(&[i; 16], false).unwrap()).unwrap();
+        assert_eq!(read_frame(&mut receiver).unwrap().payload(), [i; 16]);
+    }
+    drop(transmitter);
+
+    assert!(matches!(session.join().unwrap(), DisconnectReason::Closed));
+}
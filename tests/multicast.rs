@@ -0,0 +1,48 @@
+//! Broadcasting over UDP multicast on the loopback interface.
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use coretech_wirestorm::config::MulticastConfig;
+use coretech_wirestorm::multicast;
+use coretech_wirestorm::{broadcast_message, build_frame, Destinations, MulticastDestination};
+
+// Joins a group on the loopback interface, on a port nothing else is using.
+fn join_loopback_group(group: Ipv4Addr) -> (UdpSocket, MulticastConfig) {
+    let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let group = SocketAddrV4::new(group, port);
+    let socket = multicast::join(group, Ipv4Addr::LOCALHOST).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let config = MulticastConfig { interface: Ipv4Addr::LOCALHOST, ..MulticastConfig::new(group) };
+    (socket, config)
+}
+
+#[test]
+fn a_small_frame_reaches_the_group() {
+    let (socket, config) = join_loopback_group(Ipv4Addr::new(239, 255, 42, 1));
+    let destinations = Destinations::new().with_multicast(MulticastDestination::new(&config).unwrap());
+
+    let frame = build_frame(b"to the group", true).unwrap();
+    broadcast_message(&frame[..8], &frame[8..], &destinations);
+
+    let mut buf = [0u8; 2048];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &frame[..]);
+}
+
+#[test]
+fn frames_over_the_mtu_are_not_sent() {
+    let (socket, config) = join_loopback_group(Ipv4Addr::new(239, 255, 42, 2));
+    let config = MulticastConfig { mtu: 64, ..config };
+    let destinations = Destinations::new().with_multicast(MulticastDestination::new(&config).unwrap());
+
+    let large = build_frame(&[7; 100], false).unwrap();
+    broadcast_message(&large[..8], &large[8..], &destinations);
+    let small = build_frame(b"fits", false).unwrap();
+    broadcast_message(&small[..8], &small[8..], &destinations);
+
+    // the first datagram to arrive is the small frame, as the large one was never sent
+    let mut buf = [0u8; 2048];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &small[..]);
+}
@@ -0,0 +1,66 @@
+//! The thread pool transmitter sessions run on.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::{AutoscalePolicy, Metrics, ThreadPool};
+
+#[test]
+fn job_wait_and_run_times_are_recorded() {
+    let metrics = Arc::new(Metrics::new());
+    let pool = ThreadPool::new(1).with_metrics(Arc::clone(&metrics));
+    // the second job waits in the queue for as long as the first runs
+    for _ in 0..2 {
+        pool.execute(|| thread::sleep(Duration::from_millis(30)));
+    }
+    drop(pool);
+
+    let exec = metrics.pool_exec_time.snapshot();
+    assert_eq!(exec.count, 2);
+    assert!(exec.max >= 30_000, "{:?}", exec);
+    let wait = metrics.pool_queue_wait.snapshot();
+    assert_eq!(wait.count, 2);
+    assert!(wait.max >= 20_000, "{:?}", wait);
+    assert!(wait.sum - wait.max < 20_000, "only one job should have waited: {:?}", wait);
+}
+
+#[test]
+fn jobs_keep_running_after_one_panics() {
+    let pool = ThreadPool::new(2);
+    pool.execute(|| panic!("job failed"));
+    let (done, finished) = mpsc::channel();
+    for i in 0..5 {
+        let done = done.clone();
+        pool.execute(move || done.send(i).unwrap());
+    }
+
+    let mut ran: Vec<_> = (0..5).map(|_| finished.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+    ran.sort();
+    assert_eq!(ran, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn an_autoscaling_pool_grows_for_a_burst_and_shrinks_once_idle() {
+    let policy = AutoscalePolicy { min: 2, max: 6, idle_timeout: Duration::from_millis(50) };
+    let pool = ThreadPool::autoscaling(policy);
+    assert_eq!(pool.size(), 2);
+
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+    for _ in 0..10 {
+        let wait = Arc::clone(&wait);
+        pool.execute(move || {
+            let _ = wait.lock().unwrap().recv();
+        });
+    }
+    assert_eq!(pool.size(), 6);
+
+    drop(release);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.size() > 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(pool.size(), 2);
+    assert_eq!(pool.pending_jobs(), 0);
+}
@@ -0,0 +1,50 @@
+//! Broadcasting a capture file to receivers without a live transmitter.
+
+#![cfg(feature = "testing")]
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use coretech_wirestorm::harness::TestServer;
+use coretech_wirestorm::{build_frame, Config, PreloadConfig};
+
+// Writes a capture of a plain and a sensitive frame, named after the test using it.
+fn two_frame_capture(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("wirestorm-preload-{}-{}", name, std::process::id()));
+    let mut capture = build_frame(b"first", false).unwrap();
+    capture.extend(build_frame(b"second", true).unwrap());
+    fs::write(&path, capture).unwrap();
+    path
+}
+
+#[test]
+fn a_receiver_gets_every_preloaded_frame() {
+    let path = two_frame_capture("once");
+    let server = TestServer::start(Config { preload: Some(PreloadConfig::new(path.clone())), ..Config::default() });
+    let mut receiver = server.receiver();
+
+    assert_eq!(receiver.recv().unwrap().payload(), b"first");
+    let second = receiver.recv().unwrap();
+    assert_eq!(second.payload(), b"second");
+    assert!(second.is_sensitive());
+    // sent once only
+    drop(server);
+    assert!(receiver.recv().is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn a_looping_preload_repeats_the_capture() {
+    let path = two_frame_capture("loop");
+    let preload = PreloadConfig { repeat: true, interval: Duration::from_millis(1), ..PreloadConfig::new(path.clone()) };
+    let server = TestServer::start(Config { preload: Some(preload), ..Config::default() });
+    let mut receiver = server.receiver();
+
+    for _ in 0..3 {
+        assert_eq!(receiver.recv().unwrap().payload(), b"first");
+        assert_eq!(receiver.recv().unwrap().payload(), b"second");
+    }
+    drop(server);
+    fs::remove_file(&path).unwrap();
+}
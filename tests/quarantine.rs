@@ -0,0 +1,103 @@
+//! Turning away transmitter addresses that keep sending bad frames.
+
+#![cfg(feature = "testing")]
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{Scenario, TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::quarantine::Quarantine;
+use coretech_wirestorm::server::{admit_transmitter, RejectReason};
+use coretech_wirestorm::testing::{build_frame_with_checksum, duplex};
+use coretech_wirestorm::{Config, Metrics, QuarantinePolicy};
+
+const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+fn policy(max_offenses: u32) -> QuarantinePolicy {
+    QuarantinePolicy { max_offenses, window: Duration::from_secs(10), cooldown: Duration::from_secs(30) }
+}
+
+// Polls `condition` until it holds, giving up after `DEFAULT_TIMEOUT`.
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
+#[test]
+fn an_offending_address_is_quarantined_until_the_cooldown_passes() {
+    let mut quarantine = Quarantine::new(policy(3));
+    let start = Instant::now();
+
+    assert!(!quarantine.record(LOCALHOST, 2, start));
+    assert_eq!(quarantine.admit(Some(LOCALHOST), start), Ok(()));
+    assert!(quarantine.record(LOCALHOST, 1, start + Duration::from_secs(1)));
+
+    let quarantined = start + Duration::from_secs(1);
+    assert_eq!(quarantine.admit(Some(LOCALHOST), quarantined), Err(RejectReason::Quarantined));
+    let almost = quarantined + Duration::from_secs(30) - Duration::from_millis(1);
+    assert_eq!(quarantine.admit(Some(LOCALHOST), almost), Err(RejectReason::Quarantined));
+    assert_eq!(quarantine.admit(Some(LOCALHOST), quarantined + Duration::from_secs(30)), Ok(()));
+    // and the offenses before it do not count again
+    assert!(!quarantine.record(LOCALHOST, 1, quarantined + Duration::from_secs(31)));
+}
+
+#[test]
+fn offenses_outside_the_window_do_not_count() {
+    let mut quarantine = Quarantine::new(policy(2));
+    let start = Instant::now();
+
+    assert!(!quarantine.record(LOCALHOST, 1, start));
+    assert!(!quarantine.record(LOCALHOST, 1, start + Duration::from_secs(10)));
+    assert_eq!(quarantine.admit(Some(LOCALHOST), start + Duration::from_secs(10)), Ok(()));
+    assert!(quarantine.record(LOCALHOST, 1, start + Duration::from_secs(11)));
+}
+
+#[test]
+fn a_transmitter_is_not_blamed_for_another_sessions_bad_frames() {
+    let config = Config { allow_multiple_sources: true, quarantine: Some(policy(2)), ..Config::default() };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut good = server.transmitter();
+    good.send(b"first", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"first");
+
+    // while the good transmitter is connected, another sends a bad frame and leaves
+    let bad = build_frame_with_checksum(b"bad checksum", true, 0).unwrap();
+    drop(server.play(&Scenario::new().send(&bad)));
+    let metrics = server.handle().metrics();
+    assert!(wait_for(|| metrics.frames_rejected.get() == 1 && server.handle().transmitter_count() == 1));
+    good.send(b"second", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"second");
+    drop(good);
+    assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+
+    // one offense in all, so the address is still let in
+    let mut next = server.transmitter();
+    next.send(b"still welcome", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"still welcome");
+    assert_eq!(metrics.rejected_quarantined.get(), 0);
+}
+
+#[test]
+fn a_transmitter_whose_address_cannot_be_read_is_refused() {
+    let quarantine = Mutex::new(Quarantine::new(policy(2)));
+    let metrics = Metrics::new();
+    // a duplex stream's peer_addr fails until an address is set
+    let (stream, _transmitter) = duplex();
+
+    assert!(!admit_transmitter(&stream, &quarantine, &metrics));
+    assert_eq!(metrics.rejections(RejectReason::UnknownPeer).get(), 1);
+    assert_eq!(metrics.transmitters_rejected.get(), 1);
+
+    stream.set_peer_addr((LOCALHOST, 9000).into());
+    assert!(admit_transmitter(&stream, &quarantine, &metrics));
+    assert_eq!(metrics.transmitters_rejected.get(), 1);
+}
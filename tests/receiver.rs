@@ -0,0 +1,148 @@
+//! What receivers see of the relay's control frames.
+
+#![cfg(feature = "testing")]
+
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::testing::{duplex, DuplexStream};
+use coretech_wirestorm::{read_frame, Config, CtmpError, DeliveryPolicy, Destinations, Server};
+
+#[test]
+fn a_goodbye_frame_precedes_the_close_on_shutdown() {
+    let mut config = Config::default();
+    config.delivery.goodbye = true;
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+    transmitter.send(b"last frame", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"last frame");
+
+    drop(server);
+
+    assert_eq!(receiver.recv().unwrap_err().kind(), ErrorKind::ConnectionAborted);
+    assert!(receiver.closed_by_relay());
+    assert_eq!(receiver.recv().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn without_goodbye_frames_a_shutdown_is_a_plain_close() {
+    let server = TestServer::start(Config::default());
+    let mut receiver = server.receiver();
+
+    drop(server);
+
+    assert_eq!(receiver.recv().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert!(!receiver.closed_by_relay());
+}
+
+#[test]
+fn a_receiver_that_fails_its_probe_is_not_added() {
+    let policy = DeliveryPolicy {
+        probe: true,
+        write_timeout: Some(Duration::from_millis(10)),
+        ..DeliveryPolicy::default()
+    };
+    let destinations = Destinations::<DuplexStream>::default().with_policy(policy);
+
+    let (closed_end, closed) = duplex();
+    drop(closed);
+    assert!(!destinations.add(closed_end));
+    let (stalled_end, _stalled) = duplex();
+    stalled_end.set_capacity(Some(0));
+    assert!(!destinations.add(stalled_end));
+    assert!(destinations.is_empty());
+
+    let (healthy_end, mut healthy) = duplex();
+    assert!(destinations.add(healthy_end));
+    assert_eq!(destinations.len(), 1);
+    assert!(read_frame(&mut healthy).unwrap().is_probe());
+}
+
+#[test]
+fn probes_are_skipped_by_receivers() {
+    let mut config = Config::default();
+    config.delivery.probe = true;
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+    transmitter.send(b"after the probe", false).unwrap();
+
+    assert_eq!(receiver.recv().unwrap().payload(), b"after the probe");
+}
+
+#[test]
+fn no_more_than_the_cap_of_receivers_handshake_at_once() {
+    let wait = Duration::from_millis(100);
+    // receivers that never send a label hold their handshake for the whole wait
+    let config = Config { max_handshakes: Some(3), destination_label_wait: Some(wait), ..Config::default() };
+    let server = TestServer::start(config);
+
+    let started = Instant::now();
+    let _receivers: Vec<_> = (0..9).map(|_| TcpStream::connect(server.destination_addr()).unwrap()).collect();
+    loop {
+        let elapsed = started.elapsed();
+        let registered = server.handle().destinations().len();
+        // each handshake takes the whole wait, so at most one batch of 3 finishes per wait
+        let batches = (elapsed.as_millis() / wait.as_millis()) as usize;
+        assert!(registered <= 3 * batches, "{} registered after {:?}", registered, elapsed);
+        if registered == 9 {
+            break;
+        }
+        assert!(elapsed < DEFAULT_TIMEOUT, "only {} registered", registered);
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(started.elapsed() >= 3 * wait);
+}
+
+#[test]
+fn receivers_connecting_during_shutdown_are_closed_cleanly() {
+    let mut config = Config {
+        source_addr: "127.0.0.1:0".into(),
+        destination_addr: "127.0.0.1:0".into(),
+        ..Config::default()
+    };
+    config.delivery.goodbye = true;
+    let mut server = Server::bind(config).and_then(Server::spawn).unwrap();
+    let addr = server.destination_addr().unwrap();
+
+    // keeps connecting until the listener is gone, so some arrive as shutdown begins;
+    // a connection attempt left waiting on a full backlog is given up on
+    let stop = Arc::new(AtomicBool::new(false));
+    let connector = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut receivers = Vec::new();
+            while !stop.load(Ordering::SeqCst) {
+                match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+                    Ok(stream) => receivers.push(stream),
+                    Err(_) => break,
+                }
+            }
+            receivers
+        })
+    };
+    thread::sleep(Duration::from_millis(20));
+    server.shutdown();
+    assert!(server.destinations().is_empty());
+    stop.store(true, Ordering::SeqCst);
+    let receivers = connector.join().unwrap();
+
+    assert!(!receivers.is_empty());
+    for mut receiver in receivers {
+        receiver.set_read_timeout(Some(DEFAULT_TIMEOUT)).unwrap();
+        match read_frame(&mut receiver) {
+            Ok(frame) => {
+                assert!(frame.is_goodbye(), "{:?}", frame);
+                let end = read_frame(&mut receiver).unwrap_err();
+                assert!(matches!(&end, CtmpError::Io(e) if e.kind() == ErrorKind::UnexpectedEof), "{:?}", end);
+            }
+            Err(e) => assert!(matches!(&e, CtmpError::Io(io) if io.kind() == ErrorKind::UnexpectedEof), "{:?}", e),
+        }
+    }
+}
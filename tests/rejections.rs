@@ -0,0 +1,90 @@
+//! Why transmitters are turned away, as counted in the metrics.
+
+#![cfg(feature = "testing")]
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{Scenario, TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::server::RejectReason;
+use coretech_wirestorm::testing::build_frame_with_checksum;
+use coretech_wirestorm::{Config, QuarantinePolicy};
+
+// Polls `condition` until it holds, giving up after `DEFAULT_TIMEOUT`.
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
+#[test]
+fn a_second_transmitter_is_counted_as_already_active() {
+    let server = TestServer::start(Config::default());
+    let _first = server.transmitter();
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+    let _second = server.transmitter();
+    let _third = server.transmitter();
+    let metrics = server.handle().metrics();
+    assert!(wait_for(|| metrics.transmitters_rejected.get() == 2));
+
+    let status = server.handle().status();
+    assert_eq!(status.metrics.rejected_already_active, 2);
+    assert_eq!(status.metrics.rejections(RejectReason::AlreadyActive), 2);
+    assert_eq!(status.metrics.rejections(RejectReason::Quarantined), 0);
+    assert_eq!(
+        status.rejections_json(),
+        r#"{"total":2,"already_active":2,"quarantined":0,"unknown_peer":0,"pool_full":0}"#
+    );
+}
+
+#[test]
+fn a_quarantined_transmitter_is_counted_as_quarantined() {
+    let quarantine = QuarantinePolicy { max_offenses: 1, window: Duration::from_secs(60), cooldown: Duration::from_secs(60) };
+    let server = TestServer::start(Config { quarantine: Some(quarantine), ..Config::default() });
+    let bad = build_frame_with_checksum(b"bad checksum", true, 0).unwrap();
+    drop(server.play(&Scenario::new().send(&bad)));
+    let metrics = server.handle().metrics();
+    assert!(wait_for(|| metrics.frames_rejected.get() == 1));
+
+    // the offense is recorded just after the session ends, so a transmitter that
+    // connects in between still gets in
+    loop {
+        assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+        let _next = server.transmitter();
+        assert!(wait_for(|| metrics.transmitters_rejected.get() == 1 || server.handle().transmitter_count() == 1));
+        if metrics.transmitters_rejected.get() == 1 {
+            break;
+        }
+    }
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.rejected_quarantined, 1);
+    assert_eq!(snapshot.rejections(RejectReason::Quarantined), 1);
+    assert_eq!(snapshot.rejected_already_active, 0);
+    assert!(server.handle().status().rejections_json().contains(r#""quarantined":1"#));
+}
+
+#[test]
+fn a_transmitter_with_no_free_thread_is_counted_as_pool_full() {
+    let config = Config { allow_multiple_sources: true, thread_count: 1, reject_when_pool_full: true, ..Config::default() };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut first = server.transmitter();
+    assert!(wait_for(|| server.handle().transmitter_count() == 1));
+
+    let _second = server.transmitter();
+    let metrics = server.handle().metrics();
+    assert!(wait_for(|| metrics.transmitters_rejected.get() == 1));
+    assert_eq!(metrics.snapshot().rejections(RejectReason::PoolFull), 1);
+    assert_eq!(metrics.snapshot().rejections(RejectReason::AlreadyActive), 0);
+
+    // the first keeps its thread
+    first.send(b"still served", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"still served");
+    assert_eq!(server.handle().transmitter_count(), 1);
+}
@@ -0,0 +1,69 @@
+//! The connected transmitter's session stats.
+
+#![cfg(feature = "testing")]
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{Scenario, TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::testing::build_frame_with_checksum;
+use coretech_wirestorm::metrics::SessionSnapshot;
+use coretech_wirestorm::{build_frame, Config};
+
+// Waits for the connected transmitter's session to count `frames` broadcast, which
+// happens just after receivers are sent them.
+fn session_with(server: &TestServer, frames: u64) -> SessionSnapshot {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    loop {
+        let session = server.handle().transmitter_session();
+        if let Some(session) = session
+            && (session.frames >= frames || Instant::now() >= deadline)
+        {
+            return session;
+        }
+        assert!(Instant::now() < deadline, "no transmitter session");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn session_stats_match_what_the_transmitter_sent() {
+    let server = TestServer::start(Config::default());
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+    for payload in [&b"one"[..], b"three", b"II"] {
+        transmitter.send(payload, true).unwrap();
+        receiver.recv().unwrap();
+    }
+
+    let session = session_with(&server, 3);
+    assert_eq!((session.frames, session.bytes), (3, 10));
+    assert_eq!((session.checksum_failures, session.rejected), (0, 0));
+    assert!(session.duration > Duration::ZERO);
+    assert_eq!(server.handle().status().metrics.transmitter_session.map(|session| session.frames), Some(3));
+
+    drop(transmitter);
+    assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+    assert!(server.handle().transmitter_session().is_none());
+}
+
+#[test]
+fn each_transmitter_starts_a_fresh_session() {
+    let server = TestServer::start(Config::default());
+    let mut receiver = server.receiver();
+    let mut first = server.transmitter();
+    first.send(b"from the first", false).unwrap();
+    receiver.recv().unwrap();
+    drop(first);
+    assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+
+    let bad = build_frame_with_checksum(b"bad", true, 0).unwrap();
+    let scenario = Scenario::new().send(&bad).send(&build_frame(b"good", false).unwrap());
+    let _second = server.play(&scenario);
+    assert_eq!(receiver.recv().unwrap().payload(), b"good");
+
+    let session = session_with(&server, 1);
+    assert_eq!((session.frames, session.bytes), (1, 4));
+    assert_eq!(session.checksum_failures, 1);
+    assert_eq!(session.rejected, 0);
+}
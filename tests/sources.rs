@@ -0,0 +1,137 @@
+//! Transmitter listeners, each with its own policy.
+
+#![cfg(feature = "testing")]
+
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::{Config, CtmpClient, Server, SourceConfig, TransmitterPolicy, CTMP_MAX_PAYLOAD_SIZE};
+
+// Polls `condition` until it holds, for up to the harness's default timeout.
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+#[test]
+fn each_listener_applies_its_own_policy() {
+    let limited = TransmitterPolicy { max_payload: Some(10), ..TransmitterPolicy::default() };
+    let config = Config { extra_sources: vec![SourceConfig::new("127.0.0.1:0".into(), limited)], ..Config::default() };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let large = [7u8; 50];
+
+    let mut untrusted = CtmpClient::connect_source(server.handle().extra_source_addrs()[0]).unwrap();
+    untrusted.send(&large, false).unwrap();
+    untrusted.send(b"small", false).unwrap();
+    // the large frame was dropped, and the connection kept going
+    assert_eq!(receiver.recv().unwrap().payload(), b"small");
+    assert_eq!(server.handle().metrics().frames_rejected.get(), 1);
+    drop(untrusted);
+    assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+
+    let mut privileged = server.transmitter();
+    privileged.send(&large, false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), large);
+    assert_eq!(server.handle().metrics().frames_rejected.get(), 1);
+}
+
+#[test]
+fn listeners_share_the_transmitter_slot() {
+    let config = Config {
+        extra_sources: vec![SourceConfig::new("127.0.0.1:0".into(), TransmitterPolicy::default())],
+        ..Config::default()
+    };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut main = server.transmitter();
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+
+    let mut extra = CtmpClient::connect_source(server.handle().extra_source_addrs()[0]).unwrap();
+    let _ = extra.send(b"refused", false);
+    main.send(b"from the main listener", false).unwrap();
+
+    assert_eq!(receiver.recv().unwrap().payload(), b"from the main listener");
+    let rejected = &server.handle().metrics().rejected_already_active;
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while rejected.get() == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(rejected.get(), 1);
+    assert_eq!(server.handle().metrics().frames_relayed.get(), 1);
+}
+
+#[test]
+fn concurrent_transmitters_each_have_their_own_session() {
+    let config = Config { allow_multiple_sources: true, thread_count: 3, ..Config::default() };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut transmitters: Vec<_> = (0..3).map(|_| server.transmitter()).collect();
+    assert!(wait_for(|| server.handle().transmitter_count() == 3));
+
+    // the first sends one frame, the second two and the third three, all at once
+    thread::scope(|scope| {
+        for (i, transmitter) in transmitters.iter_mut().enumerate() {
+            scope.spawn(move || {
+                for _ in 0..=i {
+                    transmitter.send(&[i as u8; 4], false).unwrap();
+                }
+            });
+        }
+    });
+    let mut received: Vec<_> = (0..6).map(|_| receiver.recv().unwrap().payload()[0]).collect();
+    received.sort();
+    assert_eq!(received, [0, 1, 1, 2, 2, 2]);
+
+    let counts = || {
+        let mut counts: Vec<_> =
+            server.handle().transmitter_sessions().iter().map(|session| (session.frames, session.bytes)).collect();
+        counts.sort();
+        counts
+    };
+    assert!(wait_for(|| counts() == [(1, 4), (2, 8), (3, 12)]), "{:?}", counts());
+}
+
+#[test]
+fn a_transmitter_with_no_free_thread_is_held_until_one_frees_up() {
+    let config = Config { allow_multiple_sources: true, thread_count: 1, ..Config::default() };
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut first = server.transmitter();
+    assert!(wait_for(|| server.handle().transmitter_count() == 1));
+
+    // the only thread is busy with the first, so the second is accepted but not read
+    let mut second = server.transmitter();
+    first.send(b"first", false).unwrap();
+    second.send(b"held", false).unwrap();
+    first.send(b"still first", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"first");
+    assert_eq!(receiver.recv().unwrap().payload(), b"still first");
+
+    drop(first);
+    assert_eq!(receiver.recv().unwrap().payload(), b"held");
+    assert_eq!(server.handle().metrics().transmitters_rejected.get(), 0);
+}
+
+#[test]
+fn a_listener_limited_above_the_ceiling_is_refused() {
+    let over = TransmitterPolicy { max_payload: Some(1 << 20), ..TransmitterPolicy::default() };
+    let base = || Config { source_addr: "127.0.0.1:0".into(), destination_addr: "127.0.0.1:0".into(), ..Config::default() };
+    let main = Config { transmitter: over, ..base() };
+    let extra = Config { extra_sources: vec![SourceConfig::new("127.0.0.1:0".into(), over)], ..base() };
+    for config in [main, extra] {
+        let error = Server::bind(config).err().expect("bound with max_payload over the ceiling");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    let at = TransmitterPolicy { max_payload: Some(CTMP_MAX_PAYLOAD_SIZE), ..TransmitterPolicy::default() };
+    assert!(Server::bind(Config { transmitter: at, ..base() }).is_ok());
+}
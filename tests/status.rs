@@ -0,0 +1,214 @@
+//! The status snapshot of a running server.
+
+#![cfg(feature = "testing")]
+
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::net::SocketAddr;
+use std::str::Chars;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use coretech_wirestorm::harness::{TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::Config;
+
+// Just enough JSON to check the structure of what the status renders.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    // members in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Json {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars);
+        assert_eq!(chars.next(), None, "trailing characters in {}", text);
+        value
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+                .unwrap_or_else(|| panic!("no {} in {:?}", key, self)),
+            _ => panic!("{:?} is not an object", self),
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            Json::Object(members) => members.iter().map(|(name, _)| name.as_str()).collect(),
+            _ => panic!("{:?} is not an object", self),
+        }
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Json {
+    match chars.peek().copied() {
+        Some('{') => {
+            chars.next();
+            let mut members = Vec::new();
+            while chars.peek() != Some(&'}') {
+                let Json::String(name) = parse_value(chars) else { panic!("object keys are strings") };
+                assert_eq!(chars.next(), Some(':'));
+                members.push((name, parse_value(chars)));
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                }
+            }
+            chars.next();
+            Json::Object(members)
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            while chars.peek() != Some(&']') {
+                items.push(parse_value(chars));
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                }
+            }
+            chars.next();
+            Json::Array(items)
+        }
+        Some('"') => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next().expect("unterminated string") {
+                    '"' => break Json::String(string),
+                    '\\' => match chars.next().expect("unterminated escape") {
+                        'n' => string.push('\n'),
+                        'r' => string.push('\r'),
+                        't' => string.push('\t'),
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            string.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                        }
+                        c => string.push(c),
+                    },
+                    c => string.push(c),
+                }
+            }
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let mut number = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                number = number * 10 + u64::from(digit);
+                chars.next();
+            }
+            Json::Number(number)
+        }
+        _ => {
+            let word: String = std::iter::from_fn(|| chars.next_if(char::is_ascii_alphabetic)).collect();
+            match word.as_str() {
+                "null" => Json::Null,
+                "true" => Json::Bool(true),
+                "false" => Json::Bool(false),
+                _ => panic!("unexpected {:?}", word),
+            }
+        }
+    }
+}
+
+#[test]
+fn a_status_taken_mid_broadcast_is_internally_consistent() {
+    let server = TestServer::start(Config::default());
+    let mut receivers = vec![server.receiver()];
+    let mut transmitter = server.transmitter();
+    assert!(server.wait_for_transmitter(true, DEFAULT_TIMEOUT));
+    let stop = Arc::new(AtomicBool::new(false));
+    let sending = {
+        let stop = Arc::clone(&stop);
+        // few enough frames that unread receivers never fill up and get evicted
+        thread::spawn(move || {
+            for _ in 0..5000 {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                transmitter.send(b"tick", false).unwrap();
+            }
+            transmitter
+        })
+    };
+
+    let config_hash = server.handle().status().config_hash;
+    let mut last_uptime = Duration::ZERO;
+    for i in 0..200 {
+        if i % 20 == 0 {
+            receivers.push(server.receiver());
+        }
+        let status = server.handle().status();
+        assert_eq!(status.destination_count(), status.destinations.len());
+        let peers: HashSet<_> = status.destinations.iter().map(|dest| dest.peer_addr).collect();
+        assert_eq!(peers.len(), status.destination_count());
+        // every broadcast is counted under the same lock the receivers are read under
+        assert_eq!(status.generation, status.metrics.frames_relayed);
+        assert!(status.destinations.iter().all(|dest| dest.joined_at <= status.generation));
+        assert!(status.transmitter_connected);
+        assert_eq!(status.config_hash, config_hash);
+        assert!(status.uptime >= last_uptime);
+        last_uptime = status.uptime;
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    let _transmitter = sending.join().unwrap();
+    assert_eq!(server.handle().status().destination_count(), receivers.len());
+}
+
+#[test]
+fn the_receiver_list_renders_as_well_formed_json() {
+    let config = Config { destination_label_wait: Some(Duration::from_millis(100)), ..Config::default() };
+    let server = TestServer::start(config);
+    let _edge = server.labelled_receiver("edge \"1\" \\ a");
+    let _plain = server.receiver();
+    let status = server.handle().status();
+
+    let json = Json::parse(&status.destinations_json(10));
+    assert_eq!(json.keys(), ["total", "listed", "destinations"]);
+    assert_eq!(json.get("total"), &Json::Number(2));
+    assert_eq!(json.get("listed"), &Json::Number(2));
+    let Json::Array(destinations) = json.get("destinations") else { panic!("{:?}", json) };
+    assert_eq!(destinations.len(), 2);
+    let fields = [
+        "id", "addr", "label", "compressed", "joined_at", "strikes", "queued_frames", "queued_bytes",
+        "frames_dropped", "frames_expired",
+    ];
+    let mut ids = HashSet::new();
+    let mut labels = Vec::new();
+    for (dest, expected) in destinations.iter().zip(&status.destinations) {
+        assert_eq!(dest.keys(), fields);
+        let Json::Number(id) = dest.get("id") else { panic!("{:?}", dest) };
+        assert_eq!(*id, expected.id);
+        ids.insert(*id);
+        let Json::String(addr) = dest.get("addr") else { panic!("{:?}", dest) };
+        assert_eq!(addr.parse::<SocketAddr>().ok(), expected.peer_addr);
+        labels.push(match dest.get("label") {
+            Json::String(label) => Some(label.as_str()),
+            Json::Null => None,
+            other => panic!("{:?}", other),
+        });
+        assert_eq!(dest.get("compressed"), &Json::Bool(false));
+        for counter in &fields[4..] {
+            assert!(matches!(dest.get(counter), Json::Number(_)), "{:?}", dest);
+        }
+    }
+    assert_eq!(ids.len(), 2);
+    labels.sort();
+    assert_eq!(labels, [None, Some("edge \"1\" \\ a")]);
+
+    let json = Json::parse(&status.destinations_json(1));
+    assert_eq!(json.get("total"), &Json::Number(2));
+    assert_eq!(json.get("listed"), &Json::Number(1));
+    assert!(matches!(json.get("destinations"), Json::Array(listed) if listed.len() == 1));
+}
@@ -0,0 +1,61 @@
+//! Checks that a stopped server leaves no threads behind in the process.
+//!
+//! [`TestServer`] already checks the server's own count of its threads when dropped.
+//! This counts every thread the process has instead, so it also catches ones the
+//! server never counted, such as handshake threads and pool workers. It is the only
+//! test in its binary, so no other test's threads come and go while it counts.
+
+#![cfg(all(feature = "testing", target_os = "linux"))]
+
+use std::fs;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{Pace, TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::{AutoscalePolicy, Config};
+
+#[test]
+fn a_stopped_server_leaves_no_threads() {
+    let before = process_threads();
+    {
+        let config = Config {
+            allow_multiple_sources: true,
+            autoscale: Some(AutoscalePolicy { min: 1, max: 4, idle_timeout: Duration::from_millis(50) }),
+            keepalive_interval: Some(Duration::from_millis(20)),
+            stats_log_interval: Some(Duration::from_millis(20)),
+            destination_label_wait: Some(Duration::from_millis(50)),
+            max_handshakes: Some(2),
+            ..Config::default()
+        };
+        let server = TestServer::start(config);
+        let mut receiver = server.receiver();
+        let _stalled = server.slow_receiver(Pace::StopAfter(0));
+        let mut transmitters: Vec<_> = (0..3).map(|_| server.transmitter()).collect();
+        for transmitter in &mut transmitters {
+            transmitter.send(b"hello", false).unwrap();
+        }
+        for _ in &transmitters {
+            receiver.recv().unwrap();
+        }
+        let _handshaking = TcpStream::connect(server.destination_addr()).unwrap();
+        assert!(process_threads() > before);
+    }
+
+    // threads are reaped by the kernel a moment after they are joined
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while process_threads() > before && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(process_threads(), before);
+}
+
+// The number of threads in this process, from the kernel.
+fn process_threads() -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap()
+}
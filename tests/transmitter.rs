@@ -0,0 +1,405 @@
+//! How the relay reads and polices a transmitter's session.
+
+#![cfg(feature = "testing")]
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use coretech_wirestorm::harness::{TestServer, DEFAULT_TIMEOUT};
+use coretech_wirestorm::routing::routing_id;
+use coretech_wirestorm::testing::{duplex, DuplexStream};
+use coretech_wirestorm::{
+    build_frame, handle_transmitter, read_frame, ChecksumAlgorithm, ChecksumOverload, Config, Destinations,
+    DisconnectKind, DisconnectReason, FrameSample, TransmitterPolicy,
+};
+
+// A plain frame, with the continued bit set if more of its message follows.
+fn fragment(payload: &[u8], continued: bool) -> Vec<u8> {
+    let mut frame = build_frame(payload, false).unwrap();
+    if continued {
+        frame[1] |= 0x08;
+    }
+    frame
+}
+
+// Runs a transmitter session on `source` until it disconnects.
+fn run(source: DuplexStream, destinations: &Destinations<DuplexStream>, policy: TransmitterPolicy) -> DisconnectReason {
+    let slot = Arc::new(Mutex::new(None::<DuplexStream>));
+    handle_transmitter(source, None, destinations.clone(), slot, policy)
+}
+
+#[test]
+fn a_transmitter_is_disconnected_when_its_session_expires() {
+    let mut config = Config::default();
+    config.transmitter.max_session = Some(Duration::from_millis(200));
+    let server = TestServer::start(config);
+    let mut receiver = server.receiver();
+    let mut transmitter = server.transmitter();
+    transmitter.send(b"within the session", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"within the session");
+
+    let connected = Instant::now();
+    assert!(server.wait_for_transmitter(false, DEFAULT_TIMEOUT));
+    assert!(connected.elapsed() < Duration::from_secs(2));
+    assert!(server.handle().transmitter_session().is_none());
+
+    // the source slot is free for the next transmitter
+    let mut next = server.transmitter();
+    next.send(b"next session", false).unwrap();
+    assert_eq!(receiver.recv().unwrap().payload(), b"next session");
+    assert_eq!(server.handle().metrics().transmitters_rejected.get(), 0);
+}
+
+#[test]
+fn a_session_ends_between_frames_even_while_frames_keep_coming() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (source, mut transmitter) = duplex();
+    source.set_read_timeout(Some(Duration::from_millis(10)));
+    for _ in 0..1000 {
+        transmitter.write_all(&build_frame(b"tick", false).unwrap()).unwrap();
+    }
+    let policy = TransmitterPolicy { max_session: Some(Duration::ZERO), ..TransmitterPolicy::default() };
+
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::SessionExpired), "{:?}", reason);
+    assert_eq!(reason.kind(), DisconnectKind::Policy);
+    assert_eq!(destinations.metrics().frames_relayed.get(), 0);
+}
+
+#[test]
+fn a_stalled_partial_frame_times_out_at_the_session_limit() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (source, mut transmitter) = duplex();
+    source.set_read_timeout(Some(Duration::from_millis(10)));
+    let frame = build_frame(b"never finished", false).unwrap();
+    transmitter.write_all(&frame[..frame.len() - 4]).unwrap();
+    let policy = TransmitterPolicy { max_session: Some(Duration::from_millis(100)), ..TransmitterPolicy::default() };
+
+    let started = Instant::now();
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::SessionExpired), "{:?}", reason);
+    assert_eq!(reason.kind(), DisconnectKind::Policy);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(destinations.metrics().frames_relayed.get(), 0);
+    assert_eq!(destinations.metrics().transport_disconnects.get(), 0);
+    drop(transmitter);
+}
+
+#[test]
+fn a_stalled_header_times_out_at_the_session_limit() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (source, mut transmitter) = duplex();
+    source.set_read_timeout(Some(Duration::from_millis(10)));
+    transmitter.write_all(&build_frame(b"whole", false).unwrap()).unwrap();
+    transmitter.write_all(&[0xCC, 0]).unwrap();
+    let policy = TransmitterPolicy { max_session: Some(Duration::from_millis(100)), ..TransmitterPolicy::default() };
+
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::SessionExpired), "{:?}", reason);
+    assert_eq!(reason.kind(), DisconnectKind::Policy);
+    assert_eq!(destinations.metrics().frames_relayed.get(), 1);
+    drop(transmitter);
+}
+
+#[test]
+fn a_looped_frame_is_dropped() {
+    let destinations = Destinations::<DuplexStream>::default().with_loop_detection(64);
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+
+    let (source, mut transmitter) = duplex();
+    transmitter.write_all(&build_frame(b"around the mesh", true).unwrap()).unwrap();
+    transmitter.close_write();
+    run(source, &destinations, TransmitterPolicy::default());
+    let tagged = receiver.read_available();
+    assert!(read_frame(&mut &tagged[..]).is_ok());
+
+    // the broadcast comes back in as though from another relay in the chain
+    let (source, mut transmitter) = duplex();
+    transmitter.write_all(&tagged).unwrap();
+    transmitter.write_all(&build_frame(b"fresh", false).unwrap()).unwrap();
+    transmitter.close_write();
+    run(source, &destinations, TransmitterPolicy::default());
+
+    let bytes = receiver.read_available();
+    let mut reader = &bytes[..];
+    assert_eq!(read_frame(&mut reader).unwrap().payload(), b"fresh");
+    assert!(reader.is_empty());
+    assert_eq!(destinations.metrics().frames_rejected.get(), 1);
+    assert_eq!(destinations.metrics().frames_relayed.get(), 2);
+}
+
+#[test]
+fn frames_parse_the_same_through_small_and_large_read_buffers() {
+    let sizes = [1, 7, 15, 16, 17, 1000, 4096, 65_535];
+    let mut input = Vec::new();
+    for (i, &size) in sizes.iter().enumerate() {
+        let payload: Vec<u8> = (0..size).map(|b| (b + i) as u8).collect();
+        input.extend(build_frame(&payload, i % 2 == 0).unwrap());
+    }
+
+    for read_buffer_size in [16, 1024 * 1024] {
+        let destinations = Destinations::<DuplexStream>::default();
+        let (server_end, mut receiver) = duplex();
+        destinations.add(server_end);
+        let policy = TransmitterPolicy { read_buffer_size, ..TransmitterPolicy::default() };
+        let slot = Arc::new(Mutex::new(None::<DuplexStream>));
+        let reason = handle_transmitter(&input[..], None, destinations.clone(), slot, policy);
+
+        assert!(matches!(reason, DisconnectReason::Closed), "{:?}", reason);
+        assert_eq!(receiver.read_available(), input, "with a {} byte buffer", read_buffer_size);
+        assert_eq!(destinations.metrics().frames_rejected.get(), 0);
+    }
+}
+
+#[test]
+fn a_transmitter_is_disconnected_once_it_goes_over_its_quota() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source, mut transmitter) = duplex();
+    for i in 0..5u8 {
+        transmitter.write_all(&build_frame(&[i; 100], false).unwrap()).unwrap();
+    }
+    let policy = TransmitterPolicy { max_bytes_per_session: Some(250), ..TransmitterPolicy::default() };
+
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::QuotaExceeded), "{:?}", reason);
+    let bytes = receiver.read_available();
+    let mut reader = &bytes[..];
+    for i in 0..2u8 {
+        assert_eq!(read_frame(&mut reader).unwrap().payload(), [i; 100]);
+    }
+    assert!(reader.is_empty());
+    let metrics = destinations.metrics();
+    assert_eq!(metrics.transmitters_over_quota.get(), 1);
+    assert_eq!(metrics.frames_rejected.get(), 1);
+}
+
+#[test]
+fn dropped_fragments_do_not_use_up_the_quota() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source, mut transmitter) = duplex();
+    // a message over the size limit, which is dropped whole
+    transmitter.write_all(&fragment(&[1; 60], true)).unwrap();
+    transmitter.write_all(&fragment(&[1; 60], false)).unwrap();
+    transmitter.write_all(&fragment(&[2; 100], false)).unwrap();
+    transmitter.close_write();
+    let policy = TransmitterPolicy {
+        max_bytes_per_session: Some(150),
+        max_message_size: Some(100),
+        ..TransmitterPolicy::default()
+    };
+
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::Closed), "{:?}", reason);
+    assert_eq!(read_frame(&mut receiver).unwrap().payload(), [2; 100]);
+    assert_eq!(destinations.metrics().transmitters_over_quota.get(), 0);
+}
+
+#[test]
+fn a_message_cut_off_by_the_quota_sends_only_the_fragments_within_it() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source, mut transmitter) = duplex();
+    for (i, continued) in [(0u8, true), (1, true), (2, true), (3, false)] {
+        transmitter.write_all(&fragment(&[i; 40], continued)).unwrap();
+    }
+    let policy = TransmitterPolicy {
+        max_bytes_per_session: Some(100),
+        max_message_size: Some(1000),
+        ..TransmitterPolicy::default()
+    };
+
+    let reason = run(source, &destinations, policy);
+    assert!(matches!(reason, DisconnectReason::QuotaExceeded), "{:?}", reason);
+    let bytes = receiver.read_available();
+    let mut reader = &bytes[..];
+    for i in 0..2u8 {
+        assert_eq!(read_frame(&mut reader).unwrap().payload(), [i; 40]);
+    }
+    assert!(reader.is_empty());
+    // the fragment that went over and the one after it
+    assert_eq!(destinations.metrics().frames_rejected.get(), 2);
+}
+
+// Relays `input` through a relay with loop detection, returning what a receiver got.
+fn relay_with_loop_detection(input: &[u8], policy: TransmitterPolicy) -> Vec<u8> {
+    let destinations = Destinations::<DuplexStream>::default().with_loop_detection(64);
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source, mut transmitter) = duplex();
+    transmitter.write_all(input).unwrap();
+    transmitter.close_write();
+    run(source, &destinations, policy);
+    receiver.read_available()
+}
+
+#[test]
+fn a_tagged_sensitive_frame_is_resealed_so_receivers_can_verify_it() {
+    for checksum in [ChecksumAlgorithm::OnesComplement, ChecksumAlgorithm::Crc16] {
+        let policy = TransmitterPolicy { checksum, ..TransmitterPolicy::default() };
+        let mut input = build_frame(b"tagged on the way", false).unwrap();
+        input[1] |= 0x40;
+        let sealed = checksum.frame_checksum(&input[..8], &input[8..]);
+        input[4..6].copy_from_slice(&sealed.to_be_bytes());
+
+        let output = relay_with_loop_detection(&input, policy);
+        let (header, payload) = output.split_at(8);
+        assert!(routing_id(header).is_some());
+        assert_ne!(header, &input[..8]);
+        assert_eq!(payload, &input[8..]);
+        assert!(checksum.verify(header, payload), "{:?}", checksum);
+    }
+}
+
+#[test]
+fn frames_the_relay_does_not_change_keep_their_original_bytes() {
+    let tagged = relay_with_loop_detection(&build_frame(b"already routed", true).unwrap(), TransmitterPolicy::default());
+
+    // a second relay forwards the routing id it finds, leaving the frame as it was
+    assert_eq!(relay_with_loop_detection(&tagged, TransmitterPolicy::default()), tagged);
+
+    let destinations = Destinations::<DuplexStream>::default();
+    let (server_end, mut receiver) = duplex();
+    destinations.add(server_end);
+    let (source, mut transmitter) = duplex();
+    let plain = build_frame(b"untouched", true).unwrap();
+    transmitter.write_all(&plain).unwrap();
+    transmitter.close_write();
+    run(source, &destinations, TransmitterPolicy::default());
+    assert_eq!(receiver.read_available(), plain);
+}
+
+#[test]
+fn exactly_every_nth_frame_is_sampled() {
+    let samples = Arc::new(Mutex::new(Vec::new()));
+    let destinations = {
+        let samples = Arc::clone(&samples);
+        Destinations::<DuplexStream>::default().with_frame_log(move |sample: &FrameSample| {
+            samples.lock().unwrap().push(*sample)
+        })
+    };
+    let (source, mut transmitter) = duplex();
+    // frame i has an i-byte payload, so each sample shows which frame it was
+    for i in 1..=10 {
+        transmitter.write_all(&build_frame(&vec![0; i], i % 2 == 0).unwrap()).unwrap();
+    }
+    drop(transmitter);
+
+    let policy = TransmitterPolicy { log_every_nth_frame: Some(3), ..TransmitterPolicy::default() };
+    run(source, &destinations, policy);
+
+    let samples = samples.lock().unwrap();
+    let sampled: Vec<_> = samples.iter().map(|sample| (sample.index, sample.length, sample.sensitive)).collect();
+    assert_eq!(sampled, [(3, 3, false), (6, 6, true), (9, 9, false)]);
+    assert!(samples.iter().all(|sample| sample.checksum_verified == sample.sensitive && sample.peer.is_none()));
+}
+
+#[test]
+fn fragments_held_at_disconnect_count_against_the_session() {
+    let destinations = Destinations::<DuplexStream>::default();
+    let (source, mut transmitter) = duplex();
+    transmitter.write_all(&fragment(b"first part", true)).unwrap();
+    transmitter.write_all(&fragment(b"second part", true)).unwrap();
+    drop(transmitter);
+    let policy = TransmitterPolicy { max_message_size: Some(100), ..TransmitterPolicy::default() };
+
+    run(source, &destinations, policy);
+
+    let metrics = destinations.metrics();
+    assert_eq!(metrics.frames_rejected.get(), 2);
+    assert_eq!(metrics.transmitter_session.rejected.get(), 2);
+    assert_eq!(metrics.frames_relayed.get(), 0);
+}
+
+// Sensitive frames the relay drops before checking their checksums: over a 64-byte
+// payload limit, or flagged as control or compressed frames only the relay sends.
+fn flood() -> Vec<u8> {
+    let mut input = Vec::new();
+    for flag in [0, 0x20, 0x04] {
+        for _ in 0..4 {
+            let mut frame = build_frame(&[0xAB; 100], true).unwrap();
+            if flag != 0 {
+                frame.truncate(8 + 32);
+                frame[2..4].copy_from_slice(&32u16.to_be_bytes());
+                frame[1] |= flag;
+            }
+            input.extend(frame);
+        }
+    }
+    input
+}
+
+#[test]
+fn frames_dropped_before_verification_leave_the_checksum_budget_alone() {
+    for overload in [ChecksumOverload::Drop, ChecksumOverload::Throttle] {
+        let destinations = Destinations::<DuplexStream>::default().with_checksum_limit(5, overload);
+        let (source, mut transmitter) = duplex();
+        transmitter.write_all(&flood()).unwrap();
+        // exactly the budget's worth of frames that need verifying
+        for i in 0..5u8 {
+            transmitter.write_all(&build_frame(&[i; 16], true).unwrap()).unwrap();
+        }
+        drop(transmitter);
+        let policy = TransmitterPolicy { max_payload: Some(64), ..TransmitterPolicy::default() };
+
+        let started = Instant::now();
+        run(source, &destinations, policy);
+
+        let metrics = destinations.metrics();
+        assert_eq!(metrics.frames_relayed.get(), 5, "{:?}", overload);
+        assert_eq!(metrics.frames_rejected.get(), 12, "{:?}", overload);
+        assert_eq!(metrics.checksums_throttled.get(), 0, "{:?}", overload);
+        // nothing was held back for the budget to refill
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", overload);
+    }
+}
+
+#[test]
+fn sensitive_frames_over_the_checksum_budget_are_dropped_or_held() {
+    for overload in [ChecksumOverload::Drop, ChecksumOverload::Throttle] {
+        let destinations = Destinations::<DuplexStream>::default().with_checksum_limit(5, overload);
+        let (server_end, receiver) = duplex();
+        destinations.add(server_end);
+        let (source, mut transmitter) = duplex();
+        for i in 0..8u8 {
+            transmitter.write_all(&build_frame(&[i; 16], true).unwrap()).unwrap();
+        }
+        transmitter.write_all(&build_frame(b"routine", false).unwrap()).unwrap();
+        drop(transmitter);
+
+        let started = Instant::now();
+        run(source, &destinations, TransmitterPolicy::default());
+
+        let metrics = destinations.metrics();
+        let mut receiver = receiver;
+        let relayed: Vec<_> = (0..metrics.frames_relayed.get())
+            .map(|_| read_frame(&mut receiver).unwrap().into_payload())
+            .collect();
+        match overload {
+            ChecksumOverload::Drop => {
+                // the first five are verified, the rest dropped, and the routine frame still goes out
+                assert_eq!(relayed.len(), 6);
+                assert_eq!(relayed[4], [4; 16]);
+                assert_eq!(relayed[5], b"routine");
+                assert_eq!(metrics.frames_rejected.get(), 3);
+                assert_eq!(metrics.checksums_throttled.get(), 3);
+                assert!(started.elapsed() < Duration::from_millis(500));
+            }
+            ChecksumOverload::Throttle => {
+                // the transmitter is held once, until the next second's budget, and nothing is lost
+                assert_eq!(relayed.len(), 9);
+                assert_eq!(relayed[8], b"routine");
+                assert_eq!(metrics.frames_rejected.get(), 0);
+                assert_eq!(metrics.checksums_throttled.get(), 1);
+                assert!(started.elapsed() >= Duration::from_millis(500), "{:?}", started.elapsed());
+            }
+        }
+    }
+}